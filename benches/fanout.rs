@@ -0,0 +1,105 @@
+//! Throughput of preparing a relayed activity for delivery to every subscriber: the
+//! message is serialized once and each delivery then only needs its own signature.
+//!
+//! Run with `cargo bench --bench fanout`.
+use actiserve::{
+    client::new_priv_key_pem,
+    config::SignatureAlgorithm,
+    delivery::Delivery,
+    signature::{sign_request_headers, Body},
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs1v15::SigningKey, RsaPrivateKey};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+const KEY_ID: &str = "https://relay.example/actor#main-key";
+
+fn announce() -> Value {
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": "https://relay.example/activities/8c5ef1a4-54d0-4c0c-9d3b-1e7f1c6a5f0e",
+        "type": "Announce",
+        "actor": "https://relay.example/actor",
+        "to": ["https://relay.example/followers"],
+        "object": {
+            "id": "https://origin.example/users/alice/statuses/1",
+            "type": "Note",
+            "attributedTo": "https://origin.example/users/alice",
+            "content": "<p>".to_owned() + &"Hello fediverse! ".repeat(40) + "</p>",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "tag": [{ "type": "Hashtag", "name": "#relay" }],
+        },
+    })
+}
+
+fn inboxes(n: usize) -> Vec<String> {
+    (0..n)
+        .map(|i| format!("https://instance{i}.example/inbox"))
+        .collect()
+}
+
+fn fanout(c: &mut Criterion) {
+    let pem = new_priv_key_pem().expect("to generate a key");
+    let key: SigningKey<Sha256> = RsaPrivateKey::from_pkcs1_pem(&pem)
+        .expect("generated key to be valid")
+        .into();
+    let message = announce();
+
+    let mut group = c.benchmark_group("fanout");
+    group.sample_size(10);
+
+    for n in [100, 1_000] {
+        let inboxes = inboxes(n);
+        group.throughput(Throughput::Elements(n as u64));
+
+        group.bench_with_input(BenchmarkId::new("serialize", n), &inboxes, |b, inboxes| {
+            b.iter(|| Delivery::fanout("relay", inboxes, &message))
+        });
+
+        // What delivering used to cost: serializing and hashing the body per inbox
+        group.bench_with_input(
+            BenchmarkId::new("sign_per_delivery_body", n),
+            &inboxes,
+            |b, inboxes| {
+                b.iter(|| {
+                    for inbox in inboxes {
+                        let body = Body::new(serde_json::to_vec(&message).unwrap());
+                        sign_request_headers(
+                            KEY_ID,
+                            inbox,
+                            Some(&body),
+                            &key,
+                            SignatureAlgorithm::RsaSha256,
+                        )
+                        .unwrap();
+                    }
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("sign_shared_body", n),
+            &inboxes,
+            |b, inboxes| {
+                b.iter(|| {
+                    for d in Delivery::fanout("relay", inboxes, &message) {
+                        sign_request_headers(
+                            KEY_ID,
+                            &d.inbox,
+                            Some(&d.body),
+                            &key,
+                            SignatureAlgorithm::RsaSha256,
+                        )
+                        .unwrap();
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,176 @@
+//! Cost of taking a signed Create through every stage of the inbox pipeline, from the
+//! blocklist check to queueing the deliveries relaying it to each subscriber.
+//!
+//! Fetching the sending actor is replaced by a stage that hands back a pre-built actor
+//! so that nothing here touches the network.
+//!
+//! Run with `cargo bench --bench inbox`.
+use actiserve::{
+    actors::RelayActor,
+    client::{new_priv_key_pem, RemoteActor},
+    config::{Config, SignatureAlgorithm},
+    pipeline::{ActivityType, Flow, Inbound, Stage},
+    signature::{sign_request_headers, Body},
+    state::{Db, State},
+    Result,
+};
+use axum::{async_trait, http::HeaderMap};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding},
+    pkcs1v15::SigningKey,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{env::temp_dir, fs::remove_dir_all, path::Path};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+const HOST: &str = "relay.example";
+const ACTOR_ID: &str = "https://origin.example/actor";
+const KEY_ID: &str = "https://origin.example/actor#main-key";
+
+/// Stands in for fetching the sending actor from its server.
+#[derive(Debug)]
+struct MockFetchActor {
+    actor: Value,
+}
+
+#[async_trait]
+impl Stage for MockFetchActor {
+    fn name(&self) -> &'static str {
+        "mock_fetch_actor"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, _: &State) -> Result<Flow> {
+        inbound.actor = Some(RemoteActor::from_json(ACTOR_ID, self.actor.clone())?);
+
+        Ok(Flow::Continue)
+    }
+}
+
+fn state(dir: &Path, pub_key_pem: &str, n_subscribers: usize) -> State {
+    let cfg: Config = serde_yaml::from_str(&format!(
+        r#"
+listen: 127.0.0.1
+port: 4242
+dataDir: {}
+privateKeyPath: private-key.pem
+activityPub:
+  host: {HOST}
+  blockedInstances: []
+  allowList: false
+  allowedInstances: []
+"#,
+        dir.display()
+    ))
+    .expect("bench config to be valid");
+    let db = Db::new(dir.to_path_buf()).expect("to create the database");
+    let relay_key = new_priv_key_pem().expect("to generate a key");
+    let mut state = State::new(cfg, db, &relay_key).expect("to create the state");
+
+    let actor = json!({
+        "id": ACTOR_ID,
+        "type": "Application",
+        "inbox": "https://origin.example/inbox",
+        "endpoints": { "sharedInbox": "https://origin.example/inbox" },
+        "publicKey": { "id": KEY_ID, "owner": ACTOR_ID, "publicKeyPem": pub_key_pem },
+    });
+    assert!(state
+        .pipeline
+        .replace("fetch_actor", Box::new(MockFetchActor { actor })));
+
+    state
+        .db
+        .add_inbox_if_unknown("https://origin.example/inbox".into(), None)
+        .unwrap();
+    for i in 0..n_subscribers {
+        state
+            .db
+            .add_inbox_if_unknown(format!("https://instance{i}.example/inbox"), None)
+            .unwrap();
+    }
+
+    state
+}
+
+// Each activity needs its own id as repeats are dropped by the dedup stage
+fn signed_create(key: &SigningKey<Sha256>) -> (Value, Body, HeaderMap) {
+    let id = Uuid::new_v4();
+    let activity = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("https://origin.example/statuses/{id}/activity"),
+        "type": "Create",
+        "actor": ACTOR_ID,
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        "object": {
+            "id": format!("https://origin.example/statuses/{id}"),
+            "type": "Note",
+            "attributedTo": ACTOR_ID,
+            "content": "<p>".to_owned() + &"Hello fediverse! ".repeat(40) + "</p>",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        },
+    });
+    let body = Body::new(serde_json::to_vec(&activity).unwrap());
+    let headers = sign_request_headers(
+        KEY_ID,
+        &format!("https://{HOST}/inbox"),
+        Some(&body),
+        key,
+        SignatureAlgorithm::RsaSha256,
+    )
+    .expect("to sign the request");
+
+    (activity, body, headers)
+}
+
+fn inbox(c: &mut Criterion) {
+    let rt = Runtime::new().expect("to start a runtime");
+    let pem = new_priv_key_pem().expect("to generate a key");
+    let priv_key = RsaPrivateKey::from_pkcs1_pem(&pem).expect("generated key to be valid");
+    let pub_key_pem = RsaPublicKey::from(&priv_key)
+        .to_pkcs1_pem(LineEnding::default())
+        .expect("to encode the public key");
+    let key: SigningKey<Sha256> = priv_key.into();
+
+    let mut group = c.benchmark_group("inbox");
+    group.sample_size(20);
+
+    for n in [10, 100] {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let state = state(&dir, &pub_key_pem, n);
+
+        group.bench_with_input(BenchmarkId::new("pipeline", n), &state, |b, state| {
+            b.iter_batched(
+                || signed_create(&key),
+                |(activity, body, headers)| {
+                    let mut inbound = Inbound {
+                        relay: RelayActor::main(state),
+                        headers: &headers,
+                        host: HOST,
+                        path: "/inbox",
+                        body: body.bytes(),
+                        ty: ActivityType::Create,
+                        actor_id: ACTOR_ID.to_owned(),
+                        activity,
+                        actor: None,
+                    };
+                    let flow = rt
+                        .block_on(state.pipeline.run(&mut inbound, state))
+                        .expect("the activity to be accepted");
+                    assert_eq!(flow, Flow::Continue);
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, inbox);
+criterion_main!(benches);
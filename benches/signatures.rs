@@ -0,0 +1,99 @@
+//! Cost of verifying the HTTP signature of an inbox request, which every activity
+//! POSTed to one of our inboxes pays before we respond.
+//!
+//! Run with `cargo bench --bench signatures`.
+use actiserve::{
+    client::new_priv_key_pem,
+    config::SignatureAlgorithm,
+    signature::{check_signature, sign_request_headers, Body, Outcome},
+};
+use axum::http::{HeaderMap, HeaderValue};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding},
+    pkcs1v15::SigningKey,
+    RsaPrivateKey, RsaPublicKey,
+};
+use rustypub::extended::{Actor, ActorBuilder, PublicKeyInfo};
+use serde_json::json;
+use sha2::Sha256;
+
+const ACTOR_ID: &str = "https://origin.example/actor";
+const KEY_ID: &str = "https://origin.example/actor#main-key";
+const INBOX: &str = "https://relay.example/inbox";
+
+fn signatures(c: &mut Criterion) {
+    let pem = new_priv_key_pem().expect("to generate a key");
+    let priv_key = RsaPrivateKey::from_pkcs1_pem(&pem).expect("generated key to be valid");
+    let pub_key_pem = RsaPublicKey::from(&priv_key)
+        .to_pkcs1_pem(LineEnding::default())
+        .expect("to encode the public key");
+    let key: SigningKey<Sha256> = priv_key.into();
+    let actor: Actor = ActorBuilder::new("Application".to_owned())
+        .id(ACTOR_ID.parse().unwrap())
+        .inbox("https://origin.example/inbox".to_owned())
+        .public_key_info(PublicKeyInfo {
+            id: KEY_ID.to_owned(),
+            owner: ACTOR_ID.to_owned(),
+            public_key_pem: pub_key_pem,
+        })
+        .build();
+
+    let activity = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": "https://origin.example/users/alice/statuses/1/activity",
+        "type": "Create",
+        "actor": "https://origin.example/users/alice",
+        "object": {
+            "id": "https://origin.example/users/alice/statuses/1",
+            "type": "Note",
+            "content": "<p>".to_owned() + &"Hello fediverse! ".repeat(40) + "</p>",
+        },
+    });
+    let body = Body::new(serde_json::to_vec(&activity).unwrap());
+
+    let mut group = c.benchmark_group("signatures");
+    group.throughput(Throughput::Elements(1));
+
+    for algorithm in [SignatureAlgorithm::RsaSha256, SignatureAlgorithm::Hs2019] {
+        let headers = sign_request_headers(KEY_ID, INBOX, Some(&body), &key, algorithm)
+            .expect("to sign the request");
+        assert_eq!(
+            check_signature(&actor, "post", "/inbox", &headers, body.bytes()),
+            Outcome::Ok
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("verify", format!("{algorithm:?}")),
+            &headers,
+            |b, headers| {
+                b.iter(|| check_signature(&actor, "post", "/inbox", headers, body.bytes()))
+            },
+        );
+    }
+
+    // Requests whose body has been tampered with are rejected before the (much more
+    // expensive) RSA verification
+    let mut tampered = sign_request_headers(
+        KEY_ID,
+        INBOX,
+        Some(&body),
+        &key,
+        SignatureAlgorithm::RsaSha256,
+    )
+    .expect("to sign the request");
+    tampered.insert("digest", HeaderValue::from_static("SHA-256=AAAA"));
+    group.bench_function("reject_bad_digest", |b| {
+        b.iter(|| check_signature(&actor, "post", "/inbox", &tampered, body.bytes()))
+    });
+
+    let unsigned = HeaderMap::new();
+    group.bench_function("reject_missing_signature", |b| {
+        b.iter(|| check_signature(&actor, "post", "/inbox", &unsigned, body.bytes()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, signatures);
+criterion_main!(benches);
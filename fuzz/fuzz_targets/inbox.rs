@@ -0,0 +1,77 @@
+#![no_main]
+//! POSTs arbitrary bodies and signature headers to the relay's inbox, taking them
+//! through JSON parsing, JSON-LD normalization and the inbox pipeline.
+use actiserve::{
+    fuzzing::{offline_state, HOST},
+    routes::build_routes,
+    state::State,
+};
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Request},
+};
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+use std::{
+    env::temp_dir,
+    sync::{Arc, OnceLock},
+};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    path: InboxPath,
+    signature: Option<String>,
+    digest: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Arbitrary)]
+enum InboxPath {
+    Inbox,
+    ActorInbox,
+    TopicInbox(String),
+}
+
+fn harness() -> &'static (Runtime, Arc<State>) {
+    static HARNESS: OnceLock<(Runtime, Arc<State>)> = OnceLock::new();
+
+    HARNESS.get_or_init(|| {
+        let mut dir = temp_dir();
+        dir.push(format!("actiserve-fuzz-{}", uuid::Uuid::new_v4()));
+        let state = offline_state(dir).expect("to create the offline state");
+
+        (Runtime::new().expect("to start a runtime"), Arc::new(state))
+    })
+}
+
+fuzz_target!(|input: Input| {
+    let (rt, state) = harness();
+    let uri = match input.path {
+        InboxPath::Inbox => "/inbox".to_owned(),
+        InboxPath::ActorInbox => "/actor/inbox".to_owned(),
+        InboxPath::TopicInbox(name) => format!("/actors/{name}/inbox"),
+    };
+
+    let mut req = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header(header::HOST, HOST)
+        .header(header::CONTENT_TYPE, "application/activity+json")
+        .header(header::CONTENT_LENGTH, input.body.len());
+    // Values that can't be sent as headers never reach us
+    for (name, value) in [("signature", input.signature), ("digest", input.digest)] {
+        if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            req = req.header(name, value);
+        }
+    }
+    let req = match req.body(Body::from(input.body)) {
+        Ok(req) => req,
+        // e.g. a topic name that isn't valid in a path
+        Err(_) => return,
+    };
+
+    let app = build_routes(state.clone());
+    rt.block_on(app.oneshot(req))
+        .expect("the router to be infallible");
+});
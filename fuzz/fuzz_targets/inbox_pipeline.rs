@@ -0,0 +1,78 @@
+#![no_main]
+//! Takes arbitrary activities through the stages of the inbox pipeline that are run
+//! once a request has been accepted, as though they had been sent by an actor whose
+//! signature checked out. Requests to the inbox itself rarely get that far as they
+//! would need to be signed with the relay's key.
+use actiserve::{
+    actors::RelayActor,
+    fuzzing::{offline_actor, offline_state, ACCEPT_AFTER_STAGE, ACTOR_ID, HOST},
+    pipeline::{ActivityType, Inbound},
+    state::State,
+};
+use axum::http::HeaderMap;
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+use serde_json::Value;
+use std::{
+    env::temp_dir,
+    sync::{Arc, OnceLock},
+};
+use tokio::runtime::Runtime;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    path: InboxPath,
+    /// The request body, which carries the activity along with its type and actor
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Arbitrary)]
+enum InboxPath {
+    Inbox,
+    ActorInbox,
+}
+
+fn harness() -> &'static (Runtime, Arc<State>) {
+    static HARNESS: OnceLock<(Runtime, Arc<State>)> = OnceLock::new();
+
+    HARNESS.get_or_init(|| {
+        let mut dir = temp_dir();
+        dir.push(format!("actiserve-fuzz-{}", uuid::Uuid::new_v4()));
+        let state = offline_state(dir).expect("to create the offline state");
+
+        (Runtime::new().expect("to start a runtime"), Arc::new(state))
+    })
+}
+
+fuzz_target!(|input: Input| {
+    let (rt, state) = harness();
+    // Anything that isn't JSON is refused before it reaches the pipeline
+    let activity = match serde_json::from_slice::<Value>(&input.body) {
+        Ok(req) => req["activity"].clone(),
+        Err(_) => return,
+    };
+    let path = match input.path {
+        InboxPath::Inbox => "/inbox",
+        InboxPath::ActorInbox => "/actor/inbox",
+    };
+
+    let headers = HeaderMap::new();
+    let mut inbound = Inbound {
+        relay: RelayActor::main(state),
+        headers: &headers,
+        host: HOST,
+        path,
+        body: &input.body,
+        ty: ActivityType::from_value(&activity["type"]),
+        actor_id: ACTOR_ID.to_owned(),
+        activity,
+        actor: Some(offline_actor(state).expect("the offline actor to be valid")),
+    };
+
+    // Errors are expected (most activities are rejected somewhere along the way), panics
+    // are not
+    let _ = rt.block_on(
+        state
+            .pipeline
+            .run_after(ACCEPT_AFTER_STAGE, &mut inbound, state),
+    );
+});
@@ -0,0 +1,11 @@
+#![no_main]
+use actiserve::fuzzing::split_signature;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    if let Ok(params) = split_signature(s) {
+        for (k, v) in params {
+            assert!(s.contains(k) && s.contains(v));
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use actiserve::fuzzing::parse_webfinger_resource;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|resource: &str| {
+    if let Ok((user, domain)) = parse_webfinger_resource(resource) {
+        assert_eq!(resource, format!("acct:{user}@{domain}"));
+    }
+});
@@ -0,0 +1,95 @@
+//! The page describing the relay to other instances, served at /about.
+//!
+//! The operator's rules are read from `about.md` in the data directory (or the configured
+//! path) and followed by the relay's acceptance policy and the operator's contact details
+//! from the config. The page is rendered once at startup.
+use crate::{config::Config, Error, Result};
+use axum::http::StatusCode;
+use pulldown_cmark::{html, Parser};
+use std::{fs, io::ErrorKind};
+use tracing::info;
+
+/// The name of the file in the data directory that the relay's rules are read from
+pub const ABOUT_FILE: &str = "about.md";
+
+const DEFAULT_RULES: &str = "# About this relay
+
+This is an ActivityPub relay, sharing public posts between the instances subscribed to it.";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct About {
+    pub markdown: String,
+    pub html: String,
+}
+
+impl About {
+    pub fn load(cfg: &Config) -> Result<Self> {
+        let path = match &cfg.operator.about_path {
+            Some(path) => path.clone(),
+            None => cfg.data_dir.join(ABOUT_FILE),
+        };
+
+        let rules = match fs::read_to_string(&path) {
+            Ok(rules) => {
+                info!(path = %path.display(), "loaded about page");
+                rules
+            }
+            // Only a configured path has to exist
+            Err(e) if e.kind() == ErrorKind::NotFound && cfg.operator.about_path.is_none() => {
+                DEFAULT_RULES.to_owned()
+            }
+            Err(_) => {
+                return Err(Error::StatusAndMessage {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: "unable to read about page",
+                })
+            }
+        };
+
+        Ok(Self::new(&rules, cfg))
+    }
+
+    pub fn new(rules: &str, cfg: &Config) -> Self {
+        let mut sections = vec![rules.trim().to_owned(), policy_section(cfg)];
+        sections.extend(contact_section(cfg));
+        let markdown = sections.join("\n\n");
+
+        let mut html = String::new();
+        html::push_html(&mut html, Parser::new(&markdown));
+
+        Self { markdown, html }
+    }
+}
+
+fn policy_section(cfg: &Config) -> String {
+    let mut section = String::from("## Accepted instances\n\n");
+    if cfg.activity_pub.allow_list {
+        section.push_str("Only instances on an approved list may subscribe to this relay.");
+    } else {
+        section.push_str("Any instance that has not been blocked may subscribe to this relay.");
+    }
+
+    if let Some(summary) = &cfg.operator.policy_summary {
+        section.push_str("\n\n");
+        section.push_str(summary.trim());
+    }
+
+    section
+}
+
+fn contact_section(cfg: &Config) -> Option<String> {
+    let operator = &cfg.operator;
+    let mut lines = vec![];
+    if let Some(account) = &operator.contact_account {
+        lines.push(format!("- Fediverse: <{account}>"));
+    }
+    if let Some(email) = &operator.contact_email {
+        lines.push(format!("- Email: <{email}>"));
+    }
+
+    if lines.is_empty() {
+        return None;
+    }
+
+    Some(format!("## Contact\n\n{}", lines.join("\n")))
+}
@@ -0,0 +1,98 @@
+//! The types of activity that we recognise.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// The types of activity that we know how to handle (or at least recognise). Anything
+/// else is deserialized as `Other` so that unknown types can't be used to create an
+/// unbounded number of metric labels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ActivityType {
+    Accept,
+    Add,
+    Announce,
+    Create,
+    Delete,
+    EmojiReact,
+    Follow,
+    Like,
+    Reject,
+    Remove,
+    Undo,
+    Update,
+    #[serde(other)]
+    Other,
+}
+
+impl ActivityType {
+    /// Parse the `type` of an activity or object, which may be missing or (when sent
+    /// as JSON-LD) a list of types, in which case the first that we recognise is used.
+    pub fn from_value(value: &Value) -> Self {
+        match value {
+            Value::Array(types) => types
+                .iter()
+                .map(Self::from_value)
+                .find(|ty| *ty != Self::Other)
+                .unwrap_or(Self::Other),
+            _ => Self::deserialize(value).unwrap_or(Self::Other),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accept => "Accept",
+            Self::Add => "Add",
+            Self::Announce => "Announce",
+            Self::Create => "Create",
+            Self::Delete => "Delete",
+            Self::EmojiReact => "EmojiReact",
+            Self::Follow => "Follow",
+            Self::Like => "Like",
+            Self::Reject => "Reject",
+            Self::Remove => "Remove",
+            Self::Undo => "Undo",
+            Self::Update => "Update",
+            Self::Other => "Other",
+        }
+    }
+
+    /// Whether activities of this type are relayed on to other subscribers
+    pub fn is_relayable(&self) -> bool {
+        matches!(
+            self,
+            Self::Add | Self::Announce | Self::Create | Self::Delete | Self::Remove | Self::Update
+        )
+    }
+
+    /// Whether this is a reaction to an object, which is only forwarded if enabled
+    pub fn is_reaction(&self) -> bool {
+        matches!(self, Self::EmojiReact | Self::Like)
+    }
+}
+
+impl fmt::Display for ActivityType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    #[test_case(json!("Follow"), ActivityType::Follow; "known")]
+    #[test_case(json!("Add"), ActivityType::Add; "add")]
+    #[test_case(json!("Remove"), ActivityType::Remove; "remove")]
+    #[test_case(json!("EmojiReact"), ActivityType::EmojiReact; "emoji react")]
+    #[test_case(json!("Arrive"), ActivityType::Other; "unknown")]
+    #[test_case(json!(null), ActivityType::Other; "missing")]
+    #[test_case(json!(["Create"]), ActivityType::Create; "list")]
+    #[test_case(json!(["Hashtag", "Create"]), ActivityType::Create; "list with unknown types")]
+    #[test_case(json!(42), ActivityType::Other; "not a string")]
+    #[test]
+    fn activity_types_are_parsed(value: Value, expected: ActivityType) {
+        assert_eq!(ActivityType::from_value(&value), expected);
+    }
+}
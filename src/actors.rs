@@ -0,0 +1,263 @@
+//! Relay actors served by this process.
+//!
+//! Alongside the main relay actor on /actor, additional topic actors can be configured
+//! on /actors/{name}. Each actor has its own set of followers and only relays posts
+//! sent to its own inbox, effectively running multiple relays in a single process.
+use crate::{
+    config::{ActorConfig, PassphraseConfig},
+    signer::ActorKey,
+    state::{Db, State},
+    Error, Result,
+};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+// Name of the generated private key file within an actor's data directory
+const GENERATED_KEY_FILE: &str = "private-key.pem";
+
+/// The name of the main relay actor.
+pub const DEFAULT_ACTOR: &str = "relay";
+
+/// The path that the named actor is served on.
+pub fn actor_path(name: &str) -> String {
+    if name == DEFAULT_ACTOR {
+        "/actor".to_owned()
+    } else {
+        format!("/actors/{name}")
+    }
+}
+
+/// The directory that the named topic actor keeps its data in.
+pub fn actor_data_dir(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join("actors").join(name)
+}
+
+/// Profile metadata for a relay actor set via the admin API, taking precedence over the
+/// defaults and anything given in the config.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ActorMetadata {
+    /// The display name of the actor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The summary shown on the actor's profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// A topic actor configured in addition to the main relay actor.
+#[derive(Debug)]
+pub struct TopicActor {
+    pub cfg: ActorConfig,
+    pub db: Db,
+    dir: PathBuf,
+}
+
+impl TopicActor {
+    /// Open the actor's follower state from its own directory under the data dir.
+    pub fn open(cfg: ActorConfig, data_dir: &Path) -> Result<Self> {
+        check_name(&cfg.name)?;
+        let dir = actor_data_dir(data_dir, &cfg.name);
+        let db = Db::new(dir.clone())?;
+
+        Ok(Self { cfg, db, dir })
+    }
+
+    /// Check that an actor could be opened with the given config: that its name is valid
+    /// and that its private key loads, unless one is still to be generated.
+    pub fn check(cfg: &ActorConfig, data_dir: &Path, passphrase: &PassphraseConfig) -> Result<()> {
+        check_name(&cfg.name)?;
+        let path = key_path(cfg, &actor_data_dir(data_dir, &cfg.name));
+        if cfg.private_key_path.is_none() && !path.exists() {
+            return Ok(());
+        }
+
+        ActorKey::load_pem(&path, passphrase).map(|_| ())
+    }
+
+    /// Load the actor's private key (which may be encrypted, in the same way as the main
+    /// relay actor's), generating one in the actor's data directory if no path has been
+    /// configured and we haven't already done so.
+    pub fn key(&self, passphrase: &PassphraseConfig) -> Result<ActorKey> {
+        let path = key_path(&self.cfg, &self.dir);
+
+        if self.cfg.private_key_path.is_none() && !path.exists() {
+            info!(actor = %self.cfg.name, path = %path.display(), "generating private key");
+            return ActorKey::generate(&path);
+        }
+
+        ActorKey::load_pem(&path, passphrase)
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+
+    if !valid_name || name == DEFAULT_ACTOR {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "invalid actor name",
+        });
+    }
+
+    Ok(())
+}
+
+// The configured path of an actor's private key, or that of the key generated for it
+fn key_path(cfg: &ActorConfig, dir: &Path) -> PathBuf {
+    match &cfg.private_key_path {
+        Some(path) => path.clone(),
+        None => dir.join(GENERATED_KEY_FILE),
+    }
+}
+
+/// A view of one of the relay actors served by this process.
+#[derive(Debug, Clone, Copy)]
+pub struct RelayActor<'a> {
+    pub name: &'a str,
+    pub summary: Option<&'a str>,
+    pub db: &'a Db,
+    tags: &'a [String],
+}
+
+impl<'a> RelayActor<'a> {
+    /// The main relay actor.
+    pub fn main(state: &'a State) -> Self {
+        Self {
+            name: DEFAULT_ACTOR,
+            summary: None,
+            db: &state.db,
+            tags: &[],
+        }
+    }
+
+    pub fn topic(actor: &'a TopicActor) -> Self {
+        Self {
+            name: &actor.cfg.name,
+            summary: actor.cfg.summary.as_deref(),
+            db: &actor.db,
+            tags: &actor.cfg.tags,
+        }
+    }
+
+    /// The id of this actor when served on the given host
+    pub fn id(&self, host: &str) -> String {
+        format!("https://{host}{}", actor_path(self.name))
+    }
+
+    /// The followers collection of this actor when served on the given host
+    pub fn followers(&self, host: &str) -> String {
+        match self.name {
+            DEFAULT_ACTOR => format!("https://{host}/followers"),
+            name => format!("https://{host}/actors/{name}/followers"),
+        }
+    }
+
+    /// Whether or not an activity should be relayed by this actor. Topic actors with
+    /// tags configured only relay posts carrying at least one of their hashtags.
+    pub fn accepts(&self, activity: &Value) -> bool {
+        if self.tags.is_empty() {
+            return true;
+        }
+
+        let tags = match activity["object"]["tag"].as_array() {
+            Some(tags) => tags,
+            None => return false,
+        };
+
+        tags.iter()
+            .filter(|tag| tag["type"] == "Hashtag")
+            .filter_map(|tag| tag["name"].as_str())
+            .map(|name| name.trim_start_matches('#'))
+            .any(|name| self.tags.iter().any(|t| t.eq_ignore_ascii_case(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    #[test_case("relay", "/actor"; "main actor")]
+    #[test_case("art", "/actors/art"; "topic actor")]
+    #[test]
+    fn actor_path_works(name: &str, expected: &str) {
+        assert_eq!(actor_path(name), expected);
+    }
+
+    #[test_case(json!({ "object": { "tag": [{ "type": "Hashtag", "name": "#Art" }] } }), true; "matching tag")]
+    #[test_case(json!({ "object": { "tag": [{ "type": "Hashtag", "name": "#linux" }] } }), false; "other tag")]
+    #[test_case(json!({ "object": { "tag": [{ "type": "Mention", "name": "@art" }] } }), false; "mention")]
+    #[test_case(json!({ "object": "https://example.com/objects/1" }), false; "object not embedded")]
+    #[test]
+    fn tagged_actors_only_accept_matching_posts(activity: Value, expected: bool) {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+
+        let actor = TopicActor::open(
+            ActorConfig {
+                name: "art".into(),
+                summary: None,
+                tags: vec!["art".into()],
+                private_key_path: None,
+            },
+            &dir,
+        )
+        .unwrap();
+
+        assert_eq!(RelayActor::topic(&actor).accepts(&activity), expected);
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn generated_keys_are_only_readable_by_us() {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+
+        let actor = TopicActor::open(
+            ActorConfig {
+                name: "art".into(),
+                summary: None,
+                tags: vec![],
+                private_key_path: None,
+            },
+            &dir,
+        )
+        .unwrap();
+        let passphrase = PassphraseConfig::default();
+        let key = actor.key(&passphrase).unwrap();
+
+        assert_eq!(actor.key(&passphrase).unwrap().pub_key(), key.pub_key());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = actor.dir.join(GENERATED_KEY_FILE);
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("relay"; "main actor name")]
+    #[test_case("Art"; "uppercase")]
+    #[test_case("../art"; "path")]
+    #[test_case(""; "empty")]
+    #[test]
+    fn invalid_actor_names_are_rejected(name: &str) {
+        let cfg = ActorConfig {
+            name: name.into(),
+            summary: None,
+            tags: vec![],
+            private_key_path: None,
+        };
+
+        assert!(TopicActor::open(cfg, &std::env::temp_dir()).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! OAuth2 client credentials for the admin API.
+//!
+//! Operators register clients (via the admin API) for external tools, which can then
+//! exchange their client id and secret for a short lived access token. Client secrets
+//! are only ever stored hashed and are not retrievable after the client is created.
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Mutex};
+use subtle::ConstantTimeEq;
+
+/// How long an issued access token is valid for
+pub const TOKEN_TTL_SECS: i64 = 60 * 60;
+
+/// Permissions that can be granted to an access token. The admin token is implicitly
+/// granted every scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    /// Read-only access to instances, metrics and delivery status
+    #[serde(rename = "read:stats")]
+    ReadStats,
+    /// Managing blocked domains
+    #[serde(rename = "write:blocks")]
+    WriteBlocks,
+    /// Pausing, releasing and otherwise managing subscribed instances and deliveries
+    #[serde(rename = "write:instances")]
+    WriteInstances,
+    /// Reading the history of what has been relayed
+    #[serde(rename = "read:audit")]
+    ReadAudit,
+    /// Publishing statuses as the relay actor
+    #[serde(rename = "write:statuses")]
+    WriteStatuses,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ReadStats => "read:stats",
+            Self::WriteBlocks => "write:blocks",
+            Self::WriteInstances => "write:instances",
+            Self::ReadAudit => "read:audit",
+            Self::WriteStatuses => "write:statuses",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read:stats" => Ok(Self::ReadStats),
+            "write:blocks" => Ok(Self::WriteBlocks),
+            "write:instances" => Ok(Self::WriteInstances),
+            "read:audit" => Ok(Self::ReadAudit),
+            "write:statuses" => Ok(Self::WriteStatuses),
+            _ => Err(format!("unknown scope: {s}")),
+        }
+    }
+}
+
+/// A registered OAuth2 client as it is stored. Use [ClientInfo] when returning clients
+/// via the admin API so that the secret hash is never exposed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthClient {
+    pub client_id: String,
+    /// A human readable name for the client
+    pub name: String,
+    /// Clients stored before the hash was persisted have none and must be registered
+    /// again
+    #[serde(default)]
+    pub secret_hash: String,
+    /// The scopes that tokens issued to this client may be granted
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OAuthClient {
+    /// Create a new client, returning it along with its (unhashed) secret.
+    pub fn new(name: String, scopes: Vec<Scope>) -> (Self, String) {
+        let secret = random_token();
+        let client = Self {
+            client_id: random_token(),
+            name,
+            secret_hash: hash_secret(&secret),
+            scopes,
+            created_at: Utc::now(),
+        };
+
+        (client, secret)
+    }
+
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        !self.secret_hash.is_empty() && constant_time_eq(&self.secret_hash, &hash_secret(secret))
+    }
+}
+
+/// The details of an [OAuthClient] returned via the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<OAuthClient> for ClientInfo {
+    fn from(client: OAuthClient) -> Self {
+        Self {
+            client_id: client.client_id,
+            name: client.name,
+            scopes: client.scopes,
+            created_at: client.created_at,
+        }
+    }
+}
+
+/// An access token issued via the token endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IssuedToken {
+    pub client_id: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Access tokens that have been issued to clients. These are only held in memory:
+/// clients simply request a new token if the server restarts.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    // map of hashed token to the details of what it was issued for
+    issued: Mutex<HashMap<String, IssuedToken>>,
+}
+
+impl Tokens {
+    /// Issue a new access token for the given client and scopes.
+    pub fn issue(&self, client_id: &str, scopes: Vec<Scope>) -> (String, IssuedToken) {
+        let token = random_token();
+        let issued = IssuedToken {
+            client_id: client_id.to_owned(),
+            scopes,
+            expires_at: Utc::now() + Duration::seconds(TOKEN_TTL_SECS),
+        };
+
+        let mut tokens = self.issued.lock().unwrap();
+        let now = Utc::now();
+        tokens.retain(|_, t| t.expires_at > now);
+        tokens.insert(hash_secret(&token), issued.clone());
+
+        (token, issued)
+    }
+
+    /// Look up a token, returning its details if it is valid and has not expired.
+    pub fn verify(&self, token: &str) -> Option<IssuedToken> {
+        let hash = hash_secret(token);
+
+        self.issued
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(issued, _)| constant_time_eq(issued, &hash))
+            .map(|(_, t)| t)
+            .filter(|t| t.expires_at > Utc::now())
+            .cloned()
+    }
+
+    /// Revoke all tokens issued to the given client.
+    pub fn revoke_client(&self, client_id: &str) {
+        self.issued
+            .lock()
+            .unwrap()
+            .retain(|_, t| t.client_id != client_id);
+    }
+}
+
+/// Compare two secrets without leaking how much of them matched through timing.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn hash_secret(secret: &str) -> String {
+    base64::encode(Sha256::digest(secret.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_secrets_are_verified() {
+        let (client, secret) = OAuthClient::new("stats".into(), vec![]);
+
+        assert!(client.verify_secret(&secret));
+        assert!(!client.verify_secret("nope"));
+        assert_ne!(client.secret_hash, secret);
+    }
+
+    #[test]
+    fn stored_clients_can_still_be_verified() {
+        let (client, secret) = OAuthClient::new("stats".into(), vec![]);
+        let json = serde_json::to_string(&client).unwrap();
+        let stored: OAuthClient = serde_json::from_str(&json).unwrap();
+
+        assert!(!json.contains(&secret));
+        assert!(stored.verify_secret(&secret));
+    }
+
+    #[test]
+    fn client_info_does_not_include_the_secret_hash() {
+        let (client, _) = OAuthClient::new("stats".into(), vec![]);
+        let hash = client.secret_hash.clone();
+        let json = serde_json::to_string(&ClientInfo::from(client)).unwrap();
+
+        assert!(!json.contains(&hash));
+    }
+
+    #[test]
+    fn clients_stored_without_a_hash_are_rejected() {
+        let stored: OAuthClient = serde_json::from_value(serde_json::json!({
+            "clientId": "old",
+            "name": "stats",
+            "scopes": [],
+            "createdAt": "2023-01-01T00:00:00Z",
+        }))
+        .unwrap();
+
+        assert!(!stored.verify_secret(""));
+    }
+
+    #[test]
+    fn constant_time_eq_works() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secrets"));
+    }
+
+    #[test]
+    fn issued_tokens_can_be_verified_and_revoked() {
+        let tokens = Tokens::default();
+        let (token, issued) = tokens.issue("client", vec![Scope::ReadStats]);
+
+        assert_eq!(tokens.verify(&token), Some(issued));
+        assert_eq!(tokens.verify("nope"), None);
+
+        tokens.revoke_client("client");
+        assert_eq!(tokens.verify(&token), None);
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        for scope in [
+            Scope::ReadStats,
+            Scope::WriteBlocks,
+            Scope::WriteInstances,
+            Scope::ReadAudit,
+            Scope::WriteStatuses,
+        ] {
+            let json = serde_json::to_value(scope).unwrap();
+
+            assert_eq!(json, scope.as_str());
+            assert_eq!(scope.as_str().parse(), Ok(scope));
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! The effective set of blocked instances.
+//!
+//! Blocks come from the relay config and from any remote blocklist feeds that we
+//! subscribe to. We track which source(s) each block came from so that operators can
+//! see why a given domain is being rejected.
+//!
+//! Blocks from the config may apply to a whole registrable domain or to any host
+//! matching a pattern (see [DomainScope]), all others apply to exactly the domain given.
+//!
+//! As with Mastodon's domain blocks, not every block rejects everything from the
+//! instance: see [Severity] for the lighter options.
+use crate::{
+    config::{compile_patterns, DomainRule, DomainRules, DomainScope},
+    util::registrable_domain,
+    Error, Result,
+};
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::RwLock,
+};
+
+/// The source name used for blocks listed directly in the relay config.
+pub const CONFIG_SOURCE: &str = "config";
+
+/// The source name used for blocks added at runtime via the admin API. These are stored
+/// in the [Db](crate::state::Db) so that they persist across restarts.
+pub const ADMIN_SOURCE: &str = "admin";
+
+/// How much of an instance's traffic a block applies to. Severities are ordered from
+/// least to most severe.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Nothing with media attachments is relayed: they are removed from activities that
+    /// we forward as received (unless LD signed) and posts that we announce are dropped
+    StripMedia,
+    /// Follows are accepted but nothing from the instance is relayed to others
+    Silence,
+    /// Everything from the instance is refused
+    #[default]
+    Reject,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StripMedia => "strip_media",
+            Self::Silence => "silence",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+/// A blocked domain along with the sources that are blocking it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Block {
+    pub domain: String,
+    pub scope: DomainScope,
+    pub severity: Severity,
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    // map of source to the rules it blocks and how severely
+    sources: RwLock<BTreeMap<String, BTreeMap<DomainRule, Severity>>>,
+    // the compiled wildcard and regex rules of each source that has any
+    patterns: RwLock<BTreeMap<String, RegexSet>>,
+}
+
+impl Blocklist {
+    pub fn new(blocked_instances: &DomainRules) -> Result<Self> {
+        let blocklist = Self::default();
+        let rules = blocked_instances
+            .rules()
+            .iter()
+            .map(|rule| (rule.clone(), Severity::Reject))
+            .collect();
+        blocklist.set_rules(CONFIG_SOURCE, rules)?;
+
+        Ok(blocklist)
+    }
+
+    /// Replace the domains blocked by the given source. If the rules are invalid the
+    /// source's existing blocks are left in place.
+    pub fn set_source(&self, source: &str, domains: BTreeSet<String>) -> Result<()> {
+        let rules = domains
+            .iter()
+            .map(|d| (DomainRule::from(d.as_str()), Severity::Reject))
+            .collect();
+
+        self.set_rules(source, rules)
+    }
+
+    /// Replace the domains blocked by the given source, each with its own severity.
+    pub fn set_source_with_severities<'a>(
+        &self,
+        source: &str,
+        domains: impl IntoIterator<Item = (&'a String, &'a Severity)>,
+    ) -> Result<()> {
+        let rules = domains
+            .into_iter()
+            .map(|(d, severity)| (DomainRule::from(d.as_str()), *severity))
+            .collect();
+
+        self.set_rules(source, rules)
+    }
+
+    fn set_rules(&self, source: &str, rules: BTreeMap<DomainRule, Severity>) -> Result<()> {
+        let patterns = compile_patterns(rules.keys()).map_err(|e| Error::InvalidConfig {
+            error: format!("invalid block patterns from {source}: {e}"),
+        })?;
+        let mut all_patterns = self.patterns.write().unwrap();
+        if patterns.is_empty() {
+            all_patterns.remove(source);
+        } else {
+            all_patterns.insert(source.to_owned(), patterns);
+        }
+
+        self.sources
+            .write()
+            .unwrap()
+            .insert(source.to_owned(), rules);
+
+        Ok(())
+    }
+
+    /// Add a single domain to those blocked by the given source, returning false if it
+    /// was already blocked by that source with the same severity.
+    pub fn add(&self, source: &str, domain: &str, severity: Severity) -> bool {
+        self.sources
+            .write()
+            .unwrap()
+            .entry(source.to_owned())
+            .or_default()
+            .insert(DomainRule::from(domain), severity)
+            != Some(severity)
+    }
+
+    /// Remove a single domain from those blocked by the given source, returning false if
+    /// it was not blocked by that source.
+    pub fn remove(&self, source: &str, domain: &str) -> bool {
+        self.sources
+            .write()
+            .unwrap()
+            .get_mut(source)
+            .and_then(|rules| rules.remove(&DomainRule::from(domain)))
+            .is_some()
+    }
+
+    /// The sources rejecting the given domain. This is empty if the domain is not
+    /// blocked, or is only blocked with a lesser [Severity].
+    pub fn blocked_by(&self, domain: &str) -> Vec<String> {
+        self.matching(domain)
+            .into_iter()
+            .filter(|(_, severity)| *severity == Severity::Reject)
+            .map(|(source, _)| source)
+            .collect()
+    }
+
+    /// The most severe block applying to the given domain, if there is one.
+    pub fn severity(&self, domain: &str) -> Option<Severity> {
+        self.matching(domain)
+            .into_iter()
+            .map(|(_, severity)| severity)
+            .max()
+    }
+
+    // The sources with a rule matching the given domain along with the most severe
+    // matching rule for each
+    fn matching(&self, domain: &str) -> Vec<(String, Severity)> {
+        // Rules are normalized on creation so we can look up the rules that would match
+        // rather than checking every rule
+        let exact = DomainRule::from(domain);
+        let registrable = DomainRule {
+            domain: registrable_domain(&exact.domain),
+            scope: DomainScope::RegistrableDomain,
+        };
+
+        let patterns = self.patterns.read().unwrap();
+
+        self.sources
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(source, rules)| {
+                let mut severities: Vec<Severity> = [&exact, &registrable]
+                    .into_iter()
+                    .filter_map(|rule| rules.get(rule).copied())
+                    .collect();
+                if let Some(set) = patterns.get(source) {
+                    // Only the config has patterns, and its blocks always reject
+                    if set.is_match(&exact.domain) {
+                        severities.push(Severity::Reject);
+                    }
+                }
+
+                let severity = severities.into_iter().max()?;
+                Some((source.clone(), severity))
+            })
+            .collect()
+    }
+
+    pub fn is_blocked(&self, domain: &str) -> bool {
+        !self.blocked_by(domain).is_empty()
+    }
+
+    /// Every blocked domain along with where the block came from.
+    pub fn blocks(&self) -> Vec<Block> {
+        let mut blocks: BTreeMap<&DomainRule, (Severity, Vec<String>)> = BTreeMap::new();
+        let sources = self.sources.read().unwrap();
+
+        for (source, rules) in sources.iter() {
+            for (rule, &severity) in rules {
+                let (max_severity, sources) = blocks.entry(rule).or_insert((severity, vec![]));
+                *max_severity = severity.max(*max_severity);
+                sources.push(source.clone());
+            }
+        }
+
+        blocks
+            .into_iter()
+            .map(|(rule, (severity, sources))| Block {
+                domain: rule.domain.clone(),
+                scope: rule.scope,
+                severity,
+                sources,
+            })
+            .collect()
+    }
+}
+
+/// Parse the body of a blocklist feed.
+///
+/// JSON feeds may be either an array of domains or an array of objects with a
+/// `domain` field (as returned by Mastodon's domain_blocks API). Anything else is
+/// treated as CSV with the domain in the first column, optionally preceded by a
+/// header row (as produced by Mastodon's domain block export).
+pub fn parse_feed(body: &str) -> BTreeSet<String> {
+    let domains: Vec<String> = match serde_json::from_str::<Vec<Value>>(body) {
+        Ok(entries) => entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Value::String(domain) => Some(domain.clone()),
+                Value::Object(obj) => obj.get("domain")?.as_str().map(|s| s.to_owned()),
+                _ => None,
+            })
+            .collect(),
+
+        Err(_) => body
+            .lines()
+            .filter_map(|line| line.split(',').next())
+            .filter(|domain| !matches!(domain.trim(), "#domain" | "domain"))
+            .map(|domain| domain.to_owned())
+            .collect(),
+    };
+
+    domains
+        .into_iter()
+        .map(|domain| domain.trim().to_lowercase())
+        // Some instances publish partially obfuscated domains that we can't match on
+        .filter(|domain| !domain.is_empty() && !domain.contains('*'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case("a.example\nB.example\n\n"; "plain list")]
+    #[test_case("#domain,#severity\na.example,suspend\nb.example,silence\n"; "mastodon csv export")]
+    #[test_case("domain,comment\na.example,spam\nb.example,\nc*.example,\n"; "csv with obfuscated domain")]
+    #[test_case(r#"["a.example", "b.example"]"#; "json strings")]
+    #[test_case(r#"[{"domain": "a.example", "severity": "suspend"}, {"domain": "b.example"}]"#; "json objects")]
+    #[test]
+    fn parse_feed_works(body: &str) {
+        let expected: BTreeSet<String> = ["a.example", "b.example"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        assert_eq!(parse_feed(body), expected);
+    }
+
+    #[test]
+    fn blocks_track_their_sources() {
+        let blocklist =
+            Blocklist::new(&DomainRules::new(vec!["a.example".into()]).unwrap()).unwrap();
+        blocklist
+            .set_source(
+                "https://feed.example/blocks.csv",
+                parse_feed("a.example\nb.example"),
+            )
+            .unwrap();
+
+        assert_eq!(
+            blocklist.blocks(),
+            vec![
+                Block {
+                    domain: "a.example".into(),
+                    scope: DomainScope::Exact,
+                    severity: Severity::Reject,
+                    sources: vec!["config".into(), "https://feed.example/blocks.csv".into()],
+                },
+                Block {
+                    domain: "b.example".into(),
+                    scope: DomainScope::Exact,
+                    severity: Severity::Reject,
+                    sources: vec!["https://feed.example/blocks.csv".into()],
+                },
+            ]
+        );
+        assert!(blocklist.is_blocked("B.example"));
+        assert!(!blocklist.is_blocked("c.example"));
+    }
+
+    #[test_case("social.a.example", true; "blocked host")]
+    #[test_case("a.example", true; "registrable domain")]
+    #[test_case("media.a.example", true; "sibling")]
+    #[test_case("social.b.example", true; "exact block")]
+    #[test_case("b.example", false; "parent of exact block")]
+    #[test]
+    fn blocks_can_cover_a_registrable_domain(domain: &str, blocked: bool) {
+        let rules = DomainRules::new(vec![
+            DomainRule::new("social.a.example", DomainScope::RegistrableDomain),
+            DomainRule::new("social.b.example", DomainScope::Exact),
+        ]);
+        let blocklist = Blocklist::new(&rules.unwrap()).unwrap();
+
+        assert_eq!(blocklist.is_blocked(domain), blocked);
+    }
+
+    #[test_case("a.spam.example", true; "wildcard")]
+    #[test_case("spam.example", false; "parent of wildcard")]
+    #[test_case("bot123.example", true; "regex")]
+    #[test_case("bot.example", false; "regex mismatch")]
+    #[test]
+    fn blocks_can_be_patterns(domain: &str, blocked: bool) {
+        let rules = DomainRules::new(vec![
+            DomainRule::parse("*.spam.example"),
+            DomainRule::parse(r"/^bot\d+\.example$/"),
+        ]);
+        let blocklist = Blocklist::new(&rules.unwrap()).unwrap();
+
+        assert_eq!(blocklist.is_blocked(domain), blocked);
+    }
+
+    #[test]
+    fn refreshing_a_source_replaces_its_blocks() {
+        let blocklist = Blocklist::default();
+        blocklist
+            .set_source("feed", parse_feed("a.example"))
+            .unwrap();
+        blocklist
+            .set_source("feed", parse_feed("b.example"))
+            .unwrap();
+
+        assert!(!blocklist.is_blocked("a.example"));
+        assert!(blocklist.is_blocked("b.example"));
+    }
+
+    #[test]
+    fn invalid_rules_leave_existing_blocks_in_place() {
+        let blocklist = Blocklist::default();
+        blocklist
+            .set_source("feed", parse_feed("a.example"))
+            .unwrap();
+        let rules = BTreeMap::from([(DomainRule::parse("/(/"), Severity::Reject)]);
+
+        assert!(blocklist.set_rules("feed", rules).is_err());
+        assert!(blocklist.is_blocked("a.example"));
+    }
+
+    #[test]
+    fn removing_a_block_leaves_other_sources() {
+        let blocklist =
+            Blocklist::new(&DomainRules::new(vec!["a.example".into()]).unwrap()).unwrap();
+
+        assert!(blocklist.add(ADMIN_SOURCE, "A.example", Severity::Reject));
+        assert!(!blocklist.add(ADMIN_SOURCE, "a.example", Severity::Reject));
+        assert!(blocklist.remove(ADMIN_SOURCE, "a.example"));
+        assert!(!blocklist.remove(ADMIN_SOURCE, "a.example"));
+        assert!(blocklist.is_blocked("a.example"));
+    }
+
+    #[test_case("a.example", Some(Severity::Reject); "most severe wins")]
+    #[test_case("b.example", Some(Severity::Silence); "silenced")]
+    #[test_case("c.example", None; "not blocked")]
+    #[test]
+    fn severity_works(domain: &str, expected: Option<Severity>) {
+        let blocklist = Blocklist::default();
+        blocklist.add(ADMIN_SOURCE, "a.example", Severity::StripMedia);
+        blocklist.add(ADMIN_SOURCE, "b.example", Severity::Silence);
+        blocklist.add("feed", "a.example", Severity::Reject);
+
+        assert_eq!(blocklist.severity(domain), expected);
+    }
+
+    #[test]
+    fn lesser_severities_are_not_rejected() {
+        let blocklist = Blocklist::default();
+        blocklist.add(ADMIN_SOURCE, "a.example", Severity::Silence);
+
+        assert!(!blocklist.is_blocked("a.example"));
+        assert!(blocklist.add(ADMIN_SOURCE, "a.example", Severity::Reject));
+        assert!(blocklist.is_blocked("a.example"));
+    }
+}
@@ -1,89 +1,488 @@
 //! A simple API client for making activitypub related requests
-use crate::{signature::sign_request_headers, util::header_val, Error, Result};
-use reqwest::{header, Client, Response, StatusCode};
+use crate::{
+    actors::{actor_path, DEFAULT_ACTOR},
+    config::{HttpConfig, ProxyConfig, SignatureAlgorithm},
+    delivery::Delivery,
+    interop::{ACTIVITY_JSON, NODEINFO_JSON},
+    jsonld::PUBLIC,
+    signature::{check_key_pair, sign_request_headers, Body},
+    signer::ActorKey,
+    singleflight::SingleFlight,
+    util::{first_id, header_val, is_overlay_host},
+    Error, Result,
+};
+use axum::async_trait;
+use reqwest::{header, Client, Proxy, RequestBuilder, Response, StatusCode, Url};
 use rsa::{
-    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding},
-    pkcs1v15::SigningKey,
-    RsaPrivateKey, RsaPublicKey,
+    pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding},
+    RsaPrivateKey,
 };
 use rustypub::{
-    core::{ActivityBuilder, ObjectBuilder},
+    core::{ActivityBuilder, ContextBuilder, ObjectBuilder},
     extended::{Actor, ActorBuilder},
 };
-use serde::{de::DeserializeOwned, Serialize};
-use sha2::Sha256;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::lookup_host,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
 use tracing::{error, info};
 use uuid::Uuid;
 
-const KEY_LEN: usize = 1024;
-// const KEY_LEN: usize = 4096;
+// The size of generated keys. 1024 bit RSA keys are refused by some software and are
+// no longer considered secure, so generated keys have been 2048 bits since topic actors
+// were given their own keys. Existing keys are left as they are.
+const KEY_LEN: usize = 2048;
+
+// Blocklist feeds are refetched unattended so a misbehaving feed shouldn't be able to
+// exhaust our memory
+const MAX_BLOCKLIST_BYTES: usize = 10 * 1024 * 1024;
+
+/// Returned in place of sending a request to a host that already has as many requests in
+/// flight as we allow, so that the caller can try again later.
+pub const HOST_BUSY: Error = Error::StatusAndMessage {
+    status: StatusCode::TOO_MANY_REQUESTS,
+    message: "too many requests in flight to this host",
+};
+
+// Any 2.x nodeinfo schema contains the software details that we are interested in
+const NODE_INFO_REL_PREFIX: &str = "http://nodeinfo.diaspora.software/ns/schema/2.";
+
+/// A remote actor document along with the properties we need that are not modelled
+/// by [Actor].
+#[derive(Debug)]
+pub struct RemoteActor {
+    actor: Actor,
+    /// The `type` of the actor, e.g. Person, Service or Group
+    pub actor_type: Option<String>,
+    /// Additional endpoints advertised by the actor
+    pub endpoints: Endpoints,
+    /// The collection of posts the actor has pinned, if they advertise one
+    pub featured: Option<String>,
+}
+
+impl RemoteActor {
+    /// Parse a fetched actor document.
+    pub fn from_json(uri: &str, raw: Value) -> Result<Self> {
+        // A missing or malformed endpoints property just means that we fall back to
+        // delivering to the actor's personal inbox.
+        let endpoints = serde_json::from_value(raw["endpoints"].clone()).unwrap_or_default();
+        let actor_type = raw["type"].as_str().map(|t| t.to_owned());
+        let featured = first_id(&raw["featured"]).map(|f| f.to_owned());
+        let actor = serde_json::from_value(raw).map_err(|e| Error::InvalidJson {
+            uri: uri.to_owned(),
+            raw: e.to_string(),
+        })?;
+
+        Ok(Self {
+            actor,
+            actor_type,
+            endpoints,
+            featured,
+        })
+    }
+
+    /// Whether the actor is a group, such as a Lemmy or kbin community.
+    pub fn is_group(&self) -> bool {
+        self.actor_type.as_deref() == Some("Group")
+    }
+
+    /// The shared inbox for the actor's instance, if one is advertised.
+    pub fn shared_inbox(&self) -> Option<&str> {
+        self.endpoints.shared_inbox.as_deref()
+    }
+}
+
+impl Deref for RemoteActor {
+    type Target = Actor;
+
+    fn deref(&self) -> &Self::Target {
+        &self.actor
+    }
+}
+
+/// The `endpoints` property of an actor document.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoints {
+    pub shared_inbox: Option<String>,
+}
+
+/// The software details reported by a remote instance's nodeinfo.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoftwareInfo {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NodeInfoLinks {
+    links: Vec<NodeInfoLink>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NodeInfoLink {
+    rel: String,
+    href: String,
+}
+
+/// The parts of a remote instance's nodeinfo that we keep in our software inventory.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfo {
+    pub software: SoftwareInfo,
+    #[serde(default)]
+    pub open_registrations: Option<bool>,
+    /// Free form metadata about the instance, the contents of which vary by software
+    #[serde(default)]
+    pub metadata: Option<Value>,
+}
+
+/// Looks up hosts when verifying that an actor's host resolves.
+#[async_trait]
+pub trait Resolver: Send + Sync + fmt::Debug {
+    /// Whether the host resolves to at least one address.
+    async fn resolves(&self, host: &str, port: u16) -> bool;
+}
+
+/// Resolves hosts using the system resolver.
+#[derive(Debug)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolves(&self, host: &str, port: u16) -> bool {
+        lookup_host((host, port))
+            .await
+            .map(|mut addrs| addrs.next().is_some())
+            .unwrap_or(false)
+    }
+}
 
 #[derive(Debug)]
 pub struct ActivityPubClient {
-    signing_key: SigningKey<Sha256>,
-    pub_key: RsaPublicKey,
+    // map of relay actor name to its key pair
+    keys: HashMap<String, ActorKey>,
     client: Client,
     base: String,
+    // overlay network TLDs that we can reach via a proxy
+    overlay_tlds: Vec<&'static str>,
+    sig_algorithm: SignatureAlgorithm,
+    // actor fetches in flight, shared by concurrent requests for the same actor
+    actor_fetches: SingleFlight<Result<Value>>,
+    // the most requests in flight to a single host, if limited
+    max_per_host: Option<usize>,
+    // map of host to the permits for requests to it when limited, for the hosts with
+    // requests in flight
+    host_permits: Mutex<HashMap<String, Arc<Semaphore>>>,
+    resolver: Arc<dyn Resolver>,
 }
 
 impl ActivityPubClient {
     pub fn new_with_priv_key(priv_key_pem: &str, base: String) -> Self {
-        let priv_key = RsaPrivateKey::from_pkcs1_pem(priv_key_pem)
+        let key = ActorKey::from_pem(priv_key_pem)
             .expect("the provided private key for initialising the ActivityPubClient was invalid");
-        let pub_key = RsaPublicKey::from(&priv_key);
-        let signing_key = SigningKey::<Sha256>::new_with_prefix(priv_key);
 
+        Self::new_with_key(key, base)
+    }
+
+    /// Create a client signing requests as the main relay actor with the given key.
+    pub fn new_with_key(key: ActorKey, base: String) -> Self {
         Self {
-            signing_key,
-            pub_key,
+            keys: HashMap::from([(DEFAULT_ACTOR.to_owned(), key)]),
             client: Default::default(),
             base,
+            overlay_tlds: vec![],
+            sig_algorithm: Default::default(),
+            actor_fetches: Default::default(),
+            max_per_host: None,
+            host_permits: Default::default(),
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+
+    /// Route outbound requests through the configured proxies, pooling connections
+    /// as configured. The resulting client is shared by every outbound request.
+    pub fn configure(&mut self, proxy: &ProxyConfig, http: &HttpConfig) -> Result<()> {
+        self.client = http_client(proxy, http)?;
+        self.overlay_tlds = proxy.overlays().into_iter().map(|(tld, _)| tld).collect();
+        self.max_per_host = http.max_connections_per_host.filter(|&n| n > 0);
+
+        Ok(())
+    }
+
+    // Take a free slot for a request to the host of the given uri when requests per host
+    // are limited. Rather than waiting for one to free up, HOST_BUSY is returned so that
+    // the caller can get on with requests to other hosts.
+    fn host_permit(&self, uri: &str) -> Result<Option<HostPermit<'_>>> {
+        let max = match self.max_per_host {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+        let host = match Url::parse(uri)
+            .ok()
+            .and_then(|u| u.host_str().map(String::from))
+        {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        let mut permits = self.host_permits.lock().unwrap();
+        let semaphore = permits
+            .entry(host.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone();
+
+        match semaphore.try_acquire_owned() {
+            Ok(permit) => Ok(Some(HostPermit {
+                host,
+                permits: &self.host_permits,
+                permit: Some(permit),
+            })),
+            Err(_) => Err(HOST_BUSY),
+        }
+    }
+
+    /// Replace the resolver used to check that actor hosts resolve.
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Set the algorithm named in the signatures of our requests.
+    pub fn set_signature_algorithm(&mut self, algorithm: SignatureAlgorithm) {
+        self.sig_algorithm = algorithm;
+    }
+
+    /// The origin to use for requests to the given host. Instances on overlay networks
+    /// commonly serve plain HTTP as the network itself provides encryption.
+    pub fn origin(&self, host: &str) -> String {
+        if is_overlay_host(host, &self.overlay_tlds) {
+            format!("http://{host}")
+        } else {
+            format!("https://{host}")
+        }
+    }
+
+    // Requests must use HTTPS other than for hosts on overlay networks that we proxy
+    fn check_scheme(&self, uri: &str) -> Result<()> {
+        let parsed = Url::parse(uri).map_err(|_| Error::InvalidUri {
+            uri: uri.to_owned(),
+        })?;
+        let is_overlay = || {
+            parsed
+                .host_str()
+                .map(|host| is_overlay_host(host, &self.overlay_tlds))
+                .unwrap_or(false)
+        };
+
+        match parsed.scheme() {
+            "https" => Ok(()),
+            "http" if is_overlay() => Ok(()),
+            _ => Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_REQUEST,
+                message: "only https URIs are supported outside of overlay networks",
+            }),
         }
     }
 
-    pub fn pub_key(&self) -> String {
-        self.pub_key
+    /// Use a separate key pair for the named relay actor rather than the key of the
+    /// main relay actor.
+    pub fn add_actor_key(&mut self, actor: &str, key: ActorKey) {
+        self.keys.insert(actor.to_owned(), key);
+    }
+
+    // Actors without their own key pair share the key of the main relay actor
+    fn key(&self, actor: &str) -> &ActorKey {
+        self.keys
+            .get(actor)
+            .or_else(|| self.keys.get(DEFAULT_ACTOR))
+            .expect("the main relay actor to have a key")
+    }
+
+    /// The public key of the named relay actor in PEM format
+    pub fn pub_key(&self, actor: &str) -> String {
+        self.key(actor)
+            .pub_key()
             .to_pkcs1_pem(LineEnding::default())
             .expect("to encode to PEM successfully")
     }
 
-    async fn json_get<T: DeserializeOwned>(&self, uri: &str) -> Result<T> {
-        let h = sign_request_headers(&self.base, uri, None, &self.signing_key)?;
-        match self.client.get(uri).headers(h).send().await {
-            Ok(raw) => raw.json().await.map_err(|e| Error::InvalidJson {
-                uri: uri.to_owned(),
-                raw: e.to_string(),
-            }),
+    /// Check that requests signed as the given actor can be verified using the public
+    /// key that we publish for it.
+    pub async fn check_key(&self, actor: &str) -> Result<()> {
+        check_key_pair(self.key(actor).signer(), &self.pub_key(actor)).await
+    }
 
-            Err(e) => Err(map_reqwest_error(uri, "GET", e)),
-        }
+    /// The id of one of our relay actors
+    pub fn actor_id(&self, actor: &str) -> String {
+        format!("https://{}{}", self.base, actor_path(actor))
+    }
+
+    fn key_id(&self, actor: &str) -> String {
+        format!("{}#main-key", self.actor_id(actor))
+    }
+
+    // Fetches from other instances are always signed and ask for exactly the media type
+    // that we want, as some software refuses anything less (see the interop module)
+    pub(crate) async fn signed_get(&self, uri: &str, accept: &str) -> Result<RequestBuilder> {
+        self.check_scheme(uri)?;
+        let key_id = self.key_id(DEFAULT_ACTOR);
+        let signer = self.key(DEFAULT_ACTOR).signer();
+        let mut h = sign_request_headers(&key_id, uri, None, signer, self.sig_algorithm).await?;
+        h.insert(header::ACCEPT, header_val(accept)?);
+
+        Ok(self.client.get(uri).headers(h))
     }
 
-    pub async fn json_post<T: Serialize>(&self, uri: impl AsRef<str>, data: T) -> Result<Response> {
-        let body = serde_json::to_string(&data).map_err(|e| Error::InvalidJson {
+    async fn json_get<T: DeserializeOwned>(&self, uri: &str, accept: &str) -> Result<T> {
+        let res = self
+            .signed_get(uri, accept)
+            .await?
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| map_reqwest_error(uri, "GET", e))?;
+
+        res.json().await.map_err(|e| Error::InvalidJson {
+            uri: uri.to_owned(),
+            raw: e.to_string(),
+        })
+    }
+
+    /// POST a signed JSON payload on behalf of the named relay actor.
+    pub async fn json_post<T: Serialize>(
+        &self,
+        actor: &str,
+        uri: impl AsRef<str>,
+        data: T,
+    ) -> Result<Response> {
+        let body = serde_json::to_vec(&data).map_err(|e| Error::InvalidJson {
             uri: uri.as_ref().to_owned(),
             raw: e.to_string(),
         })?;
 
-        let uri = uri.as_ref();
-        let mut headers = sign_request_headers(&self.base, uri, Some(&body), &self.signing_key)?;
-        headers.insert(
-            header::CONTENT_TYPE,
-            header_val("application/activity+json")?,
-        );
+        self.post_body(actor, uri.as_ref(), &Body::new(body)).await
+    }
+
+    /// POST an already serialized JSON payload on behalf of the named relay actor. Only
+    /// the signature is computed per request, so the same body can be sent to many
+    /// inboxes without being copied.
+    pub async fn post_body(&self, actor: &str, uri: &str, body: &Body) -> Result<Response> {
+        self.check_scheme(uri)?;
+        let _permit = self.host_permit(uri)?;
+        let key_id = self.key_id(actor);
+        let signer = self.key(actor).signer();
+        let mut headers =
+            sign_request_headers(&key_id, uri, Some(body), signer, self.sig_algorithm).await?;
+        headers.insert(header::CONTENT_TYPE, header_val(ACTIVITY_JSON)?);
 
         self.client
             .post(uri)
-            .body(body)
+            .body(body.bytes().clone())
             .headers(headers)
             .send()
             .await
             .map_err(|e| map_reqwest_error(uri, "POST", e))
     }
 
-    pub async fn get_actor(&self, uri: &str) -> Result<Actor> {
-        match self.json_get(uri).await {
-            Ok(actor) => Ok(actor),
+    /// Fetch a JSON document without signing the request, failing on any non-2xx
+    /// response.
+    pub async fn get_unsigned_json(&self, uri: &str, accept: &str) -> Result<Value> {
+        self.check_scheme(uri)?;
+        let res = self
+            .client
+            .get(uri)
+            .header(header::ACCEPT, accept)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| map_reqwest_error(uri, "GET", e))?;
+
+        res.json().await.map_err(|e| Error::InvalidJson {
+            uri: uri.to_owned(),
+            raw: e.to_string(),
+        })
+    }
+
+    /// Fetch the raw body of a remote blocklist feed.
+    pub async fn get_blocklist(&self, uri: &str) -> Result<String> {
+        let res = self
+            .client
+            .get(uri)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| map_reqwest_error(uri, "GET", e))?;
+        let body = read_body(uri, res, MAX_BLOCKLIST_BYTES).await?;
+
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Check that the host of an actor resolves and that the actor is served from it
+    /// over TLS. Hosts on overlay networks are resolved by their proxy so are only
+    /// checked for serving the actor.
+    pub async fn verify_actor_host(&self, actor_id: &str) -> Result<()> {
+        self.check_scheme(actor_id)?;
+        let url = Url::parse(actor_id).map_err(|_| Error::InvalidUri {
+            uri: actor_id.to_owned(),
+        })?;
+        let host = url.host_str().ok_or(Error::InvalidUri {
+            uri: actor_id.to_owned(),
+        })?;
+
+        if !is_overlay_host(host, &self.overlay_tlds) {
+            let port = url.port_or_known_default().unwrap_or(443);
+            if !self.resolver.resolves(host, port).await {
+                return Err(Error::StatusAndMessage {
+                    status: StatusCode::BAD_REQUEST,
+                    message: "actor host does not resolve",
+                });
+            }
+        }
+
+        let res = self
+            .client
+            .head(actor_id)
+            .send()
+            .await
+            .map_err(|e| map_reqwest_error(actor_id, "HEAD", e))?;
+
+        // The request is unsigned so servers requiring signed fetches may refuse it,
+        // but that still shows that something is being served. Redirects elsewhere
+        // are not accepted as the actor could then be hosted by anyone.
+        let status = res.status();
+        let served = !(status.is_server_error()
+            || status == StatusCode::NOT_FOUND
+            || status == StatusCode::GONE);
+        if !served || res.url().host_str() != Some(host) {
+            return Err(Error::FailedRequest {
+                method: "HEAD".to_owned(),
+                status,
+                error: "actor is not served by its host".to_owned(),
+                uri: actor_id.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Fetch an actor, sharing the request with any concurrent fetches of the same actor.
+    pub async fn get_actor(&self, uri: &str) -> Result<RemoteActor> {
+        let fetched = self
+            .actor_fetches
+            .run(uri, || self.json_get::<Value>(uri, ACTIVITY_JSON))
+            .await;
+
+        match fetched {
+            Ok(raw) => RemoteActor::from_json(uri, raw),
 
             Err(Error::FailedRequest { status, .. }) if status == StatusCode::NOT_FOUND => {
                 info!(%uri, "failed to fetch actor");
@@ -100,9 +499,43 @@ impl ActivityPubClient {
         }
     }
 
-    pub async fn follow_actor(&self, actor_uri: &str) -> Result<()> {
+    /// Fetch the document holding a public key, which is either the key itself or the
+    /// actor it belongs to (for key ids that are a fragment of the actor id).
+    pub async fn get_key(&self, key_id: &str) -> Result<Value> {
+        let uri = key_id.split('#').next().unwrap_or(key_id);
+
+        self.actor_fetches
+            .run(uri, || self.json_get::<Value>(uri, ACTIVITY_JSON))
+            .await
+    }
+
+    /// Fetch an activity by id from the server that it originates from.
+    pub async fn get_activity(&self, uri: &str) -> Result<Value> {
+        self.json_get(uri, ACTIVITY_JSON).await
+    }
+
+    pub async fn get_nodeinfo(&self, host: &str) -> Result<NodeInfo> {
+        let uri = format!("{}/.well-known/nodeinfo", self.origin(host));
+        let NodeInfoLinks { links } = self.json_get(&uri, NODEINFO_JSON).await?;
+
+        let href = links
+            .into_iter()
+            .rev()
+            .find(|link| link.rel.starts_with(NODE_INFO_REL_PREFIX))
+            .map(|link| link.href)
+            .ok_or(Error::StatusAndMessage {
+                status: StatusCode::NOT_FOUND,
+                message: "no supported nodeinfo schema",
+            })?;
+
+        self.json_get(&href, NODEINFO_JSON).await
+    }
+
+    /// Build a Follow request from one of our relay actors for the given actor, ready
+    /// to be delivered to their inbox.
+    pub async fn follow_actor(&self, from: &str, actor_uri: &str) -> Result<Delivery> {
         let base = &self.base;
-        let actor: Actor = self.get_actor(actor_uri).await?;
+        let actor = self.get_actor(actor_uri).await?;
         let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
             status: StatusCode::BAD_REQUEST,
             message: "actor has no id",
@@ -116,11 +549,11 @@ impl ActivityPubClient {
             .map_err(|_e| Error::InvalidUri {
                 uri: actor_id.clone(),
             })?;
-        info!(?id, inbox=?actor_inbox, "sending follow request to inbox");
+        info!(?id, inbox=?actor_inbox, "following actor");
 
         let message_id = Uuid::new_v4();
         let message_id_uri = format!("https://{base}/activities/{message_id}");
-        let actor_uri = format!("https://{base}/actor");
+        let actor_uri = self.actor_id(from);
         let message = ActivityBuilder::new(String::from("Follow"), String::from("Following actor"))
             .actor(
                 ActorBuilder::new(String::from("Actor")).url(
@@ -138,14 +571,121 @@ impl ActivityPubClient {
                 })?)
             .build();
 
-        self.json_post(actor_inbox, message).await?;
+        Delivery::from_message(from, actor_inbox, &message)
+    }
 
-        Ok(())
+    /// Build a Follow from one of our relay actors for another relay, ready to be
+    /// delivered to its inbox, along with the id of the Follow. Relays are subscribed to
+    /// by following the public collection rather than the relay actor itself.
+    pub async fn follow_relay(&self, from: &str, actor_uri: &str) -> Result<(Delivery, String)> {
+        let actor = self.get_actor(actor_uri).await?;
+        let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "actor has no id",
+        })?;
+        let actor_inbox = actor.inbox.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "actor has no inbox",
+        })?;
+        info!(id=%actor_id, inbox=%actor_inbox, "following relay");
+
+        let follow_id = format!("https://{}/activities/{}", self.base, Uuid::new_v4());
+        let message = json!({
+            "@context": ContextBuilder::default().build(),
+            "id": follow_id,
+            "type": "Follow",
+            "actor": self.actor_id(from),
+            "to": [actor_id],
+            "object": PUBLIC,
+        });
+
+        Ok((Delivery::new(from, actor_inbox, message), follow_id))
     }
 
-    pub async fn unfollow_actor(&self, actor_uri: &str) -> Result<()> {
+    /// Build a Reject of a Follow of one of our relay actors by the given actor, ending
+    /// their subscription, ready to be delivered to their inbox. The Follow is only
+    /// referred to by id if we know it.
+    pub async fn reject_follow(
+        &self,
+        from: &str,
+        actor_uri: &str,
+        follow_id: Option<&str>,
+    ) -> Result<Delivery> {
+        let actor = self.get_actor(actor_uri).await?;
+        let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "actor has no id",
+        })?;
+        let actor_inbox = actor.inbox.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "actor has no inbox",
+        })?;
+        info!(id=%actor_id, inbox=%actor_inbox, "rejecting follow");
+
+        let mut follow = json!({
+            "type": "Follow",
+            "actor": actor_id,
+            "object": self.actor_id(from),
+        });
+        if let Some(follow_id) = follow_id {
+            follow["id"] = json!(follow_id);
+        }
+        let message = json!({
+            "@context": ContextBuilder::default().build(),
+            "id": format!("https://{}/activities/{}", self.base, Uuid::new_v4()),
+            "type": "Reject",
+            "actor": self.actor_id(from),
+            "to": [actor_id],
+            "object": follow,
+        });
+
+        Ok(Delivery::new(from, actor_inbox, message))
+    }
+
+    /// Build an Undo of a Follow sent by [ActivityPubClient::follow_relay], ready to be
+    /// delivered to the relay's inbox. The Follow is only referred to by id if we know it.
+    pub async fn unfollow_relay(
+        &self,
+        from: &str,
+        actor_uri: &str,
+        follow_id: Option<&str>,
+    ) -> Result<Delivery> {
+        let actor = self.get_actor(actor_uri).await?;
+        let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "actor has no id",
+        })?;
+        let actor_inbox = actor.inbox.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "actor has no inbox",
+        })?;
+        info!(id=%actor_id, inbox=%actor_inbox, "unfollowing relay");
+
+        let mut follow = json!({
+            "type": "Follow",
+            "actor": self.actor_id(from),
+            "object": PUBLIC,
+        });
+        if let Some(follow_id) = follow_id {
+            follow["id"] = json!(follow_id);
+        }
+        let message = json!({
+            "@context": ContextBuilder::default().build(),
+            "id": format!("https://{}/activities/{}", self.base, Uuid::new_v4()),
+            "type": "Undo",
+            "actor": self.actor_id(from),
+            "to": [actor_id],
+            "object": follow,
+        });
+
+        Ok(Delivery::new(from, actor_inbox, message))
+    }
+
+    /// Build an Undo of one of our relay actor's Follow for the given actor, ready to
+    /// be delivered to their inbox.
+    pub async fn unfollow_actor(&self, from: &str, actor_uri: &str) -> Result<Delivery> {
         let base = &self.base;
-        let actor: Actor = self.get_actor(actor_uri).await?;
+        let actor = self.get_actor(actor_uri).await?;
 
         let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
             status: StatusCode::BAD_REQUEST,
@@ -156,7 +696,7 @@ impl ActivityPubClient {
             message: "actor has no inbox",
         })?;
 
-        info!(%actor_id, %actor_inbox, "sending unfollow request to inbox");
+        info!(%actor_id, %actor_inbox, "unfollowing actor");
 
         let object_id = Uuid::new_v4();
         let message_id = Uuid::new_v4();
@@ -169,14 +709,13 @@ impl ActivityPubClient {
             .parse::<http::Uri>()
             .map_err(|_e| Error::InvalidUri { uri: object_id })?;
 
+        let our_actor = self.actor_id(from);
         let message = ActivityBuilder::new(String::from("Undo"), String::from("Unfollow actor"))
             .actor(
                 ActorBuilder::new(String::from("Actor")).url(
-                    format!("https://{base}/actor")
+                    our_actor
                         .parse::<http::Uri>()
-                        .map_err(|_e| Error::InvalidUri {
-                            uri: format!("https://{base}/actor"),
-                        })?,
+                        .map_err(|_e| Error::InvalidUri { uri: our_actor })?,
                 ),
             )
             .to(vec![actor_uri.to_owned()])
@@ -196,9 +735,63 @@ impl ActivityPubClient {
             .id(activity_id_uri)
             .build();
 
-        self.json_post(actor_inbox, message).await?;
+        Delivery::from_message(from, actor_inbox, &message)
+    }
+}
 
-        Ok(())
+/// Read a response body, giving up once it exceeds the given number of bytes rather
+/// than buffering however much the remote server chooses to send.
+pub(crate) async fn read_body(uri: &str, mut res: Response, max_bytes: usize) -> Result<Vec<u8>> {
+    let too_large = || Error::FailedRequest {
+        method: "GET".to_owned(),
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        error: format!("response body is larger than {max_bytes} bytes"),
+        uri: uri.to_owned(),
+    };
+
+    if res
+        .content_length()
+        .is_some_and(|len| len > max_bytes as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = res
+        .chunk()
+        .await
+        .map_err(|e| map_reqwest_error(uri, "GET", e))?
+    {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+// A slot for a request to a single host. The host's semaphore is forgotten once its last
+// permit is released so that we only keep track of the hosts with requests in flight.
+struct HostPermit<'a> {
+    host: String,
+    permits: &'a Mutex<HashMap<String, Arc<Semaphore>>>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        let mut permits = self.permits.lock().unwrap();
+        self.permit.take();
+
+        // Each permit holds a reference to its semaphore, so the map holding the only
+        // one left means that there are no requests in flight
+        if permits
+            .get(&self.host)
+            .is_some_and(|s| Arc::strong_count(s) == 1)
+        {
+            permits.remove(&self.host);
+        }
     }
 }
 
@@ -214,19 +807,260 @@ fn map_reqwest_error(uri: impl Into<String>, method: &str, e: reqwest::Error) ->
     }
 }
 
-#[allow(dead_code)]
+fn http_client(cfg: &ProxyConfig, http: &HttpConfig) -> Result<Client> {
+    let invalid = || Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "invalid proxy configuration",
+    };
+    let keepalive = Some(http.tcp_keepalive_secs)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let mut builder = Client::builder()
+        .pool_max_idle_per_host(http.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(http.pool_idle_timeout_secs))
+        .tcp_keepalive(keepalive)
+        .connect_timeout(Duration::from_secs(http.connect_timeout_secs))
+        .timeout(Duration::from_secs(http.request_timeout_secs))
+        .http2_adaptive_window(http.http2_adaptive_window);
+
+    // reqwest uses the first proxy that matches a request so the overlay network
+    // proxies need to be added first
+    for (tld, url) in cfg.overlays() {
+        let proxy_url = Url::parse(url).map_err(|_| invalid())?;
+        builder = builder.proxy(Proxy::custom(move |dest| {
+            let host = dest.host_str().unwrap_or_default();
+            is_overlay_host(host, &[tld]).then(|| proxy_url.clone())
+        }));
+    }
+    if let Some(url) = &cfg.url {
+        builder = builder.proxy(Proxy::all(url).map_err(|_| invalid())?);
+    }
+
+    builder.build().map_err(|_| invalid())
+}
+
 fn new_priv_key() -> RsaPrivateKey {
     RsaPrivateKey::new(&mut rand::thread_rng(), KEY_LEN).expect("failed to generate a key")
 }
 
+/// Generate a new private key in PEM format
+pub fn new_priv_key_pem() -> Result<String> {
+    let pem = new_priv_key().to_pkcs1_pem(LineEnding::default())?;
+
+    Ok(pem.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::signature::tests::TEST_PRIV_KEY;
+    use serde_json::json;
+    use simple_test_case::test_case;
 
     impl ActivityPubClient {
         pub fn new_with_test_key() -> Self {
             Self::new_with_priv_key(TEST_PRIV_KEY, "127.0.0.1:4242".to_string())
         }
     }
+
+    // Nothing resolves, without making any DNS queries
+    #[derive(Debug)]
+    struct NoHosts;
+
+    #[async_trait]
+    impl Resolver for NoHosts {
+        async fn resolves(&self, _: &str, _: u16) -> bool {
+            false
+        }
+    }
+
+    #[test_case("http://example.com/actor"; "plain http")]
+    #[test_case("https://nothing.invalid/actor"; "unresolvable")]
+    #[tokio::test]
+    async fn unverifiable_actor_hosts_are_rejected(actor_id: &str) {
+        let mut client = ActivityPubClient::new_with_test_key();
+        client.set_resolver(Arc::new(NoHosts));
+
+        assert!(client.verify_actor_host(actor_id).await.is_err());
+    }
+
+    #[test]
+    fn actors_without_their_own_key_use_the_main_key() {
+        let mut client = ActivityPubClient::new_with_test_key();
+        let main_key = client.pub_key(DEFAULT_ACTOR);
+
+        assert_eq!(client.pub_key("art"), main_key);
+
+        let pem = new_priv_key_pem().unwrap();
+        client.add_actor_key("art", ActorKey::from_pem(&pem).unwrap());
+
+        assert_ne!(client.pub_key("art"), main_key);
+        assert_eq!(client.pub_key("linux"), main_key);
+        assert_eq!(client.pub_key(DEFAULT_ACTOR), main_key);
+    }
+
+    #[test_case("https://example.com/inbox", true; "https")]
+    #[test_case("http://example.com/inbox", false; "clearnet http")]
+    #[test_case("http://abc.onion/inbox", true; "onion http")]
+    #[test_case("https://abc.onion/inbox", true; "onion https")]
+    #[test_case("http://abc.i2p/inbox", false; "unproxied overlay")]
+    #[test_case("ftp://abc.onion/inbox", false; "other scheme")]
+    #[test]
+    fn only_proxied_overlays_can_use_plain_http(uri: &str, allowed: bool) {
+        let mut client = ActivityPubClient::new_with_test_key();
+        let cfg = ProxyConfig {
+            onion_url: Some("socks5h://127.0.0.1:9050".into()),
+            ..Default::default()
+        };
+        client.configure(&cfg, &Default::default()).unwrap();
+
+        assert_eq!(client.check_scheme(uri).is_ok(), allowed);
+    }
+
+    #[test_case("abc.onion", "http://abc.onion"; "overlay")]
+    #[test_case("example.com", "https://example.com"; "clearnet")]
+    #[test]
+    fn origin_works(host: &str, expected: &str) {
+        let mut client = ActivityPubClient::new_with_test_key();
+        let cfg = ProxyConfig {
+            onion_url: Some("socks5h://127.0.0.1:9050".into()),
+            ..Default::default()
+        };
+        client.configure(&cfg, &Default::default()).unwrap();
+
+        assert_eq!(client.origin(host), expected);
+    }
+
+    #[test_case(ProxyConfig { url: Some("socks5h://127.0.0.1:9050".into()), ..Default::default() }, true; "socks")]
+    #[test_case(ProxyConfig { url: Some("http://proxy.internal:3128".into()), onion_url: Some("socks5h://127.0.0.1:9050".into()), i2p_url: Some("http://127.0.0.1:4444".into()) }, true; "http with overlays")]
+    #[test_case(ProxyConfig { url: Some("not a url".into()), ..Default::default() }, false; "invalid url")]
+    #[test_case(ProxyConfig { onion_url: Some("not a url".into()), ..Default::default() }, false; "invalid onion url")]
+    #[test]
+    fn proxy_config_is_validated(cfg: ProxyConfig, valid: bool) {
+        let mut client = ActivityPubClient::new_with_test_key();
+
+        assert_eq!(client.configure(&cfg, &Default::default()).is_ok(), valid);
+    }
+
+    #[test]
+    fn requests_per_host_can_be_limited() {
+        let mut client = ActivityPubClient::new_with_test_key();
+        assert!(client
+            .host_permit("https://a.example/inbox")
+            .unwrap()
+            .is_none());
+
+        let http = HttpConfig {
+            max_connections_per_host: Some(2),
+            ..Default::default()
+        };
+        client.configure(&Default::default(), &http).unwrap();
+
+        let first = client.host_permit("https://a.example/inbox").unwrap();
+        let _second = client.host_permit("https://a.example/users/a/inbox");
+
+        assert!(first.is_some());
+        assert!(matches!(
+            client.host_permit("https://a.example/inbox"),
+            Err(e) if e == HOST_BUSY
+        ));
+        assert!(client
+            .host_permit("https://b.example/inbox")
+            .unwrap()
+            .is_some());
+
+        drop(first);
+        assert!(client
+            .host_permit("https://a.example/inbox")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn hosts_without_requests_in_flight_are_forgotten() {
+        let mut client = ActivityPubClient::new_with_test_key();
+        let http = HttpConfig {
+            max_connections_per_host: Some(2),
+            ..Default::default()
+        };
+        client.configure(&Default::default(), &http).unwrap();
+
+        let first = client.host_permit("https://a.example/inbox").unwrap();
+        let second = client.host_permit("https://a.example/inbox").unwrap();
+        drop(first);
+        assert_eq!(client.host_permits.lock().unwrap().len(), 1);
+
+        drop(second);
+        assert!(client.host_permits.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remote_actor_picks_up_shared_inbox() {
+        let raw = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Person",
+            "id": "https://example.com/users/alice",
+            "inbox": "https://example.com/users/alice/inbox",
+            "endpoints": { "sharedInbox": "https://example.com/inbox" },
+        });
+
+        let actor = RemoteActor::from_json("https://example.com/users/alice", raw).unwrap();
+
+        assert_eq!(actor.shared_inbox(), Some("https://example.com/inbox"));
+        assert_eq!(
+            actor.inbox.as_deref(),
+            Some("https://example.com/users/alice/inbox")
+        );
+    }
+
+    #[test_case("Group", true; "group")]
+    #[test_case("Person", false; "person")]
+    #[test]
+    fn remote_actor_groups_are_recognised(actor_type: &str, expected: bool) {
+        let raw = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": actor_type,
+            "id": "https://lemmy.example/c/rust",
+            "inbox": "https://lemmy.example/c/rust/inbox",
+        });
+
+        let actor = RemoteActor::from_json("https://lemmy.example/c/rust", raw).unwrap();
+
+        assert_eq!(actor.is_group(), expected);
+    }
+
+    #[test]
+    fn remote_actor_without_endpoints_has_no_shared_inbox() {
+        let raw = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Person",
+            "id": "https://example.com/users/alice",
+            "inbox": "https://example.com/users/alice/inbox",
+        });
+
+        let actor = RemoteActor::from_json("https://example.com/users/alice", raw).unwrap();
+
+        assert_eq!(actor.shared_inbox(), None);
+    }
+
+    #[test_case(10, true; "within the limit")]
+    #[test_case(100, false; "over the limit")]
+    #[tokio::test]
+    async fn response_bodies_are_capped(len: usize, ok: bool) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .fallback(axum::routing::get(move || async move { "a".repeat(len) }));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let uri = format!("http://{addr}/feed.csv");
+
+        let res = Client::new().get(&uri).send().await.unwrap();
+        let body = read_body(&uri, res, 50).await;
+
+        assert_eq!(body.is_ok(), ok);
+    }
 }
@@ -0,0 +1,276 @@
+//! Commands that instance admins can send to the relay by mentioning one of its actors.
+//!
+//! Only the actors listed in `commands.allowedActors` may issue commands: anything
+//! else mentioning the relay is kept as a message for the operator. Each command is
+//! answered with a direct message from the mentioned relay actor.
+use crate::{
+    actors::{actor_path, RelayActor},
+    state::State,
+    util::host_from_uri,
+    Result,
+};
+use chrono::Utc;
+use rustypub::core::ContextBuilder;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const HELP: &str = "Available commands: status, subscribe, unsubscribe, help";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Whether the sender's instance is subscribed to the relay
+    Status,
+    /// How to subscribe the sender's instance to the relay
+    Subscribe,
+    /// Remove the sender's instance from the relay's subscribers
+    Unsubscribe,
+    Help,
+}
+
+impl Command {
+    /// Parse a command from the plain text of a post, ignoring any leading mentions.
+    pub fn parse(text: &str) -> Option<Self> {
+        let word = text
+            .split_whitespace()
+            .find(|w| !w.starts_with('@'))?
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_ascii_lowercase();
+
+        match word.as_str() {
+            "status" => Some(Self::Status),
+            "subscribe" | "follow" => Some(Self::Subscribe),
+            "unsubscribe" | "unfollow" => Some(Self::Unsubscribe),
+            "help" => Some(Self::Help),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Subscribe => "subscribe",
+            Self::Unsubscribe => "unsubscribe",
+            Self::Help => "help",
+        }
+    }
+}
+
+/// Whether the actor is allowed to issue commands to the relay.
+pub fn is_allowed(actor_id: &str, state: &State) -> bool {
+    state
+        .cfg
+        .commands
+        .allowed_actors
+        .iter()
+        .any(|a| a == actor_id)
+}
+
+/// Run a command sent by the given actor, returning the text of our reply.
+pub async fn execute(
+    command: Command,
+    relay: &RelayActor<'_>,
+    host: &str,
+    actor_id: &str,
+    state: &State,
+) -> Result<String> {
+    let domain = host_from_uri(actor_id)?;
+    let subscribed = relay.db.inbox(&domain).is_some();
+    let inbox = format!("https://{host}{}/inbox", actor_path(relay.name));
+    info!(%actor_id, command = command.as_str(), "running command");
+
+    let reply = match command {
+        Command::Status if subscribed => {
+            let quarantine = relay
+                .db
+                .instance(&domain)
+                .and_then(|i| i.quarantined_until.filter(|_| i.is_quarantined()));
+
+            match quarantine {
+                Some(until) => format!(
+                    "{domain} is subscribed to this relay. Its posts will be relayed once it leaves quarantine at {}.",
+                    until.to_rfc3339()
+                ),
+                None => format!("{domain} is subscribed to this relay."),
+            }
+        }
+        Command::Status => format!("{domain} is not subscribed to this relay."),
+
+        Command::Subscribe if subscribed => {
+            format!("{domain} is already subscribed to this relay.")
+        }
+        Command::Subscribe => format!(
+            "To subscribe {domain}, add {inbox} as a relay in your instance's administration settings."
+        ),
+
+        Command::Unsubscribe if subscribed => {
+            // The subscription was made by the instance's own relay actor, so that is
+            // whose Follow we reject and who we unfollow rather than the admin sending
+            // the command
+            let instance = relay.db.instance(&domain);
+            relay.db.remove_inbox(actor_id)?;
+            if let Some(instance) = instance {
+                let follower = &instance.actor;
+                let follow_id = instance.follow_id.as_deref();
+                match state.client.reject_follow(relay.name, follower, follow_id).await {
+                    Ok(reject) => state.deliver(vec![reject]),
+                    Err(e) => warn!(actor=%follower, error=%e, "unable to reject follow"),
+                }
+                match state.client.unfollow_actor(relay.name, follower).await {
+                    Ok(unfollow) => state.deliver(vec![unfollow]),
+                    Err(e) => warn!(actor=%follower, error=%e, "unable to unfollow actor"),
+                }
+            }
+
+            format!("{domain} has been unsubscribed from this relay.")
+        }
+        Command::Unsubscribe => format!("{domain} is not subscribed to this relay."),
+
+        Command::Help => HELP.to_owned(),
+    };
+
+    Ok(reply)
+}
+
+/// A direct message from the relay actor replying to the given post.
+pub fn reply(relay: &RelayActor<'_>, host: &str, to: &str, in_reply_to: &str, text: &str) -> Value {
+    let actor = relay.id(host);
+    let id = Uuid::new_v4();
+    let published = Utc::now().to_rfc3339();
+
+    json!({
+        "@context": ContextBuilder::default().build(),
+        "id": format!("https://{host}/activities/{id}/activity"),
+        "type": "Create",
+        "actor": actor,
+        "published": published,
+        "to": [to],
+        "object": {
+            "id": format!("https://{host}/activities/{id}"),
+            "type": "Note",
+            "attributedTo": actor,
+            "inReplyTo": in_reply_to,
+            "content": format!("<p>{text}</p>"),
+            "published": published,
+            "to": [to],
+            "tag": [{ "type": "Mention", "href": to }],
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ProxyConfig, state::Db};
+    use axum::{routing::get, Json, Router};
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, net::TcpListener};
+
+    #[test_case("@relay status", Some(Command::Status); "status")]
+    #[test_case("@relay@relay.example Unsubscribe!", Some(Command::Unsubscribe); "case and punctuation")]
+    #[test_case("@relay help me", Some(Command::Help); "trailing words")]
+    #[test_case("@relay can we join?", None; "message")]
+    #[test_case("@relay", None; "only a mention")]
+    #[test]
+    fn commands_are_parsed(text: &str, expected: Option<Command>) {
+        assert_eq!(Command::parse(text), expected);
+    }
+
+    #[test_case(Command::Status, true, "a.example is subscribed to this relay."; "status subscribed")]
+    #[test_case(Command::Status, false, "a.example is not subscribed to this relay."; "status not subscribed")]
+    #[test_case(Command::Subscribe, false, "To subscribe a.example, add https://relay.example/actor/inbox as a relay in your instance's administration settings."; "subscribe")]
+    #[test_case(Command::Unsubscribe, true, "a.example has been unsubscribed from this relay."; "unsubscribe")]
+    #[tokio::test]
+    async fn commands_are_answered(command: Command, subscribed: bool, expected: &str) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        if subscribed {
+            state
+                .db
+                .add_inbox_if_unknown("https://a.example/inbox".into(), None)
+                .unwrap();
+        }
+        let relay = RelayActor::main(&state);
+
+        let text = execute(
+            command,
+            &relay,
+            "relay.example",
+            "https://a.example/users/admin",
+            &state,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(text, expected);
+        if command == Command::Unsubscribe {
+            assert_eq!(state.db.inbox("a.example"), None);
+        }
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_rejects_the_instances_follow() {
+        const FOLLOWER: &str = "http://a.onion/actor";
+        const FOLLOW_ID: &str = "http://a.onion/follows/1";
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state
+            .db
+            .add_inbox_if_unknown("http://a.onion/inbox".into(), None)
+            .unwrap();
+        state.db.record_instance(FOLLOWER, None, None).unwrap();
+        state.db.update_instance("a.onion", |instance| {
+            instance.follow_id = Some(FOLLOW_ID.into())
+        });
+
+        // The instance is served over plain HTTP by proxying .onion hosts to it
+        let actor = json!({
+            "id": FOLLOWER,
+            "type": "Application",
+            "inbox": "http://a.onion/inbox",
+        });
+        let app = Router::new().route("/actor", get(move || async move { Json(actor) }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let proxy = ProxyConfig {
+            onion_url: Some(format!("http://{addr}")),
+            ..Default::default()
+        };
+        state.client.configure(&proxy, &Default::default()).unwrap();
+        let relay = RelayActor::main(&state);
+
+        execute(
+            Command::Unsubscribe,
+            &relay,
+            "relay.example",
+            "http://a.onion/users/admin",
+            &state,
+        )
+        .await
+        .unwrap();
+
+        let mut sent = vec![];
+        while let Some(queued) = state.deliveries.next_ready() {
+            assert_eq!(queued.delivery.inbox, "http://a.onion/inbox");
+            let message: Value = serde_json::from_slice(queued.delivery.body.bytes()).unwrap();
+            sent.push(message);
+        }
+        let reject = sent.iter().find(|m| m["type"] == "Reject").unwrap();
+        assert_eq!(reject["object"]["id"], FOLLOW_ID);
+        assert_eq!(reject["object"]["actor"], FOLLOWER);
+        assert!(sent.iter().any(|m| m["type"] == "Undo"));
+        assert_eq!(state.db.inbox("a.onion"), None);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
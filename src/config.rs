@@ -1,11 +1,34 @@
+use crate::{
+    client::new_priv_key_pem,
+    notifications::NotificationKind,
+    util::{registrable_domain, write_private},
+    Error,
+};
+use regex::{RegexSet, RegexSetBuilder};
 use serde::{Deserialize, Serialize};
-use std::{fs, net::Ipv4Addr, path::PathBuf};
+use serde_yaml::Value;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+/// The most deeply that config files can include one another, which also guards against
+/// files that include themselves
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// The commented config written by [Config::load_or_write_default] on first run, with
+/// placeholders for the paths of the data dir and private key.
+const DEFAULT_CONFIG_TEMPLATE: &str = include_str!("../resources/config.template.yaml");
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
-    /// IPv4 address to listen on
-    pub listen: Ipv4Addr,
+    /// Address(es) to listen on: either IP addresses (using `port`) or full socket
+    /// addresses such as `[::]:8080`
+    #[serde(with = "one_or_many")]
+    pub listen: Vec<ListenAddr>,
     /// Port to run the service on
     pub port: u16,
     /// Directory to use for storing JSON DB state
@@ -14,6 +37,97 @@ pub struct Config {
     pub private_key_path: PathBuf,
     /// Activitypub related configuration for the relay
     pub activity_pub: ActivityPubConfig,
+    /// Bearer token required for accessing the admin API. The admin API is
+    /// disabled if this is not set.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// How often (in seconds) to re-verify the actor and nodeinfo of each
+    /// subscribed instance
+    #[serde(default = "default_reverify_interval_secs")]
+    pub reverify_interval_secs: u64,
+    /// When enabled, activities are validated, cached and logged as normal but
+    /// are not actually delivered to subscriber inboxes
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Configuration for delivering activities to subscribers
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+    /// Configuration for handling activities sent to our inboxes
+    #[serde(default)]
+    pub inbox: InboxConfig,
+    /// Configuration for processing activities accepted by our inboxes
+    #[serde(default)]
+    pub ingest: IngestConfig,
+    /// What to do when a subscribed actor presents a different key to the one we
+    /// pinned when they first followed the relay
+    #[serde(default)]
+    pub key_change_policy: KeyChangePolicy,
+    /// How long (in seconds) newly subscribed instances are quarantined for. Activities
+    /// from quarantined instances are accepted and logged but not relayed. Disabled if
+    /// set to 0.
+    #[serde(default)]
+    pub quarantine_secs: u64,
+    /// Create and Announce activities whose object was published more than this many
+    /// hours ago are dropped rather than relayed. Disabled if not set.
+    #[serde(default)]
+    pub max_object_age_hours: Option<u64>,
+    /// The most instances that can be subscribed at once across all of our relay actors,
+    /// each counted once however many of them it follows. Follows from new instances
+    /// are rejected once this is reached. Unlimited if not set.
+    #[serde(default)]
+    pub max_subscribers: Option<usize>,
+    /// Actors of other relays to follow. Activities relayed to us by them are filtered
+    /// like those of any subscriber and relayed on to our own subscribers. Relays removed
+    /// from the list are unfollowed.
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    /// Remote blocklists to merge into the set of blocked instances
+    #[serde(default)]
+    pub blocklists: BlocklistConfig,
+    /// Retention of the record of relayed objects used to avoid relaying the same
+    /// object more than once
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Caching of remote objects fetched to verify them
+    #[serde(default)]
+    pub object_cache: ObjectCacheConfig,
+    /// Custom policies deciding whether or not activities are relayed
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    /// Integrity checks applied to media attachments of relayed objects
+    #[serde(default)]
+    pub attachments: AttachmentConfig,
+    /// Additional topic relay actors to serve alongside the main relay actor
+    #[serde(default)]
+    pub actors: Vec<ActorConfig>,
+    /// Proxies to use for outbound requests to other instances
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// Connection pooling and timeouts for outbound requests to other instances
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// Format and destination of log output
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Throttling of origins whose activity suddenly spikes
+    #[serde(default)]
+    pub flood: FloodConfig,
+    /// Delivery of operator notifications outside of the admin API
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    /// Commands that instance admins can send by mentioning the relay
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    /// Where the main relay actor's private key is held, if not in `privateKeyPath`
+    #[serde(default)]
+    pub signer: SignerConfig,
+    /// Profile images for our relay actors
+    #[serde(default)]
+    pub images: ImagesConfig,
+    /// Details of the relay operator published for other instances and relay
+    /// directories
+    #[serde(default)]
+    pub operator: OperatorConfig,
 }
 
 impl Config {
@@ -24,16 +138,973 @@ impl Config {
     /// This method will panic if the path given is invalid or if the file is
     /// not valid as a YAML [Config] file.
     pub fn load(path: PathBuf) -> Self {
-        match fs::read_to_string(&path) {
-            Ok(content) => serde_yaml::from_str(&content)
-                .unwrap_or_else(|e| panic!("unable to load config file: {e}")),
+        Self::from_path(&path).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Load server config from the given path, or write a commented default config there
+    /// if there isn't one yet. The default config keeps its data dir and private key
+    /// alongside the config file, with a new private key being generated if requested.
+    /// Returns `None` if the default was written, as it needs editing before use.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the default config can't be written, or under the
+    /// same conditions as [Config::load].
+    pub fn load_or_write_default(path: PathBuf, generate_key: bool) -> Option<Self> {
+        if path.exists() {
+            return Some(Self::load(path));
+        }
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let private_key_path = dir.join("private-key.pem");
+        fs::create_dir_all(dir).unwrap_or_else(|e| panic!("unable to create config dir: {e}"));
+        fs::write(&path, default_config(dir, &private_key_path))
+            .unwrap_or_else(|e| panic!("unable to write default config file: {e}"));
 
-            Err(e) => panic!("unable to read config file: {e}"),
+        if generate_key && !private_key_path.exists() {
+            let pem = new_priv_key_pem()
+                .unwrap_or_else(|e| panic!("unable to generate private key: {e}"));
+            write_private(&private_key_path, pem)
+                .unwrap_or_else(|e| panic!("unable to write private key: {e}"));
         }
+
+        None
+    }
+
+    /// Load server config from the given path, returning an error rather than panicking
+    /// if it is invalid.
+    ///
+    /// Config files can split their contents across other files by listing them under
+    /// `include`: a path or list of paths, relative to the including file, to YAML files
+    /// or directories of them (conf.d style, included in name order). Each included file
+    /// is merged over the including file in turn: mappings are merged key by key, lists
+    /// are appended to and anything else is replaced. This allows for instance a
+    /// blocklist managed by other tooling to be kept in its own file containing only
+    /// `activityPub.blockedInstances`. Included files can include further files.
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        Self::from_yaml(load_yaml(path, 0)?)
+    }
+
+    /// Load the config file at the given path as [Config::from_path] does, also rejecting
+    /// any keys that don't correspond to a setting (such as misspelt ones) rather than
+    /// ignoring them.
+    pub fn from_path_strict(path: &Path) -> Result<Self, Error> {
+        let value = load_yaml(path, 0)?;
+        let cfg = Self::from_yaml(value.clone())?;
+        let known = serde_yaml::to_value(&cfg).map_err(|e| Error::InvalidConfig {
+            error: format!("unable to serialize config: {e}"),
+        })?;
+
+        let mut unknown = Vec::new();
+        unknown_keys(&value, &known, "", &mut unknown);
+        if !unknown.is_empty() {
+            return Err(Error::InvalidConfig {
+                error: format!("unknown config keys: {}", unknown.join(", ")),
+            });
+        }
+
+        Ok(cfg)
+    }
+
+    fn from_yaml(value: Value) -> Result<Self, Error> {
+        let cfg: Self = serde_yaml::from_value(value).map_err(|e| Error::InvalidConfig {
+            error: format!("unable to load config file: {e}"),
+        })?;
+        cfg.validate()?;
+
+        Ok(cfg)
+    }
+
+    // Reject values that parse but would leave the relay unable to function
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |error: &str| {
+            Err(Error::InvalidConfig {
+                error: error.to_owned(),
+            })
+        };
+
+        if self.ingest.workers == 0 {
+            return invalid("ingest.workers must be at least 1");
+        }
+        if self.delivery.workers == 0 {
+            return invalid("delivery.workers must be at least 1");
+        }
+        if self.signer.command_timeout_secs == 0 {
+            return invalid("signer.commandTimeoutSecs must be at least 1");
+        }
+        if self.reverify_interval_secs == 0 {
+            return invalid("reverifyIntervalSecs must be at least 1");
+        }
+        if self.blocklists.refresh_interval_secs == 0 {
+            return invalid("blocklists.refreshIntervalSecs must be at least 1");
+        }
+
+        Ok(())
+    }
+
+    /// The socket addresses that the server should bind to
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.listen
+            .iter()
+            .map(|l| l.socket_addr(self.port))
+            .collect()
     }
 
     pub fn base_url(&self) -> String {
-        format!("{}:{}", self.listen, self.port)
+        match self.listen_addrs().first() {
+            Some(addr) => addr.to_string(),
+            None => format!("localhost:{}", self.port),
+        }
+    }
+}
+
+// Every setting is serialized (unset options as null) so any key in the loaded YAML that
+// is missing from the serialized config wasn't recognised. Lists are not descended into.
+fn unknown_keys(value: &Value, known: &Value, prefix: &str, unknown: &mut Vec<String>) {
+    let (value, known) = match (value, known) {
+        (Value::Mapping(value), Value::Mapping(known)) => (value, known),
+        _ => return,
+    };
+
+    for (k, v) in value {
+        let name = match k.as_str() {
+            Some(name) if prefix.is_empty() => name.to_owned(),
+            Some(name) => format!("{prefix}.{name}"),
+            None => continue,
+        };
+
+        match known.get(k) {
+            Some(known) => unknown_keys(v, known, &name, unknown),
+            None => unknown.push(name),
+        }
+    }
+}
+
+// Read a YAML config file, merging in any files that it includes
+fn load_yaml(path: &Path, depth: usize) -> Result<Value, Error> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(Error::InvalidConfig {
+            error: format!(
+                "config includes are nested too deeply at {}: is there a cycle?",
+                path.display()
+            ),
+        });
+    }
+
+    let content = fs::read_to_string(path).map_err(|e| Error::InvalidConfig {
+        error: format!("unable to read config file {}: {e}", path.display()),
+    })?;
+    let mut value: Value = serde_yaml::from_str(&content).map_err(|e| Error::InvalidConfig {
+        error: format!("unable to load config file {}: {e}", path.display()),
+    })?;
+
+    let includes = match &mut value {
+        Value::Mapping(m) => m.remove("include"),
+        _ => None,
+    };
+    // Relative includes are relative to the file including them rather than to the
+    // working directory or the top level config file
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    for include in include_paths(includes, path)? {
+        for file in expand_include(&base.join(include))? {
+            // Empty (or entirely commented out) files have nothing to merge, rather than
+            // replacing everything merged so far with null
+            let overlay = load_yaml(&file, depth + 1)?;
+            if !overlay.is_null() {
+                merge_yaml(&mut value, overlay);
+            }
+        }
+    }
+
+    Ok(value)
+}
+
+fn include_paths(includes: Option<Value>, path: &Path) -> Result<Vec<String>, Error> {
+    let invalid = || Error::InvalidConfig {
+        error: format!(
+            "include in {} must be a path or a list of paths",
+            path.display()
+        ),
+    };
+
+    match includes {
+        None | Some(Value::Null) => Ok(vec![]),
+        Some(Value::String(s)) => Ok(vec![s]),
+        Some(Value::Sequence(seq)) => seq
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s),
+                _ => Err(invalid()),
+            })
+            .collect(),
+        Some(_) => Err(invalid()),
+    }
+}
+
+// Directories are expanded to the YAML files they contain, sorted so that the order they
+// are merged in doesn't depend on the filesystem
+fn expand_include(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let entries = fs::read_dir(path).map_err(|e| Error::InvalidConfig {
+        error: format!("unable to read config dir {}: {e}", path.display()),
+    })?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && matches!(
+                    p.extension().and_then(|ext| ext.to_str()),
+                    Some("yaml" | "yml")
+                )
+        })
+        .collect();
+    files.sort();
+
+    Ok(files)
+}
+
+fn merge_yaml(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Mapping(base), Value::Mapping(other)) => {
+            for (k, v) in other {
+                match base.get_mut(&k) {
+                    Some(existing) => merge_yaml(existing, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(other)) => base.extend(other),
+        (base, other) => *base = other,
+    }
+}
+
+// Paths are written as JSON strings, which are also valid YAML, so that they are quoted
+// if needed
+fn default_config(dir: &Path, private_key_path: &Path) -> String {
+    let quote = |p: &Path| serde_json::to_string(p).expect("paths to serialize");
+
+    DEFAULT_CONFIG_TEMPLATE
+        .replace("__DATA_DIR__", &quote(&dir.join("data")))
+        .replace("__PRIVATE_KEY_PATH__", &quote(private_key_path))
+}
+
+/// An address to listen on, with the port being optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ListenAddr {
+    Socket(SocketAddr),
+    Ip(IpAddr),
+}
+
+impl ListenAddr {
+    fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        match *self {
+            Self::Socket(addr) => addr,
+            Self::Ip(ip) => SocketAddr::new(ip, default_port),
+        }
+    }
+}
+
+// Allow a single value in place of a list so that existing configs remain valid
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<T: Serialize, S: Serializer>(v: &[T], s: S) -> Result<S::Ok, S::Error> {
+        v.serialize(s)
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        match OneOrMany::deserialize(d)? {
+            OneOrMany::One(v) => Ok(vec![v]),
+            OneOrMany::Many(vs) => Ok(vs),
+        }
+    }
+}
+
+fn default_reverify_interval_secs() -> u64 {
+    60 * 60 * 24
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyChangePolicy {
+    /// Flag the instance for review but keep accepting its activities
+    #[default]
+    Flag,
+    /// Flag the instance and reject its activities until the new key is trusted
+    Reject,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeliveryConfig {
+    /// Number of deliveries that can be in flight at once
+    pub workers: usize,
+    /// Maximum number of attempts made for a delivery before it is dropped
+    pub max_attempts: u32,
+    /// Maximum number of deliveries that can be queued across all instances
+    pub max_queued: usize,
+    /// Maximum number of deliveries that can be queued for a single instance
+    pub max_queued_per_instance: usize,
+    /// Which deliveries to drop once one of the queue limits has been reached
+    pub shed_policy: ShedPolicy,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            workers: 8,
+            max_attempts: 5,
+            max_queued: 100_000,
+            max_queued_per_instance: 10_000,
+            shed_policy: ShedPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InboxConfig {
+    /// Requests taking longer than this (in milliseconds) to process are logged as
+    /// slow
+    pub slow_request_millis: u64,
+    /// Requests with a body larger than this (in bytes) are logged as large
+    pub large_payload_bytes: u64,
+    /// Requests with a body larger than this (in bytes) are rejected
+    pub max_payload_bytes: u64,
+    /// Requests carrying a PeerTube Video or Playlist, which embed every caption,
+    /// chapter and rendition of the video, are rejected above this size (in bytes)
+    /// instead
+    pub max_video_payload_bytes: u64,
+    /// Fetch Follow activities back from the instance they claim to come from before
+    /// accepting them, rejecting any that can't be verified
+    pub verify_follows: bool,
+    /// Reject requests whose signature doesn't cover the request target, host, date
+    /// and digest headers
+    pub strict_signatures: bool,
+    /// Reject signed requests whose Date header is more than this many seconds away
+    /// from our own clock, if the signature covers it
+    pub max_date_skew_secs: u64,
+    /// Respond to accepted activities with a 200 and an empty JSON object rather than
+    /// an empty 202
+    pub legacy_response: bool,
+    /// Check that the domain of an actor sending a Follow resolves and serves the actor
+    /// over TLS before accepting it
+    pub verify_domains: bool,
+    /// Fetch the object of a Create back from its origin and check that it exists and
+    /// is attributed to who it claims to be before announcing it
+    pub verify_objects: bool,
+    /// Normalize inbound activities to the compacted ActivityStreams layout before
+    /// handling them, for implementations sending expanded JSON-LD. Unmodified
+    /// activities are forwarded as they were received rather than normalized.
+    pub normalize_json_ld: bool,
+    /// Drop activities delivered by someone other than their author unless they carry
+    /// a valid Linked Data signature from the author. Invalid signatures are always
+    /// dropped.
+    pub require_ld_signatures: bool,
+    /// What to do with activities of a type that we don't recognise
+    pub unrecognized_activities: UnrecognizedActivities,
+    /// Forward Like and EmojiReact activities (and their Undos) for objects that we
+    /// have relayed to the subscribers we relayed them to
+    pub forward_reactions: bool,
+    /// The most messages (posts mentioning a relay actor) kept from a single instance
+    /// in an hour. Any more are dropped without notifying the operator.
+    pub max_messages_per_hour: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UnrecognizedActivities {
+    /// Accept and then ignore them
+    #[default]
+    Ignore,
+    /// Accept them, keeping a record of their type, origin and a sample payload that is
+    /// visible in the admin API
+    Record,
+    /// Record them as above but reject them rather than accepting them
+    Reject,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            slow_request_millis: 5_000,
+            large_payload_bytes: 1024 * 1024,
+            max_payload_bytes: 4 * 1024 * 1024,
+            max_video_payload_bytes: 16 * 1024 * 1024,
+            verify_follows: false,
+            strict_signatures: false,
+            max_date_skew_secs: 5 * 60,
+            legacy_response: false,
+            verify_domains: false,
+            verify_objects: false,
+            normalize_json_ld: false,
+            require_ld_signatures: false,
+            unrecognized_activities: Default::default(),
+            forward_reactions: false,
+            max_messages_per_hour: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IngestConfig {
+    /// Number of accepted activities that can be processed at once
+    pub workers: usize,
+    /// Maximum number of attempts made at processing an activity before it is dropped
+    pub max_attempts: u32,
+    /// Maximum number of accepted activities that can be waiting to be processed.
+    /// Requests arriving once this is reached are refused with a 503 so that the
+    /// sending server retries them later.
+    pub max_queued: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_attempts: 5,
+            max_queued: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FloodConfig {
+    pub enabled: bool,
+    /// The length (in seconds) of the windows that inbound activity is counted over
+    pub window_secs: u64,
+    /// How many previous windows the trailing average is calculated from
+    pub trailing_windows: usize,
+    /// An origin is throttled once its activity in the current window exceeds this
+    /// multiple of its trailing average
+    pub threshold: f64,
+    /// Origins are never throttled for sending fewer activities than this in a window
+    pub min_activities: u64,
+    /// How long (in seconds) a throttled origin remains throttled
+    pub throttle_secs: u64,
+    /// What happens to Announces from a throttled origin
+    pub action: FloodAction,
+    /// The most Announces held per origin when queueing, further Announces are dropped
+    pub max_queued: usize,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: 60,
+            trailing_windows: 60,
+            threshold: 10.0,
+            min_activities: 60,
+            throttle_secs: 600,
+            action: FloodAction::Queue,
+            max_queued: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FloodAction {
+    /// Hold Announces until the throttle expires and then relay them
+    Queue,
+    /// Accept Announces without relaying them
+    Drop,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NotificationsConfig {
+    /// Email notifications to the operator. Disabled if not set.
+    pub smtp: Option<SmtpConfig>,
+    /// Post notifications to a Matrix room. Disabled if not set.
+    pub matrix: Option<MatrixConfig>,
+    /// Post notifications to a Discord channel. Disabled if not set.
+    pub discord: Option<DiscordConfig>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CommandsConfig {
+    /// The actors allowed to issue commands. Mentions of the relay by anyone else are
+    /// kept as messages for the operator.
+    pub allowed_actors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SignerConfig {
+    pub backend: SignerBackend,
+    /// The program (and its arguments) run by the command backend to sign data. The
+    /// data is written to its stdin and it must write the raw RSASSA-PKCS1-v1_5 SHA-256
+    /// signature to its stdout.
+    pub command: Vec<String>,
+    /// How long the signing command may run for before it is killed and signing fails
+    pub command_timeout_secs: u64,
+    /// The public half of the key held by the command backend, in PEM format
+    pub public_key_path: Option<PathBuf>,
+    /// Where to find the passphrase for a `privateKeyPath` holding an encrypted key
+    pub passphrase: PassphraseConfig,
+}
+
+impl Default for SignerConfig {
+    fn default() -> Self {
+        Self {
+            backend: SignerBackend::default(),
+            command: vec![],
+            command_timeout_secs: 10,
+            public_key_path: None,
+            passphrase: PassphraseConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PassphraseConfig {
+    /// Environment variable to read the passphrase from. It is removed from our
+    /// environment once read.
+    pub env: String,
+    /// File to read the passphrase from if the environment variable is not set. A
+    /// trailing newline is ignored.
+    pub file: Option<PathBuf>,
+    /// Prompt for the passphrase on the terminal if it is not found elsewhere
+    pub prompt: bool,
+}
+
+impl Default for PassphraseConfig {
+    fn default() -> Self {
+        Self {
+            env: "ACTISERVE_KEY_PASSPHRASE".to_owned(),
+            file: None,
+            prompt: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SignerBackend {
+    /// The private key is read from `privateKeyPath`
+    #[default]
+    Pem,
+    /// Data is signed by running an external command, e.g. one using an HSM or KMS
+    Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpConfig {
+    /// The SMTP server to send emails through
+    pub host: String,
+    /// Defaults to the standard port for the chosen `tls` mode
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The sender of notification emails, e.g. `actiserve <relay@example.com>`
+    pub from: String,
+    /// The addresses notification emails are sent to
+    pub to: Vec<String>,
+    /// The kinds of notification to email. All are emailed if empty.
+    #[serde(default)]
+    pub events: Vec<NotificationKind>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatrixConfig {
+    /// The base URL of the homeserver, e.g. `https://matrix.example.org`
+    pub homeserver: String,
+    /// The id (not alias) of the room to post to, which the account must have joined
+    pub room_id: String,
+    /// Access token of the account posting notifications
+    pub access_token: String,
+    /// The kinds of notification to post. All are posted if empty.
+    #[serde(default)]
+    pub events: Vec<NotificationKind>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscordConfig {
+    /// The URL of a webhook created in the channel's integration settings
+    pub webhook_url: String,
+    /// The kinds of notification to post. All are posted if empty.
+    #[serde(default)]
+    pub events: Vec<NotificationKind>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SmtpTls {
+    /// Connect in plain text and upgrade the connection with STARTTLS
+    #[default]
+    StartTls,
+    /// Connect over TLS
+    Tls,
+    /// Never use TLS. Only suitable for a mail server on the local machine
+    None,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PolicyConfig {
+    /// Paths to WASM modules implementing relay filters. Requires actiserve to be
+    /// built with the `wasm-filters` feature.
+    pub wasm_filters: Vec<PathBuf>,
+    /// An external HTTP endpoint to consult about each activity
+    pub http: Option<HttpPolicyConfig>,
+    /// The only object types (e.g. Note, Article) that are relayed. All types are
+    /// relayed if this is empty.
+    pub allowed_object_types: Vec<String>,
+    /// Object types (e.g. Video, Page) that are never relayed
+    pub denied_object_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpPolicyConfig {
+    /// The URL that candidate activities are POSTed to
+    pub url: String,
+    /// How long (in milliseconds) to wait for a decision
+    #[serde(default = "default_policy_timeout_millis")]
+    pub timeout_millis: u64,
+    /// What to do with the activity if the endpoint errors or doesn't respond in time
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+fn default_policy_timeout_millis() -> u64 {
+    1_000
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FailurePolicy {
+    /// Relay the activity as if the policy had allowed it (fail open)
+    #[default]
+    Allow,
+    /// Drop the activity as if the policy had denied it (fail closed)
+    Deny,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProxyConfig {
+    /// Proxy URL (http://, https://, socks5:// or socks5h://) for all outbound requests
+    pub url: Option<String>,
+    /// Proxy URL for requests to .onion hosts, taking precedence over `url`
+    pub onion_url: Option<String>,
+    /// Proxy URL for requests to .i2p hosts, taking precedence over `url`
+    pub i2p_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HttpConfig {
+    /// Maximum number of idle connections kept open to each host for reuse
+    pub pool_max_idle_per_host: usize,
+    /// How long (in seconds) an idle pooled connection is kept open for
+    pub pool_idle_timeout_secs: u64,
+    /// Interval (in seconds) between TCP keep-alive probes on open connections.
+    /// Disabled if set to 0.
+    pub tcp_keepalive_secs: u64,
+    /// How long (in seconds) to wait for a connection to be established
+    pub connect_timeout_secs: u64,
+    /// How long (in seconds) to wait for a request to complete, including reading the
+    /// response body
+    pub request_timeout_secs: u64,
+    /// Let HTTP/2 connections grow their flow control window to suit the connection's
+    /// bandwidth, which helps when sending many deliveries to the same host
+    pub http2_adaptive_window: bool,
+    /// The most requests that can be in flight to a single host at once. Unlimited if
+    /// not set. Deliveries to a host at the limit are requeued rather than waited on.
+    pub max_connections_per_host: Option<usize>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 16,
+            pool_idle_timeout_secs: 90,
+            tcp_keepalive_secs: 60,
+            connect_timeout_secs: 10,
+            request_timeout_secs: 30,
+            http2_adaptive_window: true,
+            max_connections_per_host: None,
+        }
+    }
+}
+
+/// Profile images for our relay actors. Images not configured here are looked for in
+/// the data directory as `avatar.{png,jpg,jpeg,gif,webp}` and `header.{...}`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ImagesConfig {
+    /// Path to the image shown as the relay's avatar
+    pub avatar_path: Option<PathBuf>,
+    /// Path to the image shown as the relay's profile header
+    pub header_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OperatorConfig {
+    /// URL of the fediverse account to contact the operator at
+    pub contact_account: Option<String>,
+    /// Email address to contact the operator at
+    pub contact_email: Option<String>,
+    /// URL of the rules subscribers are expected to follow
+    pub rules_url: Option<String>,
+    /// A short summary of which instances the relay accepts and what it relays
+    pub policy_summary: Option<String>,
+    /// Path to a Markdown file with the relay's rules, shown on the /about page.
+    /// Defaults to `about.md` in the data directory if present.
+    pub about_path: Option<PathBuf>,
+}
+
+impl ProxyConfig {
+    /// The overlay network TLDs that we federate with, along with the proxy used to
+    /// reach each of them.
+    pub fn overlays(&self) -> Vec<(&'static str, &str)> {
+        [("onion", &self.onion_url), ("i2p", &self.i2p_url)]
+            .into_iter()
+            .filter_map(|(tld, url)| Some((tld, url.as_deref()?)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LoggingConfig {
+    /// How log lines are formatted
+    pub format: LogFormat,
+    /// Log filter directives (e.g. "info" or "actiserve=debug,warn"). The RUST_LOG
+    /// environment variable takes precedence if it is set.
+    pub level: Option<String>,
+    /// Write logs to rolling files rather than stdout
+    pub file: Option<LogFileConfig>,
+    /// Only write out a sample of the log lines from noisy modules
+    pub sampling: LogSamplingConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogSamplingConfig {
+    /// The modules to sample log lines from. Log lines not covered by a rule are
+    /// always written out.
+    pub rules: Vec<LogSamplingRule>,
+    /// How often (in seconds) to log how many log lines were dropped by each rule
+    pub summary_interval_secs: u64,
+}
+
+impl Default for LogSamplingConfig {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            summary_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogSamplingRule {
+    /// The module (e.g. actiserve::routes::inbox) whose log lines are sampled, along
+    /// with any modules under it
+    pub target: String,
+    /// Log lines at this level or below are sampled: more severe ones are always written
+    #[serde(default)]
+    pub level: LogLevel,
+    /// Write out one in this many of the matching log lines
+    pub one_in: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LogFormat {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// Multi-line human readable output
+    Pretty,
+    /// Single line human readable output
+    Compact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFileConfig {
+    /// Directory to write log files to
+    pub directory: PathBuf,
+    /// Log file names are this prefix followed by the date and time of the rotation
+    #[serde(default = "default_log_file_prefix")]
+    pub prefix: String,
+    /// How often to start a new log file
+    #[serde(default)]
+    pub rotation: LogRotation,
+}
+
+fn default_log_file_prefix() -> String {
+    "actiserve.log".to_owned()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    /// Always write to the same file
+    Never,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AttachmentConfig {
+    /// What to do with activities that embed data: URIs or too many attachments
+    pub policy: AttachmentPolicy,
+    /// Maximum number of attachments an object may have
+    pub max_attachments: usize,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            policy: AttachmentPolicy::Strip,
+            max_attachments: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AttachmentPolicy {
+    /// Remove offending attachments before forwarding the activity
+    Strip,
+    /// Drop the activity entirely
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ShedPolicy {
+    /// Make room by dropping the oldest queued delivery
+    DropOldest,
+    /// Drop the new delivery, keeping what is already queued
+    DropNewest,
+    /// Make room by dropping the oldest of the lowest priority deliveries, so that
+    /// bulk relay traffic is shed before follow handshakes
+    DropLowestPriority,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActorConfig {
+    /// Name of the actor, used as its username and in its URL (/actors/{name})
+    pub name: String,
+    /// Description shown on the actor's profile
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Hashtags (without the leading #) that posts must have at least one of in order
+    /// to be relayed by this actor. All posts are relayed if this is empty.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Path to a private key in PEM format for this actor, which may be encrypted with
+    /// the passphrase of `privateKeyPath`. If not set, a key is generated and stored in
+    /// the actor's data directory on first run (encrypted if `privateKeyPath` is).
+    #[serde(default)]
+    pub private_key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HistoryConfig {
+    /// Maximum number of relayed objects to remember
+    pub max_entries: usize,
+    /// How long (in hours) to remember relayed objects for
+    pub max_age_hours: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_age_hours: 72,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ObjectCacheConfig {
+    /// Maximum total size (in bytes) of the cached objects
+    pub max_bytes: usize,
+    /// How long (in seconds) a fetched object is cached for
+    pub ttl_secs: u64,
+}
+
+impl Default for ObjectCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 4 * 1024 * 1024,
+            ttl_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BlocklistConfig {
+    /// URLs of blocklist feeds (CSV or JSON) to subscribe to
+    pub feeds: Vec<String>,
+    /// How often (in seconds) to refetch each feed
+    pub refresh_interval_secs: u64,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        Self {
+            feeds: vec![],
+            refresh_interval_secs: 60 * 60,
+        }
     }
 }
 
@@ -45,10 +1116,548 @@ pub struct ActivityPubConfig {
     /// reachable by HTTPS.
     pub host: String,
     /// Instances that should always be rejected
-    pub blocked_instances: Vec<String>,
+    pub blocked_instances: DomainRules,
     /// Whether or not the allow list should be enabled (blocking
     /// anything not on the list)
     pub allow_list: bool,
     /// Instances that should accepted. Only enforced if allowList=true
-    pub allowed_instances: Vec<String>,
+    pub allowed_instances: DomainRules,
+    /// Only accept Follows from instances whose domain has been bound to an invite code
+    /// via the admin API
+    #[serde(default)]
+    pub invite_only: bool,
+    /// How the domain of an incoming request is matched against subscribed instances
+    #[serde(default)]
+    pub subscription_scope: SubscriptionScope,
+    /// The algorithm named in the signatures of our outbound requests
+    #[serde(default)]
+    pub signature_algorithm: SignatureAlgorithm,
+    /// Credit the original author in our Announces (attributedTo and cc) and address
+    /// Announces of public posts publicly
+    #[serde(default)]
+    pub preserve_attribution: bool,
+}
+
+/// A domain in a block or allow list, along with which hosts it applies to. Rules can
+/// be given as a plain string or as `{domain, scope}`. Plain strings are wildcard
+/// patterns if they contain a `*` and regexes if they are wrapped in slashes
+/// (`/pattern/`), otherwise they are matched exactly.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "RawDomainRule")]
+pub struct DomainRule {
+    pub domain: String,
+    pub scope: DomainScope,
+}
+
+impl DomainRule {
+    pub fn new(domain: &str, scope: DomainScope) -> Self {
+        let domain = domain.trim();
+        let domain = match scope {
+            DomainScope::Exact | DomainScope::Wildcard => {
+                domain.trim_end_matches('.').to_ascii_lowercase()
+            }
+            DomainScope::RegistrableDomain => {
+                registrable_domain(&domain.trim_end_matches('.').to_ascii_lowercase())
+            }
+            DomainScope::Regex => domain.to_owned(),
+        };
+
+        Self { domain, scope }
+    }
+
+    /// Parse a rule from its plain string form.
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim();
+
+        match s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(regex) if !regex.is_empty() => Self::new(regex, DomainScope::Regex),
+            _ if s.contains('*') => Self::new(s, DomainScope::Wildcard),
+            _ => Self::new(s, DomainScope::Exact),
+        }
+    }
+
+    /// The regex that hosts are matched against for pattern based rules.
+    pub fn pattern(&self) -> Option<String> {
+        match self.scope {
+            DomainScope::Exact | DomainScope::RegistrableDomain => None,
+            DomainScope::Regex => Some(self.domain.clone()),
+            DomainScope::Wildcard => {
+                let parts: Vec<String> = self.domain.split('*').map(regex::escape).collect();
+                Some(format!("^{}$", parts.join(".*")))
+            }
+        }
+    }
+}
+
+impl From<&str> for DomainRule {
+    fn from(domain: &str) -> Self {
+        Self::new(domain, DomainScope::Exact)
+    }
+}
+
+/// Rules are shown in their plain string form, other than registrable domain rules which
+/// don't have one.
+impl fmt::Display for DomainRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scope {
+            DomainScope::Exact | DomainScope::Wildcard => write!(f, "{}", self.domain),
+            DomainScope::RegistrableDomain => write!(f, "{} (registrable domain)", self.domain),
+            DomainScope::Regex => write!(f, "/{}/", self.domain),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDomainRule {
+    Domain(String),
+    Rule {
+        domain: String,
+        #[serde(default)]
+        scope: DomainScope,
+    },
+}
+
+impl From<RawDomainRule> for DomainRule {
+    fn from(raw: RawDomainRule) -> Self {
+        match raw {
+            RawDomainRule::Domain(domain) => Self::parse(&domain),
+            RawDomainRule::Rule { domain, scope } => Self::new(&domain, scope),
+        }
+    }
+}
+
+/// Which hosts a block or allow list rule applies to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DomainScope {
+    /// Only the domain itself
+    #[default]
+    Exact,
+    /// Any host sharing the domain's registrable domain (e.g. all of example.co.uk and
+    /// its subdomains for social.example.co.uk)
+    RegistrableDomain,
+    /// Hosts matching a pattern where `*` matches anything (e.g. *.spam.example)
+    Wildcard,
+    /// Hosts matching a regular expression
+    Regex,
+}
+
+/// Which subscribed instance a request is treated as coming from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SubscriptionScope {
+    /// Only the instance whose domain subscribed
+    #[default]
+    Exact,
+    /// The subscribed instance sharing the request's registrable domain, for instances
+    /// whose actors live on a different subdomain to the one that subscribed
+    RegistrableDomain,
+}
+
+/// Compile the patterns of any wildcard and regex rules into a single matcher.
+pub fn compile_patterns<'a>(
+    rules: impl IntoIterator<Item = &'a DomainRule>,
+) -> Result<RegexSet, regex::Error> {
+    RegexSetBuilder::new(rules.into_iter().filter_map(|r| r.pattern()))
+        .case_insensitive(true)
+        .build()
+}
+
+/// A list of [DomainRule]s, compiled when the config is loaded so that checking a host
+/// does not need to consider each rule in turn.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "Vec<DomainRule>", into = "Vec<DomainRule>")]
+pub struct DomainRules {
+    rules: Vec<DomainRule>,
+    exact: HashSet<String>,
+    registrable: HashSet<String>,
+    patterns: RegexSet,
+}
+
+impl DomainRules {
+    pub fn new(rules: Vec<DomainRule>) -> Result<Self, regex::Error> {
+        let domains = |scope| -> HashSet<String> {
+            rules
+                .iter()
+                .filter(|r| r.scope == scope)
+                .map(|r| r.domain.clone())
+                .collect()
+        };
+        let exact = domains(DomainScope::Exact);
+        let registrable = domains(DomainScope::RegistrableDomain);
+        let patterns = compile_patterns(&rules)?;
+
+        Ok(Self {
+            rules,
+            exact,
+            registrable,
+            patterns,
+        })
+    }
+
+    pub fn rules(&self) -> &[DomainRule] {
+        &self.rules
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        self.exact.contains(&host)
+            || (!self.registrable.is_empty()
+                && self.registrable.contains(&registrable_domain(&host)))
+            || self.patterns.is_match(&host)
+    }
+}
+
+impl Default for DomainRules {
+    fn default() -> Self {
+        Self::new(vec![]).expect("an empty rule list to be valid")
+    }
+}
+
+impl TryFrom<Vec<DomainRule>> for DomainRules {
+    type Error = regex::Error;
+
+    fn try_from(rules: Vec<DomainRule>) -> Result<Self, Self::Error> {
+        Self::new(rules)
+    }
+}
+
+impl From<DomainRules> for Vec<DomainRule> {
+    fn from(rules: DomainRules) -> Self {
+        rules.rules
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignatureAlgorithm {
+    #[default]
+    #[serde(rename = "rsa-sha256")]
+    RsaSha256,
+    /// The algorithm is left to be derived from the key (which is always RSA with
+    /// SHA-256 for us)
+    #[serde(rename = "hs2019")]
+    Hs2019,
+}
+
+impl SignatureAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RsaSha256 => "rsa-sha256",
+            Self::Hs2019 => "hs2019",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[derive(Debug, Deserialize)]
+    struct Listen {
+        #[serde(with = "one_or_many")]
+        listen: Vec<ListenAddr>,
+    }
+
+    #[test_case("social.example.com", DomainScope::Exact, true; "exact match")]
+    #[test_case("Social.Example.com.", DomainScope::Exact, true; "exact match is normalized")]
+    #[test_case("example.com", DomainScope::Exact, false; "exact parent")]
+    #[test_case("example.com", DomainScope::RegistrableDomain, true; "registrable parent")]
+    #[test_case("media.example.com", DomainScope::RegistrableDomain, true; "registrable sibling")]
+    #[test_case("example.co.uk", DomainScope::RegistrableDomain, false; "different suffix")]
+    #[test]
+    fn domain_rules_match(host: &str, scope: DomainScope, expected: bool) {
+        let rules = DomainRules::new(vec![DomainRule::new("social.example.com", scope)]).unwrap();
+
+        assert_eq!(rules.matches(host), expected);
+    }
+
+    #[test_case("a.spam.example", true; "wildcard subdomain")]
+    #[test_case("a.b.spam.example", true; "wildcard nested subdomain")]
+    #[test_case("spam.example", false; "wildcard parent")]
+    #[test_case("notspam.example", false; "wildcard suffix")]
+    #[test_case("spam42.example", true; "regex")]
+    #[test_case("SPAM7.example", true; "regex is case insensitive")]
+    #[test_case("spam1.example.com", false; "regex is anchored")]
+    #[test_case("exact.example", true; "exact")]
+    #[test]
+    fn domain_patterns_match(host: &str, expected: bool) {
+        let yaml = r#"["*.spam.example", '/^spam[0-9]+\.example$/', exact.example]"#;
+        let rules: DomainRules = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(rules.matches(host), expected);
+    }
+
+    #[test]
+    fn invalid_regexes_are_rejected_on_load() {
+        let res: Result<DomainRules, _> = serde_yaml::from_str(r#"["/spam(/"]"#);
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn domain_rules_are_parsed() {
+        let yaml = r#"[A.example, {domain: b.example}, {domain: social.c.example, scope: registrableDomain}, "*.D.example", "/^e/", {domain: "f.*", scope: regex}]"#;
+        let rules: Vec<DomainRule> = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(
+            rules,
+            vec![
+                DomainRule::new("a.example", DomainScope::Exact),
+                DomainRule::new("b.example", DomainScope::Exact),
+                DomainRule {
+                    domain: "c.example".into(),
+                    scope: DomainScope::RegistrableDomain
+                },
+                DomainRule::new("*.d.example", DomainScope::Wildcard),
+                DomainRule::new("^e", DomainScope::Regex),
+                DomainRule::new("f.*", DomainScope::Regex),
+            ]
+        );
+    }
+
+    #[test_case("listen: 127.0.0.1", &["127.0.0.1:4242"]; "single ipv4")]
+    #[test_case("listen: '::'", &["[::]:4242"]; "single ipv6")]
+    #[test_case("listen: ['[::]:8080', 0.0.0.0]", &["[::]:8080", "0.0.0.0:4242"]; "dual stack")]
+    #[test]
+    fn listen_addresses_are_parsed(yaml: &str, expected: &[&str]) {
+        let Listen { listen } = serde_yaml::from_str(yaml).unwrap();
+        let addrs: Vec<String> = listen
+            .iter()
+            .map(|l| l.socket_addr(4242).to_string())
+            .collect();
+
+        assert_eq!(addrs, expected);
+    }
+
+    #[test_case(true; "generating a key")]
+    #[test_case(false; "without a key")]
+    #[test]
+    fn a_default_config_is_written_if_missing(generate_key: bool) {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        let path = dir.join("config.yaml");
+
+        assert!(Config::load_or_write_default(path.clone(), generate_key).is_none());
+
+        let cfg = Config::load_or_write_default(path, generate_key).expect("config to load");
+        assert_eq!(cfg.data_dir, dir.join("data"));
+        assert_eq!(cfg.private_key_path, dir.join("private-key.pem"));
+        assert_eq!(cfg.private_key_path.exists(), generate_key);
+        if generate_key {
+            let pem = fs::read_to_string(&cfg.private_key_path).unwrap();
+            assert!(crate::signer::ActorKey::from_pem(&pem).is_ok());
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = fs::metadata(&cfg.private_key_path)
+                    .unwrap()
+                    .permissions()
+                    .mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+        }
+
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    // Optional settings shown in the template with their default value are uncommented,
+    // skipping those marked as examples along with anything nested under them
+    fn uncomment_defaults(template: &str) -> String {
+        let is_setting = |s: &str| match s.trim_start().trim_start_matches("- ").split_once(':') {
+            Some((key, _)) => !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric()),
+            None => false,
+        };
+
+        let mut lines = vec![];
+        let mut skipping_below = None;
+        for line in template.lines() {
+            let trimmed = line.trim_start();
+            let setting = match trimmed.strip_prefix("# ") {
+                Some(setting) if is_setting(setting) => setting,
+                _ => {
+                    skipping_below = None;
+                    lines.push(line.to_owned());
+                    continue;
+                }
+            };
+
+            let indent = line.len() - trimmed.len();
+            let depth = indent + setting.len() - setting.trim_start().len();
+            if skipping_below.is_some_and(|d| depth > d) {
+                continue;
+            }
+            skipping_below = None;
+            if setting.ends_with("# example") {
+                skipping_below = Some(depth);
+                continue;
+            }
+
+            lines.push(format!("{}{setting}", &line[..indent]));
+        }
+
+        lines.join("\n")
+    }
+
+    #[test]
+    fn settings_commented_out_in_the_template_are_the_defaults() {
+        let dir = Path::new("/srv/actiserve");
+        let template = default_config(dir, &dir.join("private-key.pem"));
+        let uncommented = uncomment_defaults(&template);
+
+        let written: Config = serde_yaml::from_str(&template).unwrap();
+        let defaults: Config = serde_yaml::from_str(&uncommented).unwrap();
+
+        assert!(uncommented.contains("\nreverifyIntervalSecs: 86400\n"));
+        assert!(uncommented.contains("\n  inviteOnly: false\n"));
+
+        let raw: Value = serde_yaml::from_str(&uncommented).unwrap();
+        let known = serde_yaml::to_value(&defaults).unwrap();
+        let mut unknown = vec![];
+        unknown_keys(&raw, &known, "", &mut unknown);
+        assert!(
+            unknown.is_empty(),
+            "unknown keys in the template: {unknown:?}"
+        );
+
+        assert_eq!(
+            serde_json::to_value(defaults).unwrap(),
+            serde_json::to_value(written).unwrap()
+        );
+    }
+
+    const MAIN_CONFIG: &str = "
+listen: 127.0.0.1
+port: 4242
+dataDir: data
+privateKeyPath: private-key.pem
+dryRun: true
+activityPub:
+  host: relay.example
+  blockedInstances: [a.example]
+  allowList: false
+  allowedInstances: []
+";
+
+    #[test]
+    fn included_files_are_merged_in_order() {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(
+            &path,
+            format!("include: [conf.d, extra.yaml]\n{MAIN_CONFIG}"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/20-more-blocks.yaml"),
+            "activityPub: {blockedInstances: [c.example]}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/10-blocks.yml"),
+            "include: ../nested.yaml\nactivityPub: {blockedInstances: [b.example]}",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/ignored.txt"), "port: 1").unwrap();
+        fs::write(
+            dir.join("nested.yaml"),
+            "upstreams: [https://up.example/actor]",
+        )
+        .unwrap();
+        fs::write(dir.join("extra.yaml"), "dryRun: false\nport: 8080").unwrap();
+
+        let cfg = Config::from_path(&path).unwrap();
+        let blocked: Vec<&str> = cfg
+            .activity_pub
+            .blocked_instances
+            .rules()
+            .iter()
+            .map(|r| r.domain.as_str())
+            .collect();
+
+        assert_eq!(blocked, vec!["a.example", "b.example", "c.example"]);
+        assert_eq!(cfg.upstreams, vec!["https://up.example/actor"]);
+        assert_eq!(cfg.port, 8080);
+        assert!(!cfg.dry_run);
+        assert_eq!(cfg.activity_pub.host, "relay.example");
+
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(dir.join("conf.d/more")).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(&path, format!("include: conf.d\n{MAIN_CONFIG}")).unwrap();
+        fs::write(dir.join("conf.d/10-port.yaml"), "include: more/port.yaml").unwrap();
+        fs::write(dir.join("conf.d/more/port.yaml"), "port: 8080").unwrap();
+        // Would be picked up if the include was resolved relative to config.yaml
+        fs::create_dir_all(dir.join("more")).unwrap();
+        fs::write(dir.join("more/port.yaml"), "port: 9090").unwrap();
+
+        let cfg = Config::from_path(&path).unwrap();
+
+        assert_eq!(cfg.port, 8080);
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("# blockedInstances: [b.example]\n"; "comments only")]
+    #[test_case("---\n"; "empty document")]
+    #[test]
+    fn empty_includes_are_ignored(include: &str) {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(&path, format!("include: blocks.yaml\n{MAIN_CONFIG}")).unwrap();
+        fs::write(dir.join("blocks.yaml"), include).unwrap();
+
+        let cfg = Config::from_path(&path).unwrap();
+
+        assert_eq!(cfg.activity_pub.host, "relay.example");
+        assert!(cfg.dry_run);
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("ingest: {workers: 0}"; "no ingest workers")]
+    #[test_case("delivery: {workers: 0}"; "no delivery workers")]
+    #[test_case("signer: {commandTimeoutSecs: 0}"; "no signing command timeout")]
+    #[test_case("reverifyIntervalSecs: 0"; "no reverify interval")]
+    #[test_case("blocklists: {refreshIntervalSecs: 0}"; "no blocklist refresh interval")]
+    #[test]
+    fn unusable_values_are_rejected(overlay: &str) {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(&path, format!("{MAIN_CONFIG}{overlay}\n")).unwrap();
+
+        assert!(Config::from_path(&path).is_err());
+
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("include: config.yaml"; "cycle")]
+    #[test_case("include: missing.yaml"; "missing file")]
+    #[test_case("include: {path: extra.yaml}"; "not a path")]
+    #[test]
+    fn invalid_includes_are_errors(include: &str) {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(&path, format!("{include}\n{MAIN_CONFIG}")).unwrap();
+
+        assert!(Config::from_path(&path).is_err());
+
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
 }
@@ -0,0 +1,803 @@
+//! Outbound deliveries to subscriber inboxes
+//!
+//! Deliveries are queued and sent by a pool of background workers so that a slow or
+//! unavailable subscriber can't hold up relaying to everyone else. Failed deliveries
+//! are retried with an exponential backoff, and the queue is bounded (both globally
+//! and per instance) so that an extended outage at one subscriber can't grow it
+//! without limit.
+use crate::{
+    config::{DeliveryConfig, ShedPolicy},
+    signature::Body,
+    state::State,
+    util::host_from_uri,
+    Error, Result,
+};
+use axum::body::Bytes;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Notify, time::timeout};
+
+// How long an idle worker waits before re-checking for deliveries whose backoff
+// has expired.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+// How long a delivery is put back for when its host already has as many requests in
+// flight as we allow
+const BUSY_HOST_DELAY: Duration = Duration::from_millis(250);
+
+// Activities that drive the follow handshake with other servers. These are sent ahead
+// of bulk relay traffic so that subscriptions aren't held up when we are backlogged.
+const CONTROL_ACTIVITIES: [&str; 4] = ["Accept", "Follow", "Reject", "Undo"];
+
+/// How urgently a delivery should be sent relative to others in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Relayed content
+    Bulk,
+    /// Activities managing subscriptions
+    Control,
+}
+
+impl Priority {
+    fn for_message(message: &Value) -> Self {
+        match message["type"].as_str() {
+            Some(ty) if CONTROL_ACTIVITIES.contains(&ty) => Self::Control,
+            _ => Self::Bulk,
+        }
+    }
+}
+
+/// A single message destined for a subscriber inbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// The name of the relay actor sending the message
+    pub actor: String,
+    pub inbox: String,
+    /// The serialized message, shared between all deliveries of the same message
+    pub body: Body,
+    pub priority: Priority,
+}
+
+impl Delivery {
+    pub fn new(actor: impl Into<String>, inbox: impl Into<String>, message: Value) -> Self {
+        let priority = Priority::for_message(&message);
+
+        Self {
+            actor: actor.into(),
+            inbox: inbox.into(),
+            body: Body::new(message.to_string()),
+            priority,
+        }
+    }
+
+    /// Deliveries of the same message to each of the given inboxes. The message is
+    /// serialized once and the resulting body shared by every delivery.
+    pub fn fanout(actor: &str, inboxes: &[String], message: &Value) -> Vec<Self> {
+        let priority = Priority::for_message(message);
+
+        Self::fanout_body(actor, inboxes, Body::new(message.to_string()), priority)
+    }
+
+    /// As [Delivery::fanout] but sending the message exactly as it was serialized when
+    /// we received it, so that anything signed over those bytes can still be verified.
+    pub fn fanout_raw(
+        actor: &str,
+        inboxes: &[String],
+        message: &Value,
+        raw: impl Into<Bytes>,
+    ) -> Vec<Self> {
+        let priority = Priority::for_message(message);
+
+        Self::fanout_body(actor, inboxes, Body::new(raw), priority)
+    }
+
+    fn fanout_body(actor: &str, inboxes: &[String], body: Body, priority: Priority) -> Vec<Self> {
+        inboxes
+            .iter()
+            .map(|inbox| Self {
+                actor: actor.to_owned(),
+                inbox: inbox.clone(),
+                body: body.clone(),
+                priority,
+            })
+            .collect()
+    }
+
+    pub fn from_message<T: Serialize>(
+        actor: impl Into<String>,
+        inbox: impl Into<String>,
+        message: &T,
+    ) -> Result<Self> {
+        let inbox = inbox.into();
+        let message = serde_json::to_value(message).map_err(|e| Error::InvalidJson {
+            uri: inbox.clone(),
+            raw: e.to_string(),
+        })?;
+
+        Ok(Self::new(actor, inbox, message))
+    }
+}
+
+/// A delivery waiting in the queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queued {
+    pub delivery: Delivery,
+    pub host: String,
+    pub attempts: u32,
+    not_before: Option<Instant>,
+}
+
+/// A delivery dropped from the queue due to the configured queue limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shed {
+    pub delivery: Delivery,
+    pub host: String,
+    pub reason: &'static str,
+}
+
+/// The current state of the delivery queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStatus {
+    pub paused: bool,
+    pub paused_instances: Vec<String>,
+    pub queued: usize,
+}
+
+// The deliveries queued for a single host
+#[derive(Debug, Default)]
+struct HostQueue {
+    by_age: BTreeSet<u64>,
+    by_priority: BTreeSet<(Priority, u64)>,
+}
+
+// Queued deliveries are keyed by a sequence number giving the order they were queued in,
+// and indexed so that picking the next delivery to send (or to shed) doesn't mean
+// scanning the whole queue.
+#[derive(Debug, Default)]
+struct Inner {
+    paused: bool,
+    paused_hosts: BTreeSet<String>,
+    next_seq: u64,
+    queue: BTreeMap<u64, Queued>,
+    // lowest priority first, then oldest first
+    by_priority: BTreeSet<(Priority, u64)>,
+    // deliveries that can be sent now (unless relaying is paused globally), highest
+    // priority first, then oldest first
+    ready: BTreeSet<(Reverse<Priority>, u64)>,
+    // deliveries waiting for their retry backoff to expire
+    waiting: BTreeSet<(Instant, u64)>,
+    hosts: HashMap<String, HostQueue>,
+}
+
+impl Inner {
+    fn n_queued(&self, host: Option<&str>) -> usize {
+        match host {
+            Some(host) => self.hosts.get(host).map_or(0, |h| h.by_age.len()),
+            None => self.queue.len(),
+        }
+    }
+
+    fn oldest(&self, host: Option<&str>) -> Option<u64> {
+        match host {
+            Some(host) => self.hosts.get(host)?.by_age.first().copied(),
+            None => self.queue.keys().next().copied(),
+        }
+    }
+
+    fn lowest_priority(&self, host: Option<&str>) -> Option<(Priority, u64)> {
+        match host {
+            Some(host) => self.hosts.get(host)?.by_priority.first().copied(),
+            None => self.by_priority.first().copied(),
+        }
+    }
+
+    fn push(&mut self, queued: Queued) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let priority = queued.delivery.priority;
+
+        let host = self.hosts.entry(queued.host.clone()).or_default();
+        host.by_age.insert(seq);
+        host.by_priority.insert((priority, seq));
+        self.by_priority.insert((priority, seq));
+        match queued.not_before {
+            Some(not_before) => {
+                self.waiting.insert((not_before, seq));
+            }
+            None if self.paused_hosts.contains(&queued.host) => (),
+            None => {
+                self.ready.insert((Reverse(priority), seq));
+            }
+        }
+
+        self.queue.insert(seq, queued);
+    }
+
+    fn remove(&mut self, seq: u64) -> Option<Queued> {
+        let queued = self.queue.remove(&seq)?;
+        let priority = queued.delivery.priority;
+
+        self.by_priority.remove(&(priority, seq));
+        self.ready.remove(&(Reverse(priority), seq));
+        if let Some(not_before) = queued.not_before {
+            self.waiting.remove(&(not_before, seq));
+        }
+        if let Some(host) = self.hosts.get_mut(&queued.host) {
+            host.by_age.remove(&seq);
+            host.by_priority.remove(&(priority, seq));
+            if host.by_age.is_empty() {
+                self.hosts.remove(&queued.host);
+            }
+        }
+
+        Some(queued)
+    }
+
+    // Deliveries whose backoff has expired become ready to send, unless their host is
+    // paused in which case they are made ready when it is resumed
+    fn promote_waiting(&mut self, now: Instant) {
+        while let Some(&(not_before, seq)) = self.waiting.first() {
+            if not_before > now {
+                break;
+            }
+
+            self.waiting.pop_first();
+            if let Some(queued) = self.queue.get(&seq) {
+                if !self.paused_hosts.contains(&queued.host) {
+                    self.ready.insert((Reverse(queued.delivery.priority), seq));
+                }
+            }
+        }
+    }
+
+    fn pause_host(&mut self, host: &str) {
+        if !self.paused_hosts.insert(host.to_owned()) {
+            return;
+        }
+
+        if let Some(queue) = self.hosts.get(host) {
+            for &(priority, seq) in &queue.by_priority {
+                self.ready.remove(&(Reverse(priority), seq));
+            }
+        }
+    }
+
+    fn resume_host(&mut self, host: &str, now: Instant) {
+        if !self.paused_hosts.remove(host) {
+            return;
+        }
+
+        if let Some(queue) = self.hosts.get(host) {
+            for &(priority, seq) in &queue.by_priority {
+                let is_waiting = self.queue[&seq].not_before.is_some_and(|t| t > now);
+                if !is_waiting {
+                    self.ready.insert((Reverse(priority), seq));
+                }
+            }
+        }
+    }
+}
+
+/// The queue of pending deliveries.
+///
+/// Deliveries are sent in the order they were queued, skipping over any that are
+/// waiting to be retried or for which relaying is currently paused. Paused
+/// deliveries remain queued (subject to the queue limits) until relaying resumes.
+#[derive(Debug)]
+pub struct Deliveries {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    max_queued: usize,
+    max_queued_per_instance: usize,
+    shed_policy: ShedPolicy,
+}
+
+impl Deliveries {
+    pub fn new(cfg: &DeliveryConfig) -> Self {
+        Self {
+            inner: Default::default(),
+            notify: Notify::new(),
+            max_queued: cfg.max_queued,
+            max_queued_per_instance: cfg.max_queued_per_instance,
+            shed_policy: cfg.shed_policy,
+        }
+    }
+
+    /// Add deliveries to the back of the queue, returning any deliveries that had to
+    /// be dropped in order to stay within the queue limits.
+    pub fn enqueue(&self, deliveries: Vec<Delivery>) -> Vec<Shed> {
+        let mut shed = vec![];
+        let mut inner = self.inner.lock().unwrap();
+
+        for delivery in deliveries {
+            let host = match host_from_uri(&delivery.inbox) {
+                Ok(host) => host,
+                Err(_) => {
+                    shed.push(Shed {
+                        delivery,
+                        host: String::new(),
+                        reason: "invalid_inbox",
+                    });
+                    continue;
+                }
+            };
+
+            let queued = Queued {
+                delivery,
+                host,
+                attempts: 0,
+                not_before: None,
+            };
+
+            if let Some(queued) = self.push_within_limits(&mut inner, queued, &mut shed) {
+                inner.push(queued);
+            }
+        }
+
+        drop(inner);
+        self.notify.notify_waiters();
+
+        shed
+    }
+
+    // Make room for a new delivery according to the shed policy, returning the
+    // delivery if it should still be queued.
+    fn push_within_limits(
+        &self,
+        inner: &mut Inner,
+        queued: Queued,
+        shed: &mut Vec<Shed>,
+    ) -> Option<Queued> {
+        let limits = [
+            (
+                "instance_limit",
+                self.max_queued_per_instance,
+                Some(queued.host.clone()),
+            ),
+            ("queue_limit", self.max_queued, None),
+        ];
+
+        for (reason, limit, host) in limits {
+            let host = host.as_deref();
+            if inner.n_queued(host) < limit {
+                continue;
+            }
+
+            let to_drop = match self.shed_policy {
+                ShedPolicy::DropNewest => None,
+                ShedPolicy::DropOldest => inner.oldest(host),
+                ShedPolicy::DropLowestPriority => inner
+                    .lowest_priority(host)
+                    .filter(|(priority, _)| *priority <= queued.delivery.priority)
+                    .map(|(_, seq)| seq),
+            };
+
+            match to_drop.and_then(|seq| inner.remove(seq)) {
+                Some(Queued { delivery, host, .. }) => shed.push(Shed {
+                    delivery,
+                    host,
+                    reason,
+                }),
+
+                // Nothing to make room with so the new delivery is dropped instead
+                None => {
+                    shed.push(Shed {
+                        delivery: queued.delivery,
+                        host: queued.host,
+                        reason,
+                    });
+
+                    return None;
+                }
+            }
+        }
+
+        Some(queued)
+    }
+
+    /// Take the next delivery that is ready to be sent, if there is one. Higher
+    /// priority deliveries are taken first.
+    pub fn next_ready(&self) -> Option<Queued> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.promote_waiting(Instant::now());
+        if inner.paused {
+            return None;
+        }
+
+        let &(_, seq) = inner.ready.first()?;
+        inner.remove(seq)
+    }
+
+    /// Requeue a failed delivery, backing off exponentially based on the number of
+    /// attempts made so far.
+    pub fn retry(&self, mut queued: Queued) -> Vec<Shed> {
+        queued.attempts += 1;
+        let backoff = RETRY_BACKOFF * 2u32.saturating_pow(queued.attempts - 1);
+        self.requeue(queued, backoff)
+    }
+
+    /// Requeue a delivery whose host was busy, to be sent once other deliveries have had
+    /// a chance. This doesn't count as a failed attempt.
+    pub fn defer(&self, queued: Queued) -> Vec<Shed> {
+        self.requeue(queued, BUSY_HOST_DELAY)
+    }
+
+    fn requeue(&self, mut queued: Queued, delay: Duration) -> Vec<Shed> {
+        queued.not_before = Some(Instant::now() + delay);
+
+        let mut shed = vec![];
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(queued) = self.push_within_limits(&mut inner, queued, &mut shed) {
+            inner.push(queued);
+        }
+
+        shed
+    }
+
+    /// Pause deliveries for the given host, or for all hosts if none is given.
+    pub fn pause(&self, host: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        match host {
+            Some(host) => inner.pause_host(host),
+            None => inner.paused = true,
+        }
+    }
+
+    /// Resume deliveries for the given host, or globally if none is given. Anything
+    /// queued while paused will be sent in the order it was received.
+    pub fn resume(&self, host: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        match host {
+            Some(host) => inner.resume_host(host, Instant::now()),
+            None => inner.paused = false,
+        }
+
+        drop(inner);
+        self.notify.notify_waiters();
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let inner = self.inner.lock().unwrap();
+
+        QueueStatus {
+            paused: inner.paused,
+            paused_instances: inner.paused_hosts.iter().cloned().collect(),
+            queued: inner.queue.len(),
+        }
+    }
+}
+
+/// Send queued deliveries until the process exits.
+pub async fn run_worker(state: Arc<State>) {
+    loop {
+        // Registered before checking the queue so that we can't miss a wakeup
+        let notified = state.deliveries.notify.notified();
+
+        match state.deliveries.next_ready() {
+            Some(queued) => state.attempt_delivery(queued).await,
+            None => {
+                let _ = timeout(POLL_INTERVAL, notified).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    fn delivery(inbox: &str, n: u8) -> Delivery {
+        Delivery::new("relay", inbox, json!({ "type": "Announce", "n": n }))
+    }
+
+    fn control(inbox: &str, n: u8) -> Delivery {
+        Delivery::new("relay", inbox, json!({ "type": "Accept", "n": n }))
+    }
+
+    fn deliveries(
+        max_queued: usize,
+        max_queued_per_instance: usize,
+        policy: ShedPolicy,
+    ) -> Deliveries {
+        Deliveries::new(&DeliveryConfig {
+            max_queued,
+            max_queued_per_instance,
+            shed_policy: policy,
+            ..Default::default()
+        })
+    }
+
+    fn drain(ds: &Deliveries) -> Vec<Delivery> {
+        std::iter::from_fn(|| ds.next_ready().map(|q| q.delivery)).collect()
+    }
+
+    #[test]
+    fn deliveries_are_sent_in_order() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        let expected = vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+            delivery("https://a.example/inbox", 3),
+        ];
+
+        assert_eq!(ds.enqueue(expected.clone()), vec![]);
+        assert_eq!(drain(&ds), expected);
+        assert_eq!(ds.status().queued, 0);
+    }
+
+    #[test]
+    fn global_pause_holds_everything_and_resume_releases_in_order() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.pause(None);
+
+        let expected = vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+            delivery("https://a.example/inbox", 3),
+        ];
+        ds.enqueue(expected.clone());
+        assert_eq!(ds.next_ready(), None);
+        assert_eq!(ds.status().queued, 3);
+
+        ds.resume(None);
+        assert_eq!(drain(&ds), expected);
+    }
+
+    #[test]
+    fn instance_pause_only_holds_that_instance() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.pause(Some("a.example"));
+        ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+        ]);
+
+        assert_eq!(drain(&ds), vec![delivery("https://b.example/inbox", 2)]);
+
+        ds.resume(Some("a.example"));
+        assert_eq!(drain(&ds), vec![delivery("https://a.example/inbox", 1)]);
+    }
+
+    #[test]
+    fn resumed_instances_keep_their_place_in_the_queue() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+            delivery("https://a.example/inbox", 3),
+        ]);
+        ds.pause(Some("a.example"));
+        assert_eq!(drain(&ds), vec![delivery("https://b.example/inbox", 2)]);
+
+        ds.enqueue(vec![delivery("https://b.example/inbox", 4)]);
+        ds.resume(Some("a.example"));
+
+        assert_eq!(
+            drain(&ds),
+            vec![
+                delivery("https://a.example/inbox", 1),
+                delivery("https://a.example/inbox", 3),
+                delivery("https://b.example/inbox", 4),
+            ]
+        );
+        assert_eq!(ds.status().queued, 0);
+    }
+
+    #[test]
+    fn resuming_globally_keeps_instance_pauses_in_place() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.pause(None);
+        ds.pause(Some("a.example"));
+        ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+        ]);
+
+        ds.resume(None);
+
+        assert_eq!(drain(&ds), vec![delivery("https://b.example/inbox", 2)]);
+        assert_eq!(
+            ds.status(),
+            QueueStatus {
+                paused: false,
+                paused_instances: vec!["a.example".to_owned()],
+                queued: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn retried_deliveries_wait_for_their_backoff() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.enqueue(vec![delivery("https://a.example/inbox", 1)]);
+
+        let queued = ds.next_ready().unwrap();
+        ds.retry(queued);
+
+        assert_eq!(ds.next_ready(), None);
+        assert_eq!(ds.status().queued, 1);
+    }
+
+    #[test]
+    fn deferred_deliveries_are_not_counted_as_attempts() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+        ]);
+
+        let queued = ds.next_ready().unwrap();
+        ds.defer(queued);
+
+        assert_eq!(
+            ds.next_ready().map(|q| q.delivery),
+            Some(delivery("https://b.example/inbox", 2))
+        );
+        std::thread::sleep(BUSY_HOST_DELAY);
+
+        let deferred = ds.next_ready().unwrap();
+        assert_eq!(deferred.delivery, delivery("https://a.example/inbox", 1));
+        assert_eq!(deferred.attempts, 0);
+    }
+
+    #[test_case(ShedPolicy::DropOldest, &[2, 3], &[1]; "drop oldest")]
+    #[test_case(ShedPolicy::DropNewest, &[1, 2], &[3]; "drop newest")]
+    #[test]
+    fn instance_limit_is_enforced(policy: ShedPolicy, kept: &[u8], dropped: &[u8]) {
+        let ds = deliveries(10, 2, policy);
+        ds.enqueue(vec![delivery("https://b.example/inbox", 0)]);
+
+        let shed = ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://a.example/inbox", 2),
+            delivery("https://a.example/inbox", 3),
+        ]);
+
+        let mut expected = vec![delivery("https://b.example/inbox", 0)];
+        expected.extend(kept.iter().map(|&n| delivery("https://a.example/inbox", n)));
+        let expected_shed: Vec<Shed> = dropped
+            .iter()
+            .map(|&n| Shed {
+                delivery: delivery("https://a.example/inbox", n),
+                host: "a.example".to_owned(),
+                reason: "instance_limit",
+            })
+            .collect();
+
+        assert_eq!(shed, expected_shed);
+        assert_eq!(drain(&ds), expected);
+    }
+
+    #[test_case(ShedPolicy::DropOldest, &[2, 3], &[1]; "drop oldest")]
+    #[test_case(ShedPolicy::DropNewest, &[1, 2], &[3]; "drop newest")]
+    #[test]
+    fn global_limit_is_enforced(policy: ShedPolicy, kept: &[u8], dropped: &[u8]) {
+        let ds = deliveries(2, 10, policy);
+
+        let shed = ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+            delivery("https://c.example/inbox", 3),
+        ]);
+
+        let host = |n: u8| ["", "a.example", "b.example", "c.example"][n as usize];
+        let inbox = |n: u8| format!("https://{}/inbox", host(n));
+        let expected: Vec<Delivery> = kept.iter().map(|&n| delivery(&inbox(n), n)).collect();
+        let expected_shed: Vec<Shed> = dropped
+            .iter()
+            .map(|&n| Shed {
+                delivery: delivery(&inbox(n), n),
+                host: host(n).to_owned(),
+                reason: "queue_limit",
+            })
+            .collect();
+
+        assert_eq!(shed, expected_shed);
+        assert_eq!(drain(&ds), expected);
+    }
+
+    #[test_case("Accept", Priority::Control; "accept")]
+    #[test_case("Follow", Priority::Control; "follow")]
+    #[test_case("Reject", Priority::Control; "reject")]
+    #[test_case("Undo", Priority::Control; "undo")]
+    #[test_case("Announce", Priority::Bulk; "announce")]
+    #[test_case("Delete", Priority::Bulk; "delete")]
+    #[test]
+    fn priority_is_determined_by_activity_type(ty: &str, expected: Priority) {
+        let d = Delivery::new("relay", "https://a.example/inbox", json!({ "type": ty }));
+
+        assert_eq!(d.priority, expected);
+    }
+
+    #[test]
+    fn fanned_out_deliveries_share_their_body() {
+        let inboxes = vec![
+            "https://a.example/inbox".to_owned(),
+            "https://b.example/inbox".to_owned(),
+        ];
+        let message = json!({ "type": "Announce", "object": "https://c.example/notes/1" });
+
+        let ds = Delivery::fanout("relay", &inboxes, &message);
+
+        assert_eq!(ds.len(), 2);
+        assert_eq!(ds[0].inbox, "https://a.example/inbox");
+        assert_eq!(ds[0].priority, Priority::Bulk);
+        assert_eq!(ds[0].body, Delivery::new("relay", "x", message).body);
+        assert_eq!(ds[0].body.bytes().as_ptr(), ds[1].body.bytes().as_ptr());
+    }
+
+    #[test]
+    fn control_activities_are_sent_first() {
+        let ds = deliveries(10, 10, ShedPolicy::DropOldest);
+        ds.enqueue(vec![
+            delivery("https://a.example/inbox", 1),
+            control("https://b.example/inbox", 2),
+            delivery("https://a.example/inbox", 3),
+            control("https://c.example/inbox", 4),
+        ]);
+
+        assert_eq!(
+            drain(&ds),
+            vec![
+                control("https://b.example/inbox", 2),
+                control("https://c.example/inbox", 4),
+                delivery("https://a.example/inbox", 1),
+                delivery("https://a.example/inbox", 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_lowest_priority_sheds_bulk_before_control() {
+        let ds = deliveries(2, 10, ShedPolicy::DropLowestPriority);
+        ds.enqueue(vec![
+            control("https://a.example/inbox", 1),
+            delivery("https://b.example/inbox", 2),
+        ]);
+
+        let shed = ds.enqueue(vec![control("https://c.example/inbox", 3)]);
+
+        assert_eq!(
+            shed,
+            vec![Shed {
+                delivery: delivery("https://b.example/inbox", 2),
+                host: "b.example".to_owned(),
+                reason: "queue_limit",
+            }]
+        );
+        assert_eq!(
+            drain(&ds),
+            vec![
+                control("https://a.example/inbox", 1),
+                control("https://c.example/inbox", 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_lowest_priority_drops_new_bulk_when_only_control_is_queued() {
+        let ds = deliveries(1, 10, ShedPolicy::DropLowestPriority);
+        ds.enqueue(vec![control("https://a.example/inbox", 1)]);
+
+        let shed = ds.enqueue(vec![delivery("https://b.example/inbox", 2)]);
+
+        assert_eq!(
+            shed,
+            vec![Shed {
+                delivery: delivery("https://b.example/inbox", 2),
+                host: "b.example".to_owned(),
+                reason: "queue_limit",
+            }]
+        );
+        assert_eq!(drain(&ds), vec![control("https://a.example/inbox", 1)]);
+    }
+}
@@ -7,7 +7,7 @@ use serde_json::json;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
 pub enum Error {
     #[error("failed http request: {method} {uri} ({status}): {error}")]
     FailedRequest {
@@ -17,6 +17,9 @@ pub enum Error {
         uri: String,
     },
 
+    #[error("{error}")]
+    InvalidConfig { error: String },
+
     #[error("invalid JSON from {uri}: {raw}")]
     InvalidJson { uri: String, raw: String },
 
@@ -62,6 +65,11 @@ impl IntoResponse for Error {
                 Json(json!({ "error": error, "uri": uri, "method": method })),
             ),
 
+            InvalidConfig { .. } => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": error })),
+            ),
+
             InvalidJson { uri, raw } => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({ "error": error, "uri": uri, "raw": raw })),
@@ -0,0 +1,279 @@
+//! Protection against a single origin flooding the relay.
+//!
+//! Inbound activities are counted per origin over fixed windows. When the count for the
+//! current window exceeds a multiple of the origin's average over the trailing windows
+//! the origin is throttled for a while: its Announces are either held back and relayed
+//! once the throttle expires or dropped (see [FloodAction](crate::config::FloodAction)).
+use crate::config::FloodConfig;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Whether an origin is currently being throttled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The origin is throttled, `newly` being true if this activity triggered it
+    Throttled {
+        newly: bool,
+    },
+}
+
+/// An Announce held back while its origin is throttled.
+#[derive(Debug, Clone)]
+pub struct Held {
+    pub relay: String,
+    pub actor_id: String,
+    pub activity: Value,
+    pub host: String,
+    pub path: String,
+}
+
+#[derive(Debug)]
+struct OriginRate {
+    window_start: Instant,
+    current: u64,
+    previous: VecDeque<u64>,
+    throttled_until: Option<Instant>,
+}
+
+impl OriginRate {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            current: 0,
+            previous: VecDeque::new(),
+            throttled_until: None,
+        }
+    }
+
+    // Move on to the window containing `now`, recording any windows with no activity
+    fn roll(&mut self, window: Duration, n_trailing: usize, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let n_windows = (elapsed.as_secs_f64() / window.as_secs_f64()) as u32;
+        if n_windows == 0 {
+            return;
+        }
+
+        self.previous.push_back(self.current);
+        let n_empty = (n_windows as usize - 1).min(n_trailing);
+        self.previous.extend(std::iter::repeat_n(0, n_empty));
+        while self.previous.len() > n_trailing {
+            self.previous.pop_front();
+        }
+        self.current = 0;
+        self.window_start += window * n_windows;
+    }
+
+    // Whether every window we have counted has passed out of the trailing windows and
+    // the origin isn't throttled, so that there is nothing worth keeping
+    fn is_idle(&self, window: Duration, n_trailing: usize, now: Instant) -> bool {
+        let expires = self.window_start + window * (n_trailing as u32 + 1);
+
+        expires <= now && self.throttled_until.map(|t| t <= now).unwrap_or(true)
+    }
+
+    fn trailing_average(&self) -> Option<f64> {
+        if self.previous.is_empty() {
+            return None;
+        }
+
+        Some(self.previous.iter().sum::<u64>() as f64 / self.previous.len() as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FloodGuard {
+    origins: Mutex<HashMap<String, OriginRate>>,
+    held: Mutex<HashMap<String, Vec<Held>>>,
+}
+
+impl FloodGuard {
+    /// Count an activity from the given origin, returning whether the origin is being
+    /// throttled.
+    pub fn record(&self, cfg: &FloodConfig, origin: &str, now: Instant) -> Verdict {
+        let window = Duration::from_secs(cfg.window_secs.max(1));
+        let mut origins = self.origins.lock().unwrap();
+        let rate = origins
+            .entry(origin.to_owned())
+            .or_insert_with(|| OriginRate::new(now));
+
+        rate.roll(window, cfg.trailing_windows, now);
+        rate.current += 1;
+
+        if rate.throttled_until.map(|t| t > now).unwrap_or(false) {
+            return Verdict::Throttled { newly: false };
+        }
+
+        match rate.trailing_average() {
+            Some(avg)
+                if rate.current >= cfg.min_activities
+                    && rate.current as f64 > avg * cfg.threshold =>
+            {
+                rate.throttled_until = Some(now + Duration::from_secs(cfg.throttle_secs));
+                Verdict::Throttled { newly: true }
+            }
+            _ => Verdict::Allow,
+        }
+    }
+
+    /// Hold an Announce from a throttled origin, returning false if the origin already
+    /// has the maximum number of Announces held.
+    pub fn hold(&self, cfg: &FloodConfig, origin: &str, held: Held) -> bool {
+        let mut all_held = self.held.lock().unwrap();
+        let queue = all_held.entry(origin.to_owned()).or_default();
+        if queue.len() >= cfg.max_queued {
+            return false;
+        }
+
+        queue.push(held);
+        true
+    }
+
+    /// Forget the counts of origins that have sent nothing within the trailing windows,
+    /// so that every origin we have ever heard from isn't kept in memory. An origin
+    /// that is pruned is treated as new if it is heard from again.
+    pub fn prune(&self, cfg: &FloodConfig, now: Instant) {
+        let window = Duration::from_secs(cfg.window_secs.max(1));
+        self.origins
+            .lock()
+            .unwrap()
+            .retain(|_, rate| !rate.is_idle(window, cfg.trailing_windows, now));
+    }
+
+    /// Take the held Announces of every origin that is no longer throttled.
+    pub fn release(&self, now: Instant) -> Vec<Held> {
+        let origins = self.origins.lock().unwrap();
+        let mut all_held = self.held.lock().unwrap();
+        let released: Vec<String> = all_held
+            .keys()
+            .filter(|origin| {
+                origins
+                    .get(*origin)
+                    .and_then(|rate| rate.throttled_until)
+                    .map(|t| t <= now)
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect();
+
+        released
+            .iter()
+            .flat_map(|origin| all_held.remove(origin).unwrap_or_default())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FloodAction;
+    use simple_test_case::test_case;
+
+    fn cfg() -> FloodConfig {
+        FloodConfig {
+            enabled: true,
+            window_secs: 60,
+            trailing_windows: 5,
+            threshold: 3.0,
+            min_activities: 10,
+            throttle_secs: 600,
+            action: FloodAction::Queue,
+            max_queued: 2,
+        }
+    }
+
+    // Send `per_window` activities in each of the given number of windows followed by
+    // `spike` activities in the next window, returning the final verdict
+    fn run(
+        guard: &FloodGuard,
+        start: Instant,
+        windows: u64,
+        per_window: u64,
+        spike: u64,
+    ) -> Verdict {
+        for w in 0..windows {
+            for _ in 0..per_window {
+                guard.record(&cfg(), "a.example", start + Duration::from_secs(w * 60));
+            }
+        }
+
+        let mut verdict = Verdict::Allow;
+        for _ in 0..spike {
+            verdict = guard.record(
+                &cfg(),
+                "a.example",
+                start + Duration::from_secs(windows * 60),
+            );
+        }
+
+        verdict
+    }
+
+    #[test_case(5, 10, 20, Verdict::Allow; "within threshold")]
+    #[test_case(5, 2, 9, Verdict::Allow; "below minimum")]
+    #[test_case(5, 2, 10, Verdict::Throttled { newly: true }; "spike")]
+    #[test_case(5, 2, 11, Verdict::Throttled { newly: false }; "already throttled")]
+    #[test_case(0, 0, 100, Verdict::Allow; "no history")]
+    #[test]
+    fn spikes_are_throttled(windows: u64, per_window: u64, spike: u64, expected: Verdict) {
+        let guard = FloodGuard::default();
+
+        assert_eq!(
+            run(&guard, Instant::now(), windows, per_window, spike),
+            expected
+        );
+    }
+
+    #[test]
+    fn held_announces_are_released_once_the_throttle_expires() {
+        let guard = FloodGuard::default();
+        let start = Instant::now();
+        run(&guard, start, 5, 2, 10);
+        let held = Held {
+            relay: "relay".into(),
+            actor_id: "https://a.example/actor".into(),
+            activity: Value::Null,
+            host: "localhost".into(),
+            path: "/inbox".into(),
+        };
+
+        assert!(guard.hold(&cfg(), "a.example", held.clone()));
+        assert!(guard.hold(&cfg(), "a.example", held.clone()));
+        assert!(!guard.hold(&cfg(), "a.example", held));
+        assert!(guard.release(start + Duration::from_secs(360)).is_empty());
+        assert_eq!(guard.release(start + Duration::from_secs(1000)).len(), 2);
+        assert!(guard.release(start + Duration::from_secs(1000)).is_empty());
+    }
+
+    #[test_case(300, 1; "within trailing windows")]
+    #[test_case(359, 1; "still in the last trailing window")]
+    #[test_case(360, 0; "outside trailing windows")]
+    #[test]
+    fn idle_origins_are_pruned(elapsed_secs: u64, expected: usize) {
+        let guard = FloodGuard::default();
+        let start = Instant::now();
+        guard.record(&cfg(), "a.example", start);
+
+        guard.prune(&cfg(), start + Duration::from_secs(elapsed_secs));
+
+        assert_eq!(guard.origins.lock().unwrap().len(), expected);
+    }
+
+    #[test]
+    fn throttled_origins_are_not_pruned() {
+        let guard = FloodGuard::default();
+        let start = Instant::now();
+        run(&guard, start, 5, 2, 10);
+
+        // The spike was in the window starting at 300s, and the throttle runs to 900s
+        guard.prune(&cfg(), start + Duration::from_secs(660));
+        assert_eq!(guard.origins.lock().unwrap().len(), 1);
+
+        guard.prune(&cfg(), start + Duration::from_secs(900));
+        assert!(guard.origins.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+//!
+//! The targets feed attacker-controlled input to parsers that aren't otherwise part of
+//! the public API, and to an inbox backed by server state that never touches the
+//! network. Only built with the `fuzzing` feature.
+use crate::{
+    actors::DEFAULT_ACTOR,
+    client::{new_priv_key_pem, RemoteActor},
+    config::Config,
+    pipeline::{Flow, Inbound, Stage},
+    routes::{inbox, well_known},
+    signature::{self, Malformed},
+    state::{Db, State},
+    Error, Result,
+};
+use axum::async_trait;
+use serde_json::{json, Value};
+use std::{collections::HashMap, path::PathBuf};
+
+/// The host that requests to the offline state are made to
+pub const HOST: &str = "relay.example";
+
+/// The actor that every inbox request to the offline state is taken to be from
+pub const ACTOR_ID: &str = "https://origin.example/actor";
+
+/// The last stage run before an inbox request is accepted, with every stage after it
+/// being run by the ingest workers.
+pub const ACCEPT_AFTER_STAGE: &str = inbox::ACCEPT_AFTER_STAGE;
+
+// Nothing listens on the discard port, so every request made through it fails at once
+const UNREACHABLE_PROXY: &str = "http://127.0.0.1:9";
+
+pub fn split_signature(s: &str) -> std::result::Result<HashMap<&str, &str>, Malformed> {
+    signature::split_signature(s)
+}
+
+pub fn parse_webfinger_resource(resource: &str) -> Result<(&str, &str)> {
+    well_known::parse_webfinger_resource(resource)
+}
+
+/// Server state that never touches the network: the sending actor is never fetched (it
+/// is always [ACTOR_ID], presenting the relay's own key), any other request fails as
+/// soon as it is made and deliveries are only logged.
+///
+/// Requests to the inbox will need to be signed with the relay's key to get past the
+/// signature check: to fuzz the stages after it, build an [Inbound] with the actor from
+/// [offline_actor] and run them directly from [ACCEPT_AFTER_STAGE].
+pub fn offline_state(data_dir: PathBuf) -> Result<State> {
+    let cfg: Config = serde_json::from_value(json!({
+        "listen": "127.0.0.1",
+        "port": 4242,
+        "dataDir": data_dir,
+        "privateKeyPath": "private-key.pem",
+        "dryRun": true,
+        "proxy": { "url": UNREACHABLE_PROXY },
+        "activityPub": {
+            "host": HOST,
+            "blockedInstances": [],
+            "allowList": false,
+            "allowedInstances": [],
+        },
+    }))
+    .map_err(|e| Error::InvalidJson {
+        uri: "fuzzing config".to_owned(),
+        raw: e.to_string(),
+    })?;
+
+    let db = Db::new(data_dir)?;
+    let mut state = State::new(cfg, db, &new_priv_key_pem()?)?;
+    let actor = actor_json(&state);
+    state
+        .pipeline
+        .replace("fetch_actor", Box::new(OfflineFetchActor { actor }));
+
+    Ok(state)
+}
+
+/// The sending actor of every inbox request to the given offline state, as it would have
+/// been fetched by the time the request was accepted.
+pub fn offline_actor(state: &State) -> Result<RemoteActor> {
+    RemoteActor::from_json(ACTOR_ID, actor_json(state))
+}
+
+fn actor_json(state: &State) -> Value {
+    json!({
+        "id": ACTOR_ID,
+        "type": "Application",
+        "inbox": "https://origin.example/inbox",
+        "publicKey": {
+            "id": format!("{ACTOR_ID}#main-key"),
+            "owner": ACTOR_ID,
+            "publicKeyPem": state.client.pub_key(DEFAULT_ACTOR),
+        },
+    })
+}
+
+#[derive(Debug)]
+struct OfflineFetchActor {
+    actor: Value,
+}
+
+#[async_trait]
+impl Stage for OfflineFetchActor {
+    fn name(&self) -> &'static str {
+        "offline_fetch_actor"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, _: &State) -> Result<Flow> {
+        inbound.actor = Some(RemoteActor::from_json(ACTOR_ID, self.actor.clone())?);
+
+        Ok(Flow::Continue)
+    }
+}
@@ -0,0 +1,311 @@
+//! A record of everything that has been relayed.
+//!
+//! This is the source of truth for whether or not we have already relayed (or
+//! forwarded) a given object, and is kept trimmed according to the configured
+//! retention policy.
+//!
+//! Entries are recorded in memory as objects are relayed and written to storage by
+//! [History::flush], which is run periodically, so that relaying an object doesn't
+//! mean rewriting the whole history. Anything recorded since the last flush is lost
+//! if the relay stops without flushing.
+use crate::{config::HistoryConfig, storage::Storage};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+/// A single relayed object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    /// The relay actor that relayed the object
+    pub relay: String,
+    pub object_id: String,
+    /// The id of the activity we sent to subscribers
+    pub activity_id: String,
+    /// The instance the object was received from
+    pub origin: String,
+    pub relayed_at: DateTime<Utc>,
+    /// The inboxes the activity was delivered to, if it wasn't sent to every subscriber
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients: Option<Vec<String>>,
+    /// Whether this records a Delete of the object being forwarded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+pub type Entries = VecDeque<HistoryEntry>;
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: Entries,
+    // map of (relay actor, object id) to the latest entry for fast lookups
+    index: HashMap<(String, String), HistoryEntry>,
+    // whether there are changes that haven't been flushed to storage
+    dirty: bool,
+}
+
+#[derive(Debug)]
+pub struct History {
+    storage: Box<dyn Storage<Entries>>,
+    inner: Mutex<Inner>,
+    // held while writing to storage so that flushes can't overtake each other
+    flushing: Mutex<()>,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+impl History {
+    pub fn new(storage: Box<dyn Storage<Entries>>, cfg: &HistoryConfig) -> Self {
+        let entries = storage.load();
+        let index = entries
+            .iter()
+            .map(|e| ((e.relay.clone(), e.object_id.clone()), e.clone()))
+            .collect();
+
+        let history = Self {
+            storage,
+            inner: Mutex::new(Inner {
+                entries,
+                index,
+                dirty: false,
+            }),
+            flushing: Mutex::new(()),
+            max_entries: cfg.max_entries,
+            max_age: Duration::hours(cfg.max_age_hours as i64),
+        };
+
+        history.apply_retention(Utc::now());
+        history.flush();
+
+        history
+    }
+
+    /// The id of the activity the given object was relayed as, if it has been relayed
+    /// by the named relay actor.
+    pub fn get(&self, relay: &str, object_id: &str) -> Option<String> {
+        self.entry(relay, object_id).map(|e| e.activity_id)
+    }
+
+    /// The most recent entry for the given object relayed by the named relay actor.
+    pub fn entry(&self, relay: &str, object_id: &str) -> Option<HistoryEntry> {
+        self.inner
+            .lock()
+            .unwrap()
+            .index
+            .get(&(relay.to_owned(), object_id.to_owned()))
+            .cloned()
+    }
+
+    pub fn record(&self, entry: HistoryEntry) {
+        let now = entry.relayed_at;
+        let mut inner = self.inner.lock().unwrap();
+        inner.index.insert(
+            (entry.relay.clone(), entry.object_id.clone()),
+            entry.clone(),
+        );
+        inner.entries.push_back(entry);
+        self.trim(&mut inner, now);
+        inner.dirty = true;
+    }
+
+    /// Forget that the named relay actor relayed the given object, so that it can be
+    /// relayed again.
+    pub fn remove(&self, relay: &str, object_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (relay.to_owned(), object_id.to_owned());
+        if inner.index.remove(&key).is_none() {
+            return;
+        }
+
+        inner
+            .entries
+            .retain(|e| e.relay != relay || e.object_id != object_id);
+        inner.dirty = true;
+    }
+
+    /// The most recently relayed objects, newest first
+    pub fn recent(&self, limit: usize) -> Vec<HistoryEntry> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.entries.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Write any entries recorded since the last flush to storage.
+    pub fn flush(&self) {
+        let _flushing = self.flushing.lock().unwrap();
+        let entries = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            inner.entries.clone()
+        };
+
+        self.storage.update(&mut |stored| *stored = entries.clone());
+    }
+
+    fn apply_retention(&self, now: DateTime<Utc>) {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.entries.len();
+        self.trim(&mut inner, now);
+        inner.dirty |= inner.entries.len() != len;
+    }
+
+    // Entries are stored in the order they were relayed so we only ever need to trim
+    // from the front.
+    fn trim(&self, inner: &mut Inner, now: DateTime<Utc>) {
+        while let Some(oldest) = inner.entries.front() {
+            let expired = now.signed_duration_since(oldest.relayed_at) > self.max_age;
+            if !expired && inner.entries.len() <= self.max_entries {
+                break;
+            }
+
+            if let Some(e) = inner.entries.pop_front() {
+                // Only drop from the index if the object hasn't been relayed again
+                let key = (e.relay.clone(), e.object_id.clone());
+                if inner.index.get(&key) == Some(&e) {
+                    inner.index.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{JsonFileStorage, MemoryStorage};
+
+    fn entry(n: u8, relayed_at: DateTime<Utc>) -> HistoryEntry {
+        HistoryEntry {
+            relay: "relay".into(),
+            object_id: format!("https://example.com/objects/{n}"),
+            activity_id: format!("https://localhost/activities/{n}"),
+            origin: "example.com".into(),
+            relayed_at,
+            recipients: Some(vec!["https://other.example/inbox".into()]),
+            deleted: false,
+        }
+    }
+
+    fn history(max_entries: usize, max_age_hours: u64) -> History {
+        History::new(
+            Box::<MemoryStorage<Entries>>::default(),
+            &HistoryConfig {
+                max_entries,
+                max_age_hours,
+            },
+        )
+    }
+
+    #[test]
+    fn relayed_objects_are_found_per_relay() {
+        let h = history(10, 24);
+        h.record(entry(1, Utc::now()));
+
+        assert_eq!(
+            h.get("relay", "https://example.com/objects/1").as_deref(),
+            Some("https://localhost/activities/1")
+        );
+        assert_eq!(h.get("art", "https://example.com/objects/1"), None);
+        assert_eq!(h.get("relay", "https://example.com/objects/2"), None);
+    }
+
+    #[test]
+    fn recipients_are_recorded_per_object() {
+        let h = history(10, 24);
+        h.record(entry(1, Utc::now()));
+        h.record(HistoryEntry {
+            recipients: None,
+            ..entry(2, Utc::now())
+        });
+
+        let recipients = |n: u8| {
+            h.entry("relay", &format!("https://example.com/objects/{n}"))
+                .and_then(|e| e.recipients)
+        };
+        assert_eq!(
+            recipients(1),
+            Some(vec!["https://other.example/inbox".to_owned()])
+        );
+        assert_eq!(recipients(2), None);
+        assert_eq!(recipients(3), None);
+    }
+
+    #[test]
+    fn history_survives_restarts() {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg = HistoryConfig::default();
+
+        let h = History::new(
+            Box::new(JsonFileStorage::open(&dir, "history.json").unwrap()),
+            &cfg,
+        );
+        h.record(entry(1, Utc::now()));
+        h.flush();
+        drop(h);
+
+        let h = History::new(
+            Box::new(JsonFileStorage::open(&dir, "history.json").unwrap()),
+            &cfg,
+        );
+        assert_eq!(
+            h.get("relay", "https://example.com/objects/1").as_deref(),
+            Some("https://localhost/activities/1")
+        );
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn removed_entries_are_forgotten() {
+        let h = history(10, 24);
+        h.record(entry(1, Utc::now()));
+        h.record(entry(2, Utc::now()));
+        h.remove("relay", "https://example.com/objects/1");
+
+        assert_eq!(h.get("relay", "https://example.com/objects/1"), None);
+        assert!(h.get("relay", "https://example.com/objects/2").is_some());
+        assert_eq!(h.recent(10).len(), 1);
+    }
+
+    #[test]
+    fn entries_are_only_written_when_flushed() {
+        let h = history(10, 24);
+        h.record(entry(1, Utc::now()));
+        assert!(h.storage.load().is_empty());
+
+        h.flush();
+        assert_eq!(h.storage.load().len(), 1);
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped_past_max_entries() {
+        let h = history(2, 24);
+        for n in 1..=3 {
+            h.record(entry(n, Utc::now()));
+        }
+
+        assert_eq!(h.get("relay", "https://example.com/objects/1"), None);
+        assert!(h.get("relay", "https://example.com/objects/2").is_some());
+        assert!(h.get("relay", "https://example.com/objects/3").is_some());
+        assert_eq!(h.recent(10).len(), 2);
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let h = history(10, 1);
+        h.record(entry(1, Utc::now() - Duration::hours(2)));
+        h.record(entry(2, Utc::now()));
+
+        assert_eq!(h.get("relay", "https://example.com/objects/1"), None);
+        assert_eq!(h.recent(10).len(), 1);
+        assert!(h.get("relay", "https://example.com/objects/2").is_some());
+    }
+}
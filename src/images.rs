@@ -0,0 +1,148 @@
+//! Profile images for our relay actors.
+//!
+//! Images are read once at startup, either from the paths given in the config or from
+//! `avatar.*` and `header.*` files in the data directory, and are served at stable URLs
+//! referenced from the `icon` and `image` properties of our actor documents.
+use crate::{config::ImagesConfig, Error, Result};
+use axum::{body::Bytes, http::StatusCode};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const EXTENSIONS: [(&str, &str); 5] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub media_type: &'static str,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Default)]
+pub struct ActorImages {
+    pub avatar: Option<Image>,
+    pub header: Option<Image>,
+}
+
+impl ActorImages {
+    pub fn load(cfg: &ImagesConfig, data_dir: &Path) -> Result<Self> {
+        let load = |configured: &Option<PathBuf>, name: &str| {
+            let path = configured.clone().or_else(|| find_in_dir(data_dir, name));
+
+            path.map(|path| {
+                info!(path = %path.display(), "loading {name} image");
+                read_image(&path)
+            })
+            .transpose()
+        };
+
+        Ok(Self {
+            avatar: load(&cfg.avatar_path, "avatar")?,
+            header: load(&cfg.header_path, "header")?,
+        })
+    }
+
+    /// The named image ("avatar" or "header"), if we have one.
+    pub fn get(&self, name: &str) -> Option<&Image> {
+        match name {
+            "avatar" => self.avatar.as_ref(),
+            "header" => self.header.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Add the `icon` and `image` properties for any images we have to an actor
+    /// document served on the given host.
+    pub fn add_to_actor(&self, actor: &mut Value, host: &str) {
+        for (property, name, image) in [
+            ("icon", "avatar", &self.avatar),
+            ("image", "header", &self.header),
+        ] {
+            if let Some(image) = image {
+                actor[property] = json!({
+                    "type": "Image",
+                    "mediaType": image.media_type,
+                    "url": format!("https://{host}/media/{name}"),
+                });
+            }
+        }
+    }
+}
+
+fn media_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    EXTENSIONS
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, media_type)| *media_type)
+}
+
+fn find_in_dir(dir: &Path, name: &str) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|(ext, _)| dir.join(format!("{name}.{ext}")))
+        .find(|path| path.is_file())
+}
+
+fn read_image(path: &Path) -> Result<Image> {
+    let media_type = media_type(path).ok_or(Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "unsupported image type",
+    })?;
+    let data = std::fs::read(path).map_err(|_| Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "unable to read image",
+    })?;
+
+    Ok(Image {
+        media_type,
+        data: data.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs};
+    use uuid::Uuid;
+
+    #[test_case("avatar.png", Some("image/png"); "png")]
+    #[test_case("avatar.JPG", Some("image/jpeg"); "upper case")]
+    #[test_case("avatar.svg", None; "unsupported")]
+    #[test_case("avatar", None; "no extension")]
+    #[test]
+    fn media_types_come_from_the_extension(path: &str, expected: Option<&str>) {
+        assert_eq!(media_type(Path::new(path)), expected);
+    }
+
+    #[test]
+    fn images_are_found_in_the_data_dir_unless_configured() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("avatar.webp"), b"avatar").unwrap();
+        fs::write(dir.join("header.png"), b"ignored").unwrap();
+        fs::write(dir.join("other.gif"), b"header").unwrap();
+        let cfg = ImagesConfig {
+            avatar_path: None,
+            header_path: Some(dir.join("other.gif")),
+        };
+
+        let images = ActorImages::load(&cfg, &dir).unwrap();
+        let mut actor = json!({});
+        images.add_to_actor(&mut actor, "relay.example");
+
+        assert_eq!(images.get("avatar").unwrap().data, &b"avatar"[..]);
+        assert_eq!(images.get("header").unwrap().data, &b"header"[..]);
+        assert_eq!(actor["icon"]["mediaType"], "image/webp");
+        assert_eq!(actor["image"]["url"], "https://relay.example/media/header");
+        fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
@@ -0,0 +1,194 @@
+//! Bulk subscribing instances, e.g. when migrating from another relay.
+//!
+//! Rather than requiring every instance to re-follow the relay manually, operators can
+//! provide the list of inboxes that were subscribed to the old relay. Each inbox is
+//! registered as a subscriber and the relay sends a Follow to the instance actor,
+//! throttled so that we don't flood the delivery queue (or the remote instances).
+use crate::{state::State, util::host_from_uri, Result};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::interval;
+use tracing::{info, warn};
+
+/// The default number of Follow requests sent per minute during an import
+pub const DEFAULT_FOLLOWS_PER_MINUTE: u32 = 60;
+
+// How often (in processed inboxes) progress is logged
+const LOG_EVERY: usize = 50;
+
+/// Progress of the current (or most recent) import.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportProgress {
+    /// The relay actor that instances are being subscribed to
+    pub actor: String,
+    pub total: usize,
+    pub followed: usize,
+    /// Inboxes that were already subscribed
+    pub skipped: usize,
+    pub failed: usize,
+    pub running: bool,
+}
+
+impl ImportProgress {
+    pub fn processed(&self) -> usize {
+        self.followed + self.skipped + self.failed
+    }
+}
+
+/// The outcome of importing a single inbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Followed,
+    Skipped,
+    Failed,
+}
+
+/// Tracks the progress of bulk imports. Only one import may run at a time.
+#[derive(Debug, Default)]
+pub struct Imports {
+    progress: Mutex<Option<ImportProgress>>,
+}
+
+impl Imports {
+    /// Begin tracking a new import, returning false if one is already running.
+    pub fn start(&self, actor: &str, total: usize) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.as_ref().map(|p| p.running).unwrap_or(false) {
+            return false;
+        }
+
+        *progress = Some(ImportProgress {
+            actor: actor.to_owned(),
+            total,
+            running: total > 0,
+            ..Default::default()
+        });
+
+        true
+    }
+
+    pub fn record(&self, outcome: Outcome) -> Option<ImportProgress> {
+        let mut guard = self.progress.lock().unwrap();
+        let progress = guard.as_mut()?;
+
+        match outcome {
+            Outcome::Followed => progress.followed += 1,
+            Outcome::Skipped => progress.skipped += 1,
+            Outcome::Failed => progress.failed += 1,
+        }
+        progress.running = progress.processed() < progress.total;
+
+        Some(progress.clone())
+    }
+
+    pub fn progress(&self) -> Option<ImportProgress> {
+        self.progress.lock().unwrap().clone()
+    }
+}
+
+/// Subscribe each of the given inboxes to the named relay actor, sending Follow
+/// requests at no more than the given rate. The import must already have been
+/// started via [Imports::start].
+pub async fn run_import(
+    state: Arc<State>,
+    actor: String,
+    inboxes: Vec<String>,
+    follows_per_minute: u32,
+) {
+    let period = Duration::from_secs(60) / follows_per_minute.max(1);
+    let mut ticker = interval(period);
+
+    for inbox in inboxes {
+        ticker.tick().await;
+
+        let outcome = match import_inbox(&state, &actor, &inbox).await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                warn!(%inbox, error=%e, "unable to import inbox");
+                Outcome::Failed
+            }
+        };
+
+        if let Some(progress) = state.imports.record(outcome) {
+            if progress.processed() % LOG_EVERY == 0 || !progress.running {
+                info!(?progress, "import progress");
+            }
+        }
+    }
+}
+
+async fn import_inbox(state: &State, actor: &str, inbox: &str) -> Result<Outcome> {
+    let relay = match state.actor(actor) {
+        Some(relay) => relay,
+        None => return Ok(Outcome::Failed),
+    };
+
+    let host = host_from_uri(inbox)?;
+    if relay.db.inbox(&host).is_some() {
+        return Ok(Outcome::Skipped);
+    }
+
+    // We only have the inbox to go on so we assume the conventional location of the
+    // instance actor (as used by Mastodon).
+    let instance_actor = format!("{}/actor", state.client.origin(&host));
+    let follow = state
+        .client
+        .follow_actor(relay.name, &instance_actor)
+        .await?;
+
+    relay.db.add_inbox_if_unknown(inbox.to_owned(), None)?;
+    relay.db.record_instance(&instance_actor, None, None)?;
+    state.deliver(vec![follow]);
+
+    Ok(Outcome::Followed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_import_can_run_at_a_time() {
+        let imports = Imports::default();
+
+        assert!(imports.start("relay", 2));
+        assert!(!imports.start("relay", 5));
+
+        imports.record(Outcome::Followed);
+        let progress = imports.record(Outcome::Failed).unwrap();
+
+        assert!(!progress.running);
+        assert!(imports.start("relay", 5));
+    }
+
+    #[test]
+    fn outcomes_are_counted() {
+        let imports = Imports::default();
+        imports.start("relay", 4);
+
+        for outcome in [
+            Outcome::Followed,
+            Outcome::Skipped,
+            Outcome::Followed,
+            Outcome::Failed,
+        ] {
+            imports.record(outcome);
+        }
+
+        assert_eq!(
+            imports.progress(),
+            Some(ImportProgress {
+                actor: "relay".into(),
+                total: 4,
+                followed: 2,
+                skipped: 1,
+                failed: 1,
+                running: false,
+            })
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Background processing of activities accepted by our inboxes.
+//!
+//! Inbox requests are only checked as far as their signature before we respond to them.
+//! The activity is then stored and queued, with the rest of the inbox pipeline being run
+//! by a pool of workers so that slow subscribers or remote servers can't hold up our
+//! response to the sending server. Stored activities survive a restart, and activities
+//! whose processing fails for a transient reason are retried with a backoff.
+//!
+//! Each activity is stored on its own so that accepting one doesn't mean rewriting
+//! everything else in the queue, and storage is only touched outside of the queue lock
+//! so that requests and workers aren't held up behind each other's disk writes.
+use crate::{
+    client::RemoteActor, config::IngestConfig, metrics::Metrics, pipeline::Inbound, state::State,
+    storage::RecordStorage,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Notify, time::timeout};
+use tracing::error;
+use uuid::Uuid;
+
+// How long an idle worker waits before re-checking for activities whose backoff has
+// expired.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The directory within the data dir that accepted activities are stored in
+pub const INGEST_DIR: &str = "ingest";
+
+/// An activity accepted by one of our inboxes that is waiting to be processed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ingested {
+    pub id: Uuid,
+    /// The relay actor whose inbox received the activity
+    pub relay: String,
+    /// The host the request was made to
+    pub host: String,
+    /// The path the request was made to
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    /// The raw request body
+    pub body: String,
+    pub received_at: DateTime<Utc>,
+    pub attempts: u32,
+    /// The last stage of the inbox pipeline to have finished with the activity, so that
+    /// retries carry on from there
+    #[serde(default)]
+    pub completed_stage: Option<String>,
+}
+
+impl Ingested {
+    pub fn new(inbound: &Inbound<'_>) -> Self {
+        // Header values that aren't valid strings can't be stored but nothing after the
+        // signature check needs them
+        let headers = inbound
+            .headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.as_str().to_owned(), v.to_str().ok()?.to_owned())))
+            .collect();
+
+        Self {
+            id: Uuid::new_v4(),
+            relay: inbound.relay.name.to_owned(),
+            host: inbound.host.to_owned(),
+            path: inbound.path.to_owned(),
+            headers,
+            body: String::from_utf8_lossy(inbound.body).into_owned(),
+            received_at: Utc::now(),
+            attempts: 0,
+            completed_stage: None,
+        }
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        self.headers
+            .iter()
+            .filter_map(|(k, v)| {
+                let name = HeaderName::from_bytes(k.as_bytes()).ok()?;
+                let value = HeaderValue::from_str(v).ok()?;
+
+                Some((name, value))
+            })
+            .collect()
+    }
+}
+
+/// An accepted activity waiting in the queue.
+#[derive(Debug)]
+pub struct Job {
+    pub ingested: Ingested,
+    /// The sending actor, if it was fetched when the activity was accepted
+    pub actor: Option<RemoteActor>,
+    not_before: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Ingest {
+    storage: Box<dyn RecordStorage<Ingested>>,
+    queue: Mutex<VecDeque<Job>>,
+    notify: Notify,
+    max_queued: usize,
+}
+
+impl Ingest {
+    /// Anything left in storage from a previous run is queued to be processed again.
+    pub fn new(storage: Box<dyn RecordStorage<Ingested>>, cfg: &IngestConfig) -> Self {
+        let mut pending = storage.load_all();
+        pending.sort_by_key(|i| i.received_at);
+        let queue = pending
+            .into_iter()
+            .map(|ingested| Job {
+                ingested,
+                actor: None,
+                not_before: None,
+            })
+            .collect();
+
+        Self {
+            storage,
+            queue: Mutex::new(queue),
+            notify: Notify::new(),
+            max_queued: cfg.max_queued,
+        }
+    }
+
+    /// Store an accepted activity and queue it for processing, returning false if the
+    /// queue is already full. The activity is stored before it is queued so that it
+    /// can't be completed (and removed from storage) before it has been written.
+    pub fn enqueue(&self, ingested: Ingested, actor: Option<RemoteActor>) -> bool {
+        if self.len() >= self.max_queued {
+            return false;
+        }
+
+        self.storage.put(&ingested.id.to_string(), &ingested);
+        self.queue.lock().unwrap().push_back(Job {
+            ingested,
+            actor,
+            not_before: None,
+        });
+        self.notify.notify_one();
+
+        true
+    }
+
+    /// Take the next activity that is ready to be processed, if there is one.
+    pub fn next_ready(&self) -> Option<Job> {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let ix = queue
+            .iter()
+            .position(|j| j.not_before.map(|t| t <= now).unwrap_or(true))?;
+
+        queue.remove(ix)
+    }
+
+    /// Requeue an activity whose processing failed, backing off exponentially based on
+    /// the number of attempts made so far.
+    pub fn retry(&self, mut job: Job) {
+        job.ingested.attempts += 1;
+        let backoff = RETRY_BACKOFF * 2u32.saturating_pow(job.ingested.attempts - 1);
+        job.not_before = Some(Instant::now() + backoff);
+
+        self.storage
+            .put(&job.ingested.id.to_string(), &job.ingested);
+        self.queue.lock().unwrap().push_back(job);
+    }
+
+    /// Remove a processed (or abandoned) activity from storage.
+    pub fn complete(&self, id: Uuid) {
+        self.storage.remove(&id.to_string());
+    }
+
+    /// The number of activities waiting to be processed
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// When the longest waiting activity in the queue was received
+    pub fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.queue
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|j| j.ingested.received_at)
+            .min()
+    }
+
+    /// Update the gauges describing the current state of the queue.
+    pub fn record_gauges(&self, metrics: &Metrics, now: DateTime<Utc>) {
+        let oldest_age = self
+            .oldest()
+            .map(|t| now.signed_duration_since(t).num_seconds().max(0) as u64)
+            .unwrap_or_default();
+
+        metrics.set("actiserve_ingest_queue_depth", &[], self.len() as u64);
+        metrics.set("actiserve_ingest_oldest_age_seconds", &[], oldest_age);
+    }
+}
+
+/// Process accepted activities until the process exits.
+pub async fn run_worker(state: Arc<State>) {
+    loop {
+        // Registered before checking the queue so that we can't miss a wakeup
+        let notified = state.ingest.notify.notified();
+
+        match state.ingest.next_ready() {
+            Some(job) => process(job, &state).await,
+            None => {
+                let _ = timeout(POLL_INTERVAL, notified).await;
+            }
+        }
+    }
+}
+
+// Each activity is processed in its own task so that one that panics is abandoned
+// rather than taking the worker down with it, or doing the same to a worker after every
+// restart if it was left in storage.
+async fn process(job: Job, state: &Arc<State>) {
+    let id = job.ingested.id;
+    let task = tokio::spawn({
+        let state = state.clone();
+        async move { crate::routes::inbox::process_ingested(job, &state).await }
+    });
+
+    if let Err(e) = task.await {
+        error!(%id, error=%e, "processing accepted activity panicked");
+        state.ingest.complete(id);
+        state.metrics.incr(
+            "actiserve_ingest_processed_total",
+            &[("outcome", "panicked")],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pipeline::{Flow, Pipeline, Stage},
+        state::Db,
+        storage::{DirRecordStorage, MemoryRecordStorage},
+        Result,
+    };
+    use serde_json::json;
+    use std::{env::temp_dir, fs::remove_dir_all};
+
+    fn ingested(n: u32) -> Ingested {
+        Ingested {
+            id: Uuid::new_v4(),
+            relay: "relay".into(),
+            host: "localhost".into(),
+            path: "/inbox".into(),
+            headers: vec![("signature".into(), format!("keyId=\"{n}\""))],
+            body: "{}".into(),
+            received_at: Utc::now() + chrono::Duration::seconds(n as i64),
+            attempts: 0,
+            completed_stage: None,
+        }
+    }
+
+    #[test]
+    fn retried_activities_wait_for_their_backoff() {
+        let ingest = Ingest::new(
+            Box::<MemoryRecordStorage<_>>::default(),
+            &Default::default(),
+        );
+        ingest.enqueue(ingested(1), None);
+        ingest.enqueue(ingested(2), None);
+
+        let first = ingest.next_ready().unwrap();
+        ingest.retry(first);
+        let second = ingest.next_ready().unwrap();
+
+        assert_eq!(second.ingested.headers, ingested(2).headers);
+        assert!(ingest.next_ready().is_none());
+        assert_eq!(ingest.len(), 1);
+    }
+
+    #[test]
+    fn pending_activities_are_restored_in_order() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ingest = Ingest::new(
+            Box::new(DirRecordStorage::open(&dir).unwrap()),
+            &Default::default(),
+        );
+        let (a, b, c) = (ingested(1), ingested(2), ingested(3));
+        ingest.enqueue(b.clone(), None);
+        ingest.enqueue(a.clone(), None);
+        ingest.enqueue(c.clone(), None);
+        ingest.complete(c.id);
+        drop(ingest);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let restored = Ingest::new(
+            Box::new(DirRecordStorage::open(&dir).unwrap()),
+            &Default::default(),
+        );
+        let ids: Vec<Uuid> = std::iter::from_fn(|| restored.next_ready())
+            .map(|j| j.ingested.id)
+            .collect();
+
+        assert_eq!(ids, vec![a.id, b.id]);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn enqueueing_is_refused_once_the_queue_is_full() {
+        let cfg = IngestConfig {
+            max_queued: 2,
+            ..Default::default()
+        };
+        let ingest = Ingest::new(Box::<MemoryRecordStorage<_>>::default(), &cfg);
+
+        assert!(ingest.enqueue(ingested(1), None));
+        assert!(ingest.enqueue(ingested(2), None));
+        assert!(!ingest.enqueue(ingested(3), None));
+        assert_eq!(ingest.len(), 2);
+    }
+
+    #[test]
+    fn gauges_describe_the_queue() {
+        let ingest = Ingest::new(
+            Box::<MemoryRecordStorage<_>>::default(),
+            &Default::default(),
+        );
+        let metrics = Metrics::default();
+        let first = ingested(0);
+        let now = first.received_at + chrono::Duration::seconds(30);
+        ingest.enqueue(first, None);
+        ingest.enqueue(ingested(10), None);
+
+        ingest.record_gauges(&metrics, now);
+
+        assert_eq!(metrics.gauge("actiserve_ingest_queue_depth", &[]), 2);
+        assert_eq!(
+            metrics.gauge("actiserve_ingest_oldest_age_seconds", &[]),
+            30
+        );
+    }
+
+    // Stands in for a stage of the pipeline, panicking when run if it isn't one of those
+    // run before an activity is accepted
+    #[derive(Debug)]
+    struct Stub(&'static str);
+
+    #[axum::async_trait]
+    impl Stage for Stub {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn run(&self, _: &mut Inbound<'_>, _: &State) -> Result<Flow> {
+            if self.0 != "signature" {
+                panic!("{} panicked", self.0);
+            }
+
+            Ok(Flow::Continue)
+        }
+    }
+
+    #[tokio::test]
+    async fn activities_that_panic_are_abandoned() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.pipeline = Pipeline::new(vec![Box::new(Stub("signature")), Box::new(Stub("policy"))]);
+        let state = Arc::new(state);
+
+        let actor_id = "https://a.example/actor";
+        let actor = json!({
+            "id": actor_id,
+            "type": "Application",
+            "inbox": "https://a.example/inbox",
+            "publicKey": { "id": actor_id, "owner": actor_id, "publicKeyPem": "key" },
+        });
+        let activity = json!({ "type": "Create", "actor": actor_id, "activity": {} });
+        let ingested = Ingested {
+            relay: "relay".into(),
+            body: activity.to_string(),
+            ..ingested(1)
+        };
+        let actor = RemoteActor::from_json(actor_id, actor).unwrap();
+        state.ingest.enqueue(ingested, Some(actor));
+
+        let job = state.ingest.next_ready().unwrap();
+        process(job, &state).await;
+
+        assert!(state.ingest.storage.load_all().is_empty());
+        assert_eq!(
+            state.metrics.counter(
+                "actiserve_ingest_processed_total",
+                &[("outcome", "panicked")]
+            ),
+            1
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn headers_survive_storage() {
+        let mut headers = HeaderMap::new();
+        headers.insert("signature", "keyId=\"a\"".parse().unwrap());
+        headers.insert("digest", "SHA-256=abc".parse().unwrap());
+        let ingested = Ingested {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_owned()))
+                .collect(),
+            ..ingested(1)
+        };
+
+        assert_eq!(ingested.header_map(), headers);
+    }
+}
@@ -0,0 +1,182 @@
+//! Integrity checks for objects embedded in relayed activities.
+//!
+//! Media attachments must link to http(s) URLs: anything else (javascript:, file: etc)
+//! causes the activity to be dropped. Inline data: URIs and long attachment lists are
+//! handled according to the configured [AttachmentPolicy]. Polls (Question objects) must
+//! have a well formed set of options.
+use crate::config::{AttachmentConfig, AttachmentPolicy};
+use serde_json::Value;
+
+/// Check the object embedded in an activity (if there is one), stripping attachments
+/// from it if the policy allows. Returns the reason for rejecting the activity if it
+/// should not be relayed.
+pub fn check_activity(activity: &mut Value, cfg: &AttachmentConfig) -> Result<(), &'static str> {
+    let object = match activity.get_mut("object") {
+        Some(object) if object.is_object() => object,
+        _ => return Ok(()),
+    };
+
+    if object["type"] == "Question" {
+        check_question(object)?;
+    }
+
+    let mut attachments: Vec<Value> = match object.get("attachment") {
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::Array(attachments)) => attachments.clone(),
+        Some(attachment) => vec![attachment.clone()],
+    };
+    let n_attachments = attachments.len();
+
+    let schemes: Vec<Scheme> = attachments.iter().flat_map(urls).map(scheme).collect();
+    if schemes.contains(&Scheme::Other) {
+        return Err("attachment URL is not http(s)");
+    }
+
+    match cfg.policy {
+        AttachmentPolicy::Reject if schemes.contains(&Scheme::Data) => {
+            Err("attachment uses a data: URI")
+        }
+
+        AttachmentPolicy::Reject if n_attachments > cfg.max_attachments => {
+            Err("too many attachments")
+        }
+
+        AttachmentPolicy::Reject => Ok(()),
+
+        AttachmentPolicy::Strip => {
+            attachments.retain(|a| !urls(a).into_iter().any(|u| scheme(u) == Scheme::Data));
+            attachments.truncate(cfg.max_attachments);
+            if attachments.len() != n_attachments {
+                object["attachment"] = Value::Array(attachments);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Whether an object has any media attachments.
+pub fn has_attachments(object: &Value) -> bool {
+    match object.get("attachment") {
+        None | Some(Value::Null) => false,
+        Some(Value::Array(attachments)) => !attachments.is_empty(),
+        Some(_) => true,
+    }
+}
+
+/// Remove any media attachments from the object embedded in an activity, returning
+/// whether there were any to remove.
+pub fn strip_attachments(activity: &mut Value) -> bool {
+    match activity.get_mut("object").and_then(|o| o.as_object_mut()) {
+        Some(object) => object.remove("attachment").is_some(),
+        None => false,
+    }
+}
+
+// Polls must offer either single choice (oneOf) or multiple choice (anyOf) options
+fn check_question(object: &Value) -> Result<(), &'static str> {
+    let options = match (object.get("oneOf"), object.get("anyOf")) {
+        (Some(Value::Array(options)), None) | (None, Some(Value::Array(options))) => options,
+        _ => return Err("invalid poll options"),
+    };
+
+    if options.iter().all(|o| o["name"].is_string()) {
+        Ok(())
+    } else {
+        Err("invalid poll options")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scheme {
+    Http,
+    Data,
+    Other,
+}
+
+fn scheme(url: &str) -> Scheme {
+    let scheme = url.split_once(':').map(|(s, _)| s.to_ascii_lowercase());
+
+    match scheme.as_deref() {
+        Some("http" | "https") => Scheme::Http,
+        Some("data") => Scheme::Data,
+        _ => Scheme::Other,
+    }
+}
+
+// The url of an attachment may be a plain string, a Link object or a list of either
+fn urls(attachment: &Value) -> Vec<&str> {
+    fn link_href(v: &Value) -> Option<&str> {
+        v.as_str().or_else(|| v["href"].as_str())
+    }
+
+    match &attachment["url"] {
+        Value::Array(links) => links.iter().filter_map(link_href).collect(),
+        link => link_href(link).into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    fn cfg(policy: AttachmentPolicy) -> AttachmentConfig {
+        AttachmentConfig {
+            policy,
+            max_attachments: 2,
+        }
+    }
+
+    fn with_attachments(urls: &[&str]) -> Value {
+        let attachments: Vec<Value> = urls
+            .iter()
+            .map(|url| json!({ "type": "Document", "url": url }))
+            .collect();
+
+        json!({ "type": "Create", "object": { "type": "Note", "attachment": attachments } })
+    }
+
+    #[test_case(json!({ "type": "Announce", "object": "https://a.example/1" }); "object not embedded")]
+    #[test_case(json!({ "object": { "type": "Note" } }); "no attachments")]
+    #[test_case(with_attachments(&["https://a.example/1.png"]); "https attachment")]
+    #[test_case(json!({ "object": { "attachment": { "url": [{ "type": "Link", "href": "https://a.example/1.mp4" }] } } }); "link objects")]
+    #[test_case(json!({ "object": { "attachment": [{ "type": "PropertyValue", "name": "a", "value": "b" }] } }); "attachment without url")]
+    #[test_case(json!({ "object": { "type": "Question", "oneOf": [{ "type": "Note", "name": "yes" }] } }); "single choice poll")]
+    #[test_case(json!({ "object": { "type": "Question", "anyOf": [{ "type": "Note", "name": "yes" }] } }); "multiple choice poll")]
+    #[test]
+    fn valid_activities_are_unchanged(activity: Value) {
+        for policy in [AttachmentPolicy::Strip, AttachmentPolicy::Reject] {
+            let mut checked = activity.clone();
+
+            assert_eq!(check_activity(&mut checked, &cfg(policy)), Ok(()));
+            assert_eq!(checked, activity);
+        }
+    }
+
+    #[test_case(with_attachments(&["javascript:alert(1)"]), AttachmentPolicy::Strip; "javascript url strip")]
+    #[test_case(with_attachments(&["file:///etc/passwd"]), AttachmentPolicy::Reject; "file url reject")]
+    #[test_case(with_attachments(&["data:image/png;base64,AAAA"]), AttachmentPolicy::Reject; "data uri")]
+    #[test_case(with_attachments(&["https://a/1", "https://a/2", "https://a/3"]), AttachmentPolicy::Reject; "too many attachments")]
+    #[test_case(json!({ "object": { "type": "Question" } }), AttachmentPolicy::Strip; "poll without options")]
+    #[test_case(json!({ "object": { "type": "Question", "oneOf": [], "anyOf": [] } }), AttachmentPolicy::Strip; "poll with both kinds of option")]
+    #[test_case(json!({ "object": { "type": "Question", "oneOf": [{ "type": "Note" }] } }), AttachmentPolicy::Strip; "poll option without name")]
+    #[test]
+    fn invalid_activities_are_rejected(mut activity: Value, policy: AttachmentPolicy) {
+        assert!(check_activity(&mut activity, &cfg(policy)).is_err());
+    }
+
+    #[test_case(&["data:image/png;base64,AAAA", "https://a/1"], &["https://a/1"]; "data uri")]
+    #[test_case(&["https://a/1", "https://a/2", "https://a/3"], &["https://a/1", "https://a/2"]; "too many attachments")]
+    #[test]
+    fn offending_attachments_are_stripped(urls: &[&str], expected: &[&str]) {
+        let mut activity = with_attachments(urls);
+
+        assert_eq!(
+            check_activity(&mut activity, &cfg(AttachmentPolicy::Strip)),
+            Ok(())
+        );
+        assert_eq!(activity, with_attachments(expected));
+    }
+}
@@ -0,0 +1,215 @@
+//! Expectations of particular server software that go beyond what most implementations
+//! require of the requests we make to them.
+//!
+//! GoToSocial (and Mastodon in secure mode) refuse to serve anything to an unsigned
+//! request, so every fetch we make is signed as the main relay actor. GoToSocial also
+//! negotiates strictly on the Accept header, responding with a 406 (or HTML) unless it
+//! names one of the ActivityPub media types exactly, and checks the signature against
+//! the full request target including any query string. Nodeinfo documents are only
+//! served as plain JSON.
+//!
+//! The tests here run our client against a mock GoToSocial server (`make test-interop`).
+
+/// The media type asked for when fetching actors and activities
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+
+/// The media type asked for when fetching nodeinfo documents
+pub const NODEINFO_JSON: &str = "application/json";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::ActivityPubClient,
+        config::ProxyConfig,
+        signature::{check_coverage, check_signature, tests::test_actor, Outcome},
+        Error,
+    };
+    use axum::{
+        http::{header, HeaderMap, Method, StatusCode, Uri},
+        response::{IntoResponse, Response},
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::json;
+    use simple_test_case::test_case;
+    use std::net::TcpListener;
+
+    /// The alternative ActivityPub media type, which must carry the ActivityStreams profile
+    const LD_JSON_ACTIVITY_STREAMS: &str =
+        r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#;
+
+    const OUR_ACTOR: &str = "https://127.0.0.1:4242/actor";
+    const GTS: &str = "gts.onion";
+
+    // Check a request the way that GoToSocial would before serving it, returning the
+    // status it would respond with
+    fn check(method: &Method, uri: &Uri, headers: &HeaderMap, accepted: &[&str]) -> StatusCode {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !accepted.contains(&accept) {
+            return StatusCode::NOT_ACCEPTABLE;
+        }
+
+        let method = method.as_str().to_ascii_lowercase();
+        if check_coverage(&method, headers) != Outcome::Ok {
+            return StatusCode::UNAUTHORIZED;
+        }
+
+        let target = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let signer = test_actor(OUR_ACTOR);
+
+        match check_signature(&signer, &method, target, headers, &[]) {
+            Outcome::Ok => StatusCode::OK,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    async fn respond(method: Method, uri: Uri, headers: HeaderMap) -> Response {
+        let activity_types = &[ACTIVITY_JSON, LD_JSON_ACTIVITY_STREAMS][..];
+        let (accepted, doc) = match uri.path() {
+            "/.well-known/nodeinfo" => (
+                &[NODEINFO_JSON][..],
+                json!({ "links": [{
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                    "href": format!("http://{GTS}/nodeinfo/2.0"),
+                }] }),
+            ),
+            "/nodeinfo/2.0" => (
+                &[NODEINFO_JSON][..],
+                json!({
+                    "software": { "name": "gotosocial", "version": "0.11.0" },
+                    "openRegistrations": false,
+                }),
+            ),
+            "/users/alice" => (
+                activity_types,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "type": "Person",
+                    "id": format!("http://{GTS}/users/alice"),
+                    "inbox": format!("http://{GTS}/users/alice/inbox"),
+                    "endpoints": { "sharedInbox": format!("http://{GTS}/inbox") },
+                }),
+            ),
+            "/users/alice/outbox" => (
+                activity_types,
+                json!({ "type": "OrderedCollectionPage", "orderedItems": [] }),
+            ),
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        };
+
+        match check(&method, &uri, &headers, accepted) {
+            StatusCode::OK => Json(doc).into_response(),
+            status => status.into_response(),
+        }
+    }
+
+    // Serve a mock GoToSocial instance, returning a client that reaches it as gts.onion
+    // (so that plain HTTP is allowed) along with its local address
+    fn serve() -> (ActivityPubClient, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().fallback(get(respond));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let mut client = ActivityPubClient::new_with_test_key();
+        let proxy = ProxyConfig {
+            onion_url: Some(format!("http://{addr}")),
+            ..Default::default()
+        };
+        client.configure(&proxy, &Default::default()).unwrap();
+
+        (client, format!("http://{addr}"))
+    }
+
+    #[tokio::test]
+    async fn actors_can_be_fetched() {
+        let (client, _) = serve();
+
+        let actor = client
+            .get_actor(&format!("http://{GTS}/users/alice"))
+            .await
+            .unwrap();
+
+        assert_eq!(actor.shared_inbox(), Some("http://gts.onion/inbox"));
+    }
+
+    #[tokio::test]
+    async fn activities_fetched_with_a_query_are_served() {
+        let (client, _) = serve();
+
+        let page = client
+            .get_activity(&format!(
+                "http://{GTS}/users/alice/outbox?page=true&min_id=01H8"
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(page["type"], "OrderedCollectionPage");
+    }
+
+    #[tokio::test]
+    async fn nodeinfo_can_be_fetched() {
+        let (client, _) = serve();
+
+        let nodeinfo = client.get_nodeinfo(GTS).await.unwrap();
+
+        assert_eq!(nodeinfo.software.name, "gotosocial");
+        assert_eq!(nodeinfo.open_registrations, Some(false));
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_not_parsed_as_documents() {
+        let (client, _) = serve();
+
+        let res = client
+            .get_activity(&format!("http://{GTS}/users/bob"))
+            .await;
+
+        assert!(
+            matches!(res, Err(Error::FailedRequest { status, .. }) if status == StatusCode::NOT_FOUND),
+            "{res:?}"
+        );
+    }
+
+    #[test_case(ACTIVITY_JSON, StatusCode::OK; "activity json")]
+    #[test_case(LD_JSON_ACTIVITY_STREAMS, StatusCode::OK; "ld json with profile")]
+    #[test_case("application/json", StatusCode::NOT_ACCEPTABLE; "plain json")]
+    #[test_case("application/ld+json", StatusCode::NOT_ACCEPTABLE; "ld json without profile")]
+    #[test_case("*/*", StatusCode::NOT_ACCEPTABLE; "anything")]
+    #[tokio::test]
+    async fn only_exact_accept_headers_are_acceptable(accept: &str, expected: StatusCode) {
+        let (client, _) = serve();
+
+        let res = client
+            .signed_get(&format!("http://{GTS}/users/alice"), accept)
+            .await
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), expected);
+    }
+
+    #[tokio::test]
+    async fn unsigned_fetches_are_unauthorized() {
+        let (_, addr) = serve();
+
+        let res = reqwest::Client::new()
+            .get(format!("{addr}/users/alice"))
+            .header(header::ACCEPT, ACTIVITY_JSON)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
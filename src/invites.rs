@@ -0,0 +1,68 @@
+//! Invite codes for relays that only accept Follows from pre-authorized instances.
+//!
+//! The operator generates a code via the admin API and passes it to the admin of the
+//! instance they want to invite. Once the instance admin sends the code back (by
+//! whatever means the community uses) the operator binds it to their domain, after
+//! which Follows from that domain are accepted. See `activityPub.inviteOnly`.
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// An invite code along with the domain it has been bound to, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Invite {
+    pub code: String,
+    /// A reminder of who the invite was generated for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// The domain allowed to follow the relay using this invite
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bound_at: Option<DateTime<Utc>>,
+}
+
+impl Invite {
+    /// Generate a new, unbound invite.
+    pub fn new(note: Option<String>) -> Self {
+        let mut bytes = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut bytes);
+
+        Self {
+            code: base64::encode_config(bytes, base64::URL_SAFE_NO_PAD),
+            note,
+            created_at: Utc::now(),
+            domain: None,
+            bound_at: None,
+        }
+    }
+
+    /// Bind the invite to a domain, returning false if it is already bound to another.
+    pub fn bind(&mut self, domain: &str) -> bool {
+        match self.domain.as_deref() {
+            Some(bound) => bound == domain,
+            None => {
+                self.domain = Some(domain.to_owned());
+                self.bound_at = Some(Utc::now());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invites_can_only_be_bound_to_one_domain() {
+        let mut invite = Invite::new(None);
+
+        assert!(invite.bind("a.example"));
+        assert!(invite.bind("a.example"));
+        assert!(!invite.bind("b.example"));
+        assert_eq!(invite.domain.as_deref(), Some("a.example"));
+    }
+}
@@ -0,0 +1,270 @@
+//! Normalization of inbound JSON-LD to the compacted ActivityStreams layout that the
+//! rest of the relay reads fields from.
+//!
+//! This is not a full JSON-LD processor: remote contexts are never fetched and only the
+//! ActivityStreams and security vocabularies are compacted, using a static set of
+//! prefixes. That is enough to handle implementations that send expanded documents or
+//! use `as:` style compact IRIs for terms that are normally bare.
+use serde_json::{Map, Value};
+
+/// The full IRI of the public collection used to address public activities
+pub const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+// Prefixes of terms in the vocabularies covered by the contexts we normally see
+const PREFIXES: [&str; 4] = [
+    "https://www.w3.org/ns/activitystreams#",
+    "as:",
+    "https://w3id.org/security#",
+    "sec:",
+];
+
+// Properties that only ever have a single value in the compacted form, so a single
+// element array (as is always produced by expansion) is unwrapped
+const FUNCTIONAL: [&str; 21] = [
+    "id",
+    "type",
+    "actor",
+    "object",
+    "target",
+    "origin",
+    "instrument",
+    "inReplyTo",
+    "published",
+    "updated",
+    "content",
+    "name",
+    "summary",
+    "mediaType",
+    "sensitive",
+    "preferredUsername",
+    "inbox",
+    "outbox",
+    "publicKey",
+    "owner",
+    "publicKeyPem",
+];
+
+const ADDRESSING: [&str; 5] = ["to", "cc", "bto", "bcc", "audience"];
+
+/// Normalize a document in place. Documents that are already compacted using the
+/// ActivityStreams context are left as they are.
+pub fn normalize(doc: &mut Value) {
+    // An expanded document is an array containing the top level node
+    if let Some(node) = single(doc) {
+        *doc = node;
+    }
+
+    normalize_value(doc);
+}
+
+fn normalize_value(val: &mut Value) {
+    match val {
+        Value::Array(arr) => arr.iter_mut().for_each(normalize_value),
+        Value::Object(map) => {
+            if let Some(compacted) = compact_value_object(map) {
+                *val = compacted;
+                return;
+            }
+            normalize_node(map);
+        }
+        _ => (),
+    }
+}
+
+fn normalize_node(map: &mut Map<String, Value>) {
+    let entries = std::mem::take(map);
+
+    for (key, mut val) in entries {
+        if key == "@context" {
+            map.insert(key, val);
+            continue;
+        }
+
+        let term = compact_term(&key);
+        normalize_value(&mut val);
+
+        if FUNCTIONAL.contains(&term.as_str()) {
+            if let Some(v) = single(&val) {
+                val = v;
+            }
+        }
+
+        if term == "type" {
+            compact_types(&mut val);
+        } else if ADDRESSING.contains(&term.as_str()) {
+            expand_public(&mut val);
+        }
+
+        map.insert(term, val);
+    }
+}
+
+fn compact_term(key: &str) -> String {
+    match key {
+        "@id" => "id".to_owned(),
+        "@type" => "type".to_owned(),
+        _ => PREFIXES
+            .iter()
+            .find_map(|p| key.strip_prefix(p))
+            .filter(|term| !term.is_empty())
+            .unwrap_or(key)
+            .to_owned(),
+    }
+}
+
+// Literal values ({"@value": ..}) are replaced by the value and bare node references
+// ({"@id": ..}) by the id
+fn compact_value_object(map: &Map<String, Value>) -> Option<Value> {
+    if let Some(v) = map.get("@value") {
+        return Some(v.clone());
+    }
+
+    match map.get("@id") {
+        Some(Value::String(id)) if map.len() == 1 => Some(Value::String(id.clone())),
+        _ => None,
+    }
+}
+
+fn compact_types(val: &mut Value) {
+    match val {
+        Value::String(s) => *s = compact_term(s),
+        Value::Array(arr) => arr.iter_mut().for_each(compact_types),
+        _ => (),
+    }
+}
+
+fn expand_public(val: &mut Value) {
+    match val {
+        Value::String(s) if s == "Public" || s == "as:Public" => *s = PUBLIC.to_owned(),
+        Value::Array(arr) => arr.iter_mut().for_each(expand_public),
+        _ => (),
+    }
+}
+
+fn single(val: &Value) -> Option<Value> {
+    match val.as_array() {
+        Some(arr) if arr.len() == 1 => Some(arr[0].clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A Create as sent by Misskey: compacted, with its own term definitions in the
+    // context, which should come through untouched
+    #[test]
+    fn compacted_misskey_activities_are_unchanged() {
+        let activity = json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                {
+                    "misskey": "https://misskey-hub.net/ns#",
+                    "_misskey_content": "misskey:_misskey_content",
+                    "sensitive": "as:sensitive",
+                }
+            ],
+            "id": "https://misskey.example/notes/9abc/activity",
+            "actor": "https://misskey.example/users/9xyz",
+            "type": "Create",
+            "published": "2023-01-01T00:00:00.000Z",
+            "object": {
+                "id": "https://misskey.example/notes/9abc",
+                "type": "Note",
+                "attributedTo": "https://misskey.example/users/9xyz",
+                "content": "<p>hello</p>",
+                "_misskey_content": "hello",
+                "sensitive": false,
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "cc": ["https://misskey.example/users/9xyz/followers"],
+                "tag": [],
+            },
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": ["https://misskey.example/users/9xyz/followers"],
+        });
+        let mut normalized = activity.clone();
+        normalize(&mut normalized);
+
+        assert_eq!(normalized, activity);
+    }
+
+    // An Announce in the expanded form that Friendica works with internally
+    #[test]
+    fn expanded_friendica_activities_are_compacted() {
+        let mut activity = json!([{
+            "@id": "https://friendica.example/objects/1234",
+            "@type": ["https://www.w3.org/ns/activitystreams#Announce"],
+            "https://www.w3.org/ns/activitystreams#actor": [
+                { "@id": "https://friendica.example/profile/alice" }
+            ],
+            "https://www.w3.org/ns/activitystreams#object": [{
+                "@id": "https://other.example/notes/1",
+                "@type": ["https://www.w3.org/ns/activitystreams#Note"],
+                "https://www.w3.org/ns/activitystreams#attributedTo": [
+                    { "@id": "https://other.example/users/bob" }
+                ],
+                "https://www.w3.org/ns/activitystreams#content": [
+                    { "@value": "hello", "@language": "en" }
+                ],
+                "http://joinmastodon.org/ns#featured": [
+                    { "@id": "https://other.example/users/bob/featured" }
+                ],
+            }],
+            "https://www.w3.org/ns/activitystreams#published": [
+                { "@type": "http://www.w3.org/2001/XMLSchema#dateTime", "@value": "2023-01-01T00:00:00Z" }
+            ],
+            "https://www.w3.org/ns/activitystreams#to": [
+                { "@id": "https://www.w3.org/ns/activitystreams#Public" }
+            ],
+            "https://www.w3.org/ns/activitystreams#cc": [
+                { "@id": "https://friendica.example/followers/alice" }
+            ],
+        }]);
+        normalize(&mut activity);
+
+        assert_eq!(
+            activity,
+            json!({
+                "id": "https://friendica.example/objects/1234",
+                "type": "Announce",
+                "actor": "https://friendica.example/profile/alice",
+                "object": {
+                    "id": "https://other.example/notes/1",
+                    "type": "Note",
+                    "attributedTo": ["https://other.example/users/bob"],
+                    "content": "hello",
+                    "http://joinmastodon.org/ns#featured": ["https://other.example/users/bob/featured"],
+                },
+                "published": "2023-01-01T00:00:00Z",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "cc": ["https://friendica.example/followers/alice"],
+            })
+        );
+    }
+
+    #[test]
+    fn compact_iris_and_public_shorthands_are_normalized() {
+        let mut activity = json!({
+            "as:type": "as:Create",
+            "as:actor": "https://a.example/actor",
+            "as:object": { "type": "Note", "sec:publicKeyPem": "key" },
+            "to": "as:Public",
+            "cc": ["Public"],
+        });
+        normalize(&mut activity);
+
+        assert_eq!(
+            activity,
+            json!({
+                "type": "Create",
+                "actor": "https://a.example/actor",
+                "object": { "type": "Note", "publicKeyPem": "key" },
+                "to": PUBLIC,
+                "cc": [PUBLIC],
+            })
+        );
+    }
+}
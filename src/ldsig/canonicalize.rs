@@ -0,0 +1,1006 @@
+//! Conversion of JSON-LD documents to canonical N-Quads (URDNA2015), as hashed when
+//! creating and verifying Linked Data Signatures.
+//!
+//! Only the parts of JSON-LD that ActivityPub implementations use are supported: local
+//! contexts, term definitions with type coercion, and list and language containers.
+//! Anything else (remote contexts we don't have a copy of, named graphs, reverse
+//! properties etc) is an error rather than a guess, as a guess would only ever produce
+//! a hash that doesn't match.
+use super::contexts;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const XSD: &str = "http://www.w3.org/2001/XMLSchema#";
+
+// Blank nodes sharing a hash are distinguished by trying each ordering of them, so the
+// number that can be tied is capped to keep the work bounded
+const MAX_PERMUTED: usize = 6;
+// Even with that cap, blank nodes that are all tied with each other (or chains of ties)
+// take exponential work to order, so the total number of paths tried for a document is
+// bounded as well. Activities rarely need more than a handful.
+const MAX_WORK: usize = 10_000;
+const MAX_TERM_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanonicalizeError {
+    /// A remote context that we don't have a local copy of
+    UnknownContext,
+    /// JSON-LD that we don't support
+    Unsupported,
+    /// Too many indistinguishable blank nodes, or too much work to order them
+    TooComplex,
+}
+
+type Result<T> = std::result::Result<T, CanonicalizeError>;
+
+/// The canonical N-Quads serialization of a document.
+pub fn canonicalize(doc: &Value) -> Result<String> {
+    let quads = to_rdf(doc)?;
+
+    Ok(normalize(quads)?.concat())
+}
+
+/// The hex encoded SHA-256 hash of the canonical form of a document.
+pub fn hash(doc: &Value) -> Result<String> {
+    Ok(sha256_hex(&canonicalize(doc)?))
+}
+
+fn sha256_hex(s: &str) -> String {
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Term {
+    Iri(String),
+    Blank(String),
+    Literal {
+        value: String,
+        datatype: String,
+        language: Option<String>,
+    },
+}
+
+impl Term {
+    fn literal(value: impl Into<String>, datatype: impl Into<String>) -> Self {
+        Self::Literal {
+            value: value.into(),
+            datatype: datatype.into(),
+            language: None,
+        }
+    }
+
+    fn blank_id(&self) -> Option<&str> {
+        match self {
+            Self::Blank(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    // Blank nodes are written with the label they are given by the closure
+    fn to_nquads(&self, label: &dyn Fn(&str) -> String) -> String {
+        match self {
+            Self::Iri(iri) => format!("<{iri}>"),
+            Self::Blank(id) => label(id),
+            Self::Literal {
+                value,
+                datatype,
+                language,
+            } => {
+                let escaped = escape(value);
+                match language {
+                    Some(lang) => format!("\"{escaped}\"@{lang}"),
+                    None if datatype == &format!("{XSD}string") => format!("\"{escaped}\""),
+                    None => format!("\"{escaped}\"^^<{datatype}>"),
+                }
+            }
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Quad {
+    subject: Term,
+    predicate: Term,
+    object: Term,
+}
+
+impl Quad {
+    fn to_nquads(&self, label: &dyn Fn(&str) -> String) -> String {
+        format!(
+            "{} {} {} .\n",
+            self.subject.to_nquads(label),
+            self.predicate.to_nquads(label),
+            self.object.to_nquads(label)
+        )
+    }
+}
+
+/// A term definition from a context.
+#[derive(Debug, Clone, Default)]
+struct Definition {
+    id: Option<String>,
+    ty: Option<String>,
+    container: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Context {
+    vocab: Option<String>,
+    terms: HashMap<String, Definition>,
+}
+
+impl Context {
+    fn with(&self, local: &Value) -> Result<Self> {
+        let mut ctx = self.clone();
+        match local {
+            Value::Null => ctx = Self::default(),
+            Value::Array(arr) => {
+                for c in arr {
+                    ctx = ctx.with(c)?;
+                }
+            }
+            Value::String(url) => {
+                let remote = contexts::lookup(url).ok_or(CanonicalizeError::UnknownContext)?;
+                ctx = ctx.with(&remote)?;
+            }
+            Value::Object(defs) => {
+                for (term, def) in defs {
+                    match term.as_str() {
+                        "@vocab" => ctx.vocab = def.as_str().map(String::from),
+                        "@version" | "@language" | "@base" => (),
+                        _ => match def {
+                            Value::Null => {
+                                ctx.terms.remove(term);
+                            }
+                            Value::String(id) => {
+                                let def = Definition {
+                                    id: Some(id.clone()),
+                                    ..Default::default()
+                                };
+                                ctx.terms.insert(term.clone(), def);
+                            }
+                            Value::Object(def) => {
+                                let get = |k| def.get(k).and_then(Value::as_str).map(String::from);
+                                let def = Definition {
+                                    id: get("@id"),
+                                    ty: get("@type"),
+                                    container: get("@container"),
+                                };
+                                ctx.terms.insert(term.clone(), def);
+                            }
+                            _ => return Err(CanonicalizeError::Unsupported),
+                        },
+                    }
+                }
+            }
+            _ => return Err(CanonicalizeError::Unsupported),
+        }
+
+        Ok(ctx)
+    }
+
+    // Expand a compact IRI or absolute IRI, along with terms if vocab is set
+    fn expand(&self, value: &str, vocab: bool) -> Option<String> {
+        self.expand_at(value, vocab, 0)
+    }
+
+    fn expand_at(&self, value: &str, vocab: bool, depth: usize) -> Option<String> {
+        if value.starts_with('@') {
+            return Some(value.to_owned());
+        }
+
+        // Terms may be defined in terms of each other, but not endlessly
+        if vocab && depth < MAX_TERM_DEPTH {
+            if let Some(def) = self.terms.get(value) {
+                let id = def.id.as_deref().unwrap_or(value);
+                return match id == value {
+                    true if !id.contains(':') => self.vocab.as_ref().map(|v| format!("{v}{id}")),
+                    true => self.expand_compact(id),
+                    false => self.expand_at(id, true, depth + 1),
+                };
+            }
+        }
+
+        if value.contains(':') {
+            return self.expand_compact(value);
+        }
+
+        match &self.vocab {
+            Some(v) if vocab => Some(format!("{v}{value}")),
+            _ => None,
+        }
+    }
+
+    fn expand_compact(&self, value: &str) -> Option<String> {
+        let (prefix, suffix) = value.split_once(':')?;
+        if prefix == "_" || suffix.starts_with("//") {
+            return Some(value.to_owned());
+        }
+
+        match self.terms.get(prefix).and_then(|d| d.id.as_deref()) {
+            Some(iri) => Some(format!("{iri}{suffix}")),
+            None => Some(value.to_owned()),
+        }
+    }
+
+    fn definition(&self, term: &str) -> Option<&Definition> {
+        self.terms.get(term)
+    }
+
+    fn coerced_type(&self, term: &str) -> Option<String> {
+        let ty = self.definition(term)?.ty.as_deref()?;
+        match ty {
+            "@id" | "@vocab" => Some(ty.to_owned()),
+            _ => self.expand(ty, true),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Converter {
+    quads: Vec<Quad>,
+    seen: HashSet<Quad>,
+    // Blank node labels used in the document, mapped to the ones we issue
+    labels: HashMap<String, String>,
+    counter: usize,
+}
+
+impl Converter {
+    fn blank(&mut self, label: Option<&str>) -> Term {
+        if let Some(label) = label {
+            if let Some(id) = self.labels.get(label) {
+                return Term::Blank(id.clone());
+            }
+        }
+
+        let id = format!("b{}", self.counter);
+        self.counter += 1;
+        if let Some(label) = label {
+            self.labels.insert(label.to_owned(), id.clone());
+        }
+
+        Term::Blank(id)
+    }
+
+    // Subjects and objects given as IRIs, which may be blank node labels
+    fn resource(&mut self, iri: &str) -> Option<Term> {
+        if iri.starts_with("_:") {
+            Some(self.blank(Some(iri)))
+        } else if is_absolute(iri) {
+            Some(Term::Iri(iri.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    fn node(&mut self, node: &Map<String, Value>, ctx: &Context) -> Result<Term> {
+        let ctx = match node.get("@context") {
+            Some(local) => ctx.with(local)?,
+            None => ctx.clone(),
+        };
+
+        let mut subject = None;
+        for (key, val) in node {
+            if ctx.expand(key, true).as_deref() == Some("@id") {
+                let id = val.as_str().ok_or(CanonicalizeError::Unsupported)?;
+                let iri = ctx
+                    .expand(id, false)
+                    .ok_or(CanonicalizeError::Unsupported)?;
+                subject = Some(self.resource(&iri).ok_or(CanonicalizeError::Unsupported)?);
+            }
+        }
+        let subject = subject.unwrap_or_else(|| self.blank(None));
+
+        for (key, val) in node {
+            let predicate = match ctx.expand(key, true) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            match predicate.as_str() {
+                "@context" | "@id" => (),
+                "@type" => {
+                    for ty in as_array(val) {
+                        let ty = ty.as_str().ok_or(CanonicalizeError::Unsupported)?;
+                        if let Some(object) = ctx.expand(ty, true).and_then(|t| self.resource(&t)) {
+                            self.push(&subject, &format!("{RDF}type"), object);
+                        }
+                    }
+                }
+                p if p.starts_with('@') => return Err(CanonicalizeError::Unsupported),
+                // Blank node properties are only output as generalized RDF
+                p if p.starts_with("_:") || !is_absolute(p) => (),
+                _ => {
+                    let def = ctx.definition(key).cloned().unwrap_or_default();
+                    match def.container.as_deref() {
+                        Some("@list") => {
+                            let items = as_array(val)
+                                .into_iter()
+                                .map(|v| self.value(v, key, &ctx))
+                                .collect::<Result<Vec<_>>>()?;
+                            let list = self.list(items.into_iter().flatten().collect());
+                            self.push(&subject, &predicate, list);
+                        }
+                        Some("@language") if val.is_object() => {
+                            for (lang, v) in val.as_object().into_iter().flatten() {
+                                for s in as_array(v) {
+                                    let value = s.as_str().ok_or(CanonicalizeError::Unsupported)?;
+                                    let object = Term::Literal {
+                                        value: value.to_owned(),
+                                        datatype: format!("{RDF}langString"),
+                                        language: Some(lang.clone()),
+                                    };
+                                    self.push(&subject, &predicate, object);
+                                }
+                            }
+                        }
+                        _ => {
+                            for v in as_array(val) {
+                                if let Some(object) = self.value(v, key, &ctx)? {
+                                    self.push(&subject, &predicate, object);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(subject)
+    }
+
+    fn value(&mut self, val: &Value, term: &str, ctx: &Context) -> Result<Option<Term>> {
+        let coerced = ctx.coerced_type(term);
+        // Only strings can be coerced to IRIs
+        let datatype = coerced.clone().filter(|t| !t.starts_with('@'));
+
+        let term = match val {
+            Value::Null => None,
+            Value::Bool(b) => Some(Term::literal(
+                b.to_string(),
+                datatype.unwrap_or_else(|| format!("{XSD}boolean")),
+            )),
+            Value::Number(n) => Some(number(n, datatype)),
+            Value::String(s) => match coerced.as_deref() {
+                Some("@id") => ctx.expand(s, false).and_then(|iri| self.resource(&iri)),
+                Some("@vocab") => ctx.expand(s, true).and_then(|iri| self.resource(&iri)),
+                Some(datatype) => Some(Term::literal(s.clone(), datatype)),
+                None => Some(Term::literal(s.clone(), format!("{XSD}string"))),
+            },
+            Value::Array(_) => return Err(CanonicalizeError::Unsupported),
+            Value::Object(obj) => self.object(obj, ctx)?,
+        };
+
+        Ok(term)
+    }
+
+    fn object(&mut self, obj: &Map<String, Value>, ctx: &Context) -> Result<Option<Term>> {
+        let keyword = |k: &str| {
+            obj.iter()
+                .find(|(key, _)| ctx.expand(key, true).as_deref() == Some(k))
+                .map(|(_, v)| v)
+        };
+
+        if let Some(value) = keyword("@value") {
+            let datatype = keyword("@type")
+                .and_then(Value::as_str)
+                .and_then(|t| ctx.expand(t, true));
+            let language = keyword("@language").and_then(Value::as_str);
+
+            let term = match (value, language) {
+                (Value::Null, _) => None,
+                (Value::String(s), Some(lang)) => Some(Term::Literal {
+                    value: s.clone(),
+                    datatype: format!("{RDF}langString"),
+                    language: Some(lang.to_owned()),
+                }),
+                (Value::String(s), None) => Some(Term::literal(
+                    s.clone(),
+                    datatype.unwrap_or_else(|| format!("{XSD}string")),
+                )),
+                (Value::Bool(b), _) => Some(Term::literal(
+                    b.to_string(),
+                    datatype.unwrap_or_else(|| format!("{XSD}boolean")),
+                )),
+                (Value::Number(n), _) => Some(number(n, datatype)),
+                _ => return Err(CanonicalizeError::Unsupported),
+            };
+
+            return Ok(term);
+        }
+
+        if let Some(items) = keyword("@list") {
+            let items = as_array(items)
+                .into_iter()
+                .map(|v| self.value(v, "", ctx))
+                .collect::<Result<Vec<_>>>()?;
+
+            return Ok(Some(self.list(items.into_iter().flatten().collect())));
+        }
+
+        if keyword("@set").is_some() || keyword("@graph").is_some() {
+            return Err(CanonicalizeError::Unsupported);
+        }
+
+        self.node(obj, ctx).map(Some)
+    }
+
+    fn list(&mut self, items: Vec<Term>) -> Term {
+        let nil = Term::Iri(format!("{RDF}nil"));
+        let nodes: Vec<Term> = items.iter().map(|_| self.blank(None)).collect();
+
+        for (i, item) in items.into_iter().enumerate() {
+            let rest = nodes.get(i + 1).cloned().unwrap_or_else(|| nil.clone());
+            self.push(&nodes[i], &format!("{RDF}first"), item);
+            self.push(&nodes[i], &format!("{RDF}rest"), rest);
+        }
+
+        nodes.into_iter().next().unwrap_or(nil)
+    }
+
+    fn push(&mut self, subject: &Term, predicate: &str, object: Term) {
+        let quad = Quad {
+            subject: subject.clone(),
+            predicate: Term::Iri(predicate.to_owned()),
+            object,
+        };
+
+        // Duplicate triples are the same statement so only appear once
+        if self.seen.insert(quad.clone()) {
+            self.quads.push(quad);
+        }
+    }
+}
+
+fn as_array(val: &Value) -> Vec<&Value> {
+    match val {
+        Value::Array(arr) => arr.iter().collect(),
+        v => vec![v],
+    }
+}
+
+fn is_absolute(iri: &str) -> bool {
+    match iri.split_once(':') {
+        Some((scheme, _)) => {
+            !scheme.is_empty()
+                && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        None => false,
+    }
+}
+
+// Numbers without a fractional part are integers unless coerced to doubles, which are
+// written in their canonical form (e.g. 1.5E0)
+fn number(n: &serde_json::Number, datatype: Option<String>) -> Term {
+    let double = format!("{XSD}double");
+    let f = n.as_f64().unwrap_or_default();
+    let integral = n.is_i64() || n.is_u64() || (f.fract() == 0.0 && f.abs() < 1e21);
+
+    if integral && datatype.as_deref() != Some(double.as_str()) {
+        let value = match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => i.to_string(),
+            (_, Some(u)) => u.to_string(),
+            _ => format!("{}", f as i64),
+        };
+        return Term::literal(value, datatype.unwrap_or_else(|| format!("{XSD}integer")));
+    }
+
+    let formatted = format!("{f:.15e}");
+    let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+    let mut mantissa = mantissa.trim_end_matches('0').to_owned();
+    if mantissa.ends_with('.') {
+        mantissa.push('0');
+    }
+
+    Term::literal(format!("{mantissa}E{exponent}"), datatype.unwrap_or(double))
+}
+
+fn to_rdf(doc: &Value) -> Result<Vec<Quad>> {
+    let mut converter = Converter::default();
+    let ctx = Context::default();
+
+    for node in as_array(doc) {
+        match node {
+            Value::Object(obj) => {
+                converter.node(obj, &ctx)?;
+            }
+            _ => return Err(CanonicalizeError::Unsupported),
+        }
+    }
+
+    Ok(converter.quads)
+}
+
+#[derive(Debug, Clone)]
+struct IdentifierIssuer {
+    prefix: &'static str,
+    counter: usize,
+    issued: Vec<(String, String)>,
+}
+
+impl IdentifierIssuer {
+    fn new(prefix: &'static str) -> Self {
+        Self {
+            prefix,
+            counter: 0,
+            issued: vec![],
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<&str> {
+        self.issued
+            .iter()
+            .find(|(existing, _)| existing == id)
+            .map(|(_, issued)| issued.as_str())
+    }
+
+    fn issue(&mut self, id: &str) -> String {
+        if let Some(issued) = self.get(id) {
+            return issued.to_owned();
+        }
+
+        let issued = format!("_:{}{}", self.prefix, self.counter);
+        self.counter += 1;
+        self.issued.push((id.to_owned(), issued.clone()));
+
+        issued
+    }
+}
+
+struct Normalizer {
+    quads: Vec<Quad>,
+    blank_to_quads: BTreeMap<String, Vec<usize>>,
+    canonical: IdentifierIssuer,
+    work: Cell<usize>,
+}
+
+// The URDNA2015 algorithm from the RDF Dataset Normalization spec, returning the sorted
+// N-Quads lines for the dataset
+fn normalize(quads: Vec<Quad>) -> Result<Vec<String>> {
+    let mut blank_to_quads: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, q) in quads.iter().enumerate() {
+        for id in [&q.subject, &q.object]
+            .into_iter()
+            .filter_map(Term::blank_id)
+        {
+            let entry = blank_to_quads.entry(id.to_owned()).or_default();
+            if !entry.contains(&i) {
+                entry.push(i);
+            }
+        }
+    }
+
+    let mut n = Normalizer {
+        quads,
+        blank_to_quads,
+        canonical: IdentifierIssuer::new("c14n"),
+        work: Cell::new(0),
+    };
+
+    let mut hash_to_blanks: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for id in n.blank_to_quads.keys() {
+        hash_to_blanks
+            .entry(n.hash_first_degree(id))
+            .or_default()
+            .push(id.clone());
+    }
+
+    for ids in hash_to_blanks.values().filter(|ids| ids.len() == 1) {
+        n.canonical.issue(&ids[0]);
+    }
+
+    for ids in hash_to_blanks.values().filter(|ids| ids.len() > 1) {
+        let mut paths = vec![];
+        for id in ids {
+            if n.canonical.get(id).is_some() {
+                continue;
+            }
+            let mut issuer = IdentifierIssuer::new("b");
+            issuer.issue(id);
+            paths.push(n.hash_n_degree(id, issuer)?);
+        }
+
+        paths.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, issuer) in paths {
+            for (id, _) in issuer.issued {
+                n.canonical.issue(&id);
+            }
+        }
+    }
+
+    let label = |id: &str| n.canonical.get(id).unwrap_or(id).to_owned();
+    let mut lines: Vec<String> = n.quads.iter().map(|q| q.to_nquads(&label)).collect();
+    lines.sort();
+    lines.dedup();
+
+    Ok(lines)
+}
+
+impl Normalizer {
+    // Fails once the work budget for the document has been used up
+    fn spend(&self) -> Result<()> {
+        let work = self.work.get() + 1;
+        self.work.set(work);
+
+        match work > MAX_WORK {
+            true => Err(CanonicalizeError::TooComplex),
+            false => Ok(()),
+        }
+    }
+
+    fn hash_first_degree(&self, id: &str) -> String {
+        let label = |other: &str| if other == id { "_:a" } else { "_:z" }.to_owned();
+        let mut lines: Vec<String> = self.blank_to_quads[id]
+            .iter()
+            .map(|&i| self.quads[i].to_nquads(&label))
+            .collect();
+        lines.sort();
+
+        sha256_hex(&lines.concat())
+    }
+
+    fn hash_related(
+        &self,
+        related: &str,
+        predicate: &Term,
+        issuer: &IdentifierIssuer,
+        position: &str,
+    ) -> String {
+        let id = match self.canonical.get(related).or_else(|| issuer.get(related)) {
+            Some(id) => id.to_owned(),
+            None => self.hash_first_degree(related),
+        };
+        let predicate = match predicate {
+            Term::Iri(p) => format!("<{p}>"),
+            _ => String::new(),
+        };
+
+        sha256_hex(&format!("{position}{predicate}{id}"))
+    }
+
+    fn hash_n_degree(
+        &self,
+        id: &str,
+        mut issuer: IdentifierIssuer,
+    ) -> Result<(String, IdentifierIssuer)> {
+        self.spend()?;
+        let mut hash_to_related: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for &i in &self.blank_to_quads[id] {
+            let q = &self.quads[i];
+            for (term, position) in [(&q.subject, "s"), (&q.object, "o")] {
+                match term.blank_id() {
+                    Some(related) if related != id => {
+                        let hash = self.hash_related(related, &q.predicate, &issuer, position);
+                        hash_to_related
+                            .entry(hash)
+                            .or_default()
+                            .push(related.to_owned());
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let mut data = String::new();
+        for (related_hash, blanks) in hash_to_related {
+            if blanks.len() > MAX_PERMUTED {
+                return Err(CanonicalizeError::TooComplex);
+            }
+
+            data.push_str(&related_hash);
+            let mut chosen: Option<(String, IdentifierIssuer)> = None;
+
+            'permutations: for permutation in permutations(&blanks) {
+                self.spend()?;
+                let mut issuer_copy = issuer.clone();
+                let mut path = String::new();
+                let mut recursion = vec![];
+                let longer = |path: &String, chosen: &Option<(String, IdentifierIssuer)>| matches!(chosen, Some((c, _)) if path.len() >= c.len() && path > c);
+
+                for related in permutation {
+                    match self.canonical.get(related) {
+                        Some(c) => path.push_str(c),
+                        None => {
+                            if issuer_copy.get(related).is_none() {
+                                recursion.push(related);
+                            }
+                            path.push_str(&issuer_copy.issue(related));
+                        }
+                    }
+                    if longer(&path, &chosen) {
+                        continue 'permutations;
+                    }
+                }
+
+                for related in recursion {
+                    let (hash, result_issuer) = self.hash_n_degree(related, issuer_copy.clone())?;
+                    path.push_str(&issuer_copy.issue(related));
+                    path.push_str(&format!("<{hash}>"));
+                    issuer_copy = result_issuer;
+                    if longer(&path, &chosen) {
+                        continue 'permutations;
+                    }
+                }
+
+                if chosen.as_ref().map(|(c, _)| &path < c).unwrap_or(true) {
+                    chosen = Some((path, issuer_copy));
+                }
+            }
+
+            if let Some((path, chosen_issuer)) = chosen {
+                data.push_str(&path);
+                issuer = chosen_issuer;
+            }
+        }
+
+        Ok((sha256_hex(&data), issuer))
+    }
+}
+
+fn permutations(items: &[String]) -> Vec<Vec<&str>> {
+    let items: Vec<&str> = items.iter().map(String::as_str).collect();
+
+    permute(&items)
+}
+
+fn permute<'a>(items: &[&'a str]) -> Vec<Vec<&'a str>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+
+    let mut result = vec![];
+    for (i, first) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(i);
+        for p in permute(&rest) {
+            let mut perm = vec![*first];
+            perm.extend(p);
+            result.push(perm);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    #[test]
+    fn activities_are_converted_to_sorted_nquads() {
+        let doc = json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                { "ostatus": "http://ostatus.org#", "atomUri": "ostatus:atomUri" }
+            ],
+            "id": "https://a.example/users/alice/statuses/1#delete",
+            "type": "Delete",
+            "actor": "https://a.example/users/alice",
+            "object": {
+                "id": "https://a.example/users/alice/statuses/1",
+                "type": "Tombstone",
+                "atomUri": "https://a.example/users/alice/statuses/1"
+            },
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "unknownTerm": "dropped"
+        });
+
+        let expected = "\
+<https://a.example/users/alice/statuses/1#delete> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Delete> .
+<https://a.example/users/alice/statuses/1#delete> <https://www.w3.org/ns/activitystreams#actor> <https://a.example/users/alice> .
+<https://a.example/users/alice/statuses/1#delete> <https://www.w3.org/ns/activitystreams#object> <https://a.example/users/alice/statuses/1> .
+<https://a.example/users/alice/statuses/1#delete> <https://www.w3.org/ns/activitystreams#to> <https://www.w3.org/ns/activitystreams#Public> .
+<https://a.example/users/alice/statuses/1> <http://ostatus.org#atomUri> \"https://a.example/users/alice/statuses/1\" .
+<https://a.example/users/alice/statuses/1> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Tombstone> .
+";
+
+        assert_eq!(canonicalize(&doc).unwrap(), expected);
+    }
+
+    #[test]
+    fn blank_nodes_are_labelled_canonically() {
+        let options = json!({
+            "@context": "https://w3id.org/identity/v1",
+            "creator": "https://a.example/users/alice#main-key",
+            "created": "2023-01-01T00:00:00Z"
+        });
+
+        let expected = "\
+_:c14n0 <http://purl.org/dc/terms/created> \"2023-01-01T00:00:00Z\"^^<http://www.w3.org/2001/XMLSchema#dateTime> .
+_:c14n0 <http://purl.org/dc/terms/creator> <https://a.example/users/alice#main-key> .
+";
+
+        assert_eq!(canonicalize(&options).unwrap(), expected);
+    }
+
+    // The order that properties and array items appear in makes no difference
+    #[test]
+    fn canonical_form_is_independent_of_ordering() {
+        let a = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": "Person",
+            "id": "https://a.example/users/alice",
+            "attachment": [
+                { "type": "Link", "name": "one", "href": "https://one.example" },
+                { "type": "Link", "name": "two", "href": "https://two.example" }
+            ],
+            "endpoints": { "sharedInbox": "https://a.example/inbox" }
+        });
+        let b = json!({
+            "endpoints": { "sharedInbox": "https://a.example/inbox" },
+            "attachment": [
+                { "href": "https://two.example", "name": "two", "type": "Link" },
+                { "href": "https://one.example", "type": "Link", "name": "one" }
+            ],
+            "id": "https://a.example/users/alice",
+            "type": "Person",
+            "@context": "https://www.w3.org/ns/activitystreams"
+        });
+
+        assert_eq!(canonicalize(&a).unwrap(), canonicalize(&b).unwrap());
+    }
+
+    #[test]
+    fn indistinguishable_blank_nodes_are_ordered_deterministically() {
+        let doc = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://a.example/notes/1",
+            "tag": [{ "type": "Mention" }, { "type": "Mention" }]
+        });
+
+        let res = canonicalize(&doc).unwrap();
+
+        assert!(res.contains("_:c14n0"));
+        assert!(res.contains("_:c14n1"));
+    }
+
+    #[test]
+    fn literals_are_typed_and_escaped() {
+        let doc = json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                { "sensitive": "as:sensitive", "focalPoint": { "@id": "http://joinmastodon.org/ns#focalPoint", "@container": "@list" } }
+            ],
+            "id": "https://a.example/media/1",
+            "content": "say \"hi\"\nthen\\leave",
+            "sensitive": false,
+            "width": 640,
+            "focalPoint": [0.5, -1.0],
+            "contentMap": { "en": "hello" }
+        });
+
+        let res = canonicalize(&doc).unwrap();
+
+        assert!(res.contains(r#""say \"hi\"\nthen\\leave" ."#));
+        assert!(res.contains(r#""false"^^<http://www.w3.org/2001/XMLSchema#boolean>"#));
+        assert!(res.contains(r#""640"^^<http://www.w3.org/2001/XMLSchema#nonNegativeInteger>"#));
+        assert!(res.contains(r#""5.0E-1"^^<http://www.w3.org/2001/XMLSchema#double>"#));
+        assert!(res.contains(r#""-1"^^<http://www.w3.org/2001/XMLSchema#integer>"#));
+        assert!(res.contains(r#""hello"@en"#));
+        assert!(res.contains("<http://www.w3.org/1999/02/22-rdf-syntax-ns#nil>"));
+    }
+
+    // Only the IRIs and blank nodes used by the test vectors below are handled
+    fn parse_nquads(nquads: &str) -> Vec<Quad> {
+        let term = |t: &str| match t.strip_prefix("_:") {
+            Some(id) => Term::Blank(id.to_owned()),
+            None => Term::Iri(t.trim_matches(|c| c == '<' || c == '>').to_owned()),
+        };
+
+        nquads
+            .lines()
+            .map(|line| {
+                let terms: Vec<&str> = line.split_whitespace().collect();
+                Quad {
+                    subject: term(terms[0]),
+                    predicate: term(terms[1]),
+                    object: term(terms[2]),
+                }
+            })
+            .collect()
+    }
+
+    // The "unique hashes" and "shared hashes" examples from the W3C RDF Dataset
+    // Canonicalization recommendation
+    #[test_case(
+        "\
+<http://example.com/#p> <http://example.com/#q> _:e0 .
+<http://example.com/#p> <http://example.com/#r> _:e1 .
+_:e0 <http://example.com/#s> <http://example.com/#u> .
+_:e1 <http://example.com/#t> <http://example.com/#u> .",
+        "\
+<http://example.com/#p> <http://example.com/#q> _:c14n0 .
+<http://example.com/#p> <http://example.com/#r> _:c14n1 .
+_:c14n0 <http://example.com/#s> <http://example.com/#u> .
+_:c14n1 <http://example.com/#t> <http://example.com/#u> .
+";
+        "unique hashes"
+    )]
+    #[test_case(
+        "\
+<http://example.com/#p> <http://example.com/#q> _:e0 .
+<http://example.com/#p> <http://example.com/#q> _:e1 .
+_:e0 <http://example.com/#p> _:e2 .
+_:e1 <http://example.com/#p> _:e3 .
+_:e2 <http://example.com/#r> _:e3 .",
+        "\
+<http://example.com/#p> <http://example.com/#q> _:c14n2 .
+<http://example.com/#p> <http://example.com/#q> _:c14n3 .
+_:c14n0 <http://example.com/#r> _:c14n1 .
+_:c14n2 <http://example.com/#p> _:c14n1 .
+_:c14n3 <http://example.com/#p> _:c14n0 .
+";
+        "shared hashes"
+    )]
+    #[test]
+    fn spec_examples_are_canonicalized(input: &str, expected: &str) {
+        let res = normalize(parse_nquads(input)).unwrap().concat();
+
+        assert_eq!(res, expected);
+    }
+
+    // Every blank node linked to every other has no way of telling them apart, so all
+    // orderings of them are tried recursively
+    fn clique(size: usize) -> Vec<Quad> {
+        let mut quads = vec![];
+        for a in 0..size {
+            for b in (0..size).filter(|&b| b != a) {
+                quads.push(Quad {
+                    subject: Term::Blank(format!("e{a}")),
+                    predicate: Term::Iri("http://example.com/#p".to_owned()),
+                    object: Term::Blank(format!("e{b}")),
+                });
+            }
+        }
+
+        quads
+    }
+
+    #[test_case(4, true; "small clique")]
+    #[test_case(6, false; "clique over the work budget")]
+    #[test_case(10, false; "clique with too many ties")]
+    #[test]
+    fn the_work_to_canonicalize_is_bounded(size: usize, ok: bool) {
+        let res = normalize(clique(size));
+
+        match ok {
+            true => assert!(res.is_ok()),
+            false => assert_eq!(res, Err(CanonicalizeError::TooComplex)),
+        }
+    }
+
+    #[test_case(json!({ "@context": "https://unknown.example/context" }), CanonicalizeError::UnknownContext; "unknown context")]
+    #[test_case(json!({ "@context": "https://www.w3.org/ns/activitystreams", "@graph": [] }), CanonicalizeError::Unsupported; "named graph")]
+    #[test]
+    fn unsupported_documents_are_errors(doc: Value, expected: CanonicalizeError) {
+        assert_eq!(canonicalize(&doc), Err(expected));
+    }
+}
@@ -0,0 +1,369 @@
+//! Linked Data Signatures (RsaSignature2017) as used by Mastodon and others.
+//!
+//! Activities forwarded by a third party (e.g. a Delete forwarded by an instance where
+//! the deleted post had been boosted) are delivered with the HTTP signature of the
+//! forwarding instance rather than the author. Those that are signed by their author
+//! carry a `signature` property that can be checked against the author's key without
+//! trusting whoever delivered them.
+//!
+//! The signed data is the hex encoded SHA-256 hash of the canonicalized signature
+//! options (the signature minus its type, id and value) followed by that of the
+//! canonicalized document without its signature. Documents that can't be canonicalized
+//! (most often as they refer to a context we have no copy of) can't be checked either
+//! way, so are reported separately from those with invalid signatures.
+use crate::{signer::Signer, Error, Result};
+use axum::http::StatusCode;
+use chrono::{SecondsFormat, Utc};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey,
+    pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+    signature::Verifier,
+    RsaPublicKey,
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tracing::debug;
+
+mod canonicalize;
+mod contexts;
+
+pub use canonicalize::{canonicalize, CanonicalizeError};
+
+pub const SIGNATURE_TYPE: &str = "RsaSignature2017";
+
+const INVALID_SIG: Error = Error::StatusAndMessage {
+    status: StatusCode::UNAUTHORIZED,
+    message: "invalid linked data signature",
+};
+
+/// Returned when verifying a signed document that can't be canonicalized.
+pub const UNVERIFIABLE_SIG: Error = Error::StatusAndMessage {
+    status: StatusCode::UNAUTHORIZED,
+    message: "unable to canonicalize linked data signed document",
+};
+
+/// The id of the key that a document claims to be signed with, if it is signed.
+pub fn creator(doc: &Value) -> Option<&str> {
+    let signature = &doc["signature"];
+    if signature["type"] != SIGNATURE_TYPE {
+        return None;
+    }
+
+    signature["creator"].as_str()
+}
+
+/// The public key with the given id in a fetched document, along with the id of the
+/// actor that owns it. The document is either the key itself or an actor listing it
+/// among its keys, in which case the key must belong to that actor.
+pub fn find_key(doc: &Value, key_id: &str) -> Result<(String, RsaPublicKey)> {
+    let embedded = doc.get("publicKey").is_some();
+    let keys: Vec<&Value> = match &doc["publicKey"] {
+        Value::Array(keys) => keys.iter().collect(),
+        Value::Null => vec![doc],
+        key => vec![key],
+    };
+    let key = keys
+        .into_iter()
+        .find(|key| key["id"] == key_id)
+        .ok_or(INVALID_SIG)?;
+
+    let owner = match (key["owner"].as_str(), doc["id"].as_str()) {
+        (Some(owner), Some(actor)) if embedded && owner != actor => return Err(INVALID_SIG),
+        (None, Some(actor)) if embedded => actor,
+        (Some(owner), _) => owner,
+        _ => return Err(INVALID_SIG),
+    };
+    let pem = key["publicKeyPem"].as_str().ok_or(INVALID_SIG)?;
+    let pub_key = RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .map_err(|_| INVALID_SIG)?;
+
+    Ok((owner.to_owned(), pub_key))
+}
+
+/// Verify the signature of a document using the public key of its creator.
+pub fn verify(doc: &Value, key: &RsaPublicKey) -> Result<()> {
+    let signature = doc["signature"]
+        .get("signatureValue")
+        .and_then(Value::as_str)
+        .and_then(|s| base64::decode(s).ok())
+        .ok_or(INVALID_SIG)?;
+    let data = signed_data(doc)?;
+
+    VerifyingKey::<Sha256>::new_with_prefix(key.clone())
+        .verify(data.as_bytes(), &Signature::from(signature))
+        .map_err(|e| {
+            debug!(%e, "invalid linked data signature");
+            INVALID_SIG
+        })
+}
+
+/// Sign a document, replacing any existing signature.
+pub async fn sign(doc: &mut Value, key_id: &str, signer: &dyn Signer) -> Result<()> {
+    doc["signature"] = json!({
+        "type": SIGNATURE_TYPE,
+        "creator": key_id,
+        "created": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    });
+    let data = signed_data(doc)?;
+    let signature = signer.sign(data.as_bytes()).await?;
+    doc["signature"]["signatureValue"] = base64::encode(signature).into();
+
+    Ok(())
+}
+
+fn signed_data(doc: &Value) -> Result<String> {
+    let mut options = doc["signature"].clone();
+    let options_map = options.as_object_mut().ok_or(INVALID_SIG)?;
+    for key in ["type", "id", "signatureValue"] {
+        options_map.remove(key);
+    }
+    options_map.insert("@context".into(), contexts::IDENTITY_V1.into());
+
+    let mut document = doc.clone();
+    if let Some(map) = document.as_object_mut() {
+        map.remove("signature");
+    }
+
+    // Documents that would take too much work to canonicalize fail closed rather than
+    // being treated as unsigned
+    let hash = |v: &Value| {
+        canonicalize::hash(v).map_err(|e| {
+            debug!(?e, "unable to canonicalize document");
+            match e {
+                CanonicalizeError::TooComplex => INVALID_SIG,
+                _ => UNVERIFIABLE_SIG,
+            }
+        })
+    };
+
+    Ok(format!("{}{}", hash(&options)?, hash(&document)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::tests::{TEST_PRIV_KEY, TEST_PUB_KEY};
+    use rsa::{
+        pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+        pkcs1v15::SigningKey,
+        RsaPrivateKey,
+    };
+    use simple_test_case::test_case;
+
+    const KEY_ID: &str = "https://a.example/users/alice#main-key";
+
+    fn keys() -> (SigningKey<Sha256>, RsaPublicKey) {
+        let priv_key = RsaPrivateKey::from_pkcs1_pem(TEST_PRIV_KEY).unwrap();
+        let pub_key = RsaPublicKey::from_pkcs1_pem(TEST_PUB_KEY).unwrap();
+
+        (SigningKey::new_with_prefix(priv_key), pub_key)
+    }
+
+    fn delete() -> Value {
+        json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                { "ostatus": "http://ostatus.org#", "atomUri": "ostatus:atomUri" }
+            ],
+            "id": "https://a.example/users/alice/statuses/1#delete",
+            "type": "Delete",
+            "actor": "https://a.example/users/alice",
+            "object": {
+                "id": "https://a.example/users/alice/statuses/1",
+                "type": "Tombstone",
+                "atomUri": "https://a.example/users/alice/statuses/1"
+            },
+            "to": ["https://www.w3.org/ns/activitystreams#Public"]
+        })
+    }
+
+    #[tokio::test]
+    async fn signed_documents_verify() {
+        let (signing_key, pub_key) = keys();
+        let mut doc = delete();
+        sign(&mut doc, KEY_ID, &signing_key).await.unwrap();
+
+        assert_eq!(creator(&doc), Some(KEY_ID));
+        assert_eq!(verify(&doc, &pub_key), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn reordering_properties_does_not_break_signatures() {
+        let (signing_key, pub_key) = keys();
+        let mut doc = delete();
+        sign(&mut doc, KEY_ID, &signing_key).await.unwrap();
+
+        let mut reordered = serde_json::Map::new();
+        for (k, v) in doc.as_object().unwrap().iter().rev() {
+            reordered.insert(k.clone(), v.clone());
+        }
+
+        assert_eq!(verify(&Value::Object(reordered), &pub_key), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn tampered_documents_do_not_verify() {
+        let (signing_key, pub_key) = keys();
+        let mut doc = delete();
+        sign(&mut doc, KEY_ID, &signing_key).await.unwrap();
+        doc["object"]["id"] = "https://a.example/users/alice/statuses/2".into();
+
+        assert_eq!(verify(&doc, &pub_key), Err(INVALID_SIG));
+    }
+
+    // Terms that aren't defined by any context aren't part of the signed data so can be
+    // added without breaking the signature, as with any other implementation
+    #[tokio::test]
+    async fn undefined_terms_are_not_signed() {
+        let (signing_key, pub_key) = keys();
+        let mut doc = delete();
+        sign(&mut doc, KEY_ID, &signing_key).await.unwrap();
+        doc["undefined"] = "ignored".into();
+
+        assert_eq!(verify(&doc, &pub_key), Ok(()));
+    }
+
+    // An account deletion in the form Mastodon sends them, signed with the test key
+    const MASTODON_DELETE: &str = r#"{
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": "https://mastodon.example/users/alice#delete",
+        "type": "Delete",
+        "actor": "https://mastodon.example/users/alice",
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        "object": "https://mastodon.example/users/alice",
+        "signature": {
+            "type": "RsaSignature2017",
+            "creator": "https://mastodon.example/users/alice#main-key",
+            "created": "2023-03-14T09:26:53Z",
+            "signatureValue": "r7H7Yxf9Q9FAryMUH6SJ1XZV64d2xvmalrGlxsQgh9q3hO8nKVaGbyZN7aSYkQtgSgXPKxZiMkU2kplzJR5TyxPt9saocI2E5oqmn4LGNu8raefMIW/n8XMBYADpEYRrHEyXeWb0yATFj412+eOUppYktA8WBCZXnONe0Bcxucs="
+        }
+    }"#;
+
+    // A post in the form Mastodon sends them, signed with the test key. The signature was
+    // made independently of our own canonicalization, over the N-Quads below
+    const MASTODON_CREATE: &str = r##"{
+        "@context": [
+            "https://www.w3.org/ns/activitystreams",
+            {
+                "ostatus": "http://ostatus.org#",
+                "atomUri": "ostatus:atomUri",
+                "sensitive": "as:sensitive",
+                "Hashtag": "as:Hashtag"
+            }
+        ],
+        "id": "https://mastodon.example/users/alice/statuses/1/activity",
+        "type": "Create",
+        "actor": "https://mastodon.example/users/alice",
+        "published": "2023-03-14T09:26:53Z",
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        "cc": ["https://mastodon.example/users/alice/followers"],
+        "object": {
+            "id": "https://mastodon.example/users/alice/statuses/1",
+            "type": "Note",
+            "attributedTo": "https://mastodon.example/users/alice",
+            "published": "2023-03-14T09:26:53Z",
+            "content": "<p>Hello #rust</p>",
+            "contentMap": { "en": "<p>Hello #rust</p>" },
+            "sensitive": false,
+            "atomUri": "https://mastodon.example/users/alice/statuses/1",
+            "tag": [{ "type": "Hashtag", "href": "https://mastodon.example/tags/rust", "name": "#rust" }]
+        },
+        "signature": {
+            "type": "RsaSignature2017",
+            "creator": "https://mastodon.example/users/alice#main-key",
+            "created": "2023-03-14T09:27:01Z",
+            "signatureValue": "MLYeyg/LAswbCEl5YCvJJcDN9jzL+k7n4nDn+jrU65VENJ4r2rWH3zUqJU+QJXfUtSqJ4ddpasEu8/HiU2uguGuqSJSlPyVXkpOafZVOGBTxekpSOJWJYXir8ZC9NHRI4PlFK1ErsLymgQsbd8GweakgXycX7EpGDhQ/EK8pLMk="
+        }
+    }"##;
+
+    // The canonical form of MASTODON_CREATE (without its signature), written out by hand
+    const MASTODON_CREATE_NQUADS: &str = "\
+<https://mastodon.example/users/alice/statuses/1/activity> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Create> .
+<https://mastodon.example/users/alice/statuses/1/activity> <https://www.w3.org/ns/activitystreams#actor> <https://mastodon.example/users/alice> .
+<https://mastodon.example/users/alice/statuses/1/activity> <https://www.w3.org/ns/activitystreams#cc> <https://mastodon.example/users/alice/followers> .
+<https://mastodon.example/users/alice/statuses/1/activity> <https://www.w3.org/ns/activitystreams#object> <https://mastodon.example/users/alice/statuses/1> .
+<https://mastodon.example/users/alice/statuses/1/activity> <https://www.w3.org/ns/activitystreams#published> \"2023-03-14T09:26:53Z\"^^<http://www.w3.org/2001/XMLSchema#dateTime> .
+<https://mastodon.example/users/alice/statuses/1/activity> <https://www.w3.org/ns/activitystreams#to> <https://www.w3.org/ns/activitystreams#Public> .
+<https://mastodon.example/users/alice/statuses/1> <http://ostatus.org#atomUri> \"https://mastodon.example/users/alice/statuses/1\" .
+<https://mastodon.example/users/alice/statuses/1> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Note> .
+<https://mastodon.example/users/alice/statuses/1> <https://www.w3.org/ns/activitystreams#attributedTo> <https://mastodon.example/users/alice> .
+<https://mastodon.example/users/alice/statuses/1> <https://www.w3.org/ns/activitystreams#content> \"<p>Hello #rust</p>\" .
+<https://mastodon.example/users/alice/statuses/1> <https://www.w3.org/ns/activitystreams#content> \"<p>Hello #rust</p>\"@en .
+<https://mastodon.example/users/alice/statuses/1> <https://www.w3.org/ns/activitystreams#published> \"2023-03-14T09:26:53Z\"^^<http://www.w3.org/2001/XMLSchema#dateTime> .
+<https://mastodon.example/users/alice/statuses/1> <https://www.w3.org/ns/activitystreams#sensitive> \"false\"^^<http://www.w3.org/2001/XMLSchema#boolean> .
+<https://mastodon.example/users/alice/statuses/1> <https://www.w3.org/ns/activitystreams#tag> _:c14n0 .
+_:c14n0 <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Hashtag> .
+_:c14n0 <https://www.w3.org/ns/activitystreams#href> <https://mastodon.example/tags/rust> .
+_:c14n0 <https://www.w3.org/ns/activitystreams#name> \"#rust\" .
+";
+
+    #[test]
+    fn mastodon_creates_are_canonicalized_as_expected() {
+        let mut doc: Value = serde_json::from_str(MASTODON_CREATE).unwrap();
+        doc.as_object_mut().unwrap().remove("signature");
+
+        assert_eq!(canonicalize(&doc).unwrap(), MASTODON_CREATE_NQUADS);
+    }
+
+    fn tamper(fixture: &str, pointer: &str, value: Value) -> Value {
+        let mut doc: Value = serde_json::from_str(fixture).unwrap();
+        *doc.pointer_mut(pointer).unwrap() = value;
+
+        doc
+    }
+
+    #[test_case(serde_json::from_str(MASTODON_DELETE).unwrap(), Ok(()); "delete")]
+    #[test_case(serde_json::from_str(MASTODON_CREATE).unwrap(), Ok(()); "create")]
+    #[test_case(tamper(MASTODON_CREATE, "/object/content", json!("<p>Goodbye</p>")), Err(INVALID_SIG); "edited content")]
+    #[test_case(tamper(MASTODON_CREATE, "/cc/0", json!("https://evil.example/users/mallory")), Err(INVALID_SIG); "edited addressing")]
+    #[test_case(tamper(MASTODON_CREATE, "/signature/created", json!("2024-01-01T00:00:00Z")), Err(INVALID_SIG); "edited signature options")]
+    #[test_case(tamper(MASTODON_DELETE, "/object", json!("https://mastodon.example/users/bob")), Err(INVALID_SIG); "edited delete")]
+    #[test]
+    fn mastodon_signed_activities_are_verified(doc: Value, expected: Result<()>) {
+        let (_, pub_key) = keys();
+
+        assert_eq!(
+            creator(&doc),
+            Some("https://mastodon.example/users/alice#main-key")
+        );
+        assert_eq!(verify(&doc, &pub_key), expected);
+    }
+
+    fn public_key(id: &str, owner: &str) -> Value {
+        json!({ "id": id, "owner": owner, "publicKeyPem": TEST_PUB_KEY })
+    }
+
+    #[test_case(json!({ "id": "https://a.example/users/alice", "publicKey": public_key(KEY_ID, "https://a.example/users/alice") }), Some("https://a.example/users/alice"); "main key")]
+    #[test_case(json!({ "id": "https://a.example/users/alice", "publicKey": [public_key("https://a.example/users/alice#other-key", "https://a.example/users/alice"), public_key(KEY_ID, "https://a.example/users/alice")] }), Some("https://a.example/users/alice"); "one of several keys")]
+    #[test_case(json!({ "id": "https://a.example/users/alice", "publicKey": public_key("https://a.example/users/alice#other-key", "https://a.example/users/alice") }), None; "different key")]
+    #[test_case(json!({ "id": "https://a.example/users/alice", "publicKey": public_key(KEY_ID, "https://a.example/users/bob") }), None; "embedded key owned by someone else")]
+    #[test_case(public_key(KEY_ID, "https://a.example/users/bob"), Some("https://a.example/users/bob"); "key document")]
+    #[test_case(json!({ "id": KEY_ID, "publicKeyPem": TEST_PUB_KEY }), None; "key document without an owner")]
+    #[test]
+    fn keys_are_found_by_id(doc: Value, expected_owner: Option<&str>) {
+        let res = find_key(&doc, KEY_ID);
+
+        assert_eq!(res.ok().map(|(owner, _)| owner).as_deref(), expected_owner);
+    }
+
+    #[tokio::test]
+    async fn documents_with_unknown_contexts_are_unverifiable() {
+        let (signing_key, pub_key) = keys();
+        let mut doc = delete();
+        sign(&mut doc, KEY_ID, &signing_key).await.unwrap();
+        doc["@context"] = json!([
+            "https://www.w3.org/ns/activitystreams",
+            "https://unknown.example/context"
+        ]);
+
+        assert_eq!(verify(&doc, &pub_key), Err(UNVERIFIABLE_SIG));
+    }
+
+    #[test]
+    fn unsigned_documents_have_no_creator() {
+        assert_eq!(creator(&delete()), None);
+    }
+}
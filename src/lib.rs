@@ -1,9 +1,68 @@
+pub mod about;
+pub mod activities;
+pub mod actors;
+pub mod auth;
+pub mod blocklist;
+pub mod check;
 pub mod client;
+pub mod commands;
 pub mod config;
+pub mod delivery;
 pub mod error;
+pub mod flood;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod history;
+pub mod images;
+pub mod import;
+pub mod ingest;
+pub mod integrity;
+pub mod interop;
+pub mod invites;
+pub mod jsonld;
+pub mod ldsig;
+pub mod logging;
+pub mod mailer;
+pub mod messages;
+pub mod metrics;
+pub mod migrations;
+pub mod notifications;
+pub mod notifiers;
+pub mod objects;
+pub mod pipeline;
+pub mod policy;
+pub mod probe;
+pub mod reactions;
 pub mod routes;
+pub mod selftest;
 pub mod signature;
+pub mod signer;
+pub mod singleflight;
 pub mod state;
+pub mod stats;
+pub mod storage;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tasks;
+pub mod timeseries;
+pub mod unrecognized;
+pub mod upstreams;
 pub mod util;
+pub mod visibility;
 
 pub use error::{Error, Result};
+
+// Stand in for platforms without systemd: there are never any listeners passed to us or
+// a socket to notify, just as when running on unix outside of systemd.
+#[cfg(not(unix))]
+pub mod systemd {
+    use std::net::TcpListener;
+
+    pub fn listeners() -> Vec<TcpListener> {
+        Vec::new()
+    }
+
+    pub fn notify(_: &str) {}
+
+    pub async fn run_watchdog() {}
+}
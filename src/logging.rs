@@ -0,0 +1,374 @@
+//! Log output, and changing what gets logged while the relay is running.
+//!
+//! The filter directives set by `logging.level` (or `RUST_LOG`) can be replaced via the
+//! admin API, e.g. to log `actiserve::signature=trace` while debugging federation with
+//! a peer, without a restart losing queued deliveries.
+//!
+//! Busy relays log a line for every activity they relay, so modules can also be
+//! configured to only have a sample of their log lines written out, with a periodic
+//! summary of how many were dropped.
+use crate::{
+    config::{LogFormat, LogLevel, LogRotation, LogSamplingConfig, LoggingConfig},
+    Error, Result,
+};
+use axum::http::StatusCode;
+use std::{
+    env, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::{info, subscriber, subscriber::Interest, Level, Metadata};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt,
+    layer::{Context, Filter},
+    prelude::*,
+    reload, EnvFilter, Registry,
+};
+
+/// Install the global tracing subscriber. Buffered log lines are flushed when the
+/// returned guard is dropped so it needs to live until we exit.
+///
+/// # Panics
+///
+/// This function will panic if a global subscriber has already been installed.
+pub fn init_tracing(cfg: &LoggingConfig) -> (WorkerGuard, LogFilter) {
+    let filter = match &cfg.level {
+        Some(level) if env::var_os(EnvFilter::DEFAULT_ENV).is_none() => EnvFilter::new(level),
+        _ => EnvFilter::from_default_env(),
+    };
+
+    let (writer, guard) = match &cfg.file {
+        Some(file) => {
+            let rotation = match file.rotation {
+                LogRotation::Minutely => Rotation::MINUTELY,
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            let appender = RollingFileAppender::new(rotation, &file.directory, &file.prefix);
+            tracing_appender::non_blocking(appender)
+        }
+
+        None => tracing_appender::non_blocking(io::stdout()),
+    };
+
+    let (filter, handle) = reload::Layer::new(filter);
+    let sampler = Arc::new(Sampler::new(&cfg.sampling));
+    let sampling = Sampling(sampler.clone());
+    let registry = Registry::default().with(filter);
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(cfg.file.is_none());
+
+    let res = match cfg.format {
+        LogFormat::Json => subscriber::set_global_default(
+            registry.with(layer.json().flatten_event(true).with_filter(sampling)),
+        ),
+        LogFormat::Pretty => {
+            subscriber::set_global_default(registry.with(layer.pretty().with_filter(sampling)))
+        }
+        LogFormat::Compact => {
+            subscriber::set_global_default(registry.with(layer.compact().with_filter(sampling)))
+        }
+    };
+    res.expect("this to be the only global subscriber");
+
+    let mut log_filter = LogFilter::new(handle);
+    log_filter.sampler = sampler;
+
+    (guard, log_filter)
+}
+
+/// A handle for replacing the filter of the global tracing subscriber.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    // Not set when running without the subscriber installed by [init_tracing], e.g. in
+    // tests
+    handle: Option<reload::Handle<EnvFilter, Registry>>,
+    sampler: Arc<Sampler>,
+}
+
+impl LogFilter {
+    pub fn new(handle: reload::Handle<EnvFilter, Registry>) -> Self {
+        Self {
+            handle: Some(handle),
+            sampler: Default::default(),
+        }
+    }
+
+    /// Whether any modules have their log lines sampled.
+    pub fn is_sampling(&self) -> bool {
+        !self.sampler.rules.is_empty()
+    }
+
+    /// Log how many log lines each sampling rule has dropped since the last summary.
+    pub fn summarize_sampling(&self) {
+        self.sampler.summarize()
+    }
+
+    /// The filter directives currently in use.
+    pub fn current(&self) -> Result<String> {
+        self.handle()?
+            .with_current(|filter| filter.to_string())
+            .map_err(|_| UNAVAILABLE)
+    }
+
+    /// Replace the filter directives in use, returning the new directives.
+    pub fn set(&self, directives: &str) -> Result<String> {
+        let handle = self.handle()?;
+        let filter = EnvFilter::try_new(directives).map_err(|_| Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "invalid log filter",
+        })?;
+
+        handle.reload(filter).map_err(|_| UNAVAILABLE)?;
+        let current = self.current()?;
+        info!(filter=%current, "log filter updated");
+
+        Ok(current)
+    }
+
+    fn handle(&self) -> Result<&reload::Handle<EnvFilter, Registry>> {
+        self.handle.as_ref().ok_or(UNAVAILABLE)
+    }
+}
+
+const UNAVAILABLE: Error = Error::StatusAndMessage {
+    status: StatusCode::NOT_IMPLEMENTED,
+    message: "the log filter can not be changed",
+};
+
+/// Writes out one in every N log lines from the modules covered by its rules.
+#[derive(Debug, Default)]
+struct Sampler {
+    rules: Vec<SamplingRule>,
+}
+
+#[derive(Debug)]
+struct SamplingRule {
+    target: String,
+    level: Level,
+    one_in: u64,
+    seen: AtomicU64,
+    written: AtomicU64,
+}
+
+impl SamplingRule {
+    fn covers(&self, meta: &Metadata<'_>) -> bool {
+        let in_module = match meta.target().strip_prefix(self.target.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with("::"),
+            None => false,
+        };
+
+        // More verbose levels compare as greater
+        in_module && *meta.level() >= self.level
+    }
+}
+
+impl Sampler {
+    fn new(cfg: &LogSamplingConfig) -> Self {
+        let rules = cfg
+            .rules
+            .iter()
+            .map(|rule| SamplingRule {
+                target: rule.target.clone(),
+                level: match rule.level {
+                    LogLevel::Error => Level::ERROR,
+                    LogLevel::Warn => Level::WARN,
+                    LogLevel::Info => Level::INFO,
+                    LogLevel::Debug => Level::DEBUG,
+                    LogLevel::Trace => Level::TRACE,
+                },
+                one_in: rule.one_in.max(1),
+                seen: AtomicU64::new(0),
+                written: AtomicU64::new(0),
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    // Spans are never sampled as events logged within them need them to exist, and
+    // neither are our own summaries
+    fn rule(&self, meta: &Metadata<'_>) -> Option<&SamplingRule> {
+        if !meta.is_event() || meta.target() == module_path!() {
+            return None;
+        }
+
+        self.rules.iter().find(|rule| rule.covers(meta))
+    }
+
+    fn sample(&self, meta: &Metadata<'_>) -> bool {
+        let rule = match self.rule(meta) {
+            Some(rule) => rule,
+            None => return true,
+        };
+
+        let keep = rule.seen.fetch_add(1, Ordering::Relaxed) % rule.one_in == 0;
+        if keep {
+            rule.written.fetch_add(1, Ordering::Relaxed);
+        }
+
+        keep
+    }
+
+    fn summarize(&self) {
+        for rule in self.rules.iter() {
+            let seen = rule.seen.swap(0, Ordering::Relaxed);
+            let written = rule.written.swap(0, Ordering::Relaxed);
+
+            if seen > written {
+                info!(
+                    target_module = %rule.target,
+                    seen,
+                    written,
+                    dropped = seen - written,
+                    "sampled log lines"
+                );
+            }
+        }
+    }
+}
+
+// The filter applied to our log output, sharing its sampler with the [LogFilter] so
+// that summaries can be logged
+struct Sampling(Arc<Sampler>);
+
+impl<S> Filter<S> for Sampling {
+    fn enabled(&self, meta: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        self.0.sample(meta)
+    }
+
+    // Callsites that can be sampled need checking every time they are hit, but anything
+    // else can be cached as always being written out
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.0.rule(meta).is_some() {
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test]
+    fn filters_can_be_replaced() {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        // The handle only works for as long as the layer is alive
+        let _subscriber = Registry::default().with(layer);
+        let log_filter = LogFilter::new(handle);
+
+        assert_eq!(log_filter.current().unwrap(), "info");
+
+        // Directives are reordered by how specific they are
+        let updated = log_filter.set("warn,actiserve::signature=trace").unwrap();
+        let mut directives: Vec<&str> = updated.split(',').collect();
+        directives.sort();
+
+        assert_eq!(directives, vec!["actiserve::signature=trace", "warn"]);
+        assert_eq!(log_filter.current().unwrap(), updated);
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = Registry::default().with(layer);
+        let log_filter = LogFilter::new(handle);
+
+        assert!(log_filter.set("actiserve=loud").is_err());
+        assert_eq!(log_filter.current().unwrap(), "info");
+    }
+
+    #[test]
+    fn filters_can_not_be_changed_without_a_subscriber() {
+        assert_eq!(LogFilter::default().set("info"), Err(UNAVAILABLE));
+    }
+
+    fn sampler(target: &str, level: LogLevel, one_in: u64) -> Sampler {
+        let cfg: LogSamplingConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{ "target": target, "level": level, "oneIn": one_in }],
+        }))
+        .unwrap();
+
+        Sampler::new(&cfg)
+    }
+
+    // Only the metadata of the events that are logged is needed, so these are captured
+    // rather than written out
+    fn metadata_of(f: impl FnOnce()) -> &'static Metadata<'static> {
+        use std::sync::Mutex;
+
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Option<&'static Metadata<'static>>>>);
+
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                *self.0.lock().unwrap() = Some(event.metadata());
+            }
+        }
+
+        let capture = Capture::default();
+        subscriber::with_default(Registry::default().with(capture.clone()), f);
+        let meta = capture.0.lock().unwrap().take();
+
+        meta.expect("an event to have been logged")
+    }
+
+    #[test]
+    fn one_in_n_matching_log_lines_are_written() {
+        let sampler = sampler(module_path!(), LogLevel::Info, 3);
+        let meta = metadata_of(|| info!("relaying post from actor"));
+
+        let written: Vec<bool> = (0..7).map(|_| sampler.sample(meta)).collect();
+
+        assert_eq!(written, vec![true, false, false, true, false, false, true]);
+    }
+
+    #[test_case("actiserve", LogLevel::Info, true; "parent module")]
+    #[test_case("actiserve::logging::tests", LogLevel::Info, true; "exact module")]
+    #[test_case("actiserve::log", LogLevel::Info, false; "module name prefix")]
+    #[test_case("actiserve::routes", LogLevel::Info, false; "other module")]
+    #[test_case("actiserve::logging", LogLevel::Debug, false; "more severe event")]
+    #[test]
+    fn rules_cover_their_module_and_level(target: &str, level: LogLevel, covered: bool) {
+        let sampler = sampler(target, level, 10);
+        let meta = metadata_of(|| info!("hello"));
+
+        assert_eq!(sampler.rule(meta).is_some(), covered);
+    }
+
+    #[test]
+    fn warnings_and_errors_are_written_unless_configured() {
+        let sampler = sampler("actiserve", LogLevel::Info, 10);
+        let meta = metadata_of(|| tracing::warn!("uh oh"));
+
+        assert!((0..5).all(|_| sampler.sample(meta)));
+    }
+
+    #[test]
+    fn summaries_reset_the_counts() {
+        let sampler = sampler("actiserve", LogLevel::Info, 2);
+        let meta = metadata_of(|| info!("hello"));
+        for _ in 0..5 {
+            sampler.sample(meta);
+        }
+
+        assert_eq!(sampler.rules[0].seen.load(Ordering::Relaxed), 5);
+        assert_eq!(sampler.rules[0].written.load(Ordering::Relaxed), 3);
+
+        sampler.summarize();
+
+        assert_eq!(sampler.rules[0].seen.load(Ordering::Relaxed), 0);
+        assert_eq!(sampler.rules[0].written.load(Ordering::Relaxed), 0);
+    }
+}
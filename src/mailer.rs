@@ -0,0 +1,161 @@
+//! Emailing of operator notifications over SMTP.
+use crate::{
+    config::{SmtpConfig, SmtpTls},
+    notifications::Notification,
+    notifiers::Notifier,
+    Error, Result,
+};
+use axum::{async_trait, http::StatusCode};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+#[derive(Debug)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    /// Our host, for linking to the admin API
+    host: String,
+}
+
+impl Mailer {
+    pub fn new(cfg: &SmtpConfig, host: &str) -> Result<Self> {
+        let invalid = || Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "invalid smtp configuration",
+        };
+
+        let mut builder = match cfg.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&cfg.host)
+                .map_err(|_| invalid())?,
+            SmtpTls::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&cfg.host).map_err(|_| invalid())?
+            }
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&cfg.host),
+        };
+        if let Some(port) = cfg.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&cfg.username, &cfg.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let from = cfg.from.parse().map_err(|_| invalid())?;
+        let to = cfg
+            .to
+            .iter()
+            .map(|addr| addr.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        if to.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from,
+            to,
+            host: host.to_owned(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Mailer {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    /// Email the notification to each of the configured recipients.
+    async fn send(&self, notification: &Notification) -> std::result::Result<(), String> {
+        let (subject, body) = render(notification, &self.host);
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in self.to.iter() {
+            builder = builder.to(to.clone());
+        }
+
+        let message = builder.body(body).map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The subject and plain text body of the email for a notification.
+pub fn render(notification: &Notification, host: &str) -> (String, String) {
+    let title = notification.kind.title();
+    let subject = match &notification.instance {
+        Some(instance) => format!("[{host}] {title}: {instance}"),
+        None => format!("[{host}] {title}"),
+    };
+
+    let mut body = format!("{}\n\n", notification.message);
+    if let Some(instance) = &notification.instance {
+        body.push_str(&format!("Instance: {instance}\n"));
+    }
+    body.push_str(&format!(
+        "Time: {}\n\nRecent notifications are listed at https://{host}/api/v1/admin/notifications\n",
+        notification.created_at.to_rfc3339()
+    ));
+
+    (subject, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::NotificationKind;
+    use chrono::{TimeZone, Utc};
+
+    fn smtp_config(from: &str, to: &[&str]) -> SmtpConfig {
+        SmtpConfig {
+            host: "smtp.example.com".into(),
+            port: None,
+            tls: SmtpTls::StartTls,
+            username: None,
+            password: None,
+            from: from.into(),
+            to: to.iter().map(|s| s.to_string()).collect(),
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn notifications_are_rendered_as_plain_text() {
+        let notification = Notification {
+            kind: NotificationKind::InstanceThrottled,
+            instance: Some("spam.example".into()),
+            message: "spam.example has been throttled".into(),
+            created_at: Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap(),
+        };
+
+        let (subject, body) = render(&notification, "relay.example");
+
+        assert_eq!(subject, "[relay.example] Instance throttled: spam.example");
+        assert_eq!(
+            body,
+            "spam.example has been throttled\n\n\
+             Instance: spam.example\n\
+             Time: 2023-01-01T12:00:00+00:00\n\n\
+             Recent notifications are listed at https://relay.example/api/v1/admin/notifications\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_addresses_are_rejected() {
+        let valid = smtp_config("actiserve <relay@example.com>", &["admin@example.com"]);
+        let invalid_from = smtp_config("not an address", &["admin@example.com"]);
+        let no_recipients = smtp_config("relay@example.com", &[]);
+
+        assert!(Mailer::new(&valid, "relay.example").is_ok());
+        assert!(Mailer::new(&invalid_from, "relay.example").is_err());
+        assert!(Mailer::new(&no_recipients, "relay.example").is_err());
+    }
+}
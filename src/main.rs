@@ -1,36 +1,92 @@
-use axum::Server;
-use clap::Parser;
-use std::{net::SocketAddr, panic, path::PathBuf, sync::Arc};
-use tracing::{error, info, subscriber};
-use tracing_subscriber::EnvFilter;
+use axum::{Router, Server};
+use clap::{Parser, Subcommand};
+use futures::future::join_all;
+use socket2::{Domain, Socket, Type};
+use std::{
+    env, fs,
+    net::{SocketAddr, TcpListener},
+    panic,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{error, info};
+use uuid::Uuid;
 
 use actiserve::{
-    config::Config,
+    actors::actor_data_dir,
+    check::check_config,
+    config::{Config, SignerBackend},
+    delivery, ingest,
+    logging::{init_tracing, LogFilter},
+    migrations::{self, MigrationReport},
+    probe::probe,
     routes::build_routes,
+    signer::ActorKey,
     state::{Db, State},
+    systemd, tasks,
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to the YAML config file to use
+    /// Path to the YAML config file to use. A commented default config is written here
+    /// if there isn't one yet.
     #[arg(long, default_value = "config.yaml")]
     config_path: PathBuf,
+    /// Don't generate a private key for the relay actor when writing a default config
+    #[arg(long)]
+    no_generate_key: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the relay server (the default if no command is given)
+    Serve,
+    /// Run deliverability diagnostics against a remote instance
+    Probe {
+        /// The domain of the instance to probe
+        domain: String,
+    },
+    /// Validate the config file, keys and data dir without starting the server
+    CheckConfig,
+    /// Apply any pending data migrations without starting the server. Migrations are
+    /// also applied on startup.
+    Migrate {
+        /// List the migrations that would be applied without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let cfg = Config::load(args.config_path);
-
-    subscriber::set_global_default(
-        tracing_subscriber::fmt()
-            .json()
-            .flatten_event(true)
-            .with_env_filter(EnvFilter::from_default_env())
-            .finish(),
-    )
-    .expect("this to be the only global subscriber");
+    // Checking the config must not panic on the config being invalid, so this runs
+    // before we load it
+    if let Some(Command::CheckConfig) = args.command {
+        return run_check_config(&args.config_path).await;
+    }
+
+    let path = args.config_path;
+    let cfg = match Config::load_or_write_default(path.clone(), !args.no_generate_key) {
+        Some(cfg) => cfg,
+        None => {
+            eprintln!(
+                "wrote a default config to {}: edit it and run actiserve again",
+                path.display()
+            );
+            return;
+        }
+    };
+    // Buffered log lines are flushed when the guard is dropped so it needs to live
+    // until we exit
+    let (_guard, log_filter) = init_tracing(&cfg.logging);
 
     panic::set_hook(Box::new(|panic| {
         if let Some(location) = panic.location() {
@@ -45,13 +101,22 @@ async fn main() {
         }
     }));
 
-    run_server(cfg).await
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => run_server(cfg, log_filter).await,
+        Command::Probe { domain } => run_probe(cfg, &domain).await,
+        Command::Migrate { dry_run } => run_migrate(&cfg, dry_run),
+        Command::CheckConfig => unreachable!("handled before loading the config"),
+    }
 }
 
-async fn run_server(cfg: Config) {
-    info!(path = %cfg.private_key_path.display(), "loading private key");
-    let priv_key_pem =
-        std::fs::read_to_string(&cfg.private_key_path).expect("unable to read private key");
+fn load_state(cfg: Config) -> State {
+    match cfg.signer.backend {
+        SignerBackend::Pem => {
+            info!(path = %cfg.private_key_path.display(), "loading private key")
+        }
+        SignerBackend::Command => info!(command = ?cfg.signer.command, "using signing command"),
+    }
+    let key = ActorKey::load(&cfg).expect("unable to load the relay actor's key");
 
     info!(
         data_dir = %cfg.data_dir.display(),
@@ -59,18 +124,178 @@ async fn run_server(cfg: Config) {
     );
     let db = Db::new(cfg.data_dir.clone()).expect("unable to create database");
 
-    let addr: SocketAddr = cfg
-        .base_url()
-        .parse()
-        .expect("unable to parse address and port");
-    let port = cfg.port;
+    State::new_with_key(cfg, db, key).expect("unable to open relay actor databases")
+}
+
+// The probe only needs the main relay actor's key, so rather than opening the data dir
+// of what may well be a running relay (and generating keys for topic actors in it) it
+// gets an empty one of its own
+async fn run_probe(mut cfg: Config, domain: &str) {
+    let dir = env::temp_dir().join(format!("actiserve-probe-{}", Uuid::new_v4()));
+    cfg.data_dir = dir.clone();
+    cfg.actors.clear();
+
+    let report = probe(&load_state(cfg), domain).await;
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        error!(dir=%dir.display(), error=%e, "unable to remove probe data dir");
+    }
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).expect("report to serialize")
+    );
+    if !report.ok {
+        std::process::exit(1);
+    }
+}
+
+// Data dirs that don't exist yet are stamped with the latest schema version when they
+// are created so there is nothing to migrate
+fn run_migrate(cfg: &Config, dry_run: bool) {
+    let mut dirs = vec![cfg.data_dir.clone()];
+    dirs.extend(
+        cfg.actors
+            .iter()
+            .map(|actor| actor_data_dir(&cfg.data_dir, &actor.name)),
+    );
+
+    let reports: Result<Vec<MigrationReport>, _> = dirs
+        .iter()
+        .filter(|dir| dir.is_dir())
+        .map(|dir| {
+            if dry_run {
+                migrations::plan(dir)
+            } else {
+                migrations::migrate(dir)
+            }
+        })
+        .collect();
+
+    match reports {
+        Ok(reports) => println!(
+            "{}",
+            serde_json::to_string_pretty(&reports).expect("report to serialize")
+        ),
+        Err(e) => {
+            eprintln!("unable to migrate data dir: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_check_config(path: &Path) {
+    let report = check_config(path).await;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).expect("report to serialize")
+    );
+    if !report.ok {
+        std::process::exit(1);
+    }
+}
+
+// IPv6 sockets are bound as IPv6 only so that they can be used alongside a separate
+// IPv4 socket on the same port
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
 
-    let state: Arc<State> = Arc::new(State::new(cfg, db, &priv_key_pem));
-    let app = build_routes(state);
+    Ok(socket.into())
+}
 
-    info!(%port, "starting service");
-    Server::bind(&addr)
+async fn serve(listener: TcpListener, app: Router, mut stopping: watch::Receiver<bool>) {
+    let addr = listener.local_addr().expect("listener to have an address");
+
+    info!(%addr, "starting service");
+    Server::from_tcp(listener)
+        .expect("listener to be usable")
         .serve(app.into_make_service())
+        .with_graceful_shutdown(async move {
+            let _ = stopping.changed().await;
+        })
         .await
         .expect("server to start");
 }
+
+// Wait for Ctrl-C or the SIGTERM that systemd stops the service with
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("to be able to handle SIGTERM");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+async fn run_server(cfg: Config, log_filter: LogFilter) {
+    // Listeners passed to us via systemd socket activation take precedence over the
+    // addresses in our config
+    let mut listeners = systemd::listeners();
+    if listeners.is_empty() {
+        listeners = cfg
+            .listen_addrs()
+            .into_iter()
+            .map(|addr| bind(addr).unwrap_or_else(|e| panic!("unable to bind to {addr}: {e}")))
+            .collect();
+    }
+    if listeners.is_empty() {
+        panic!("at least one listen address is required");
+    }
+
+    let mut state = load_state(cfg);
+    state.log_filter = log_filter;
+    let state = Arc::new(state);
+    tokio::spawn(tasks::reverify_instances(state.clone()));
+    tokio::spawn(tasks::roll_up_metrics(state.clone()));
+    tokio::spawn(tasks::flush_stores(state.clone()));
+    if !state.cfg.blocklists.feeds.is_empty() {
+        tokio::spawn(tasks::refresh_blocklists(state.clone()));
+    }
+    // Also run when upstreams have been removed from the config so that they're unfollowed
+    if !state.cfg.upstreams.is_empty() || !state.db.upstreams().is_empty() {
+        tokio::spawn(tasks::follow_upstreams(state.clone()));
+    }
+    if state.log_filter.is_sampling() {
+        tokio::spawn(tasks::summarize_sampled_logs(state.clone()));
+    }
+    // Also needed when throttled Announces are dropped, to prune the counts of origins
+    if state.cfg.flood.enabled {
+        tokio::spawn(tasks::release_held_announces(state.clone()));
+    }
+    for _ in 0..state.cfg.delivery.workers {
+        tokio::spawn(delivery::run_worker(state.clone()));
+    }
+    for _ in 0..state.cfg.ingest.workers {
+        tokio::spawn(ingest::run_worker(state.clone()));
+    }
+    let app = build_routes(state.clone());
+
+    let (stop, stopping) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("shutting down");
+        systemd::notify("STOPPING=1");
+        let _ = stop.send(true);
+    });
+
+    tokio::spawn(systemd::run_watchdog());
+    systemd::notify("READY=1");
+    join_all(
+        listeners
+            .into_iter()
+            .map(|l| serve(l, app.clone(), stopping.clone())),
+    )
+    .await;
+
+    // Anything changed since the last periodic flush would otherwise be lost, leading
+    // to posts being relayed again after a restart
+    state.flush();
+    info!("stores flushed");
+}
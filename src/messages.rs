@@ -0,0 +1,187 @@
+//! Messages sent to the relay operator by mentioning one of the relay actors.
+//!
+//! Posts mentioning a relay actor are not relayed: instead they are kept so that they
+//! can be reviewed via the admin API and the operator is notified of them, giving the
+//! admins of other instances a way of contacting the relay from their own accounts.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// The number of messages kept for the admin API
+pub const MAX_MESSAGES: usize = 500;
+
+const MESSAGE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The length that message content is cut down to in operator notifications
+const MAX_SUMMARY_CHARS: usize = 500;
+
+/// A post mentioning one of our relay actors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub id: String,
+    /// The relay actor that was mentioned
+    pub recipient: String,
+    /// The actor that sent the message
+    pub actor: String,
+    /// The id of the mentioning post
+    pub object: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The content of the post, as HTML
+    pub content: String,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Counts the messages received from each instance over the last hour, so that no one
+/// instance can flood the operator with notifications.
+#[derive(Debug, Default)]
+pub struct MessageLimits {
+    // map of domain to the start of its current window and the messages received in it
+    counts: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl MessageLimits {
+    /// Count a message from the given domain, returning false if it has already sent
+    /// the maximum number of messages this window.
+    pub fn allow(&self, domain: &str, max_per_hour: u32, now: Instant) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        counts.retain(|_, (start, _)| now.saturating_duration_since(*start) < MESSAGE_WINDOW);
+
+        let (_, n) = counts.entry(domain.to_owned()).or_insert((now, 0));
+        if *n >= max_per_hour {
+            return false;
+        }
+
+        *n += 1;
+        true
+    }
+}
+
+impl Message {
+    /// The message carried by a Create, if its object mentions or is addressed to the
+    /// relay actor with the given id.
+    pub fn from_create(actor_id: &str, recipient: &str, activity: &Value) -> Option<Self> {
+        let object = &activity["object"];
+        if !mentions(object, recipient) {
+            return None;
+        }
+
+        Some(Self {
+            id: Uuid::new_v4().to_string(),
+            recipient: recipient.to_owned(),
+            actor: actor_id.to_owned(),
+            object: object["id"].as_str().unwrap_or_default().to_owned(),
+            url: object["url"].as_str().map(String::from),
+            content: object["content"].as_str().unwrap_or_default().to_owned(),
+            received_at: Utc::now(),
+        })
+    }
+
+    /// The content of the message as plain text, cut down to a length suitable for
+    /// sending on in a notification.
+    pub fn summary(&self) -> String {
+        let mut text = String::with_capacity(self.content.len());
+        let mut tag: Option<String> = None;
+        for c in self.content.chars() {
+            match (&mut tag, c) {
+                (None, '<') => tag = Some(String::new()),
+                (None, c) => text.push(c),
+                (Some(name), '>') => {
+                    // Paragraphs and line breaks separate words, other tags don't
+                    let name = name.trim_start_matches('/').trim_end_matches('/');
+                    if matches!(name.split_whitespace().next(), Some("p" | "br")) {
+                        text.push(' ');
+                    }
+                    tag = None;
+                }
+                (Some(name), c) => name.push(c),
+            }
+        }
+
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        match text.char_indices().nth(MAX_SUMMARY_CHARS) {
+            Some((i, _)) => format!("{}…", &text[..i]),
+            None => text,
+        }
+    }
+}
+
+fn mentions(object: &Value, recipient: &str) -> bool {
+    let tagged = object["tag"]
+        .as_array()
+        .map(|tags| {
+            tags.iter()
+                .any(|t| t["type"] == "Mention" && t["href"] == recipient)
+        })
+        .unwrap_or(false);
+
+    let addressed = ["to", "cc"].iter().any(|field| match &object[field] {
+        Value::String(s) => s == recipient,
+        Value::Array(values) => values.iter().any(|v| v == recipient),
+        _ => false,
+    });
+
+    tagged || addressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    const RELAY: &str = "https://relay.example/actor";
+
+    #[test_case(json!({ "tag": [{ "type": "Mention", "href": RELAY }] }), true; "mention")]
+    #[test_case(json!({ "to": [RELAY] }), true; "addressed")]
+    #[test_case(json!({ "cc": RELAY }), true; "addressed as a string")]
+    #[test_case(json!({ "tag": [{ "type": "Hashtag", "href": RELAY }] }), false; "hashtag")]
+    #[test_case(json!({ "tag": [{ "type": "Mention", "href": "https://a.example/users/b" }] }), false; "other mention")]
+    #[test_case(json!("https://a.example/notes/1"), false; "object not embedded")]
+    #[test]
+    fn messages_are_posts_mentioning_the_relay(object: Value, expected: bool) {
+        let activity = json!({ "type": "Create", "object": object });
+
+        let msg = Message::from_create("https://a.example/users/admin", RELAY, &activity);
+
+        assert_eq!(msg.is_some(), expected);
+    }
+
+    #[test]
+    fn messages_are_limited_per_domain_each_hour() {
+        let limits = MessageLimits::default();
+        let now = Instant::now();
+
+        assert!(limits.allow("a.example", 2, now));
+        assert!(limits.allow("a.example", 2, now));
+        assert!(!limits.allow("a.example", 2, now), "third message allowed");
+        assert!(limits.allow("b.example", 2, now), "other domain limited");
+
+        let later = now + MESSAGE_WINDOW;
+        assert!(limits.allow("a.example", 2, later), "window not reset");
+    }
+
+    #[test]
+    fn summaries_are_plain_text() {
+        let activity = json!({
+            "type": "Create",
+            "object": {
+                "id": "https://a.example/notes/1",
+                "to": [RELAY],
+                "content": "<p><span class=\"h-card\"><a href=\"https://relay.example/actor\">@<span>relay</span></a></span> can we join?</p><p>Thanks!</p>",
+            }
+        });
+
+        let msg = Message::from_create("https://a.example/users/admin", RELAY, &activity).unwrap();
+
+        assert_eq!(msg.object, "https://a.example/notes/1");
+        assert_eq!(msg.summary(), "@relay can we join? Thanks!");
+    }
+}
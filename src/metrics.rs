@@ -0,0 +1,188 @@
+//! Simple in-process metrics, exposed in the Prometheus text format
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+type Labels = Vec<(&'static str, String)>;
+type Series = BTreeMap<&'static str, BTreeMap<Labels, u64>>;
+
+/// The most series kept for a single metric. Past this, new series have their
+/// `instance` label replaced with [OTHER_INSTANCE] so that a stream of previously unseen
+/// instances can't grow the registry without bound.
+pub const MAX_SERIES: usize = 5_000;
+
+/// The `instance` label of series beyond [MAX_SERIES]
+pub const OTHER_INSTANCE: &str = "other";
+
+/// A registry of labelled counters and gauges.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Series>,
+    gauges: Mutex<Series>,
+}
+
+impl Metrics {
+    pub fn incr(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.incr_by(name, labels, 1);
+    }
+
+    pub fn incr_by(&self, name: &'static str, labels: &[(&'static str, &str)], n: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        let series = counters.entry(name).or_default();
+        let labels = bounded_labels(series, labels);
+
+        *series.entry(labels).or_default() += n;
+    }
+
+    /// The current value of a counter (zero if it has never been incremented)
+    pub fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+        current(&self.counters, name, labels)
+    }
+
+    /// The totals of a counter across all of its series, grouped by the value of one of
+    /// its labels. Series without that label are ignored.
+    pub fn sum_by(&self, name: &'static str, label: &str) -> BTreeMap<String, u64> {
+        let mut totals = BTreeMap::new();
+        if let Some(series) = self.counters.lock().unwrap().get(name) {
+            for (labels, n) in series.iter() {
+                if let Some((_, value)) = labels.iter().find(|(k, _)| *k == label) {
+                    *totals.entry(value.clone()).or_default() += n;
+                }
+            }
+        }
+
+        totals
+    }
+
+    /// Set the current value of a gauge
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        let series = gauges.entry(name).or_default();
+        let labels = bounded_labels(series, labels);
+
+        series.insert(labels, value);
+    }
+
+    /// The current value of a gauge (zero if it has never been set)
+    pub fn gauge(&self, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+        current(&self.gauges, name, labels)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    // https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        render_series(&mut s, "counter", &self.counters.lock().unwrap());
+        render_series(&mut s, "gauge", &self.gauges.lock().unwrap());
+
+        s
+    }
+}
+
+fn bounded_labels(series: &BTreeMap<Labels, u64>, labels: &[(&'static str, &str)]) -> Labels {
+    let mut labels: Labels = labels.iter().map(|&(k, v)| (k, v.to_owned())).collect();
+    if series.len() >= MAX_SERIES && !series.contains_key(&labels) {
+        for (_, v) in labels.iter_mut().filter(|(k, _)| *k == "instance") {
+            *v = OTHER_INSTANCE.to_owned();
+        }
+    }
+
+    labels
+}
+
+fn current(series: &Mutex<Series>, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+    let labels: Labels = labels.iter().map(|&(k, v)| (k, v.to_owned())).collect();
+
+    series
+        .lock()
+        .unwrap()
+        .get(name)
+        .and_then(|series| series.get(&labels))
+        .copied()
+        .unwrap_or_default()
+}
+
+fn render_series(s: &mut String, ty: &str, all: &Series) {
+    for (name, series) in all.iter() {
+        let _ = writeln!(s, "# TYPE {name} {ty}");
+        for (labels, value) in series.iter() {
+            let _ = writeln!(s, "{name}{} {value}", render_labels(labels));
+        }
+    }
+}
+
+fn render_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{k}=\"{v}\"")
+        })
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_tracked_per_label_set() {
+        let m = Metrics::default();
+
+        m.incr("shed_total", &[("instance", "a.example")]);
+        m.incr("shed_total", &[("instance", "a.example")]);
+        m.incr_by("shed_total", &[("instance", "b.example")], 5);
+
+        assert_eq!(m.counter("shed_total", &[("instance", "a.example")]), 2);
+        assert_eq!(m.counter("shed_total", &[("instance", "b.example")]), 5);
+        assert_eq!(m.counter("shed_total", &[("instance", "c.example")]), 0);
+    }
+
+    #[test]
+    fn instances_beyond_the_series_limit_are_grouped() {
+        let m = Metrics::default();
+        for n in 0..MAX_SERIES {
+            m.incr("shed_total", &[("instance", &format!("{n}.example"))]);
+        }
+
+        m.incr("shed_total", &[("instance", "0.example")]);
+        m.incr("shed_total", &[("instance", "new.example")]);
+        m.incr("shed_total", &[("instance", "newer.example")]);
+
+        assert_eq!(m.counter("shed_total", &[("instance", "0.example")]), 2);
+        assert_eq!(m.counter("shed_total", &[("instance", "new.example")]), 0);
+        assert_eq!(m.counter("shed_total", &[("instance", OTHER_INSTANCE)]), 2);
+    }
+
+    #[test]
+    fn render_works() {
+        let m = Metrics::default();
+
+        m.incr("b_total", &[]);
+        m.set("depth", &[], 3);
+        m.set("depth", &[], 2);
+        m.incr(
+            "a_total",
+            &[("reason", "quote\"d"), ("instance", "a.example")],
+        );
+
+        let expected = "\
+# TYPE a_total counter
+a_total{reason=\"quote\\\"d\",instance=\"a.example\"} 1
+# TYPE b_total counter
+b_total 1
+# TYPE depth gauge
+depth 2
+";
+
+        assert_eq!(m.render(), expected);
+    }
+}
@@ -0,0 +1,429 @@
+//! Versioned migrations of the data kept in a data dir.
+//!
+//! Each data dir records the version of the layout its JSON stores were written with in
+//! `schema.json`. When a stored structure changes in a way that existing data can't
+//! simply be deserialized into (for example replacing a map of host to inbox with full
+//! instance records), a [Migration] rewriting the affected files is added to
+//! [MIGRATIONS] rather than leaving operators to edit them by hand.
+//!
+//! Pending migrations are run in order whenever a [Db](crate::state::Db) is opened, and
+//! can be run ahead of time (or previewed) using the `migrate` command. Data dirs that
+//! were written by a newer version of actiserve are refused rather than risk older
+//! code misreading them.
+use crate::{blocklist::Severity, ingest::INGEST_DIR, Error, Result};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+/// The file within a data dir recording its schema version
+pub const SCHEMA_FILE: &str = "schema.json";
+
+/// The migrations that have been defined, ordered by version.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "record the schema version of data dirs created before it was tracked",
+        apply: |_| Ok(()),
+    },
+    Migration {
+        version: 2,
+        description: "store queued inbox activities individually rather than in ingest.json",
+        apply: split_ingest_queue,
+    },
+    Migration {
+        version: 3,
+        description: "record the severity of each domain block in domainblocks.json",
+        apply: add_domain_block_severities,
+    },
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Schema {
+    version: u32,
+}
+
+/// A single change to the layout of a data dir.
+#[derive(Serialize)]
+pub struct Migration {
+    /// The schema version that the data dir is at once this migration has been applied
+    pub version: u32,
+    pub description: &'static str,
+    /// Rewrite the stores in the given data dir
+    #[serde(skip)]
+    apply: fn(&Path) -> Result<()>,
+}
+
+impl fmt::Debug for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migration")
+            .field("version", &self.version)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+/// The migrations applied (or that would be applied) to a data dir.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationReport {
+    pub dir: PathBuf,
+    pub from_version: u32,
+    pub to_version: u32,
+    pub migrations: Vec<&'static Migration>,
+}
+
+/// The schema version that data dirs are migrated to.
+pub fn latest_version() -> u32 {
+    latest(MIGRATIONS)
+}
+
+/// The schema version of the data in the given dir. Data dirs that predate schema
+/// versions are at version 0.
+pub fn schema_version(dir: &Path) -> Result<u32> {
+    let bytes = match fs::read(dir.join(SCHEMA_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(_) => {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "unable to read data dir schema version",
+            })
+        }
+    };
+
+    serde_json::from_slice::<Schema>(&bytes)
+        .map(|schema| schema.version)
+        .map_err(|_| Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "unable to read data dir schema version",
+        })
+}
+
+/// Record that a newly created data dir is already at the latest schema version, so
+/// that it isn't mistaken for one that predates schema versions.
+pub fn initialize(dir: &Path) -> Result<()> {
+    write_schema_version(dir, latest_version())
+}
+
+/// The migrations that would be applied to the given data dir, without applying them.
+pub fn plan(dir: &Path) -> Result<MigrationReport> {
+    plan_with(dir, MIGRATIONS)
+}
+
+/// Apply any pending migrations to the given data dir. The schema version is updated
+/// after each migration so that a failure part way through can be resumed.
+pub fn migrate(dir: &Path) -> Result<MigrationReport> {
+    migrate_with(dir, MIGRATIONS)
+}
+
+fn migration_error() -> Error {
+    Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "unable to migrate data dir",
+    }
+}
+
+// Activities that were waiting to be processed are moved to one file per activity
+fn split_ingest_queue(dir: &Path) -> Result<()> {
+    let path = dir.join("ingest.json");
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(_) => return Err(migration_error()),
+    };
+    let pending: BTreeMap<String, Value> =
+        serde_json::from_slice(&bytes).map_err(|_| migration_error())?;
+
+    let ingest_dir = dir.join(INGEST_DIR);
+    fs::create_dir_all(&ingest_dir).map_err(|_| migration_error())?;
+    for (id, ingested) in pending {
+        let bytes = serde_json::to_vec(&ingested).map_err(|_| migration_error())?;
+        fs::write(ingest_dir.join(format!("{id}.json")), bytes).map_err(|_| migration_error())?;
+    }
+
+    fs::remove_file(path).map_err(|_| migration_error())
+}
+
+// Domain blocks were a list of domains, all of which rejected everything from the
+// instance: they become a map of domain to severity
+fn add_domain_block_severities(dir: &Path) -> Result<()> {
+    let path = dir.join("domainblocks.json");
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(_) => return Err(migration_error()),
+    };
+    let domains: Vec<String> = match serde_json::from_slice(&bytes) {
+        Ok(domains) => domains,
+        // Already a map of domain to severity
+        Err(_) if serde_json::from_slice::<BTreeMap<String, Severity>>(&bytes).is_ok() => {
+            return Ok(())
+        }
+        Err(_) => return Err(migration_error()),
+    };
+
+    let blocks: BTreeMap<String, Severity> = domains
+        .into_iter()
+        .map(|domain| (domain, Severity::Reject))
+        .collect();
+    let bytes = serde_json::to_vec(&blocks).map_err(|_| migration_error())?;
+
+    fs::write(path, bytes).map_err(|_| migration_error())
+}
+
+fn latest(migrations: &[Migration]) -> u32 {
+    migrations.last().map(|m| m.version).unwrap_or_default()
+}
+
+fn plan_with(dir: &Path, migrations: &'static [Migration]) -> Result<MigrationReport> {
+    let from_version = schema_version(dir)?;
+    let to_version = latest(migrations);
+    if from_version > to_version {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "data dir was written by a newer version of actiserve",
+        });
+    }
+
+    Ok(MigrationReport {
+        dir: dir.to_owned(),
+        from_version,
+        to_version,
+        migrations: migrations
+            .iter()
+            .filter(|m| m.version > from_version)
+            .collect(),
+    })
+}
+
+fn migrate_with(dir: &Path, migrations: &'static [Migration]) -> Result<MigrationReport> {
+    let report = plan_with(dir, migrations)?;
+
+    for migration in &report.migrations {
+        info!(
+            dir = %dir.display(),
+            version = migration.version,
+            description = migration.description,
+            "applying data migration"
+        );
+        (migration.apply)(dir)?;
+        write_schema_version(dir, migration.version)?;
+    }
+
+    Ok(report)
+}
+
+// Written to a temporary file first so that the version is never left half written
+fn write_schema_version(dir: &Path, version: u32) -> Result<()> {
+    let err = || Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "unable to write data dir schema version",
+    };
+
+    let bytes = serde_json::to_vec(&Schema { version }).map_err(|_| err())?;
+    let tmp = dir.join(format!("{SCHEMA_FILE}.tmp"));
+    fs::write(&tmp, bytes).map_err(|_| err())?;
+
+    fs::rename(&tmp, dir.join(SCHEMA_FILE)).map_err(|_| err())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all};
+    use uuid::Uuid;
+
+    // Replaces the map of host to inbox with a list of subscriber records
+    fn inboxes_to_subscribers(dir: &Path) -> Result<()> {
+        let path = dir.join("statedb.json");
+        let inboxes: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let subscribers: Vec<Value> = inboxes
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(host, inbox)| json!({ "host": host, "inbox": inbox }))
+            .collect();
+
+        fs::write(path, serde_json::to_vec(&subscribers).unwrap()).unwrap();
+        Ok(())
+    }
+
+    fn fail(_: &Path) -> Result<()> {
+        Err(Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "migration failed",
+        })
+    }
+
+    static TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "baseline",
+            apply: |_| Ok(()),
+        },
+        Migration {
+            version: 2,
+            description: "inboxes to subscribers",
+            apply: inboxes_to_subscribers,
+        },
+    ];
+
+    static FAILING_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "baseline",
+            apply: |_| Ok(()),
+        },
+        Migration {
+            version: 2,
+            description: "broken",
+            apply: fail,
+        },
+    ];
+
+    fn data_dir() -> PathBuf {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn migrations_are_ordered_by_version() {
+        let versions: Vec<u32> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<u32> = (1..=latest_version()).collect();
+
+        assert_eq!(versions, expected);
+    }
+
+    #[test]
+    fn pending_migrations_are_applied_once() {
+        let dir = data_dir();
+        fs::write(
+            dir.join("statedb.json"),
+            r#"{"example.com":"https://example.com/inbox"}"#,
+        )
+        .unwrap();
+
+        let report = migrate_with(&dir, TEST_MIGRATIONS).unwrap();
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.to_version, 2);
+        assert_eq!(report.migrations.len(), 2);
+        assert_eq!(schema_version(&dir).unwrap(), 2);
+
+        let report = migrate_with(&dir, TEST_MIGRATIONS).unwrap();
+        assert!(report.migrations.is_empty());
+
+        let migrated: Value =
+            serde_json::from_slice(&fs::read(dir.join("statedb.json")).unwrap()).unwrap();
+        assert_eq!(
+            migrated,
+            json!([{ "host": "example.com", "inbox": "https://example.com/inbox" }])
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn planning_does_not_apply_migrations() {
+        let dir = data_dir();
+
+        let report = plan_with(&dir, TEST_MIGRATIONS).unwrap();
+
+        assert_eq!(report.migrations.len(), 2);
+        assert_eq!(schema_version(&dir).unwrap(), 0);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn failed_migrations_are_resumed_from_the_last_success() {
+        let dir = data_dir();
+
+        assert!(migrate_with(&dir, FAILING_MIGRATIONS).is_err());
+        assert_eq!(schema_version(&dir).unwrap(), 1);
+
+        let report = plan_with(&dir, FAILING_MIGRATIONS).unwrap();
+        let versions: Vec<u32> = report.migrations.iter().map(|m| m.version).collect();
+        assert_eq!(versions, vec![2]);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn queued_activities_are_split_into_their_own_files() {
+        let dir = data_dir();
+        let ingested = json!({ "id": "a", "body": "{}" });
+        fs::write(
+            dir.join("ingest.json"),
+            serde_json::to_vec(&json!({ "a": ingested })).unwrap(),
+        )
+        .unwrap();
+
+        split_ingest_queue(&dir).unwrap();
+
+        let stored: Value =
+            serde_json::from_slice(&fs::read(dir.join(INGEST_DIR).join("a.json")).unwrap())
+                .unwrap();
+        assert_eq!(stored, ingested);
+        assert!(!dir.join("ingest.json").exists());
+        assert!(split_ingest_queue(&dir).is_ok());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(json!(["a.example", "b.example"]); "list of domains")]
+    #[test_case(json!({ "a.example": "reject", "b.example": "reject" }); "already migrated")]
+    #[test]
+    fn domain_blocks_are_given_severities(stored: Value) {
+        let dir = data_dir();
+        let path = dir.join("domainblocks.json");
+        fs::write(&path, serde_json::to_vec(&stored).unwrap()).unwrap();
+
+        add_domain_block_severities(&dir).unwrap();
+
+        let migrated: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            migrated,
+            json!({ "a.example": "reject", "b.example": "reject" })
+        );
+        assert!(add_domain_block_severities(&dir).is_ok());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn new_data_dirs_are_at_the_latest_version() {
+        let dir = data_dir();
+
+        initialize(&dir).unwrap();
+
+        assert_eq!(schema_version(&dir).unwrap(), latest_version());
+        assert!(plan(&dir).unwrap().migrations.is_empty());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn unreadable_schema_versions_are_an_error() {
+        let dir = data_dir();
+        fs::create_dir_all(dir.join(SCHEMA_FILE)).unwrap();
+
+        assert!(schema_version(&dir).is_err());
+        assert!(migrate(&dir).is_err());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn data_dirs_from_newer_versions_are_refused() {
+        let dir = data_dir();
+        write_schema_version(&dir, latest_version() + 1).unwrap();
+
+        assert!(migrate(&dir).is_err());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
@@ -0,0 +1,128 @@
+//! Notifications for the relay operator about events needing their attention.
+//!
+//! Notifications are logged and the most recent are kept in memory so that they can be
+//! reviewed via the admin API. They can also be sent on to the operator by email, to a
+//! Matrix room or to a Discord channel, each of which can be limited to particular kinds
+//! of notification.
+use crate::{
+    config::NotificationsConfig,
+    mailer::Mailer,
+    notifiers::{Discord, Matrix, Notifier},
+    Result,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::runtime::Handle;
+use tracing::warn;
+
+/// The number of notifications kept for the admin API
+const MAX_NOTIFICATIONS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NotificationKind {
+    /// An instance's activity spiked and it is being throttled
+    InstanceThrottled,
+    /// A post mentioning one of the relay actors was received
+    Message,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InstanceThrottled => "instanceThrottled",
+            Self::Message => "message",
+        }
+    }
+
+    /// A short human readable summary of the event.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::InstanceThrottled => "Instance throttled",
+            Self::Message => "Message for the relay",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub kind: NotificationKind,
+    /// The instance the notification is about, if any
+    pub instance: Option<String>,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct Notifications {
+    recent: Mutex<VecDeque<Notification>>,
+    /// Where notifications are sent on to, along with the kinds sent (all if empty)
+    channels: Vec<(Arc<dyn Notifier>, Vec<NotificationKind>)>,
+}
+
+impl Notifications {
+    pub fn new(cfg: &NotificationsConfig, host: &str) -> Result<Self> {
+        let mut channels: Vec<(Arc<dyn Notifier>, Vec<NotificationKind>)> = vec![];
+        if let Some(smtp) = &cfg.smtp {
+            channels.push((Arc::new(Mailer::new(smtp, host)?), smtp.events.clone()));
+        }
+        if let Some(matrix) = &cfg.matrix {
+            channels.push((Arc::new(Matrix::new(matrix)?), matrix.events.clone()));
+        }
+        if let Some(discord) = &cfg.discord {
+            channels.push((Arc::new(Discord::new(discord)?), discord.events.clone()));
+        }
+
+        Ok(Self {
+            recent: Default::default(),
+            channels,
+        })
+    }
+
+    /// The channels that the given kind of notification is sent on to.
+    fn channels_for(&self, kind: NotificationKind) -> Vec<Arc<dyn Notifier>> {
+        self.channels
+            .iter()
+            .filter(|(_, kinds)| kinds.is_empty() || kinds.contains(&kind))
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+
+    pub fn notify(&self, kind: NotificationKind, instance: Option<&str>, message: String) {
+        warn!(kind = kind.as_str(), ?instance, %message, "operator notification");
+
+        let notification = Notification {
+            kind,
+            instance: instance.map(|s| s.to_owned()),
+            message,
+            created_at: Utc::now(),
+        };
+
+        // Sending is done in the background so that the event being notified about isn't
+        // held up by a slow mail server or chat service
+        if let Ok(handle) = Handle::try_current() {
+            for channel in self.channels_for(kind) {
+                let notification = notification.clone();
+                handle.spawn(async move {
+                    if let Err(error) = channel.send(&notification).await {
+                        warn!(channel = channel.name(), %error, "unable to send notification");
+                    }
+                });
+            }
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_front(notification);
+        recent.truncate(MAX_NOTIFICATIONS);
+    }
+
+    /// The most recent notifications, newest first.
+    pub fn recent(&self) -> Vec<Notification> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,236 @@
+//! Channels that operator notifications can be sent on to, in addition to being listed
+//! by the admin API.
+use crate::{
+    config::{DiscordConfig, MatrixConfig},
+    notifications::Notification,
+    Error, Result,
+};
+use axum::{async_trait, http::StatusCode};
+use reqwest::{Client, Url};
+use serde_json::json;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long to wait for a chat service to accept a notification
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Discord rejects messages longer than this
+const MAX_DISCORD_CONTENT: usize = 2000;
+
+#[async_trait]
+pub trait Notifier: std::fmt::Debug + Send + Sync {
+    /// The name of the channel, for logging
+    fn name(&self) -> &'static str;
+
+    async fn send(&self, notification: &Notification) -> std::result::Result<(), String>;
+}
+
+/// The notification as a single chat message.
+pub fn chat_message(notification: &Notification) -> String {
+    let title = notification.kind.title();
+    match &notification.instance {
+        Some(instance) => format!("{title} ({instance}): {}", notification.message),
+        None => format!("{title}: {}", notification.message),
+    }
+}
+
+fn client() -> Result<Client> {
+    Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(|_| Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "unable to create notification client",
+        })
+}
+
+/// Posts notifications to a Matrix room using the client-server API.
+#[derive(Debug)]
+pub struct Matrix {
+    client: Client,
+    /// The endpoint for sending messages to the room, missing the transaction id
+    url: Url,
+    access_token: String,
+}
+
+impl Matrix {
+    pub fn new(cfg: &MatrixConfig) -> Result<Self> {
+        let mut url = Url::parse(&cfg.homeserver).map_err(|_| Error::InvalidUri {
+            uri: cfg.homeserver.clone(),
+        })?;
+        url.path_segments_mut()
+            .map_err(|_| Error::InvalidUri {
+                uri: cfg.homeserver.clone(),
+            })?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "rooms", &cfg.room_id])
+            .extend(["send", "m.room.message"]);
+
+        Ok(Self {
+            client: client()?,
+            url,
+            access_token: cfg.access_token.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Matrix {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    async fn send(&self, notification: &Notification) -> std::result::Result<(), String> {
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.push(&Uuid::new_v4().to_string());
+        }
+
+        self.client
+            .put(url)
+            .bearer_auth(&self.access_token)
+            .json(&json!({ "msgtype": "m.text", "body": chat_message(notification) }))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Posts notifications to a Discord channel via a webhook.
+#[derive(Debug)]
+pub struct Discord {
+    client: Client,
+    webhook_url: String,
+}
+
+impl Discord {
+    pub fn new(cfg: &DiscordConfig) -> Result<Self> {
+        Url::parse(&cfg.webhook_url).map_err(|_| Error::InvalidUri {
+            uri: cfg.webhook_url.clone(),
+        })?;
+
+        Ok(Self {
+            client: client()?,
+            webhook_url: cfg.webhook_url.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Discord {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    async fn send(&self, notification: &Notification) -> std::result::Result<(), String> {
+        let content: String = chat_message(notification)
+            .chars()
+            .take(MAX_DISCORD_CONTENT)
+            .collect();
+
+        self.client
+            .post(&self.webhook_url)
+            .json(&json!({ "content": content }))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::NotificationKind;
+    use axum::{
+        extract::{Json, OriginalUri},
+        http::{header::AUTHORIZATION, HeaderMap},
+        routing::any,
+        Router,
+    };
+    use chrono::Utc;
+    use serde_json::Value;
+    use std::net::TcpListener;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    // Serve an endpoint that passes on the path, authorization header and body of each
+    // request it receives
+    fn serve() -> (String, UnboundedReceiver<(String, Option<String>, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = unbounded_channel();
+        let app = Router::new().fallback(any(
+            move |uri: OriginalUri, headers: HeaderMap, Json(body): Json<Value>| async move {
+                let auth = headers
+                    .get(AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from);
+                tx.send((uri.0.path().to_owned(), auth, body)).unwrap();
+                Json(json!({}))
+            },
+        ));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (format!("http://{addr}"), rx)
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            kind: NotificationKind::InstanceThrottled,
+            instance: Some("spam.example".into()),
+            message: "spam.example has been throttled".into(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn matrix_messages_are_sent_to_the_room() {
+        let (homeserver, mut rx) = serve();
+        let matrix = Matrix::new(&MatrixConfig {
+            homeserver: format!("{homeserver}/"),
+            room_id: "!room:matrix.example".into(),
+            access_token: "secret".into(),
+            events: vec![],
+        })
+        .unwrap();
+
+        matrix.send(&notification()).await.unwrap();
+        let (path, auth, body) = rx.recv().await.unwrap();
+
+        assert!(
+            path.starts_with("/_matrix/client/v3/rooms/!room:matrix.example/send/m.room.message/")
+        );
+        assert_eq!(auth.as_deref(), Some("Bearer secret"));
+        assert_eq!(body["msgtype"], "m.text");
+        assert_eq!(
+            body["body"],
+            "Instance throttled (spam.example): spam.example has been throttled"
+        );
+    }
+
+    #[tokio::test]
+    async fn discord_messages_are_posted_to_the_webhook() {
+        let (base, mut rx) = serve();
+        let discord = Discord::new(&DiscordConfig {
+            webhook_url: format!("{base}/api/webhooks/1/token"),
+            events: vec![],
+        })
+        .unwrap();
+
+        discord.send(&notification()).await.unwrap();
+        let (path, _, body) = rx.recv().await.unwrap();
+
+        assert_eq!(path, "/api/webhooks/1/token");
+        assert_eq!(
+            body["content"],
+            "Instance throttled (spam.example): spam.example has been throttled"
+        );
+    }
+}
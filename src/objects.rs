@@ -0,0 +1,257 @@
+//! A cache of remote objects that we have fetched from their origin.
+//!
+//! Verifying an object means fetching it back from the server that claims to host it,
+//! and the same object commonly arrives from several instances in quick succession. The
+//! cache is bounded by the total size of the objects it holds and by how long each one
+//! is kept for. It is held in memory and written to the data directory by
+//! [ObjectCache::flush], which is run periodically, so that it survives restarts without
+//! being rewritten on every fetch.
+use crate::{config::ObjectCacheConfig, metrics::Metrics, storage::Storage};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// A single fetched object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedObject {
+    pub object: Value,
+    pub fetched_at: DateTime<Utc>,
+    /// The size of the object in bytes when serialized
+    pub size: usize,
+}
+
+pub type Objects = BTreeMap<String, CachedObject>;
+
+#[derive(Debug)]
+pub struct ObjectCache {
+    storage: Box<dyn Storage<Objects>>,
+    objects: Mutex<Objects>,
+    // whether there are changes that haven't been flushed to storage
+    dirty: AtomicBool,
+    // held while writing to storage so that flushes can't overtake each other
+    flushing: Mutex<()>,
+    max_bytes: usize,
+    ttl: Duration,
+}
+
+impl ObjectCache {
+    pub fn new(storage: Box<dyn Storage<Objects>>, cfg: &ObjectCacheConfig) -> Self {
+        let cache = Self {
+            objects: Mutex::new(storage.load()),
+            storage,
+            dirty: AtomicBool::new(false),
+            flushing: Mutex::new(()),
+            max_bytes: cfg.max_bytes,
+            ttl: Duration::seconds(cfg.ttl_secs as i64),
+        };
+
+        let evicted = cache.trim(&mut cache.objects.lock().unwrap(), Utc::now());
+        if evicted > 0 {
+            cache.dirty.store(true, Ordering::Relaxed);
+            cache.flush();
+        }
+
+        cache
+    }
+
+    /// The cached copy of the given object, if we have one that hasn't expired.
+    pub fn get(&self, id: &str, now: DateTime<Utc>) -> Option<Value> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|c| !self.is_expired(c, now))
+            .map(|c| c.object.clone())
+    }
+
+    /// Cache a fetched object, returning the number of objects evicted to make room for
+    /// it. Objects that are larger than the cache on their own are not stored.
+    pub fn insert(&self, id: &str, object: Value, now: DateTime<Utc>) -> usize {
+        let size = serde_json::to_vec(&object).map(|v| v.len()).unwrap_or(0);
+        if size == 0 || size > self.max_bytes {
+            return 0;
+        }
+
+        let mut objects = self.objects.lock().unwrap();
+        let cached = CachedObject {
+            object,
+            fetched_at: now,
+            size,
+        };
+        objects.insert(id.to_owned(), cached);
+        self.dirty.store(true, Ordering::Relaxed);
+
+        self.trim(&mut objects, now)
+    }
+
+    /// Write the cache to storage if it has changed since the last flush.
+    pub fn flush(&self) {
+        let _flushing = self.flushing.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let objects = self.objects.lock().unwrap().clone();
+        self.storage.update(&mut |stored| *stored = objects.clone());
+    }
+
+    /// The number of objects currently cached
+    pub fn len(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total size in bytes of the objects currently cached
+    pub fn bytes(&self) -> usize {
+        self.objects.lock().unwrap().values().map(|c| c.size).sum()
+    }
+
+    /// Update the gauges describing the current size of the cache.
+    pub fn record_gauges(&self, metrics: &Metrics) {
+        metrics.set("actiserve_object_cache_entries", &[], self.len() as u64);
+        metrics.set("actiserve_object_cache_bytes", &[], self.bytes() as u64);
+    }
+
+    fn is_expired(&self, cached: &CachedObject, now: DateTime<Utc>) -> bool {
+        now.signed_duration_since(cached.fetched_at) > self.ttl
+    }
+
+    // Expired objects are always dropped, followed by the least recently fetched until
+    // we are back within the size limit.
+    fn trim(&self, objects: &mut Objects, now: DateTime<Utc>) -> usize {
+        let before = objects.len();
+        objects.retain(|_, c| !self.is_expired(c, now));
+
+        let mut total: usize = objects.values().map(|c| c.size).sum();
+        if total > self.max_bytes {
+            let mut by_age: Vec<_> = objects
+                .iter()
+                .map(|(id, c)| (c.fetched_at, id.clone(), c.size))
+                .collect();
+            by_age.sort();
+
+            for (_, id, size) in by_age {
+                if total <= self.max_bytes {
+                    break;
+                }
+                objects.remove(&id);
+                total -= size;
+            }
+        }
+
+        before - objects.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{JsonFileStorage, MemoryStorage};
+    use serde_json::json;
+
+    fn note(n: u8) -> (String, Value) {
+        let id = format!("https://example.com/notes/{n}");
+        let object = json!({ "id": id, "type": "Note", "content": "0123456789" });
+
+        (id, object)
+    }
+
+    fn cache(max_bytes: usize, ttl_secs: u64) -> ObjectCache {
+        ObjectCache::new(
+            Box::<MemoryStorage<Objects>>::default(),
+            &ObjectCacheConfig {
+                max_bytes,
+                ttl_secs,
+            },
+        )
+    }
+
+    #[test]
+    fn cached_objects_are_returned_until_they_expire() {
+        let c = cache(10_000, 60);
+        let (id, object) = note(1);
+        let now = Utc::now();
+        c.insert(&id, object.clone(), now);
+
+        assert_eq!(c.get(&id, now + Duration::seconds(30)), Some(object));
+        assert_eq!(c.get(&id, now + Duration::seconds(90)), None);
+        assert_eq!(c.get("https://example.com/notes/2", now), None);
+    }
+
+    #[test]
+    fn oldest_objects_are_evicted_past_max_bytes() {
+        let (_, object) = note(1);
+        let size = serde_json::to_vec(&object).unwrap().len();
+        let c = cache(size * 2, 60);
+        let now = Utc::now();
+
+        let evicted: Vec<usize> = (1..=3)
+            .map(|n| {
+                let (id, object) = note(n);
+                c.insert(&id, object, now + Duration::seconds(n as i64))
+            })
+            .collect();
+
+        assert_eq!(evicted, vec![0, 0, 1]);
+        assert_eq!(c.len(), 2);
+        assert!(c.bytes() <= size * 2);
+        assert_eq!(c.get("https://example.com/notes/1", now), None);
+        assert!(c.get("https://example.com/notes/3", now).is_some());
+    }
+
+    #[test]
+    fn objects_larger_than_the_cache_are_not_stored() {
+        let c = cache(10, 60);
+        let (id, object) = note(1);
+
+        assert_eq!(c.insert(&id, object, Utc::now()), 0);
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    fn objects_are_only_written_when_flushed() {
+        let c = cache(10_000, 60);
+        let (id, object) = note(1);
+        c.insert(&id, object, Utc::now());
+        assert!(c.storage.load().is_empty());
+
+        c.flush();
+        assert!(c.storage.load().contains_key(&id));
+    }
+
+    #[test]
+    fn cached_objects_survive_restarts() {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg = ObjectCacheConfig::default();
+        let (id, object) = note(1);
+
+        let c = ObjectCache::new(
+            Box::new(JsonFileStorage::open(&dir, "objects.json").unwrap()),
+            &cfg,
+        );
+        c.insert(&id, object.clone(), Utc::now());
+        c.flush();
+        drop(c);
+
+        let c = ObjectCache::new(
+            Box::new(JsonFileStorage::open(&dir, "objects.json").unwrap()),
+            &cfg,
+        );
+        assert_eq!(c.get(&id, Utc::now()), Some(object));
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
@@ -0,0 +1,253 @@
+//! The stages that activities POSTed to our inboxes pass through.
+//!
+//! Each [Stage] either lets the activity continue on to the next stage, stops it (the
+//! request is still accepted, but the activity goes no further) or rejects the request
+//! with an error. New filters and policies can be added by implementing [Stage] and
+//! inserting it into the [Pipeline] held in the server [State].
+use crate::{actors::RelayActor, client::RemoteActor, state::State, Error, Result};
+use axum::{
+    async_trait,
+    http::{HeaderMap, StatusCode},
+};
+use serde_json::Value;
+use std::time::Instant;
+
+pub use crate::activities::ActivityType;
+
+/// An activity received by one of our inboxes along with the request it arrived in.
+#[derive(Debug)]
+pub struct Inbound<'a> {
+    /// The relay actor whose inbox received the activity
+    pub relay: RelayActor<'a>,
+    pub headers: &'a HeaderMap,
+    /// The host the request was made to
+    pub host: &'a str,
+    /// The path the request was made to
+    pub path: &'a str,
+    /// The raw request body
+    pub body: &'a [u8],
+    pub ty: ActivityType,
+    pub actor_id: String,
+    pub activity: Value,
+    /// The sending actor, once it has been fetched
+    pub actor: Option<RemoteActor>,
+}
+
+impl<'a> Inbound<'a> {
+    /// The sending actor, which is available to every stage after the one fetching it.
+    pub fn actor(&self) -> Result<&RemoteActor> {
+        self.actor.as_ref().ok_or(Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "actor has not been fetched",
+        })
+    }
+
+    /// Whether the activity is one that we relay on to other subscribers
+    pub fn is_relayable(&self) -> bool {
+        self.ty.is_relayable()
+    }
+}
+
+/// What should happen to an activity after a [Stage] has run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// Accept the activity without running any further stages
+    Stop,
+}
+
+#[async_trait]
+pub trait Stage: Send + Sync + std::fmt::Debug {
+    /// The name of the stage, used to label its metrics and to position other stages
+    /// relative to it
+    fn name(&self) -> &'static str;
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow>;
+}
+
+#[derive(Debug)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Self { stages }
+    }
+
+    /// The names of the stages in the order that they are run.
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    /// Insert a stage to be run immediately before the named stage, or at the end of
+    /// the pipeline if there is no stage with that name.
+    pub fn insert_before(&mut self, name: &str, stage: Box<dyn Stage>) {
+        match self.stages.iter().position(|s| s.name() == name) {
+            Some(ix) => self.stages.insert(ix, stage),
+            None => self.stages.push(stage),
+        }
+    }
+
+    /// Replace the named stage, returning false if there is no stage with that name. This
+    /// allows a stage such as fetching the sending actor to be swapped out for one that
+    /// doesn't need the network, e.g. when benchmarking.
+    pub fn replace(&mut self, name: &str, stage: Box<dyn Stage>) -> bool {
+        match self.stages.iter_mut().find(|s| s.name() == name) {
+            Some(existing) => {
+                *existing = stage;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run each stage in turn until one stops the activity or returns an error.
+    pub async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        run_stages(&self.stages, inbound, state, None).await
+    }
+
+    /// Run the stages up to and including the named stage, or every stage if there is
+    /// no stage with that name.
+    pub async fn run_until(
+        &self,
+        name: &str,
+        inbound: &mut Inbound<'_>,
+        state: &State,
+    ) -> Result<Flow> {
+        let ix = self.split_point(name);
+        run_stages(&self.stages[..ix], inbound, state, None).await
+    }
+
+    /// Run the stages following the named stage, or none if there is no stage with
+    /// that name.
+    pub async fn run_after(
+        &self,
+        name: &str,
+        inbound: &mut Inbound<'_>,
+        state: &State,
+    ) -> Result<Flow> {
+        let ix = self.split_point(name);
+        run_stages(&self.stages[ix..], inbound, state, None).await
+    }
+
+    /// Run the stages following the stage named by `completed`, updating it as each
+    /// stage finishes. A run that fails part way through can then pick up where it left
+    /// off, rather than repeating stages whose effects have already happened.
+    pub async fn resume_after(
+        &self,
+        completed: &mut String,
+        inbound: &mut Inbound<'_>,
+        state: &State,
+    ) -> Result<Flow> {
+        let ix = self.split_point(completed);
+        run_stages(&self.stages[ix..], inbound, state, Some(completed)).await
+    }
+
+    fn split_point(&self, name: &str) -> usize {
+        self.stages
+            .iter()
+            .position(|s| s.name() == name)
+            .map(|ix| ix + 1)
+            .unwrap_or(self.stages.len())
+    }
+}
+
+async fn run_stages(
+    stages: &[Box<dyn Stage>],
+    inbound: &mut Inbound<'_>,
+    state: &State,
+    mut completed: Option<&mut String>,
+) -> Result<Flow> {
+    for stage in stages.iter() {
+        let start = Instant::now();
+        let res = stage.run(inbound, state).await;
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+
+        let result = match &res {
+            Ok(Flow::Continue) => "continue",
+            Ok(Flow::Stop) => "stop",
+            Err(_) => "error",
+        };
+        let name = stage.name();
+        state.metrics.incr(
+            "actiserve_pipeline_stage_runs_total",
+            &[
+                ("stage", name),
+                ("type", inbound.ty.as_str()),
+                ("result", result),
+            ],
+        );
+        state.metrics.incr_by(
+            "actiserve_pipeline_stage_millis_total",
+            &[("stage", name)],
+            elapsed_ms,
+        );
+
+        let flow = res?;
+        if let Some(completed) = completed.as_deref_mut() {
+            *completed = name.to_owned();
+        }
+        if flow == Flow::Stop {
+            return Ok(Flow::Stop);
+        }
+    }
+
+    Ok(Flow::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::inbox::default_pipeline;
+    use simple_test_case::test_case;
+
+    #[derive(Debug)]
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl Stage for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn run(&self, _: &mut Inbound<'_>, _: &State) -> Result<Flow> {
+            Ok(Flow::Continue)
+        }
+    }
+
+    #[test]
+    fn stages_can_be_inserted() {
+        let mut pipeline = default_pipeline();
+        pipeline.insert_before("dispatch", Box::new(Named("custom")));
+        pipeline.insert_before("missing", Box::new(Named("last")));
+
+        let names = pipeline.stage_names();
+
+        assert_eq!(&names[names.len() - 3..], &["custom", "dispatch", "last"]);
+    }
+
+    #[test]
+    fn stages_can_be_replaced() {
+        let mut pipeline = default_pipeline();
+        let replaced = pipeline.replace("fetch_actor", Box::new(Named("mock_fetch_actor")));
+        let missing = pipeline.replace("missing", Box::new(Named("unused")));
+
+        let names = pipeline.stage_names();
+
+        assert!(replaced);
+        assert!(!missing);
+        assert_eq!(&names[..3], &["blocklist", "mock_fetch_actor", "signature"]);
+        assert_eq!(names.len(), default_pipeline().stage_names().len());
+    }
+
+    #[test_case("signature", Some(3); "named stage")]
+    #[test_case("missing", None; "missing stage")]
+    #[test]
+    fn pipelines_split_after_the_named_stage(name: &str, expected: Option<usize>) {
+        let pipeline = default_pipeline();
+        let n_stages = pipeline.stage_names().len();
+
+        assert_eq!(pipeline.split_point(name), expected.unwrap_or(n_stages));
+    }
+}
@@ -0,0 +1,158 @@
+//! Relay policies implemented by an external HTTP service.
+//!
+//! Each candidate activity is POSTed to the configured endpoint, which decides whether
+//! to allow, deny or modify it. This allows operators to write policies in whatever
+//! language they like without needing to rebuild actiserve.
+use crate::{
+    config::{FailurePolicy, HttpPolicyConfig},
+    Error, Result,
+};
+use axum::http::StatusCode;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use tracing::warn;
+
+/// The decision made by the endpoint about an activity.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "decision", rename_all = "camelCase")]
+pub enum Verdict {
+    Allow,
+    Deny,
+    /// Relay the activity provided in place of the original
+    Modify {
+        activity: Value,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct PolicyRequest<'a> {
+    domain: &'a str,
+    activity: &'a Value,
+}
+
+#[derive(Debug)]
+pub struct HttpPolicy {
+    cfg: HttpPolicyConfig,
+    client: Client,
+}
+
+impl HttpPolicy {
+    pub fn new(cfg: HttpPolicyConfig) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_millis(cfg.timeout_millis))
+            .build()
+            .map_err(|_| Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "unable to create policy client",
+            })?;
+
+        Ok(Self { cfg, client })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.cfg.url
+    }
+
+    /// Ask the endpoint for a verdict on the activity, falling back to the configured
+    /// failure policy if we don't get a valid response in time.
+    pub async fn check(&self, domain: &str, activity: &Value) -> Verdict {
+        match self.request(domain, activity).await {
+            Ok(Verdict::Modify { activity }) if !activity.is_object() => {
+                warn!(url=%self.cfg.url, "policy endpoint returned an invalid activity");
+                self.on_failure()
+            }
+
+            Ok(verdict) => verdict,
+
+            Err(e) => {
+                warn!(url=%self.cfg.url, error=%e, "policy endpoint request failed");
+                self.on_failure()
+            }
+        }
+    }
+
+    async fn request(&self, domain: &str, activity: &Value) -> reqwest::Result<Verdict> {
+        self.client
+            .post(&self.cfg.url)
+            .json(&PolicyRequest { domain, activity })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    fn on_failure(&self) -> Verdict {
+        match self.cfg.on_failure {
+            FailurePolicy::Allow => Verdict::Allow,
+            FailurePolicy::Deny => Verdict::Deny,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use serde_json::json;
+    use simple_test_case::test_case;
+    use std::net::TcpListener;
+
+    // Serve a policy endpoint that responds with the given body after the given delay
+    fn serve(delay_millis: u64, response: Value) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/policy",
+            post(move || async move {
+                tokio::time::sleep(Duration::from_millis(delay_millis)).await;
+                Json(response)
+            }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        format!("http://{addr}/policy")
+    }
+
+    fn policy(url: String, on_failure: FailurePolicy) -> HttpPolicy {
+        HttpPolicy::new(HttpPolicyConfig {
+            url,
+            timeout_millis: 200,
+            on_failure,
+        })
+        .unwrap()
+    }
+
+    #[test_case(json!({ "decision": "allow" }), Verdict::Allow; "allow")]
+    #[test_case(json!({ "decision": "deny" }), Verdict::Deny; "deny")]
+    #[test_case(json!({ "decision": "modify", "activity": { "type": "Create" } }), Verdict::Modify { activity: json!({ "type": "Create" }) }; "modify")]
+    #[test_case(json!({ "decision": "modify", "activity": "nope" }), Verdict::Deny; "invalid modification")]
+    #[test_case(json!({ "decision": "maybe" }), Verdict::Deny; "unknown decision")]
+    #[tokio::test]
+    async fn endpoint_verdicts_are_used(response: Value, expected: Verdict) {
+        let url = serve(0, response);
+        let verdict = policy(url, FailurePolicy::Deny)
+            .check("a.example", &json!({ "type": "Create" }))
+            .await;
+
+        assert_eq!(verdict, expected);
+    }
+
+    #[test_case(FailurePolicy::Allow, Verdict::Allow; "fail open")]
+    #[test_case(FailurePolicy::Deny, Verdict::Deny; "fail closed")]
+    #[tokio::test]
+    async fn slow_endpoints_use_the_failure_policy(on_failure: FailurePolicy, expected: Verdict) {
+        let url = serve(1_000, json!({ "decision": "modify", "activity": {} }));
+        let verdict = policy(url, on_failure)
+            .check("a.example", &json!({ "type": "Create" }))
+            .await;
+
+        assert_eq!(verdict, expected);
+    }
+}
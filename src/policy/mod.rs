@@ -0,0 +1,198 @@
+//! Custom policies deciding whether or not an activity should be relayed.
+//!
+//! Policies are consulted by the policy stage of the inbox pipeline for activities that
+//! are to be relayed. That runs after the flood, block, quarantine and block severity
+//! stages but before the object of a Create is fetched back for verification, so
+//! policies see activities that may still go on to be dropped. Within
+//! the stage, blocked authors and the allowed / denied object types are checked first,
+//! then the WASM filters in the order that they are configured, followed by the
+//! external HTTP policy (if one is configured).
+use crate::{activities::ActivityType, config::PolicyConfig, Result};
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+mod http;
+#[cfg(feature = "wasm-filters")]
+mod wasm;
+
+pub use http::{HttpPolicy, Verdict};
+pub use wasm::WasmFilter;
+
+/// The outcome of applying a policy to an activity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// The details of an activity made available to policies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyInput {
+    /// The domain of the instance that sent the activity
+    pub domain: String,
+    pub activity_type: ActivityType,
+    /// The content of the object embedded in the activity (empty if there isn't one)
+    pub content: String,
+}
+
+impl PolicyInput {
+    pub fn new(domain: &str, activity: &Value) -> Self {
+        Self {
+            domain: domain.to_owned(),
+            activity_type: ActivityType::from_value(&activity["type"]),
+            content: activity["object"]["content"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Policy {
+    // Shared with the blocking task that runs them
+    wasm_filters: Arc<Vec<WasmFilter>>,
+    http: Option<HttpPolicy>,
+}
+
+impl Policy {
+    pub fn new(cfg: &PolicyConfig) -> Result<Self> {
+        let wasm_filters = cfg
+            .wasm_filters
+            .iter()
+            .map(|path| WasmFilter::load(path))
+            .collect::<Result<_>>()?;
+        let http = cfg.http.clone().map(HttpPolicy::new).transpose()?;
+
+        Ok(Self {
+            wasm_filters: Arc::new(wasm_filters),
+            http,
+        })
+    }
+
+    /// Apply each of the configured policies to an activity from the given domain,
+    /// stopping at the first that denies it. WASM filters that fail to run are logged
+    /// and skipped. The activity is replaced if the HTTP policy modifies it.
+    pub async fn check(&self, domain: &str, activity: &mut Value) -> Decision {
+        let input = PolicyInput::new(domain, activity);
+        if !self.wasm_filters.is_empty() {
+            let (filters, wasm_input) = (self.wasm_filters.clone(), input.clone());
+            let decision =
+                tokio::task::spawn_blocking(move || run_wasm_filters(&filters, &wasm_input))
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!(error=%e, "WASM filters panicked");
+                        Decision::Allow
+                    });
+            if decision == Decision::Deny {
+                return Decision::Deny;
+            }
+        }
+
+        if let Some(http) = &self.http {
+            match http.check(domain, activity).await {
+                Verdict::Allow => (),
+                Verdict::Deny => {
+                    debug!(url=%http.url(), ?input, "activity denied by HTTP policy");
+                    return Decision::Deny;
+                }
+                Verdict::Modify { activity: modified } => {
+                    debug!(url=%http.url(), ?input, "activity modified by HTTP policy");
+                    *activity = modified;
+                }
+            }
+        }
+
+        Decision::Allow
+    }
+}
+
+// Run each filter in turn, stopping at the first to deny the activity
+fn run_wasm_filters(filters: &[WasmFilter], input: &PolicyInput) -> Decision {
+    for filter in filters {
+        match filter.filter(input) {
+            Ok(Decision::Allow) => (),
+            Ok(Decision::Deny) => {
+                debug!(filter=%filter.name(), ?input, "activity denied by WASM filter");
+                return Decision::Deny;
+            }
+            Err(e) => warn!(filter=%filter.name(), error=%e, "WASM filter failed"),
+        }
+    }
+
+    Decision::Allow
+}
+
+// Stand in for when we are built without WASM support: configuring any filters is an
+// error so there is never a filter to run.
+#[cfg(not(feature = "wasm-filters"))]
+mod wasm {
+    use super::{Decision, PolicyInput};
+    use crate::{Error, Result};
+    use axum::http::StatusCode;
+    use std::path::Path;
+
+    #[derive(Debug)]
+    pub enum WasmFilter {}
+
+    impl WasmFilter {
+        pub fn load(_: &Path) -> Result<Self> {
+            Err(Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "WASM filters require the wasm-filters feature",
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            match *self {}
+        }
+
+        pub fn filter(&self, _: &PolicyInput) -> Result<Decision> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    #[test]
+    fn policy_input_is_extracted_from_the_activity() {
+        let activity = json!({ "type": "Create", "object": { "content": "<p>hi</p>" } });
+
+        assert_eq!(
+            PolicyInput::new("a.example", &activity),
+            PolicyInput {
+                domain: "a.example".into(),
+                activity_type: ActivityType::Create,
+                content: "<p>hi</p>".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn unloadable_wasm_filters_are_an_error() {
+        let cfg = PolicyConfig {
+            wasm_filters: vec![PathBuf::from("does-not-exist.wasm")],
+            http: None,
+            ..Default::default()
+        };
+
+        assert!(Policy::new(&cfg).is_err());
+    }
+
+    #[tokio::test]
+    async fn everything_is_allowed_by_default() {
+        let policy = Policy::new(&PolicyConfig::default()).unwrap();
+        let mut activity = json!({ "type": "Create" });
+
+        assert_eq!(
+            policy.check("a.example", &mut activity).await,
+            Decision::Allow
+        );
+    }
+}
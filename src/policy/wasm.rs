@@ -0,0 +1,275 @@
+//! Relay filters implemented as WASM modules.
+//!
+//! A filter module exports its linear memory as `memory` along with a function
+//! `filter() -> i32` returning 0 to allow the activity and 1 to deny it. The details of
+//! the activity being filtered are provided by functions imported from the `actiserve`
+//! module, each of which copies the value into the buffer at `(ptr, len)` and returns
+//! the full length of the value so that truncation can be detected:
+//!
+//!   domain(ptr: i32, len: i32) -> i32
+//!   activity_type(ptr: i32, len: i32) -> i32
+//!   content(ptr: i32, len: i32) -> i32
+//!
+//! Each run is bounded by the fuel it may consume, by wall clock time (using epoch
+//! interruption) and by the memory and tables that the module may allocate. Filters
+//! block while they run so are called from a blocking task rather than on the runtime.
+use super::{Decision, PolicyInput};
+use crate::{Error, Result};
+use axum::http::StatusCode;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+// Upper bound on the work a filter can do for a single activity
+const FUEL_PER_CALL: u64 = 10_000_000;
+// Upper bound on the time a filter can run for, in case host calls or compiled code
+// make fuel a poor measure of it
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+const EPOCHS_PER_CALL: u64 = 10;
+// Upper bound on the linear memory a filter can allocate
+const MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+const MAX_TABLE_ELEMENTS: u32 = 10_000;
+
+// The data held by the store of a running filter
+struct Run {
+    input: PolicyInput,
+    limits: StoreLimits,
+}
+
+pub struct WasmFilter {
+    name: String,
+    engine: Engine,
+    module: Module,
+    linker: Linker<Run>,
+    // Stops the thread advancing the engine's epoch when the filter is dropped
+    ticking: Arc<AtomicBool>,
+}
+
+impl Drop for WasmFilter {
+    fn drop(&mut self) {
+        self.ticking.store(false, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Debug for WasmFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmFilter")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl WasmFilter {
+    pub fn load(path: &Path) -> Result<Self> {
+        let name = path.display().to_string();
+        info!(%name, "loading WASM filter");
+        let bytes = std::fs::read(path).map_err(|e| {
+            warn!(%name, error=%e, "unable to read WASM filter");
+            Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "unable to read WASM filter",
+            }
+        })?;
+
+        Self::new(name, &bytes)
+    }
+
+    /// Compile a filter from either the binary or text format of a WASM module.
+    pub fn new(name: String, bytes: &[u8]) -> Result<Self> {
+        let invalid = |e: wasmtime::Error| {
+            warn!(%name, error=%e, "invalid WASM filter");
+            Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "invalid WASM filter",
+            }
+        };
+
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config).map_err(invalid)?;
+        let module = Module::new(&engine, bytes).map_err(invalid)?;
+
+        let mut linker = Linker::new(&engine);
+        let fields: [(&str, Field); 3] = [
+            ("domain", |i| &i.domain),
+            ("activity_type", |i| i.activity_type.as_str()),
+            ("content", |i| &i.content),
+        ];
+        for (import, field) in fields {
+            linker
+                .func_wrap(
+                    "actiserve",
+                    import,
+                    move |caller: Caller<'_, Run>, ptr: i32, len: i32| {
+                        copy_out(caller, field, ptr, len)
+                    },
+                )
+                .map_err(invalid)?;
+        }
+
+        let ticking = Arc::new(AtomicBool::new(true));
+        let (ticker, running) = (engine.clone(), ticking.clone());
+        thread::spawn(move || {
+            while running.load(Ordering::Relaxed) {
+                thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            }
+        });
+
+        Ok(Self {
+            name,
+            engine,
+            module,
+            linker,
+            ticking,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the filter against an activity. Each call gets a fresh instance of the module
+    /// so no state is carried between activities.
+    pub fn filter(&self, input: &PolicyInput) -> Result<Decision> {
+        let failed = |e: wasmtime::Error| {
+            warn!(name=%self.name, error=%e, "error running WASM filter");
+            Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "error running WASM filter",
+            }
+        };
+
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .table_elements(MAX_TABLE_ELEMENTS)
+            .instances(1)
+            .build();
+        let run = Run {
+            input: input.clone(),
+            limits,
+        };
+        let mut store = Store::new(&self.engine, run);
+        store.limiter(|run| &mut run.limits);
+        store.add_fuel(FUEL_PER_CALL).map_err(failed)?;
+        store.set_epoch_deadline(EPOCHS_PER_CALL);
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)
+            .map_err(failed)?;
+        let filter = instance
+            .get_typed_func::<(), i32>(&mut store, "filter")
+            .map_err(failed)?;
+
+        match filter.call(&mut store, ()).map_err(failed)? {
+            0 => Ok(Decision::Allow),
+            _ => Ok(Decision::Deny),
+        }
+    }
+}
+
+type Field = fn(&PolicyInput) -> &str;
+
+// Copy a field of the input into the guest's memory, returning its full length
+fn copy_out(mut caller: Caller<'_, Run>, field: Field, ptr: i32, len: i32) -> i32 {
+    let bytes = field(&caller.data().input).as_bytes().to_vec();
+    let n = bytes.len().min(len.max(0) as usize);
+
+    if let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) {
+        if let Err(e) = memory.write(&mut caller, ptr as usize, &bytes[..n]) {
+            warn!(error=%e, "WASM filter provided an invalid buffer");
+        }
+    }
+
+    bytes.len() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activities::ActivityType;
+    use simple_test_case::test_case;
+
+    // Denies activities from spam.example by comparing the domain byte by byte
+    const DENY_DOMAIN: &str = r#"
+        (module
+          (import "actiserve" "domain" (func $domain (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "spam.example")
+          (func (export "filter") (result i32)
+            (local $i i32)
+            (if (i32.ne (call $domain (i32.const 100) (i32.const 100)) (i32.const 12))
+              (then (return (i32.const 0))))
+            (block $done
+              (loop $next
+                (br_if $done (i32.eq (local.get $i) (i32.const 12)))
+                (if (i32.ne
+                      (i32.load8_u (local.get $i))
+                      (i32.load8_u (i32.add (local.get $i) (i32.const 100))))
+                  (then (return (i32.const 0))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i32.const 1)))
+    "#;
+
+    const INFINITE_LOOP: &str = r#"
+        (module
+          (func (export "filter") (result i32)
+            (loop $forever (br $forever))
+            (i32.const 0)))
+    "#;
+
+    // Asks for 64MiB of memory up front
+    const GREEDY: &str = r#"
+        (module
+          (memory (export "memory") 1024)
+          (func (export "filter") (result i32)
+            (i32.const 0)))
+    "#;
+
+    fn input(domain: &str) -> PolicyInput {
+        PolicyInput {
+            domain: domain.into(),
+            activity_type: ActivityType::Create,
+            content: "hello".into(),
+        }
+    }
+
+    #[test_case("spam.example", Decision::Deny; "denied domain")]
+    #[test_case("good.example", Decision::Allow; "other domain")]
+    #[test_case("spam.example.org", Decision::Allow; "longer domain")]
+    #[test]
+    fn filters_can_inspect_the_activity(domain: &str, expected: Decision) {
+        let filter = WasmFilter::new("deny".into(), DENY_DOMAIN.as_bytes()).unwrap();
+
+        assert_eq!(filter.filter(&input(domain)), Ok(expected));
+    }
+
+    #[test]
+    fn runaway_filters_are_stopped() {
+        let filter = WasmFilter::new("loop".into(), INFINITE_LOOP.as_bytes()).unwrap();
+
+        assert!(filter.filter(&input("a.example")).is_err());
+    }
+
+    #[test]
+    fn filters_cannot_exceed_the_memory_limit() {
+        let filter = WasmFilter::new("greedy".into(), GREEDY.as_bytes()).unwrap();
+
+        assert!(filter.filter(&input("a.example")).is_err());
+    }
+
+    #[test]
+    fn invalid_modules_are_rejected() {
+        assert!(WasmFilter::new("bad".into(), b"not wasm").is_err());
+    }
+}
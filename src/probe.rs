@@ -0,0 +1,408 @@
+//! Deliverability diagnostics for a remote instance.
+//!
+//! Probing runs through the same requests that we make when an instance subscribes to
+//! the relay, reporting the outcome of each step so that operators can see exactly
+//! where federation with an instance is breaking down. Instances that are already
+//! subscribed can also be sent a test activity directly at the inbox we deliver to.
+use crate::{actors::DEFAULT_ACTOR, client::read_body, state::State, Error, Result};
+use axum::http::StatusCode;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, future::Future, time::Instant};
+use tracing::info;
+use uuid::Uuid;
+
+/// The most of a response body included in a [TestSendReport]
+const MAX_REPORTED_BODY: usize = 4096;
+
+/// The largest response body read in response to a test activity
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// The outcome of a single step of a probe.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProbeStep {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What we found, or why the step failed
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProbeReport {
+    pub domain: String,
+    pub ok: bool,
+    pub steps: Vec<ProbeStep>,
+}
+
+impl ProbeReport {
+    pub(crate) fn new(domain: &str) -> Self {
+        Self {
+            domain: domain.to_owned(),
+            ok: true,
+            steps: vec![],
+        }
+    }
+
+    // Run a step, returning its output if it succeeded
+    pub(crate) async fn step<T>(
+        &mut self,
+        name: &'static str,
+        f: impl Future<Output = Result<(T, String)>>,
+    ) -> Option<T> {
+        let (ok, detail, output) = match f.await {
+            Ok((output, detail)) => (true, detail, Some(output)),
+            Err(e) => (false, e.to_string(), None),
+        };
+
+        info!(domain=%self.domain, step=%name, %ok, %detail, "probe step");
+        self.ok &= ok;
+        self.steps.push(ProbeStep { name, ok, detail });
+
+        output
+    }
+}
+
+/// Probe the given instance, stopping at the first step that fails (other than the
+/// unsigned actor fetch which instances requiring signed fetches will reject).
+pub async fn probe(state: &State, domain: &str) -> ProbeReport {
+    let mut report = ProbeReport::new(domain);
+
+    let actor_uri = match report.step("webfinger", webfinger(state, domain)).await {
+        Some(uri) => uri,
+        None => return report,
+    };
+
+    let unsigned = async {
+        let actor = state
+            .client
+            .get_unsigned_json(&actor_uri, "application/activity+json")
+            .await?;
+
+        Ok(((), format!("fetched {} actor", actor["type"])))
+    };
+    report.step("actor fetch", unsigned).await;
+
+    let signed = async {
+        let actor = state.client.get_actor(&actor_uri).await?;
+        let inbox = actor
+            .shared_inbox()
+            .map(|s| s.to_owned())
+            .or_else(|| actor.inbox.clone())
+            .ok_or(Error::StatusAndMessage {
+                status: StatusCode::NOT_FOUND,
+                message: "actor has no inbox",
+            })?;
+        let detail = format!("fetched actor with inbox {inbox}");
+
+        Ok((inbox, detail))
+    };
+    let inbox = match report.step("signed GET", signed).await {
+        Some(inbox) => inbox,
+        None => return report,
+    };
+
+    let post = async {
+        let res = state
+            .client
+            .json_post(DEFAULT_ACTOR, &inbox, probe_activity(state))
+            .await?
+            .error_for_status()
+            .map_err(|e| Error::FailedRequest {
+                method: "POST".into(),
+                status: e.status().unwrap_or_default(),
+                error: e.to_string(),
+                uri: inbox.clone(),
+            })?;
+
+        Ok(((), format!("{inbox} responded with {}", res.status())))
+    };
+    report.step("signed POST", post).await;
+
+    report
+}
+
+/// The full outcome of sending a test activity to a subscriber's inbox.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestSendReport {
+    pub domain: String,
+    pub inbox: String,
+    pub ok: bool,
+    /// The status of the response, if we got one
+    pub status: Option<u16>,
+    pub headers: BTreeMap<String, String>,
+    /// The start of the response body
+    pub body: Option<String>,
+    /// Why the request failed, if it did
+    pub error: Option<String>,
+    pub elapsed_millis: u64,
+}
+
+/// Send a signed (but harmless) activity to the inbox we deliver to for a subscribed
+/// instance, reporting the response in full.
+pub async fn test_send(state: &State, domain: &str) -> Result<TestSendReport> {
+    let inbox = state
+        .db
+        .delivery_inbox(&format!("https://{domain}"))
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown instance",
+        })?;
+
+    let mut report = TestSendReport {
+        domain: domain.to_owned(),
+        inbox: inbox.clone(),
+        ok: false,
+        status: None,
+        headers: BTreeMap::new(),
+        body: None,
+        error: None,
+        elapsed_millis: 0,
+    };
+
+    let started = Instant::now();
+    let res = state
+        .client
+        .json_post(DEFAULT_ACTOR, &inbox, probe_activity(state))
+        .await;
+
+    match res {
+        Ok(res) => {
+            let status = res.status();
+            report.ok = status.is_success();
+            report.status = Some(status.as_u16());
+            for (name, value) in res.headers() {
+                let value = String::from_utf8_lossy(value.as_bytes());
+                report
+                    .headers
+                    .entry(name.to_string())
+                    .and_modify(|v| *v = format!("{v}, {value}"))
+                    .or_insert_with(|| value.into_owned());
+            }
+            match read_body(&inbox, res, MAX_RESPONSE_BYTES).await {
+                Ok(body) => {
+                    let body = String::from_utf8_lossy(&body);
+                    report.body = Some(body.chars().take(MAX_REPORTED_BODY).collect());
+                }
+                Err(e) => report.error = Some(e.to_string()),
+            }
+        }
+        Err(e) => report.error = Some(e.to_string()),
+    }
+    report.elapsed_millis = started.elapsed().as_millis() as u64;
+
+    info!(%domain, %inbox, ok=%report.ok, status=?report.status, "sent test activity");
+
+    Ok(report)
+}
+
+// Instances advertise their instance actor via webfinger as acct:{domain}@{domain}
+async fn webfinger(state: &State, domain: &str) -> Result<(String, String)> {
+    let resource = format!("acct:{domain}@{domain}");
+    let origin = state.client.origin(domain);
+    let uri = format!("{origin}/.well-known/webfinger?resource={resource}");
+    let jrd = state
+        .client
+        .get_unsigned_json(&uri, "application/jrd+json")
+        .await?;
+
+    let href = actor_link(&jrd).ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "webfinger response has no ActivityPub actor link",
+    })?;
+    let detail = format!("resolved {resource} to {href}");
+
+    Ok((href, detail))
+}
+
+/// The ActivityPub actor linked from a webfinger response
+pub(crate) fn actor_link(jrd: &Value) -> Option<String> {
+    jrd["links"]
+        .as_array()?
+        .iter()
+        .filter(|link| link["rel"] == "self")
+        .filter(|link| {
+            let ty = link["type"].as_str().unwrap_or_default();
+            ty.contains("activity+json") || ty.contains("ld+json")
+        })
+        .find_map(|link| link["href"].as_str())
+        .map(|href| href.to_owned())
+}
+
+// Deleting an object that never existed is ignored by receiving servers, but still
+// exercises signature verification of our POST.
+fn probe_activity(state: &State) -> Value {
+    let actor = state.client.actor_id(DEFAULT_ACTOR);
+    let probe_id = Uuid::new_v4();
+
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{actor}/probes/{probe_id}#delete"),
+        "type": "Delete",
+        "actor": actor,
+        "object": format!("{actor}/probes/{probe_id}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ProxyConfig, signature::tests::TEST_PUB_KEY, state::Db};
+    use axum::{
+        routing::{get, post},
+        Json, Router,
+    };
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, net::TcpListener};
+
+    // Requests to .onion hosts are sent to the given address, where a mock instance can
+    // be served over plain HTTP without any names needing to be resolved
+    fn proxy_onions_to(state: &mut State, addr: &str) {
+        let proxy = ProxyConfig {
+            onion_url: Some(format!("http://{addr}")),
+            ..Default::default()
+        };
+        state.client.configure(&proxy, &Default::default()).unwrap();
+    }
+
+    fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        addr.to_string()
+    }
+
+    // An address that refuses connections, for instances that can't be reached
+    fn unreachable_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn mock_instance() -> Router {
+        let actor = json!({
+            "id": "http://mock.onion/actor",
+            "type": "Application",
+            "inbox": "http://mock.onion/inbox",
+            "publicKey": {
+                "id": "http://mock.onion/actor#main-key",
+                "owner": "http://mock.onion/actor",
+                "publicKeyPem": TEST_PUB_KEY,
+            },
+        });
+        let jrd = json!({
+            "links": [{ "rel": "self", "type": "application/activity+json", "href": "http://mock.onion/actor" }]
+        });
+
+        Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(move || async move { Json(jrd) }),
+            )
+            .route("/actor", get(move || async move { Json(actor) }))
+            .route("/inbox", post(|| async { StatusCode::ACCEPTED }))
+    }
+
+    #[test_case(json!({ "links": [{ "rel": "self", "type": "application/activity+json", "href": "https://a.example/actor" }] }), Some("https://a.example/actor"); "activity json")]
+    #[test_case(json!({ "links": [{ "rel": "self", "type": "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"", "href": "https://a.example/actor" }] }), Some("https://a.example/actor"); "ld json")]
+    #[test_case(json!({ "links": [{ "rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": "https://a.example/@a" }] }), None; "profile page only")]
+    #[test_case(json!({}), None; "no links")]
+    #[test]
+    fn actor_link_works(jrd: Value, expected: Option<&str>) {
+        assert_eq!(actor_link(&jrd).as_deref(), expected);
+    }
+
+    #[tokio::test]
+    async fn probing_runs_every_step() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        proxy_onions_to(&mut state, &serve(mock_instance()));
+
+        let report = probe(&state, "mock.onion").await;
+        let steps: Vec<(&str, bool)> = report.steps.iter().map(|s| (s.name, s.ok)).collect();
+
+        assert_eq!(
+            steps,
+            vec![
+                ("webfinger", true),
+                ("actor fetch", true),
+                ("signed GET", true),
+                ("signed POST", true)
+            ]
+        );
+        assert!(report.ok);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn probing_stops_at_the_first_failure() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        proxy_onions_to(&mut state, &unreachable_addr());
+
+        let report = probe(&state, "unreachable.onion").await;
+
+        assert!(!report.ok);
+        assert_eq!(report.steps.len(), 1);
+        assert_eq!(report.steps[0].name, "webfinger");
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn test_sends_report_failed_requests() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        proxy_onions_to(&mut state, &unreachable_addr());
+        state
+            .db
+            .add_inbox_if_unknown("http://unreachable.onion/inbox".into(), None)
+            .unwrap();
+
+        let report = test_send(&state, "unreachable.onion").await.unwrap();
+        let unknown = test_send(&state, "unknown.example").await;
+
+        assert!(!report.ok);
+        assert_eq!(report.inbox, "http://unreachable.onion/inbox");
+        assert_eq!(report.status, None);
+        assert!(report.error.is_some());
+        assert!(unknown.is_err());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(MAX_REPORTED_BODY, true; "small")]
+    #[test_case(MAX_RESPONSE_BYTES + 1, false; "too large")]
+    #[tokio::test]
+    async fn test_send_response_bodies_are_limited(len: usize, read: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        let app = Router::new().route("/inbox", post(move || async move { "a".repeat(len) }));
+        proxy_onions_to(&mut state, &serve(app));
+        state
+            .db
+            .add_inbox_if_unknown("http://big.onion/inbox".into(), None)
+            .unwrap();
+
+        let report = test_send(&state, "big.onion").await.unwrap();
+
+        assert_eq!(report.status, Some(200));
+        assert_eq!(
+            report.body.map(|b| b.len()),
+            read.then_some(MAX_REPORTED_BODY)
+        );
+        assert_eq!(report.error.is_some(), !read);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
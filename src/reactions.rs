@@ -0,0 +1,85 @@
+//! Tracking of the reactions (Likes and EmojiReacts) forwarded to subscribers.
+//!
+//! Reactions are far more numerous than the objects they react to, so rather than being
+//! recorded in the history the ids of those most recently forwarded are kept in memory,
+//! which is enough to stop the same reaction being forwarded more than once as it is
+//! delivered to each of our relay actors or retried by the sending instance.
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+};
+
+/// The number of forwarded reactions remembered
+pub const MAX_FORWARDED_REACTIONS: usize = 10_000;
+
+#[derive(Debug, Default)]
+pub struct ForwardedReactions {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // (relay, activity id) pairs with the oldest first
+    order: VecDeque<(String, String)>,
+    seen: HashSet<(String, String)>,
+}
+
+impl ForwardedReactions {
+    /// Record that a reaction is being forwarded by the named relay actor, returning
+    /// false if it already has been. The oldest reaction is forgotten once
+    /// [MAX_FORWARDED_REACTIONS] are being remembered.
+    pub fn insert(&self, relay: &str, activity_id: &str) -> bool {
+        let key = (relay.to_owned(), activity_id.to_owned());
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.seen.insert(key.clone()) {
+            return false;
+        }
+
+        inner.order.push_back(key);
+        if inner.order.len() > MAX_FORWARDED_REACTIONS {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactions_are_only_inserted_once_per_relay() {
+        let reactions = ForwardedReactions::default();
+
+        assert!(reactions.insert("relay", "https://a.example/likes/1"));
+        assert!(!reactions.insert("relay", "https://a.example/likes/1"));
+        assert!(reactions.insert("art", "https://a.example/likes/1"));
+        assert_eq!(reactions.len(), 2);
+    }
+
+    #[test]
+    fn the_oldest_reactions_are_forgotten() {
+        let reactions = ForwardedReactions::default();
+        for i in 0..=MAX_FORWARDED_REACTIONS {
+            reactions.insert("relay", &format!("https://a.example/likes/{i}"));
+        }
+
+        assert_eq!(reactions.len(), MAX_FORWARDED_REACTIONS);
+        assert!(reactions.insert("relay", "https://a.example/likes/0"));
+        assert!(!reactions.insert(
+            "relay",
+            &format!("https://a.example/likes/{MAX_FORWARDED_REACTIONS}")
+        ));
+    }
+}
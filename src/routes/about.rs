@@ -0,0 +1,114 @@
+//! A page describing the relay's rules, who it accepts and how to contact its operator
+//! (see [crate::about]).
+use crate::{actors::RelayActor, routes::extractors, state::State};
+use axum::{
+    extract::{Extension, Host},
+    http::{header, HeaderMap},
+    response::{Html, IntoResponse, Response},
+};
+use rustypub::core::ContextBuilder;
+use serde_json::json;
+use std::sync::Arc;
+
+const TITLE: &str = "About this relay";
+
+pub async fn get(
+    headers: HeaderMap,
+    Host(host): Host,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let about = &state.about;
+
+    if !wants_activity_json(&headers) {
+        let page = format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{TITLE}</title>
+</head>
+<body>
+{}</body>
+</html>
+"#,
+            about.html
+        );
+
+        return Html(page).into_response();
+    }
+
+    let id = format!("https://{host}/about");
+    extractors::Activity(json!({
+        "@context": ContextBuilder::default().build(),
+        "type": "Page",
+        "id": id,
+        "url": id,
+        "name": TITLE,
+        "attributedTo": RelayActor::main(&state).id(&host),
+        "mediaType": "text/html",
+        "content": about.html,
+        "source": {
+            "content": about.markdown,
+            "mediaType": "text/markdown",
+        },
+    }))
+    .into_response()
+}
+
+fn wants_activity_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|accept| accept.contains("activity+json") || accept.contains("ld+json"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        about::About,
+        routes::build_routes,
+        state::{Db, State},
+    };
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, sync::Arc};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    #[test_case(None, "text/html; charset=utf-8"; "browser")]
+    #[test_case(Some("application/activity+json"), "application/activity+json"; "activitypub")]
+    #[tokio::test]
+    async fn about_page_is_negotiated(accept: Option<&str>, content_type: &str) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.operator.contact_email = Some("admin@relay.example".into());
+        state.about = About::new("# Rules\n\nBe excellent", &state.cfg);
+        let app = build_routes(Arc::new(state));
+
+        let mut req = Request::builder()
+            .uri("/about")
+            .header(header::HOST, "relay.example");
+        if let Some(accept) = accept {
+            req = req.header(header::ACCEPT, accept);
+        }
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        let ct = res.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert_eq!(ct, content_type);
+        assert!(body.contains("<h1>Rules</h1>"));
+        assert!(body.contains("Accepted instances"));
+        assert!(body.contains("mailto:admin@relay.example"));
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
@@ -0,0 +1,1294 @@
+//! Admin API for relay operators.
+//!
+//! All routes require either the configured admin token or an access token issued to an
+//! OAuth client (see [crate::auth]) to be provided as a bearer token. Access tokens are
+//! limited to the endpoints covered by their scopes, and managing OAuth clients
+//! themselves requires the admin token.
+//!
+//! Endpoints listing resources can also return them as CSV by passing `?format=csv`.
+use crate::{
+    actors::DEFAULT_ACTOR,
+    auth::{constant_time_eq, ClientInfo, OAuthClient, Scope},
+    blocklist::{Severity, ADMIN_SOURCE},
+    config::DomainRule,
+    delivery::QueueStatus,
+    import::{run_import, ImportProgress, DEFAULT_FOLLOWS_PER_MINUTE},
+    invites::Invite,
+    messages::Message,
+    probe::{probe, test_send, ProbeReport, TestSendReport},
+    selftest,
+    state::{Instance, State},
+    timeseries::{parse_range, Counts},
+    util::host_from_uri,
+    Error, Result,
+};
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, Json, OriginalUri, Path, Query, RequestParts},
+    http::{header::AUTHORIZATION, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc};
+use tracing::info;
+
+mod bootstrap;
+mod csv;
+mod mastodon;
+
+use csv::{ExportParams, Format};
+use mastodon::{endpoint_url, paginate, Page, PageParams};
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/instances", get(list_instances))
+        .route("/instances/:domain", get(get_instance))
+        .route("/instances/:domain/pause", post(pause_instance))
+        .route("/instances/:domain/resume", post(resume_instance))
+        .route("/instances/:domain/trust-key", post(trust_key))
+        .route("/instances/:domain/release", post(release_instance))
+        .route("/instances/:domain/test", post(test_instance))
+        .route("/software", get(software_inventory))
+        .route("/probe/:domain", post(probe_instance))
+        .route("/blocks", get(list_blocks))
+        .route("/blocks/:domain", put(add_block).delete(remove_block))
+        .route("/domain_blocks", get(mastodon::list_domain_blocks))
+        .route("/domain_blocks/:id", get(mastodon::get_domain_block))
+        .route("/reports", get(mastodon::list_reports))
+        .route(
+            "/actor-blocks",
+            get(list_actor_blocks)
+                .put(add_actor_block)
+                .delete(remove_actor_block),
+        )
+        .route("/bootstrap", put(bootstrap::put_bootstrap))
+        .route("/import", get(import_status).post(start_import))
+        .route("/upstreams", get(list_upstreams))
+        .route("/history", get(recent_history))
+        .route("/metrics", get(metrics))
+        .route("/metrics/timeseries", get(metrics_timeseries))
+        .route("/stats/origins", get(origin_stats))
+        .route("/notifications", get(notifications))
+        .route("/messages", get(list_messages))
+        .route("/messages/:id", get(get_message).delete(delete_message))
+        .route("/unrecognized", get(list_unrecognized))
+        .route("/log-filter", get(log_filter).put(set_log_filter))
+        .route("/deliveries", get(delivery_status))
+        .route("/deliveries/pause", post(pause))
+        .route("/deliveries/resume", post(resume))
+        .route("/invites", get(list_invites).post(create_invite))
+        .route("/invites/:code", delete(revoke_invite))
+        .route("/invites/:code/bind", post(bind_invite))
+        .route("/oauth/clients", get(list_clients).post(create_client))
+        .route("/oauth/clients/:client_id", delete(delete_client))
+}
+
+/// A scope required in order to access an admin endpoint.
+pub trait RequiredScope: Send {
+    const SCOPE: Scope;
+}
+
+macro_rules! required_scope {
+    ($name:ident) => {
+        #[derive(Debug)]
+        pub struct $name;
+
+        impl RequiredScope for $name {
+            const SCOPE: Scope = Scope::$name;
+        }
+    };
+}
+
+required_scope!(ReadStats);
+required_scope!(WriteBlocks);
+required_scope!(WriteInstances);
+required_scope!(ReadAudit);
+required_scope!(WriteStatuses);
+
+/// Extractor that rejects any request not bearing either the configured admin token or
+/// a valid access token issued to an OAuth client that has been granted the scope `S`.
+#[derive(Debug)]
+pub struct Admin<S>(PhantomData<S>);
+
+#[async_trait]
+impl<B: Send, S: RequiredScope> FromRequest<B> for Admin<S> {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let (state, token) = admin_credentials(req)?;
+
+        if is_admin_token(state, token) {
+            return Ok(Admin(PhantomData));
+        }
+
+        match token.and_then(|t| state.tokens.verify(t)) {
+            Some(issued) if issued.scopes.contains(&S::SCOPE) => Ok(Admin(PhantomData)),
+            Some(_) => Err(Error::StatusAndMessage {
+                status: StatusCode::FORBIDDEN,
+                message: "insufficient scope",
+            }),
+            None => Err(Error::StatusAndMessage {
+                status: StatusCode::UNAUTHORIZED,
+                message: "invalid admin token",
+            }),
+        }
+    }
+}
+
+/// Extractor that only accepts the configured admin token, for operations that OAuth
+/// clients should never be able to perform.
+#[derive(Debug)]
+pub struct Operator;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Operator {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let (state, token) = admin_credentials(req)?;
+
+        if is_admin_token(state, token) {
+            Ok(Operator)
+        } else {
+            Err(Error::StatusAndMessage {
+                status: StatusCode::UNAUTHORIZED,
+                message: "invalid admin token",
+            })
+        }
+    }
+}
+
+fn is_admin_token(state: &State, token: Option<&str>) -> bool {
+    match (token, state.cfg.admin_token.as_deref()) {
+        (Some(token), Some(admin_token)) => constant_time_eq(token, admin_token),
+        _ => false,
+    }
+}
+
+// The server state along with the bearer token provided with the request (if any)
+fn admin_credentials<B>(req: &RequestParts<B>) -> Result<(&State, Option<&str>)> {
+    let state = req
+        .extensions()
+        .get::<Arc<State>>()
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "internal server error",
+        })?;
+
+    // Without a configured token the admin API is disabled entirely
+    if state.cfg.admin_token.is_none() {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "admin API is disabled",
+        });
+    }
+
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    Ok((state, provided))
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstanceEntry {
+    domain: String,
+    #[serde(flatten)]
+    instance: Instance,
+}
+
+/// All subscribed instances, which are only paginated (Mastodon style) if any
+/// pagination parameters are given.
+pub async fn list_instances(
+    _: Admin<ReadStats>,
+    uri: OriginalUri,
+    Query(params): Query<PageParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Page<InstanceEntry> {
+    let mut instances: Vec<(String, InstanceEntry)> = state
+        .db
+        .instances()
+        .into_iter()
+        .map(|(domain, instance)| (domain.clone(), InstanceEntry { domain, instance }))
+        .collect();
+    instances.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if params.is_empty() {
+        return Page {
+            items: instances.into_iter().map(|(_, entry)| entry).collect(),
+            next: None,
+            prev: None,
+            format: params.format,
+        };
+    }
+
+    paginate(instances, &params, &endpoint_url(&state, &uri))
+}
+
+pub async fn get_instance(
+    _: Admin<ReadStats>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<InstanceEntry>> {
+    instance_entry(&state, domain)
+}
+
+fn instance_entry(state: &State, domain: String) -> Result<Json<InstanceEntry>> {
+    let instance = state.db.instance(&domain).ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "unknown instance",
+    })?;
+
+    Ok(Json(InstanceEntry { domain, instance }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SoftwareParams {
+    /// Only include software with this name
+    name: Option<String>,
+    #[serde(default)]
+    format: csv::Format,
+}
+
+/// A software version run by one or more subscribed instances.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SoftwareEntry {
+    name: String,
+    version: String,
+    /// The number of these instances reporting open registrations
+    open_registrations: usize,
+    instances: Vec<String>,
+}
+
+/// The software run by subscribed instances, grouped by name and version. Instances
+/// whose nodeinfo has never been successfully fetched are listed as "unknown".
+pub async fn software_inventory(
+    _: Admin<ReadStats>,
+    Query(params): Query<SoftwareParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let mut grouped: BTreeMap<(String, String), SoftwareEntry> = BTreeMap::new();
+
+    for (domain, instance) in state.db.instances() {
+        let (name, version) = match instance.software {
+            Some(s) => (s.name.to_lowercase(), s.version),
+            None => ("unknown".to_owned(), String::new()),
+        };
+        if matches!(&params.name, Some(wanted) if !wanted.eq_ignore_ascii_case(&name)) {
+            continue;
+        }
+
+        let entry = grouped
+            .entry((name.clone(), version.clone()))
+            .or_insert_with(|| SoftwareEntry {
+                name,
+                version,
+                open_registrations: 0,
+                instances: vec![],
+            });
+        if instance.open_registrations == Some(true) {
+            entry.open_registrations += 1;
+        }
+        entry.instances.push(domain);
+    }
+
+    let entries = grouped
+        .into_values()
+        .map(|mut entry| {
+            entry.instances.sort();
+            entry
+        })
+        .collect();
+
+    csv::respond(entries, params.format)
+}
+
+/// Accept the new key presented by an instance whose key has changed since it was
+/// pinned.
+pub async fn trust_key(
+    _: Admin<WriteInstances>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<InstanceEntry>> {
+    let mut trusted = None;
+    state
+        .db
+        .update_instance(&domain, |instance| trusted = instance.trust_new_key());
+
+    let fingerprint = trusted.ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "no key change to trust",
+    })?;
+    info!(%domain, %fingerprint, "trusting new key for instance");
+
+    instance_entry(&state, domain)
+}
+
+/// End the quarantine of a newly subscribed instance early.
+pub async fn release_instance(
+    _: Admin<WriteInstances>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<InstanceEntry>> {
+    info!(%domain, "releasing instance from quarantine");
+    state
+        .db
+        .update_instance(&domain, |instance| instance.quarantined_until = None);
+
+    instance_entry(&state, domain)
+}
+
+/// Run deliverability diagnostics against an instance. This sends a signed (but
+/// harmless) activity to the instance's inbox.
+pub async fn probe_instance(
+    _: Admin<WriteInstances>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Json<ProbeReport> {
+    info!(%domain, "probing instance");
+
+    Json(probe(&state, &domain).await)
+}
+
+/// Send a signed (but harmless) activity to the inbox we deliver to for a subscribed
+/// instance and report the response in full, to check that deliveries are getting
+/// through after an incident. Failed requests are still a 200: see the report's `ok`.
+pub async fn test_instance(
+    _: Admin<WriteInstances>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<TestSendReport>> {
+    Ok(Json(test_send(&state, &domain).await?))
+}
+
+/// Check that our own actor, webfinger and DNS are set up correctly for the configured
+/// public host.
+pub async fn selftest(
+    _: Admin<ReadStats>,
+    Extension(state): Extension<Arc<State>>,
+) -> Json<ProbeReport> {
+    Json(selftest::selftest(&state).await)
+}
+
+/// All blocked domains along with the sources (config or blocklist feed) blocking them
+pub async fn list_blocks(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.blocklist.blocks(), params.format)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BlockParams {
+    #[serde(default)]
+    severity: Severity,
+}
+
+/// Block a domain with the given severity (rejecting everything by default), replacing
+/// any existing admin block for the domain.
+pub async fn add_block(
+    _: Admin<WriteBlocks>,
+    Path(domain): Path<String>,
+    Query(params): Query<BlockParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> StatusCode {
+    let domain = DomainRule::from(domain.as_str()).domain;
+    let severity = params.severity;
+    state.db.add_domain_block(&domain, severity);
+
+    if state.blocklist.add(ADMIN_SOURCE, &domain, severity) {
+        info!(%domain, severity = severity.as_str(), "blocking domain");
+        StatusCode::CREATED
+    } else {
+        StatusCode::NO_CONTENT
+    }
+}
+
+/// Remove a block previously added via [add_block]. Blocks from the config or from
+/// blocklist feeds can't be removed this way.
+pub async fn remove_block(
+    _: Admin<WriteBlocks>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<StatusCode> {
+    let domain = DomainRule::from(domain.as_str()).domain;
+    state.db.remove_domain_block(&domain);
+
+    if !state.blocklist.remove(ADMIN_SOURCE, &domain) {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "domain is not blocked via the admin API",
+        });
+    }
+
+    info!(%domain, "unblocking domain");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The ids of all individually blocked actors
+pub async fn list_actor_blocks(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.db.actor_blocks(), params.format)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActorBlockParams {
+    /// The id of the actor
+    actor: String,
+}
+
+/// Block an individual actor. Posts authored by the actor are not relayed even when
+/// their instance is allowed.
+pub async fn add_actor_block(
+    _: Admin<WriteBlocks>,
+    Query(params): Query<ActorBlockParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<StatusCode> {
+    let actor = params.actor;
+    if host_from_uri(&actor).is_err() {
+        return Err(Error::InvalidUri { uri: actor });
+    }
+
+    if state.db.add_actor_block(&actor) {
+        info!(%actor, "blocking actor");
+        Ok(StatusCode::CREATED)
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+pub async fn remove_actor_block(
+    _: Admin<WriteBlocks>,
+    Query(params): Query<ActorBlockParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<StatusCode> {
+    let actor = params.actor;
+    if !state.db.remove_actor_block(&actor) {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "actor is not blocked",
+        });
+    }
+
+    info!(%actor, "unblocking actor");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportRequest {
+    /// Inboxes of the instances to subscribe
+    inboxes: Vec<String>,
+    /// The relay actor to subscribe the instances to
+    #[serde(default = "default_import_actor")]
+    actor: String,
+    #[serde(default = "default_follows_per_minute")]
+    follows_per_minute: u32,
+}
+
+fn default_import_actor() -> String {
+    DEFAULT_ACTOR.to_owned()
+}
+
+fn default_follows_per_minute() -> u32 {
+    DEFAULT_FOLLOWS_PER_MINUTE
+}
+
+/// Bulk subscribe a list of instance inboxes (e.g. when migrating from another relay).
+/// The import runs in the background: progress can be checked via [import_status].
+pub async fn start_import(
+    _: Admin<WriteInstances>,
+    Extension(state): Extension<Arc<State>>,
+    Json(req): Json<ImportRequest>,
+) -> Result<(StatusCode, Json<Option<ImportProgress>>)> {
+    if state.actor(&req.actor).is_none() {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown actor",
+        });
+    }
+    if req.inboxes.is_empty() {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "no inboxes to import",
+        });
+    }
+    if !state.imports.start(&req.actor, req.inboxes.len()) {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::CONFLICT,
+            message: "an import is already running",
+        });
+    }
+
+    info!(actor=%req.actor, n_inboxes=req.inboxes.len(), "starting import");
+    tokio::spawn(run_import(
+        state.clone(),
+        req.actor,
+        req.inboxes,
+        req.follows_per_minute,
+    ));
+
+    Ok((StatusCode::ACCEPTED, Json(state.imports.progress())))
+}
+
+pub async fn import_status(
+    _: Admin<ReadStats>,
+    Extension(state): Extension<Arc<State>>,
+) -> Json<Option<ImportProgress>> {
+    Json(state.imports.progress())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+    #[serde(default)]
+    format: csv::Format,
+}
+
+fn default_history_limit() -> usize {
+    100
+}
+
+/// The most recently relayed objects, newest first
+pub async fn recent_history(
+    _: Admin<ReadAudit>,
+    Query(params): Query<HistoryParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.history.recent(params.limit), params.format)
+}
+
+/// Metrics in the Prometheus text exposition format
+pub async fn metrics(_: Admin<ReadStats>, Extension(state): Extension<Arc<State>>) -> String {
+    state.ingest.record_gauges(&state.metrics, Utc::now());
+    state.objects.record_gauges(&state.metrics);
+    state.metrics.render()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeseriesParams {
+    #[serde(default = "default_timeseries_range")]
+    range: String,
+    #[serde(default)]
+    format: csv::Format,
+}
+
+fn default_timeseries_range() -> String {
+    "7d".to_owned()
+}
+
+/// The key relay counters for a single hour.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeseriesPoint {
+    /// The start of the hour
+    hour: DateTime<Utc>,
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+/// Hourly rollups of the key relay counters over the requested range (e.g. `7d` or
+/// `24h`), oldest first. Hours without any recorded activity are omitted.
+pub async fn metrics_timeseries(
+    _: Admin<ReadStats>,
+    Query(params): Query<TimeseriesParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Response> {
+    let range = parse_range(&params.range).ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "range should be a number of hours or days, e.g. 24h or 7d",
+    })?;
+
+    let points: Vec<TimeseriesPoint> = state
+        .db
+        .rollups_since(Utc::now() - range)
+        .into_iter()
+        .map(|(hour, counts)| TimeseriesPoint { hour, counts })
+        .collect();
+
+    Ok(csv::respond(points, params.format))
+}
+
+/// Counts of the activities received from each origin instance and what became of them,
+/// busiest first
+pub async fn origin_stats(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.stats.origins(), params.format)
+}
+
+/// The upstream relays we follow and whether they have accepted our Follow
+pub async fn list_upstreams(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.db.upstreams(), params.format)
+}
+
+/// Recent notifications about events needing the operator's attention, newest first
+pub async fn notifications(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.notifications.recent(), params.format)
+}
+
+/// Posts mentioning the relay actors, newest first
+pub async fn list_messages(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.db.messages(), params.format)
+}
+
+/// Activities of types that we don't recognise, recorded if
+/// `inbox.unrecognizedActivities` is set to record or reject them.
+pub async fn list_unrecognized(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.db.unrecognized(), params.format)
+}
+
+pub async fn get_message(
+    _: Admin<ReadStats>,
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Message>> {
+    state
+        .db
+        .message(&id)
+        .map(Json)
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown message",
+        })
+}
+
+/// Delete a message once it has been dealt with
+pub async fn delete_message(
+    _: Admin<WriteInstances>,
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<StatusCode> {
+    let message = state
+        .db
+        .remove_message(&id)
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown message",
+        })?;
+
+    info!(actor=%message.actor, "deleting message");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The tracing filter directives in use, in the same format as `RUST_LOG`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogFilterDirectives {
+    pub filter: String,
+}
+
+pub async fn log_filter(
+    _: Operator,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<LogFilterDirectives>> {
+    let filter = state.log_filter.current()?;
+
+    Ok(Json(LogFilterDirectives { filter }))
+}
+
+/// Replace the log filter until the relay is restarted, e.g. with
+/// `info,actiserve::signature=trace` while debugging federation with a peer.
+pub async fn set_log_filter(
+    _: Operator,
+    Extension(state): Extension<Arc<State>>,
+    Json(req): Json<LogFilterDirectives>,
+) -> Result<Json<LogFilterDirectives>> {
+    let filter = state.log_filter.set(&req.filter)?;
+
+    Ok(Json(LogFilterDirectives { filter }))
+}
+
+pub async fn delivery_status(
+    _: Admin<ReadStats>,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let status = state.deliveries.status();
+    match params.format {
+        // Kept as a single object rather than a list of one for existing clients
+        Format::Json => Json(status).into_response(),
+        Format::Csv => csv::respond(vec![status], params.format),
+    }
+}
+
+pub async fn pause(
+    _: Admin<WriteInstances>,
+    Extension(state): Extension<Arc<State>>,
+) -> Json<QueueStatus> {
+    info!("pausing all deliveries");
+    state.deliveries.pause(None);
+
+    Json(state.deliveries.status())
+}
+
+pub async fn resume(
+    _: Admin<WriteInstances>,
+    Extension(state): Extension<Arc<State>>,
+) -> Json<QueueStatus> {
+    info!("resuming all deliveries");
+    state.deliveries.resume(None);
+
+    Json(state.deliveries.status())
+}
+
+pub async fn pause_instance(
+    _: Admin<WriteInstances>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<QueueStatus>> {
+    if state.db.instance(&domain).is_none() {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown instance",
+        });
+    }
+
+    info!(%domain, "pausing deliveries to instance");
+    state.deliveries.pause(Some(&domain));
+
+    Ok(Json(state.deliveries.status()))
+}
+
+pub async fn resume_instance(
+    _: Admin<WriteInstances>,
+    Path(domain): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Json<QueueStatus> {
+    info!(%domain, "resuming deliveries to instance");
+    state.deliveries.resume(Some(&domain));
+
+    Json(state.deliveries.status())
+}
+
+/// Unused invite codes are as good as an invite, so only the operator can list them.
+pub async fn list_invites(
+    _: Operator,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    csv::respond(state.db.invites(), params.format)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct NewInvite {
+    note: Option<String>,
+}
+
+/// Generate an invite code to be passed on to the admin of an instance that should be
+/// allowed to follow the relay when `activityPub.inviteOnly` is set.
+pub async fn create_invite(
+    _: Admin<WriteInstances>,
+    Extension(state): Extension<Arc<State>>,
+    Json(req): Json<NewInvite>,
+) -> (StatusCode, Json<Invite>) {
+    let invite = Invite::new(req.note);
+    info!(note=?invite.note, "generating invite");
+    state.db.add_invite(invite.clone());
+
+    (StatusCode::CREATED, Json(invite))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BindInvite {
+    domain: String,
+}
+
+/// Bind an invite to the domain of the instance it was given to, allowing that instance
+/// to follow the relay. Each invite can only be bound to a single domain.
+pub async fn bind_invite(
+    _: Admin<WriteInstances>,
+    Path(code): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+    Json(req): Json<BindInvite>,
+) -> Result<Json<Invite>> {
+    let domain = DomainRule::from(req.domain.as_str()).domain;
+    let invite = state.db.bind_invite(&code, &domain)?;
+    info!(%domain, "binding invite to domain");
+
+    Ok(Json(invite))
+}
+
+/// Revoke an invite. Instances that have already followed using it remain subscribed
+/// but will be rejected if they follow again.
+pub async fn revoke_invite(
+    _: Admin<WriteInstances>,
+    Path(code): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<StatusCode> {
+    let invite = state
+        .db
+        .remove_invite(&code)
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown invite",
+        })?;
+
+    info!(domain=?invite.domain, "revoking invite");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewClient {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedClient {
+    #[serde(flatten)]
+    client: ClientInfo,
+    /// Only returned when the client is created: it is not retrievable afterwards
+    client_secret: String,
+}
+
+pub async fn list_clients(
+    _: Operator,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let clients: Vec<ClientInfo> = state
+        .db
+        .oauth_clients()
+        .into_iter()
+        .map(ClientInfo::from)
+        .collect();
+
+    csv::respond(clients, params.format)
+}
+
+/// Register a new OAuth client that can request access tokens for the admin API.
+pub async fn create_client(
+    _: Operator,
+    Extension(state): Extension<Arc<State>>,
+    Json(req): Json<NewClient>,
+) -> (StatusCode, Json<CreatedClient>) {
+    let (client, client_secret) = OAuthClient::new(req.name, req.scopes);
+    info!(client_id=%client.client_id, name=%client.name, "registering OAuth client");
+    state.db.add_oauth_client(client.clone());
+
+    (
+        StatusCode::CREATED,
+        Json(CreatedClient {
+            client: client.into(),
+            client_secret,
+        }),
+    )
+}
+
+/// Remove an OAuth client, revoking any access tokens issued to it.
+pub async fn delete_client(
+    _: Operator,
+    Path(client_id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<StatusCode> {
+    state
+        .db
+        .remove_oauth_client(&client_id)
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown client",
+        })?;
+
+    info!(%client_id, "removing OAuth client");
+    state.tokens.revoke_client(&client_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogFilterDirectives, SoftwareEntry, TimeseriesPoint};
+    use crate::{
+        auth::Scope,
+        blocklist::Severity,
+        client::{NodeInfo, SoftwareInfo},
+        logging::LogFilter,
+        routes::build_routes,
+        state::{Db, State},
+        timeseries::Counts,
+    };
+    use axum::{
+        body::Body,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE, LINK},
+            Request, StatusCode,
+        },
+    };
+    use chrono::{Duration, Utc};
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, sync::Arc};
+    use tower::ServiceExt;
+    use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+    use uuid::Uuid;
+
+    #[test_case(None, StatusCode::UNAUTHORIZED; "missing token")]
+    #[test_case(Some("Bearer nope"), StatusCode::UNAUTHORIZED; "wrong token")]
+    #[test_case(Some("test-token"), StatusCode::UNAUTHORIZED; "not a bearer token")]
+    #[test_case(Some("Bearer test-token"), StatusCode::OK; "valid token")]
+    #[tokio::test]
+    async fn admin_routes_require_the_admin_token(auth: Option<&str>, expected: StatusCode) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let mut req = Request::builder().uri("/api/v1/admin/instances");
+        if let Some(auth) = auth {
+            req = req.header(AUTHORIZATION, auth);
+        }
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+
+        assert_eq!(res.status(), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("GET", "/api/v1/admin/metrics", StatusCode::OK; "granted scope")]
+    #[test_case("GET", "/api/v1/admin/history", StatusCode::FORBIDDEN; "read without scope")]
+    #[test_case("POST", "/api/v1/admin/deliveries/pause", StatusCode::FORBIDDEN; "write without scope")]
+    #[test_case("PUT", "/api/v1/admin/blocks/example.com", StatusCode::FORBIDDEN; "block without scope")]
+    #[test_case("GET", "/api/v1/admin/oauth/clients", StatusCode::UNAUTHORIZED; "client management")]
+    #[test_case("GET", "/api/v1/admin/log-filter", StatusCode::UNAUTHORIZED; "log filter")]
+    #[test_case("GET", "/api/v1/admin/invites", StatusCode::UNAUTHORIZED; "invite codes")]
+    #[tokio::test]
+    async fn access_tokens_are_limited_to_their_scopes(
+        method: &str,
+        uri: &str,
+        expected: StatusCode,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let (token, _) = state.tokens.issue("client", vec![Scope::ReadStats]);
+        let app = build_routes(Arc::new(state));
+
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn admin_blocks_are_stored_with_their_severity() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let app = build_routes(state.clone());
+
+        let req = Request::builder()
+            .method("PUT")
+            .uri("/api/v1/admin/blocks/Media.example?severity=strip_media")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(
+            state.db.domain_blocks().get("media.example"),
+            Some(&Severity::StripMedia)
+        );
+        assert_eq!(
+            state.blocklist.severity("media.example"),
+            Some(Severity::StripMedia)
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn invites_are_bound_to_a_single_domain() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let app = build_routes(state.clone());
+        let request = |method: &str, uri: String, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, "Bearer test-token")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+
+        let req = request(
+            "POST",
+            "/api/v1/admin/invites".into(),
+            r#"{"note":"friends"}"#,
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let invite: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let bind = format!(
+            "/api/v1/admin/invites/{}/bind",
+            invite["code"].as_str().unwrap()
+        );
+
+        let req = request("POST", bind.clone(), r#"{"domain":"Friends.example"}"#);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state.db.is_invited("friends.example"));
+
+        let req = request("POST", bind, r#"{"domain":"other.example"}"#);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert!(!state.db.is_invited("other.example"));
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn domain_blocks_are_paginated_mastodon_style() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        for domain in ["a.example", "b.example", "c.example"] {
+            state.blocklist.add("admin", domain, Severity::Silence);
+        }
+        let app = build_routes(Arc::new(state));
+
+        let req = Request::builder()
+            .uri("/api/v1/admin/domain_blocks?limit=1&min_id=a.example")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let link = res
+            .headers()
+            .get(LINK)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let blocks: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            link,
+            "<https://localhost/api/v1/admin/domain_blocks?limit=1&min_id=b.example>; rel=\"next\", \
+             <https://localhost/api/v1/admin/domain_blocks?limit=1&max_id=b.example>; rel=\"prev\""
+        );
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0]["domain"], "b.example");
+        assert_eq!(blocks[0]["severity"], "silence");
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn lists_can_be_exported_as_csv() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        db.add_actor_block("https://a.example/users/alice");
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let req = Request::builder()
+            .uri("/api/v1/admin/actor-blocks?format=csv")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE).unwrap(),
+            "text/csv; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"value\r\nhttps://a.example/users/alice\r\n");
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn delivery_status_can_be_exported_as_csv() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state.deliveries.pause(Some("a.example"));
+        state.deliveries.pause(Some("b.example"));
+        let app = build_routes(Arc::new(state));
+
+        let req = Request::builder()
+            .uri("/api/v1/admin/deliveries?format=csv")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE).unwrap(),
+            "text/csv; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            &body[..],
+            b"paused,paused_instances,queued\r\nfalse,a.example;b.example,0\r\n"
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("24h", StatusCode::OK, 1; "recent")]
+    #[test_case("fortnight", StatusCode::BAD_REQUEST, 0; "invalid range")]
+    #[tokio::test]
+    async fn metrics_timeseries_covers_the_requested_range(
+        range: &str,
+        expected: StatusCode,
+        n_points: usize,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let counts = Counts {
+            relayed: 3,
+            ..Default::default()
+        };
+        db.record_rollup(Utc::now() - Duration::days(3), &counts);
+        db.record_rollup(Utc::now(), &counts);
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let req = Request::builder()
+            .uri(format!("/api/v1/admin/metrics/timeseries?range={range}"))
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), expected);
+        if expected == StatusCode::OK {
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let points: Vec<TimeseriesPoint> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(points.len(), n_points);
+            assert_eq!(points[0].counts, counts);
+        }
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn software_inventory_groups_instances_by_version() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        for (domain, software, open) in [
+            ("a.example", Some(("mastodon", "4.1.0")), true),
+            ("b.example", Some(("Mastodon", "4.1.0")), false),
+            ("c.example", Some(("misskey", "13.0.0")), true),
+            ("d.example", None, false),
+        ] {
+            db.record_instance(&format!("https://{domain}/actor"), None, None)
+                .unwrap();
+            if let Some((name, version)) = software {
+                db.update_instance(domain, |instance| {
+                    instance.record_nodeinfo(NodeInfo {
+                        software: SoftwareInfo {
+                            name: name.into(),
+                            version: version.into(),
+                        },
+                        open_registrations: Some(open),
+                        metadata: None,
+                    })
+                });
+            }
+        }
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let req = Request::builder()
+            .uri("/api/v1/admin/software?name=mastodon")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let entries: Vec<SoftwareEntry> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![SoftwareEntry {
+                name: "mastodon".into(),
+                version: "4.1.0".into(),
+                open_registrations: 1,
+                instances: vec!["a.example".into(), "b.example".into()],
+            }]
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(r#"{"filter":"warn"}"#, StatusCode::OK, "warn"; "valid filter")]
+    #[test_case(r#"{"filter":"actiserve=loud"}"#, StatusCode::BAD_REQUEST, "info"; "invalid filter")]
+    #[tokio::test]
+    async fn the_log_filter_can_be_changed(body: &str, expected: StatusCode, filter: &str) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = Registry::default().with(layer);
+        state.log_filter = LogFilter::new(handle);
+        let app = build_routes(Arc::new(state));
+
+        let req = Request::builder()
+            .method("PUT")
+            .uri("/api/v1/admin/log-filter")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), expected);
+
+        let req = Request::builder()
+            .uri("/api/v1/admin/log-filter")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let current: LogFilterDirectives = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(current.filter, filter);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
@@ -0,0 +1,457 @@
+//! Declarative management of the relay state that is otherwise built up one change at a
+//! time via the admin API, so that configuration management tools can converge a relay
+//! on a desired state.
+//!
+//! Each section given in the document replaces the corresponding state entirely:
+//! anything listed is added (or updated) and anything not listed is removed. Sections
+//! left out of the document are not touched, and applying the same document again makes
+//! no further changes. Blocks and allowed instances from the config file and blocks from
+//! blocklist feeds are never modified.
+use super::Operator;
+use crate::{
+    actors::ActorMetadata,
+    blocklist::{Severity, ADMIN_SOURCE},
+    config::{DomainRule, DomainRules},
+    delivery::Delivery,
+    routes::actor_update,
+    state::State,
+    util::{host_from_uri, normalize_id},
+    Error, Result,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tracing::info;
+
+/// The desired state of the relay.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DesiredState {
+    /// Domains blocked via the admin API along with the severity of each block
+    blocks: Option<BTreeMap<String, Severity>>,
+    /// Ids of individually blocked actors
+    actor_blocks: Option<BTreeSet<String>>,
+    /// Instances allowed to subscribe when the allow list is enabled, in addition to those
+    /// in the config. Rules are given in the same way as in the config.
+    allowed_instances: Option<DomainRules>,
+    /// Profile metadata for the relay actors keyed by name. Actors that are not listed
+    /// are reset to their defaults.
+    actors: Option<BTreeMap<String, ActorMetadata>>,
+}
+
+impl DesiredState {
+    // Reject the document outright rather than applying part of it
+    fn validate(&self, state: &State) -> Result<()> {
+        let blocked = self
+            .blocks
+            .iter()
+            .flat_map(|b| b.keys())
+            .map(|d| DomainRule::from(d.as_str()));
+        let allowed = self
+            .allowed_instances
+            .iter()
+            .flat_map(|r| r.rules())
+            .cloned();
+        if blocked.chain(allowed).any(|r| r.domain.is_empty()) {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_REQUEST,
+                message: "invalid domain",
+            });
+        }
+
+        for actor in self.actor_blocks.iter().flatten() {
+            if host_from_uri(actor).is_err() {
+                return Err(Error::InvalidUri { uri: actor.clone() });
+            }
+        }
+
+        let names = self.actors.iter().flat_map(|a| a.keys());
+        for name in names {
+            if state.actor(name).is_none() {
+                return Err(Error::StatusAndMessage {
+                    status: StatusCode::BAD_REQUEST,
+                    message: "unknown actor",
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The changes made to one section of the relay state.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Changes {
+    added: Vec<String>,
+    updated: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The changes that were needed to converge on the desired state.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootstrapReport {
+    /// Whether anything was changed
+    changed: bool,
+    blocks: Changes,
+    actor_blocks: Changes,
+    allowed_instances: Changes,
+    actors: Changes,
+}
+
+/// Converge the relay on the given desired state, reporting what had to change.
+pub async fn put_bootstrap(
+    _: Operator,
+    Extension(state): Extension<Arc<State>>,
+    Json(desired): Json<DesiredState>,
+) -> Result<Json<BootstrapReport>> {
+    desired.validate(&state)?;
+
+    let mut report = BootstrapReport::default();
+    if let Some(blocks) = desired.blocks {
+        report.blocks = converge_blocks(blocks, &state);
+    }
+    if let Some(actor_blocks) = desired.actor_blocks {
+        report.actor_blocks = converge_actor_blocks(actor_blocks, &state);
+    }
+    if let Some(allowed) = desired.allowed_instances {
+        report.allowed_instances = converge_allowed_instances(allowed, &state);
+    }
+    if let Some(actors) = desired.actors {
+        report.actors = converge_actors(actors, &state);
+    }
+
+    report.changed = [
+        &report.blocks,
+        &report.actor_blocks,
+        &report.allowed_instances,
+        &report.actors,
+    ]
+    .iter()
+    .any(|c| !c.is_empty());
+
+    if report.changed {
+        info!(?report, "converged on desired relay state");
+    }
+
+    Ok(Json(report))
+}
+
+fn converge_blocks(desired: BTreeMap<String, Severity>, state: &State) -> Changes {
+    let desired: BTreeMap<String, Severity> = desired
+        .into_iter()
+        .map(|(domain, severity)| (DomainRule::from(domain.as_str()).domain, severity))
+        .collect();
+    let current = state.db.domain_blocks();
+    let mut changes = Changes::default();
+
+    let mut removed: Vec<String> = current
+        .keys()
+        .filter(|domain| !desired.contains_key(*domain))
+        .cloned()
+        .collect();
+    removed.sort();
+
+    for domain in removed {
+        state.db.remove_domain_block(&domain);
+        state.blocklist.remove(ADMIN_SOURCE, &domain);
+        changes.removed.push(domain);
+    }
+
+    for (domain, severity) in desired {
+        match current.get(&domain) {
+            Some(&current) if current == severity => continue,
+            Some(_) => changes.updated.push(domain.clone()),
+            None => changes.added.push(domain.clone()),
+        }
+
+        state.db.add_domain_block(&domain, severity);
+        state.blocklist.add(ADMIN_SOURCE, &domain, severity);
+    }
+
+    changes
+}
+
+fn converge_actor_blocks(desired: BTreeSet<String>, state: &State) -> Changes {
+    let desired: BTreeSet<String> = desired.iter().map(|id| normalize_id(id)).collect();
+    let current: BTreeSet<String> = state.db.actor_blocks().into_iter().collect();
+    let mut changes = Changes::default();
+
+    for actor in current.difference(&desired) {
+        state.db.remove_actor_block(actor);
+        changes.removed.push(actor.clone());
+    }
+
+    for actor in desired.difference(&current) {
+        state.db.add_actor_block(actor);
+        changes.added.push(actor.clone());
+    }
+
+    changes
+}
+
+fn converge_allowed_instances(desired: DomainRules, state: &State) -> Changes {
+    let rules: BTreeSet<DomainRule> = desired.rules().iter().cloned().collect();
+    let current = state.db.allowed_instances();
+
+    let changes = Changes {
+        added: rules.difference(&current).map(|r| r.to_string()).collect(),
+        updated: vec![],
+        removed: current.difference(&rules).map(|r| r.to_string()).collect(),
+    };
+    state.db.set_allowed_instances(desired);
+
+    changes
+}
+
+fn converge_actors(mut desired: BTreeMap<String, ActorMetadata>, state: &State) -> Changes {
+    let mut changes = Changes::default();
+
+    for relay in state.relay_actors() {
+        let metadata = desired.remove(relay.name).unwrap_or_default();
+        let current = relay.db.actor_metadata();
+        if metadata == current {
+            continue;
+        }
+
+        let name = relay.name.to_owned();
+        if current == ActorMetadata::default() {
+            changes.added.push(name);
+        } else if metadata == ActorMetadata::default() {
+            changes.removed.push(name);
+        } else {
+            changes.updated.push(name);
+        }
+
+        relay.db.set_actor_metadata(metadata);
+
+        // Followers only see the new profile once they're told about it
+        let update = actor_update(&relay, state);
+        let deliveries = Delivery::fanout(relay.name, &relay.db.delivery_inboxes(), &update);
+        info!(
+            actor = relay.name,
+            n_inboxes = deliveries.len(),
+            "sending profile update"
+        );
+        state.deliver(deliveries);
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        actors::ActorMetadata,
+        blocklist::Severity,
+        config::DomainRules,
+        routes::build_routes,
+        state::{Db, State},
+    };
+    use axum::{
+        body::Body,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE},
+            Request, StatusCode,
+        },
+        Router,
+    };
+    use serde_json::{json, Value};
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, sync::Arc};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    async fn put(app: &Router, body: Value) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method("PUT")
+            .uri("/api/v1/admin/bootstrap")
+            .header(AUTHORIZATION, "Bearer test-token")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn bootstrapping_converges_idempotently() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let app = build_routes(state.clone());
+
+        state.db.add_domain_block("old.example", Severity::Reject);
+        state
+            .db
+            .add_domain_block("changed.example", Severity::Silence);
+        state.db.add_actor_block("https://old.example/users/spam");
+        state
+            .db
+            .set_allowed_instances(DomainRules::new(vec!["old.example".into()]).unwrap());
+
+        let desired = json!({
+            "blocks": {
+                "changed.example": "reject",
+                "New.example": "strip_media",
+            },
+            "actorBlocks": ["https://spam.example/users/spam"],
+            "allowedInstances": [
+                "friends.example",
+                "*.pals.example",
+                { "domain": "social.mates.example", "scope": "registrableDomain" },
+            ],
+            "actors": {
+                "relay": { "name": "Friendly relay", "summary": "Relaying between friends" },
+            },
+        });
+
+        let (status, report) = put(&app, desired.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            report,
+            json!({
+                "changed": true,
+                "blocks": {
+                    "added": ["new.example"],
+                    "updated": ["changed.example"],
+                    "removed": ["old.example"],
+                },
+                "actorBlocks": {
+                    "added": ["https://spam.example/users/spam"],
+                    "updated": [],
+                    "removed": ["https://old.example/users/spam"],
+                },
+                "allowedInstances": {
+                    "added": [
+                        "*.pals.example",
+                        "friends.example",
+                        "mates.example (registrable domain)",
+                    ],
+                    "updated": [],
+                    "removed": ["old.example"],
+                },
+                "actors": { "added": ["relay"], "updated": [], "removed": [] },
+            })
+        );
+
+        let blocks = state.db.domain_blocks();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks.get("changed.example"), Some(&Severity::Reject));
+        assert_eq!(blocks.get("new.example"), Some(&Severity::StripMedia));
+        assert_eq!(
+            state.blocklist.severity("new.example"),
+            Some(Severity::StripMedia)
+        );
+        assert_eq!(state.blocklist.severity("old.example"), None);
+        assert_eq!(
+            state.db.actor_blocks(),
+            vec!["https://spam.example/users/spam".to_owned()]
+        );
+        assert!(state.db.is_allowed_instance("friends.example"));
+        assert!(state.db.is_allowed_instance("a.pals.example"));
+        assert!(state.db.is_allowed_instance("media.mates.example"));
+        assert!(!state.db.is_allowed_instance("old.example"));
+        assert_eq!(
+            state.db.actor_metadata(),
+            ActorMetadata {
+                name: Some("Friendly relay".into()),
+                summary: Some("Relaying between friends".into()),
+            }
+        );
+
+        let (status, report) = put(&app, desired).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["changed"], false);
+
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn profile_changes_are_sent_to_followers() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let app = build_routes(state.clone());
+        state
+            .db
+            .add_inbox_if_unknown("https://a.example/inbox".into(), None)
+            .unwrap();
+        let desired = json!({ "actors": { "relay": { "name": "Friendly relay" } } });
+
+        put(&app, desired.clone()).await;
+
+        let queued = state.deliveries.next_ready().unwrap();
+        let update: Value = serde_json::from_slice(queued.delivery.body.bytes()).unwrap();
+        assert_eq!(queued.delivery.inbox, "https://a.example/inbox");
+        assert_eq!(update["type"], "Update");
+        assert_eq!(update["object"]["id"], update["actor"]);
+        assert_eq!(update["object"]["name"], "Friendly relay");
+        assert!(state.deliveries.next_ready().is_none());
+
+        // Nothing has changed the second time around so there is nothing to send
+        put(&app, desired).await;
+        assert!(state.deliveries.next_ready().is_none());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn sections_left_out_are_not_touched() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let app = build_routes(state.clone());
+
+        state
+            .db
+            .add_domain_block("blocked.example", Severity::Reject);
+        state.db.add_actor_block("https://spam.example/users/spam");
+
+        let (status, report) = put(&app, json!({ "allowedInstances": [] })).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["changed"], false);
+        assert_eq!(state.db.domain_blocks().len(), 1);
+        assert_eq!(state.db.actor_blocks().len(), 1);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(json!({ "blocks": { "": "reject" } }); "empty domain")]
+    #[test_case(json!({ "blocks": { "ok.example": "obliterate" } }); "unknown severity")]
+    #[test_case(json!({ "actorBlocks": ["not a uri"] }); "invalid actor")]
+    #[test_case(json!({ "actors": { "nope": { "name": "Nope" } } }); "unknown actor")]
+    #[tokio::test]
+    async fn invalid_documents_are_not_applied(body: Value) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let app = build_routes(state.clone());
+
+        let mut body = body;
+        body["allowedInstances"] = json!(["friends.example"]);
+        let (status, _) = put(&app, body).await;
+
+        assert!(status.is_client_error());
+        assert!(!state.db.is_allowed_instance("friends.example"));
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
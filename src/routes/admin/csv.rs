@@ -0,0 +1,164 @@
+//! CSV export of the admin list endpoints, selected with `?format=csv`.
+//!
+//! Each item becomes a row and each field a column. Nested objects are flattened into
+//! dotted column names (e.g. `software.name`) and arrays of plain values are joined
+//! with `;`. Anything else is written as JSON.
+use axum::{
+    extract::Json,
+    http::{header::CONTENT_TYPE, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// The representation requested for a list of results.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    pub format: Format,
+}
+
+/// Respond with the given items in the requested format.
+pub fn respond<T: Serialize>(items: Vec<T>, format: Format) -> Response {
+    match format {
+        Format::Json => Json(items).into_response(),
+        Format::Csv => {
+            let mut res = to_csv(&items).into_response();
+            res.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/csv; charset=utf-8"),
+            );
+            res
+        }
+    }
+}
+
+/// Render the items as CSV with a header row. Columns are listed in the order they are
+/// first seen and rows without a given column leave it empty.
+pub fn to_csv<T: Serialize>(items: &[T]) -> String {
+    let rows: Vec<Vec<(String, String)>> = items
+        .iter()
+        .map(|item| {
+            let mut cells = vec![];
+            match serde_json::to_value(item).unwrap_or_default() {
+                Value::Object(fields) => flatten("", fields, &mut cells),
+                value => cells.push(("value".to_owned(), cell(value))),
+            }
+            cells
+        })
+        .collect();
+
+    let mut columns: Vec<&str> = vec![];
+    for (column, _) in rows.iter().flatten() {
+        if !columns.contains(&column.as_str()) {
+            columns.push(column);
+        }
+    }
+
+    let mut out = String::new();
+    write_row(&mut out, columns.iter().copied());
+    for row in &rows {
+        write_row(
+            &mut out,
+            columns.iter().map(|&c| {
+                row.iter()
+                    .find(|(column, _)| column == c)
+                    .map_or("", |(_, v)| v.as_str())
+            }),
+        );
+    }
+
+    out
+}
+
+fn flatten(prefix: &str, fields: Map<String, Value>, cells: &mut Vec<(String, String)>) {
+    for (key, value) in fields {
+        let column = format!("{prefix}{key}");
+        match value {
+            Value::Object(nested) => flatten(&format!("{column}."), nested, cells),
+            value => cells.push((column, cell(value))),
+        }
+    }
+}
+
+fn cell(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s,
+        Value::Array(arr) if arr.iter().all(|v| !v.is_array() && !v.is_object()) => {
+            arr.into_iter().map(cell).collect::<Vec<_>>().join(";")
+        }
+        value => value.to_string(),
+    }
+}
+
+fn write_row<'a>(out: &mut String, cells: impl Iterator<Item = &'a str>) {
+    let cells: Vec<String> = cells.map(escape).collect();
+    out.push_str(&cells.join(","));
+    out.push_str("\r\n");
+}
+
+// Spreadsheets treat cells starting with these as formulas, which remote instances
+// could otherwise use to smuggle formulas into a moderator's spreadsheet
+const FORMULA_PREFIXES: [char; 4] = ['=', '+', '-', '@'];
+
+fn escape(cell: &str) -> String {
+    let cell = if cell.starts_with(FORMULA_PREFIXES) && cell.parse::<f64>().is_err() {
+        format!("'{cell}")
+    } else {
+        cell.to_owned()
+    };
+
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    #[test_case("plain", "plain"; "plain")]
+    #[test_case("a, b", "\"a, b\""; "comma")]
+    #[test_case("say \"hi\"", "\"say \"\"hi\"\"\""; "quotes")]
+    #[test_case("=HYPERLINK(\"x\")", "\"'=HYPERLINK(\"\"x\"\")\""; "formula")]
+    #[test_case("-1.5", "-1.5"; "negative number")]
+    #[test]
+    fn cells_are_escaped(cell: &str, expected: &str) {
+        assert_eq!(escape(cell), expected);
+    }
+
+    #[test]
+    fn objects_are_flattened_into_columns() {
+        let items = vec![
+            json!({ "domain": "a.example", "flags": ["x", "y"], "software": { "name": "mastodon" } }),
+            json!({ "domain": "b.example", "software": null, "flags": [] }),
+        ];
+
+        assert_eq!(
+            to_csv(&items),
+            "domain,flags,software.name,software\r\n\
+             a.example,x;y,mastodon,\r\n\
+             b.example,,,\r\n"
+        );
+    }
+
+    #[test]
+    fn plain_values_are_a_single_column() {
+        let items = vec!["https://a.example/users/alice"];
+
+        assert_eq!(to_csv(&items), "value\r\nhttps://a.example/users/alice\r\n");
+    }
+}
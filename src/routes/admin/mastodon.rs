@@ -0,0 +1,303 @@
+//! Read-only endpoints shaped like Mastodon's admin API so that existing admin tooling
+//! and scripts can be pointed at the relay.
+//!
+//! Lists are paginated the same way Mastodon paginates them: `limit` caps the number of
+//! results, `max_id` and `min_id` return the results immediately before or after the
+//! given id, and the URLs of the neighbouring pages are given in a `Link` header. Our
+//! ids are the domains of the instances or blocks concerned and results are ordered by
+//! id.
+use super::{
+    csv::{self, Format},
+    Admin, ReadStats,
+};
+use crate::{
+    blocklist::{Block, Severity},
+    state::State,
+    Error, Result,
+};
+use axum::{
+    extract::{Extension, Json, OriginalUri, Path, Query},
+    http::{header::LINK, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// The number of results returned when no limit is requested.
+pub const DEFAULT_LIMIT: usize = 100;
+/// The most results that will be returned in a single page.
+pub const MAX_LIMIT: usize = 200;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PageParams {
+    limit: Option<usize>,
+    /// Return results immediately before this id
+    max_id: Option<String>,
+    /// Return results immediately after this id
+    min_id: Option<String>,
+    #[serde(default)]
+    pub format: Format,
+}
+
+impl PageParams {
+    /// Whether any pagination was requested at all.
+    pub fn is_empty(&self) -> bool {
+        self.limit.is_none() && self.max_id.is_none() && self.min_id.is_none()
+    }
+}
+
+/// A page of results along with the links to the pages either side of it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+    pub format: Format,
+}
+
+impl<T: Serialize> IntoResponse for Page<T> {
+    fn into_response(self) -> Response {
+        let link = [(self.next, "next"), (self.prev, "prev")]
+            .into_iter()
+            .filter_map(|(url, rel)| Some(format!("<{}>; rel=\"{rel}\"", url?)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut res = csv::respond(self.items, self.format);
+        if !link.is_empty() {
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                res.headers_mut().insert(LINK, link);
+            }
+        }
+
+        res
+    }
+}
+
+/// Select the requested page of `(id, item)` pairs, which must already be sorted by id.
+/// Links to neighbouring pages are relative to `base`, the URL of the endpoint being
+/// paginated.
+pub fn paginate<T>(items: Vec<(String, T)>, params: &PageParams, base: &str) -> Page<T> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let in_range = |id: &str| {
+        params.min_id.as_deref().map_or(true, |min| id > min)
+            && params.max_id.as_deref().map_or(true, |max| id < max)
+    };
+
+    let candidates: Vec<usize> = (0..items.len())
+        .filter(|&i| in_range(&items[i].0))
+        .collect();
+    // Paging backwards from max_id wants the results closest to it rather than the first
+    let selected = if params.max_id.is_some() && params.min_id.is_none() {
+        &candidates[candidates.len().saturating_sub(limit)..]
+    } else {
+        &candidates[..candidates.len().min(limit)]
+    };
+
+    let (first, last) = match (selected.first(), selected.last()) {
+        (Some(&first), Some(&last)) => (first, last),
+        _ => {
+            return Page {
+                items: vec![],
+                next: None,
+                prev: None,
+                format: params.format,
+            }
+        }
+    };
+
+    let link = |param: &str, id: &str| {
+        let limit = limit.to_string();
+        let mut query = vec![("limit", limit.as_str()), (param, id)];
+        if params.format == Format::Csv {
+            query.push(("format", "csv"));
+        }
+        Url::parse_with_params(base, query).ok().map(String::from)
+    };
+    let next = if last + 1 < items.len() {
+        link("min_id", &items[last].0)
+    } else {
+        None
+    };
+    let prev = if first > 0 {
+        link("max_id", &items[first].0)
+    } else {
+        None
+    };
+
+    let items = items
+        .into_iter()
+        .skip(first)
+        .take(last - first + 1)
+        .map(|(_, item)| item)
+        .collect();
+
+    Page {
+        items,
+        next,
+        prev,
+        format: params.format,
+    }
+}
+
+/// The public URL of the endpoint handling the current request, without its query.
+pub fn endpoint_url(state: &State, uri: &OriginalUri) -> String {
+    format!("https://{}{}", state.cfg.activity_pub.host, uri.0.path())
+}
+
+/// A domain block as represented by Mastodon.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainBlock {
+    id: String,
+    domain: String,
+    /// One of `suspend`, `silence` or `noop`
+    severity: String,
+    reject_media: bool,
+    reject_reports: bool,
+    private_comment: Option<String>,
+    public_comment: Option<String>,
+    obfuscate: bool,
+}
+
+impl From<Block> for DomainBlock {
+    fn from(block: Block) -> Self {
+        let severity = match block.severity {
+            Severity::StripMedia => "noop",
+            Severity::Silence => "silence",
+            Severity::Reject => "suspend",
+        };
+
+        Self {
+            id: block.domain.clone(),
+            domain: block.domain,
+            severity: severity.to_owned(),
+            reject_media: block.severity == Severity::StripMedia,
+            reject_reports: false,
+            private_comment: Some(format!("blocked by: {}", block.sources.join(", "))),
+            public_comment: None,
+            obfuscate: false,
+        }
+    }
+}
+
+fn domain_blocks(state: &State) -> Vec<(String, DomainBlock)> {
+    let mut blocks: Vec<(String, DomainBlock)> = state
+        .blocklist
+        .blocks()
+        .into_iter()
+        .map(|b| (b.domain.clone(), b.into()))
+        .collect();
+    blocks.sort_by(|a, b| a.0.cmp(&b.0));
+
+    blocks
+}
+
+/// All blocked domains, in the shape of Mastodon's `GET /api/v1/admin/domain_blocks`
+pub async fn list_domain_blocks(
+    _: Admin<ReadStats>,
+    uri: OriginalUri,
+    Query(params): Query<PageParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Page<DomainBlock> {
+    paginate(domain_blocks(&state), &params, &endpoint_url(&state, &uri))
+}
+
+pub async fn get_domain_block(
+    _: Admin<ReadStats>,
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<DomainBlock>> {
+    domain_blocks(&state)
+        .into_iter()
+        .find(|(domain, _)| *domain == id)
+        .map(|(_, block)| Json(block))
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown domain block",
+        })
+}
+
+/// The relay doesn't accept reports, so this is always empty. It is served so that
+/// tooling polling Mastodon's `GET /api/v1/admin/reports` doesn't fail outright.
+pub async fn list_reports(
+    _: Admin<ReadStats>,
+    uri: OriginalUri,
+    Query(params): Query<PageParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Page<Value> {
+    paginate(vec![], &params, &endpoint_url(&state, &uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    const BASE: &str = "https://relay.example/api/v1/admin/domain_blocks";
+
+    fn items() -> Vec<(String, u8)> {
+        (1..=5).map(|n| (format!("{n}.example"), n)).collect()
+    }
+
+    fn params(limit: usize, max_id: Option<&str>, min_id: Option<&str>) -> PageParams {
+        PageParams {
+            limit: Some(limit),
+            max_id: max_id.map(String::from),
+            min_id: min_id.map(String::from),
+            format: Format::Json,
+        }
+    }
+
+    #[test_case(params(2, None, None), &[1, 2], Some("min_id=2.example"), None; "first page")]
+    #[test_case(params(2, None, Some("2.example")), &[3, 4], Some("min_id=4.example"), Some("max_id=3.example"); "after min_id")]
+    #[test_case(params(2, Some("4.example"), None), &[2, 3], Some("min_id=3.example"), Some("max_id=2.example"); "before max_id")]
+    #[test_case(params(2, None, Some("4.example")), &[5], None, Some("max_id=5.example"); "last page")]
+    #[test_case(params(10, None, None), &[1, 2, 3, 4, 5], None, None; "everything")]
+    #[test_case(params(10, None, Some("9.example")), &[], None, None; "past the end")]
+    #[test]
+    fn pages_are_selected_by_id(
+        params: PageParams,
+        expected: &[u8],
+        next: Option<&str>,
+        prev: Option<&str>,
+    ) {
+        let page = paginate(items(), &params, BASE);
+        let link = |query: Option<&str>| {
+            query.map(|q| format!("{BASE}?limit={}&{q}", params.limit.unwrap()))
+        };
+
+        assert_eq!(page.items, expected);
+        assert_eq!(page.next, link(next));
+        assert_eq!(page.prev, link(prev));
+    }
+
+    #[test]
+    fn links_keep_the_requested_format() {
+        let params = PageParams {
+            format: Format::Csv,
+            ..params(2, None, Some("2.example"))
+        };
+        let page = paginate(items(), &params, BASE);
+
+        assert_eq!(
+            page.next.as_deref(),
+            Some(format!("{BASE}?limit=2&min_id=4.example&format=csv").as_str())
+        );
+        assert_eq!(
+            page.prev.as_deref(),
+            Some(format!("{BASE}?limit=2&max_id=3.example&format=csv").as_str())
+        );
+    }
+
+    #[test]
+    fn limits_are_capped() {
+        let items = (0..MAX_LIMIT + 10)
+            .map(|n| (format!("{n:04}"), n))
+            .collect();
+        let page = paginate(items, &params(MAX_LIMIT + 10, None, None), BASE);
+
+        assert_eq!(page.items.len(), MAX_LIMIT);
+    }
+}
@@ -0,0 +1,104 @@
+//! Advertising the Fediverse Enhancement Proposals and relay features we support so
+//! that subscribing software can adapt to how we relay activities.
+//!
+//! The catalog of FEPs can be found here:
+//!   https://codeberg.org/fediverse/fep
+use crate::{config::Config, state::State};
+use axum::{extract::Json, Extension};
+use serde::Serialize;
+use std::sync::Arc;
+
+pub async fn get(Extension(state): Extension<Arc<State>>) -> Json<Capabilities> {
+    Json(Capabilities::new(&state.cfg))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    feps: Vec<Fep>,
+    features: RelayFeatures,
+}
+
+impl Capabilities {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            feps: vec![
+                Fep {
+                    id: "ae0c",
+                    name: "Fediverse Relay Protocols: Mastodon and LitePub",
+                },
+                Fep {
+                    id: "f1d5",
+                    name: "NodeInfo in Fediverse Software",
+                },
+            ],
+            features: RelayFeatures {
+                announce_mode: true,
+                forward_mode: true,
+                hashtag_filtering: cfg.actors.iter().any(|actor| !actor.tags.is_empty()),
+            },
+        }
+    }
+}
+
+/// A Fediverse Enhancement Proposal that we implement.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fep {
+    id: &'static str,
+    name: &'static str,
+}
+
+/// How we handle activities sent to the relay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayFeatures {
+    /// Posts are wrapped in an Announce from the relay actor (LitePub style)
+    announce_mode: bool,
+    /// Deletes and Updates are forwarded to subscribers as-is (Mastodon style)
+    forward_mode: bool,
+    /// Subscribers can choose to only receive posts with particular hashtags, by
+    /// following one of the topic actors configured with `tags`
+    hashtag_filtering: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ActorConfig, state::Db};
+    use serde_json::json;
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all};
+    use uuid::Uuid;
+
+    #[test_case(None, false; "no topic actors")]
+    #[test_case(Some(&[]), false; "topic actor without tags")]
+    #[test_case(Some(&["art", "mastoart"]), true; "topic actor with tags")]
+    #[test]
+    fn capabilities_serialize_as_expected(tags: Option<&[&str]>, hashtag_filtering: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        if let Some(tags) = tags {
+            state.cfg.actors.push(ActorConfig {
+                name: "art".into(),
+                summary: None,
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                private_key_path: None,
+            });
+        }
+
+        let v = serde_json::to_value(Capabilities::new(&state.cfg)).unwrap();
+
+        assert_eq!(v["feps"][0]["id"], json!("ae0c"));
+        assert_eq!(
+            v["features"],
+            json!({
+                "announceMode": true,
+                "forwardMode": true,
+                "hashtagFiltering": hashtag_filtering,
+            })
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
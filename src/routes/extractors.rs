@@ -46,3 +46,13 @@ where
         }
     }
 }
+
+/// A helper for returning an XRD (XML) document with the correct content header
+#[derive(Debug)]
+pub struct Xrd(pub String);
+
+impl IntoResponse for Xrd {
+    fn into_response(self) -> Response {
+        ([(header::CONTENT_TYPE, "application/xrd+xml")], self.0).into_response()
+    }
+}
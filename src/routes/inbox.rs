@@ -1,263 +1,3535 @@
 use crate::{
+    activities::ActivityType,
+    actors::{RelayActor, DEFAULT_ACTOR},
+    blocklist::Severity,
+    client::RemoteActor,
+    commands::{self, Command},
+    config::{
+        FloodAction, InboxConfig, KeyChangePolicy, PolicyConfig, SubscriptionScope,
+        UnrecognizedActivities,
+    },
+    delivery::Delivery,
+    flood::{Held, Verdict},
+    ingest::{Ingested, Job},
+    integrity::{check_activity, has_attachments, strip_attachments},
+    jsonld, ldsig,
+    messages::Message,
+    notifications::NotificationKind,
+    pipeline::{Flow, Inbound},
+    policy::Decision,
     routes::extractors,
-    signature::validate_signature,
+    signature::{
+        check_coverage, check_date, check_signature, key_fingerprint, signature_algorithm,
+        signature_key_id, Outcome,
+    },
     state::State,
-    util::{host_from_uri, id_from_json},
+    stats::Event,
+    unrecognized::UnrecognizedActivity,
+    upstreams::{is_upstream, origin_actor},
+    util::{first_id, host_from_uri, id_from_json, registrable_domain},
+    visibility::{is_addressed, is_public, Visibility},
     Error, Result,
 };
 use axum::{
-    extract::{Extension, Host, Json, OriginalUri},
-    http::{header::HeaderMap, StatusCode},
+    body::{Body, Bytes, HttpBody},
+    extract::{Extension, Host, OriginalUri, Path},
+    http::{
+        header::{HeaderMap, CONTENT_LENGTH},
+        Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
+use chrono::{DateTime, Duration, Utc};
+use rsa::RsaPublicKey;
 use rustypub::{
     core::{ActivityBuilder, ObjectBuilder},
     extended::{Actor, ActorBuilder},
 };
-use serde::Deserialize;
-use serde_json::{json, Value};
-use std::sync::Arc;
-use tracing::{debug, info};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{json, value::RawValue, Value};
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+pub(crate) mod stages;
+
+pub use stages::default_pipeline;
+
 #[derive(Debug, Deserialize)]
 pub struct InboxRequest {
-    #[serde(rename = "type")]
-    ty: String,
+    #[serde(rename = "type", deserialize_with = "deserialize_type")]
+    ty: ActivityType,
+    #[serde(deserialize_with = "deserialize_actor")]
     actor: String,
     activity: Value,
 }
 
+fn deserialize_type<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<ActivityType, D::Error> {
+    Ok(ActivityType::from_value(&Value::deserialize(d)?))
+}
+
+// Misskey and its forks sometimes send the actor as an array or embedded object
+fn deserialize_actor<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<String, D::Error> {
+    let actor = Value::deserialize(d)?;
+
+    first_id(&actor)
+        .map(|id| id.to_owned())
+        .ok_or_else(|| de::Error::custom("actor has no id"))
+}
+
 #[tracing::instrument(level = "debug", fields(host, headers), err)]
 pub async fn post(
     headers: HeaderMap,
     Host(host): Host,
     OriginalUri(uri): OriginalUri,
     Extension(state): Extension<Arc<State>>,
-    Json(req): Json<InboxRequest>,
-) -> Result<extractors::Activity<Value>> {
-    let actor = state.client.get_actor(&req.actor).await?;
-
-    validate_signature(&actor, "post", uri.path(), &headers)?;
-    validate_request(&actor, &req.ty, &state).await?;
-
-    match req.ty.as_str() {
-        "Announce" | "Create" => handle_relay(&actor, req.activity, &host, state).await?,
-        "Delete" | "Update" => handle_forward(&actor, req.activity, state).await?,
-        "Follow" => handle_follow(&actor, req.activity, &host, state).await?,
-        "Undo" => handle_undo(&actor, req.activity, state).await?,
-        _ => (),
+    body: Bytes,
+) -> Result<Response> {
+    handle_post(DEFAULT_ACTOR, &headers, &host, uri.path(), &state, &body).await
+}
+
+/// The inbox of one of our topic actors
+#[tracing::instrument(level = "debug", fields(host, headers), err)]
+pub async fn post_for_topic(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Host(host): Host,
+    OriginalUri(uri): OriginalUri,
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Result<Response> {
+    handle_post(&name, &headers, &host, uri.path(), &state, &body).await
+}
+
+/// The label used in place of the sending domain for requests whose signature wasn't
+/// verified, as anyone can claim to be sending from any domain.
+pub const UNVERIFIED_ORIGIN: &str = "unverified";
+
+// Slow or oversized requests are flagged (tagged with the sending domain once the
+// request has been verified) so that operators can identify abusive or broken peers.
+async fn handle_post(
+    name: &str,
+    headers: &HeaderMap,
+    host: &str,
+    path: &str,
+    state: &State,
+    body: &[u8],
+) -> Result<Response> {
+    // Nothing can be larger than a video so anything over that limit isn't parsed at all
+    let cfg = &state.cfg.inbox;
+    check_payload_size(body, cfg.max_payload_bytes.max(cfg.max_video_payload_bytes))?;
+
+    // The raw body is needed to check the digest of signed requests
+    let req = parse_request(body, state)?;
+    let domain = host_from_uri(&req.actor).unwrap_or_else(|_| "unknown".to_owned());
+    let ty = req.ty;
+    state
+        .metrics
+        .incr("actiserve_inbox_activities_total", &[("type", ty.as_str())]);
+
+    let start = Instant::now();
+    let mut verified = false;
+    let res = process_post(name, headers, host, path, state, req, body, &mut verified).await;
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+
+    let label = if verified { &domain } else { UNVERIFIED_ORIGIN };
+    state.record_origin_event(label, Event::Received);
+    if res.is_err() {
+        state.record_origin_event(label, Event::Rejected);
+    }
+
+    let size = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(size) = size.filter(|&s| s > state.cfg.inbox.large_payload_bytes) {
+        warn!(%domain, verified, %ty, size, "large inbox payload");
+        state.metrics.incr(
+            "actiserve_inbox_large_payloads_total",
+            &[("instance", label)],
+        );
+    }
+
+    if elapsed_ms > state.cfg.inbox.slow_request_millis {
+        warn!(%domain, verified, %ty, elapsed_ms, "slow inbox request");
+        state.metrics.incr(
+            "actiserve_inbox_slow_requests_total",
+            &[("instance", label)],
+        );
+    }
+
+    res
+}
+
+// Requests are checked as far as their signature before we respond, with the rest of
+// the pipeline being run in the background by the ingest workers
+pub(crate) const ACCEPT_AFTER_STAGE: &str = "signature";
+
+// `verified` is set once the request has made it past the signature check, after which
+// it can safely be attributed to the domain of its actor
+#[allow(clippy::too_many_arguments)]
+async fn process_post(
+    name: &str,
+    headers: &HeaderMap,
+    host: &str,
+    path: &str,
+    state: &State,
+    req: InboxRequest,
+    body: &[u8],
+    verified: &mut bool,
+) -> Result<Response> {
+    check_payload_size(body, max_payload_bytes(&req.activity, &state.cfg.inbox))?;
+    let relay = state.actor(name).ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "unknown actor",
+    })?;
+
+    let mut inbound = Inbound {
+        relay,
+        headers,
+        host,
+        path,
+        body,
+        ty: req.ty,
+        actor_id: req.actor,
+        activity: req.activity,
+        actor: None,
+    };
+    let flow = state
+        .pipeline
+        .run_until(ACCEPT_AFTER_STAGE, &mut inbound, state)
+        .await?;
+    *verified = flow == Flow::Continue;
+    if flow == Flow::Continue {
+        // Everything after this point relies on relayed activities having an object id,
+        // and anything without one is no use to our subscribers anyway
+        if inbound.is_relayable() {
+            id_from_json(&inbound.activity)?;
+        }
+        check_unrecognized(&inbound, state).await?;
+        let actor = inbound.actor.take();
+        if !state.ingest.enqueue(Ingested::new(&inbound), actor) {
+            let domain = host_from_uri(&inbound.actor_id).unwrap_or_default();
+            warn!(%domain, "ingest queue full: refusing inbox request");
+            state
+                .metrics
+                .incr("actiserve_ingest_refused_total", &[("instance", &domain)]);
+
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                message: "too many activities waiting to be processed",
+            });
+        }
+    }
+
+    Ok(accepted(state))
+}
+
+fn check_payload_size(body: &[u8], max_bytes: u64) -> Result<()> {
+    if body.len() as u64 > max_bytes {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            message: "payload too large",
+        });
+    }
+
+    Ok(())
+}
+
+fn max_payload_bytes(activity: &Value, cfg: &InboxConfig) -> u64 {
+    if is_peertube_object(&activity["object"]) {
+        cfg.max_video_payload_bytes
+    } else {
+        cfg.max_payload_bytes
+    }
+}
+
+// PeerTube videos and playlists are only partially understood by most other software,
+// which works from the object embedded in the Create rather than fetching it back by id
+pub(crate) fn is_peertube_object(object: &Value) -> bool {
+    let is_video = |ty: &Value| matches!(ty.as_str(), Some("Video" | "Playlist"));
+
+    match &object["type"] {
+        Value::Array(types) => types.iter().any(is_video),
+        ty => is_video(ty),
+    }
+}
+
+/// Reject request bodies larger than anything that we would accept (the limit for
+/// videos) while they are being read, rather than buffering all of them first.
+pub(crate) async fn limit_body(req: Request<Body>, next: Next<Body>) -> Result<Response> {
+    let max_bytes = match req.extensions().get::<Arc<State>>() {
+        Some(state) => {
+            let cfg = &state.cfg.inbox;
+            cfg.max_payload_bytes.max(cfg.max_video_payload_bytes) as usize
+        }
+        None => return Ok(next.run(req).await),
+    };
+    let too_large = || Error::StatusAndMessage {
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        message: "payload too large",
+    };
+
+    let (parts, mut body) = req.into_parts();
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "unable to read request body",
+        })?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+// Only activities from subscribers (or upstream relays) whose signature has been checked
+// are recorded, so that the record can't be filled up by anyone able to send us a request
+async fn check_unrecognized(inbound: &Inbound<'_>, state: &State) -> Result<()> {
+    let policy = state.cfg.inbox.unrecognized_activities;
+    if inbound.ty != ActivityType::Other || policy == UnrecognizedActivities::Ignore {
+        return Ok(());
+    }
+
+    if is_subscribed(inbound, state).await {
+        let origin = host_from_uri(&inbound.actor_id)?;
+        let unrecognized = UnrecognizedActivity::new(&origin, &inbound.activity);
+        info!(%origin, activity_type=%unrecognized.activity_type, "unrecognized activity type");
+        state.metrics.incr(
+            "actiserve_unrecognized_activities_total",
+            &[("instance", &origin)],
+        );
+        state.db.record_unrecognized(unrecognized);
+    }
+
+    if policy == UnrecognizedActivities::Reject {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message: "unsupported activity type",
+        });
+    }
+
+    Ok(())
+}
+
+// The same check as the subscription stage, which isn't run until after the activity has
+// been accepted
+async fn is_subscribed(inbound: &Inbound<'_>, state: &State) -> bool {
+    if is_upstream(&inbound.actor_id, state) {
+        return true;
+    }
+
+    let scope = state.cfg.activity_pub.subscription_scope;
+    match inbound.actor() {
+        Ok(actor) => validate_request(&inbound.relay, actor, inbound.ty, scope)
+            .await
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Run the rest of the inbox pipeline for an activity accepted by one of our inboxes,
+/// retrying it later if that fails for a reason that may be transient.
+pub(crate) async fn process_ingested(mut job: Job, state: &State) {
+    let id = job.ingested.id;
+    let attempts = job.ingested.attempts + 1;
+    let waited_ms = (Utc::now() - job.ingested.received_at)
+        .num_milliseconds()
+        .max(0) as u64;
+
+    let start = Instant::now();
+    let res = run_ingested(&mut job, state).await;
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+
+    let outcome = match res {
+        Ok(_) => {
+            state.ingest.complete(id);
+            "processed"
+        }
+
+        Err(e) if is_transient(&e) && attempts < state.cfg.ingest.max_attempts => {
+            debug!(%id, error=%e, attempts, "processing accepted activity failed");
+            state.ingest.retry(job);
+            "retried"
+        }
+
+        Err(e) => {
+            warn!(%id, error=%e, attempts, "giving up on accepted activity");
+            state.ingest.complete(id);
+            "failed"
+        }
     };
 
-    Ok(extractors::Activity(json!({})))
+    state
+        .metrics
+        .incr("actiserve_ingest_processed_total", &[("outcome", outcome)]);
+    state
+        .metrics
+        .incr_by("actiserve_ingest_processing_millis_total", &[], elapsed_ms);
+    state
+        .metrics
+        .incr_by("actiserve_ingest_wait_millis_total", &[], waited_ms);
+}
+
+async fn run_ingested(job: &mut Job, state: &State) -> Result<Flow> {
+    let ingested = &job.ingested;
+    let req = parse_request(ingested.body.as_bytes(), state)?;
+    let relay = state
+        .actor(&ingested.relay)
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown actor",
+        })?;
+
+    // Activities restored after a restart need their actor fetching again
+    let actor = match job.actor.take() {
+        Some(actor) => actor,
+        None => state.client.get_actor(&req.actor).await?,
+    };
+
+    let headers = ingested.header_map();
+    let mut inbound = Inbound {
+        relay,
+        headers: &headers,
+        host: &ingested.host,
+        path: &ingested.path,
+        body: ingested.body.as_bytes(),
+        ty: req.ty,
+        actor_id: req.actor,
+        activity: req.activity,
+        actor: Some(actor),
+    };
+    let mut completed = ingested
+        .completed_stage
+        .clone()
+        .unwrap_or_else(|| ACCEPT_AFTER_STAGE.to_owned());
+    let res = state
+        .pipeline
+        .resume_after(&mut completed, &mut inbound, state)
+        .await;
+    job.actor = inbound.actor.take();
+    job.ingested.completed_stage = Some(completed);
+
+    res
+}
+
+/// Run an Announce held back while its origin was throttled through the stages that
+/// follow the flood check.
+pub(crate) async fn relay_held(held: Held, state: &State) -> Result<Flow> {
+    let relay = state.actor(&held.relay).ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "unknown actor",
+    })?;
+    let actor = state.client.get_actor(&held.actor_id).await?;
+    let body = held.activity.to_string();
+
+    let headers = HeaderMap::new();
+    let mut inbound = Inbound {
+        relay,
+        headers: &headers,
+        host: &held.host,
+        path: &held.path,
+        body: body.as_bytes(),
+        ty: ActivityType::from_value(&held.activity["type"]),
+        actor_id: held.actor_id,
+        activity: held.activity,
+        actor: Some(actor),
+    };
+
+    state.pipeline.run_after("flood", &mut inbound, state).await
+}
+
+// Failures talking to other servers may well succeed later on, whereas anything
+// wrong with the activity itself will not
+fn is_transient(e: &Error) -> bool {
+    match e {
+        Error::FailedRequest { status, .. } | Error::StatusAndMessage { status, .. } => {
+            status.is_server_error()
+                || *status == StatusCode::REQUEST_TIMEOUT
+                || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+fn parse_request(body: &[u8], state: &State) -> Result<InboxRequest> {
+    let invalid = |_| Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "invalid inbox request",
+    };
+
+    if !state.cfg.inbox.normalize_json_ld {
+        return serde_json::from_slice(body).map_err(invalid);
+    }
+
+    let mut req: Value = serde_json::from_slice(body).map_err(invalid)?;
+    jsonld::normalize(&mut req);
+
+    serde_json::from_value(req).map_err(invalid)
+}
+
+// The activity in an inbox request exactly as it was serialized by the sender
+fn raw_activity(body: &[u8]) -> Option<&[u8]> {
+    #[derive(Deserialize)]
+    struct RawRequest<'a> {
+        #[serde(borrow)]
+        activity: &'a RawValue,
+    }
+
+    serde_json::from_slice::<RawRequest<'_>>(body)
+        .ok()
+        .map(|req| req.activity.get().as_bytes())
+}
+
+// Activities are accepted with an empty 202 unless configured to respond as older
+// versions did, some peers logging a warning for any response body
+fn accepted(state: &State) -> Response {
+    if state.cfg.inbox.legacy_response {
+        extractors::Activity(json!({})).into_response()
+    } else {
+        StatusCode::ACCEPTED.into_response()
+    }
+}
+
+// An actor whose inbox or key lives on a different site to the actor itself could point
+// our deliveries at someone else's inbox, so everything must share a registrable domain
+fn check_follow_domains(actor_id: &str, related: &[&str]) -> Result<()> {
+    let domain = registrable_domain(&host_from_uri(actor_id)?);
+
+    for uri in related {
+        if registrable_domain(&host_from_uri(uri)?) != domain {
+            warn!(actor=%actor_id, %uri, "rejecting follow with mismatched domains");
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::FORBIDDEN,
+                message: "actor, inbox and key domains do not match",
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Tracked per instance so that interop problems with particular peers stand out
+fn record_signature_outcome(actor_id: &str, headers: &HeaderMap, outcome: Outcome, state: &State) {
+    let domain = host_from_uri(actor_id).unwrap_or_else(|_| "unknown".to_owned());
+    if outcome != Outcome::Ok {
+        debug!(%domain, outcome = outcome.as_str(), "signature validation failed");
+    }
+
+    state.metrics.incr(
+        "actiserve_signature_validations_total",
+        &[
+            ("outcome", outcome.as_str()),
+            ("algorithm", signature_algorithm(headers)),
+            ("instance", &domain),
+        ],
+    );
+}
+
+// Checked before fetching the actor so that we never make requests to blocked instances
+fn check_not_blocked(actor_id: &str, state: &State) -> Result<()> {
+    let domain = host_from_uri(actor_id)?;
+    let sources = state.blocklist.blocked_by(&domain);
+
+    if sources.is_empty() {
+        return Ok(());
+    }
+
+    info!(%domain, ?sources, "rejecting request from blocked instance");
+    Err(Error::StatusAndMessage {
+        status: StatusCode::FORBIDDEN,
+        message: "instance is blocked",
+    })
+}
+
+// Why a Follow from the given actor should be rejected, if it should be. Follows from
+// blocked instances never get this far as they are dropped by the blocklist stage
+fn follow_rejection(actor_id: &str, state: &State) -> Result<Option<&'static str>> {
+    let domain = host_from_uri(actor_id)?;
+    let ap = &state.cfg.activity_pub;
+    if ap.allow_list
+        && !ap.allowed_instances.matches(&domain)
+        && !state.db.is_allowed_instance(&domain)
+    {
+        info!(%domain, "rejecting follow from instance not on the allow list");
+        return Ok(Some(
+            "This relay only accepts followers from an approved list of instances",
+        ));
+    }
+
+    if ap.invite_only && !state.db.is_invited(&domain.to_ascii_lowercase()) {
+        info!(%domain, "rejecting follow from instance without an invite");
+        return Ok(Some(
+            "This relay only accepts followers that have been invited",
+        ));
+    }
+
+    // Instances subscribed to several of our relay actors only count once, and those
+    // already subscribed to any of them are free to follow another as it costs us no
+    // new instance to deliver to
+    if let Some(max) = state.cfg.max_subscribers {
+        let domains: BTreeSet<String> = state
+            .relay_actors()
+            .iter()
+            .flat_map(|r| r.db.subscribed_domains())
+            .collect();
+        let subscribers = domains.len();
+        if subscribers >= max && !domains.contains(&domain) {
+            info!(%domain, %subscribers, "rejecting follow as the relay is full");
+            return Ok(Some(
+                "This relay has reached its maximum number of subscribers",
+            ));
+        }
+    }
+
+    Ok(None)
+}
+
+async fn validate_request(
+    relay: &RelayActor<'_>,
+    actor: &Actor,
+    ty: ActivityType,
+    scope: SubscriptionScope,
+) -> Result<()> {
+    // TODO: reject the request based on config (banned actors / software etc)
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+
+    let actor_domain = host_from_uri(actor_id)?;
+    if ty != ActivityType::Follow && relay.db.matching_inbox(&actor_domain, scope).is_none() {
+        info!(actor=%actor_id, "rejecting actor for trying to POST without following");
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::UNAUTHORIZED,
+            message: "access denied",
+        });
+    }
+
+    Ok(())
+}
+
+// A subscriber presenting a different key to the one we pinned when they followed may
+// indicate that their domain has been taken over, so we don't silently trust it.
+fn check_pinned_key(relay: &RelayActor<'_>, actor: &Actor, state: &State) -> Result<()> {
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+    let fingerprint = key_fingerprint(&actor.key()?)?;
+
+    if relay.db.check_actor_key(actor_id, &fingerprint)? {
+        return Ok(());
+    }
+
+    let instance = host_from_uri(actor_id)?;
+    warn!(actor=%actor_id, %fingerprint, "actor key does not match pinned key");
+    state
+        .metrics
+        .incr("actiserve_key_changes_total", &[("instance", &instance)]);
+
+    match state.cfg.key_change_policy {
+        KeyChangePolicy::Flag => Ok(()),
+        KeyChangePolicy::Reject => Err(Error::StatusAndMessage {
+            status: StatusCode::UNAUTHORIZED,
+            message: "actor key has changed",
+        }),
+    }
 }
 
-async fn validate_request(actor: &Actor, ty: &str, state: &State) -> Result<()> {
-    // TODO: reject the request based on config (block list, banned actors / software etc)
+// Activities from newly subscribed instances are accepted but not relayed until the
+// instance has been through its probation period.
+fn is_quarantined(relay: &RelayActor<'_>, actor: &Actor, state: &State) -> Result<bool> {
     let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
         status: StatusCode::BAD_REQUEST,
         message: "actor has no id",
     })?;
+    let instance = host_from_uri(actor_id)?;
+
+    match relay.db.instance(&instance) {
+        Some(i) if i.is_quarantined() => {
+            let until = i.quarantined_until;
+            info!(actor=%actor_id, ?until, "not relaying activity from quarantined instance");
+            state.metrics.incr(
+                "actiserve_quarantined_activities_total",
+                &[("instance", &instance)],
+            );
+            state.record_origin_event(&instance, Event::Filtered);
+
+            Ok(true)
+        }
+
+        _ => Ok(false),
+    }
+}
+
+// Instances blocked with a lesser severity than reject may still follow the relay, but
+// their activities are either not relayed at all or relayed without media attachments.
+// Returns whether the activity should still be relayed.
+async fn apply_block_severity(actor_id: &str, activity: &mut Value, state: &State) -> Result<bool> {
+    let domain = host_from_uri(actor_id)?;
+
+    match state.blocklist.severity(&domain) {
+        Some(Severity::Silence) => {
+            info!(actor=%actor_id, "not relaying activity from silenced instance");
+            state.metrics.incr(
+                "actiserve_silenced_activities_total",
+                &[("instance", &domain)],
+            );
+            state.record_origin_event(&domain, Event::Filtered);
+
+            Ok(false)
+        }
+
+        Some(Severity::StripMedia) => match strip_media(activity, state).await {
+            Ok(true) => Ok(true),
+            Err(e) if is_transient(&e) => Err(e),
+            res => {
+                if let Err(e) = res {
+                    warn!(actor=%actor_id, error=%e, "unable to fetch object to check for media");
+                }
+                info!(actor=%actor_id, "not relaying media from strip_media instance");
+                state.metrics.incr(
+                    "actiserve_stripped_media_activities_total",
+                    &[("instance", &domain)],
+                );
+                state.record_origin_event(&domain, Event::Filtered);
+
+                Ok(false)
+            }
+        },
+
+        _ => Ok(true),
+    }
+}
+
+// Creates and Announces are relayed as an Announce of the object id, so subscribers fetch
+// the object (media and all) from its origin: the only way to keep its media from them is
+// not to relay it. Everything else is forwarded as received, where we can remove the
+// attachments ourselves unless the activity is LD signed, as the signature would then no
+// longer verify. Returns whether the activity can be relayed.
+async fn strip_media(activity: &mut Value, state: &State) -> Result<bool> {
+    match ActivityType::from_value(&activity["type"]) {
+        ActivityType::Create | ActivityType::Announce => match &activity["object"] {
+            Value::String(object_id) => {
+                let object = state.fetch_object(object_id).await?;
+                Ok(!has_attachments(&object))
+            }
+            object => Ok(!has_attachments(object)),
+        },
+
+        _ if activity.get("signature").is_some() => Ok(!has_attachments(&activity["object"])),
+
+        _ => {
+            if strip_attachments(activity) {
+                debug!("stripped media from activity");
+            }
+
+            Ok(true)
+        }
+    }
+}
+
+// Activities by blocked actors or denied by a custom policy are accepted but not relayed
+async fn is_allowed_by_policy(actor_id: &str, activity: &mut Value, state: &State) -> Result<bool> {
+    let domain = host_from_uri(actor_id)?;
+
+    if let Some(author) = authors(actor_id, activity, state)
+        .await?
+        .into_iter()
+        .find(|a| state.db.is_actor_blocked(a))
+    {
+        info!(actor=%actor_id, %author, "not relaying activity by blocked actor");
+        state.metrics.incr(
+            "actiserve_blocked_actor_activities_total",
+            &[("instance", &domain)],
+        );
+        state.record_origin_event(&domain, Event::Filtered);
+
+        return Ok(false);
+    }
+
+    if let Some(object_type) = disallowed_object_type(activity, &state.cfg.policy) {
+        info!(actor=%actor_id, %object_type, "not relaying activity with disallowed object type");
+        state.metrics.incr(
+            "actiserve_object_type_filtered_total",
+            &[
+                ("instance", &domain),
+                ("type", object_type_label(&object_type, &state.cfg.policy)),
+            ],
+        );
+        state.record_origin_event(&domain, Event::Filtered);
+
+        return Ok(false);
+    }
+
+    match state.policy.check(&domain, activity).await {
+        Decision::Allow => Ok(true),
+        Decision::Deny => {
+            info!(actor=%actor_id, "not relaying activity denied by policy");
+            state
+                .metrics
+                .incr("actiserve_policy_denied_total", &[("instance", &domain)]);
+            state.record_origin_event(&domain, Event::Filtered);
+
+            Ok(false)
+        }
+    }
+}
+
+// Whether an activity is the same as the body it was parsed from. Normalizing JSON-LD
+// doesn't count as a modification, as it is only done for the sake of our own checks.
+fn is_unmodified(raw: &[u8], activity: &Value, state: &State) -> bool {
+    let mut original: Value = match serde_json::from_slice(raw) {
+        Ok(original) => original,
+        Err(_) => return false,
+    };
+    if state.cfg.inbox.normalize_json_ld {
+        jsonld::normalize(&mut original);
+    }
+
+    original == *activity
+}
+
+// The body to forward in place of an activity: anything we haven't modified is forwarded
+// exactly as we received it so that other servers see what its author sent, rather than
+// our normalized copy of it.
+fn unmodified_body(raw: Option<&[u8]>, activity: &Value, state: &State) -> Option<Bytes> {
+    raw.filter(|raw| is_unmodified(raw, activity, state))
+        .map(Bytes::copy_from_slice)
+}
+
+// Groups announce every activity in their community to the community's followers,
+// wrapping the original activity as the object of the Announce. Relaying that as is
+// would announce the id of an activity rather than of a post, so a wrapped Create is
+// replaced by the post it creates (credited to the Create's actor if it isn't already
+// attributed to someone). Anything else that groups announce, such as votes, edits and
+// moderation actions, isn't relayed. Returns whether the activity should be relayed.
+fn unwrap_group_announce(activity: &mut Value) -> bool {
+    let wrapped = &activity["object"];
+    if !wrapped.is_object() || wrapped.get("actor").is_none() || wrapped.get("object").is_none() {
+        return true;
+    }
+
+    let ty = ActivityType::from_value(&wrapped["type"]);
+    if ty != ActivityType::Create {
+        debug!(activity_type=%ty, "not relaying activity announced by group");
+        return false;
+    }
+
+    let author = wrapped["actor"].clone();
+    let mut object = wrapped["object"].clone();
+    if object.is_object() && object.get("attributedTo").is_none() {
+        object["attributedTo"] = author;
+    }
+    activity["object"] = object;
+
+    true
+}
+
+// Only objects that are relayed to subscribers' timelines are checked: Deletes carry a
+// Tombstone and Add/Remove only refer to an object by id. An object with several types
+// is disallowed if any of them are.
+fn disallowed_object_type(activity: &Value, cfg: &PolicyConfig) -> Option<String> {
+    let ty = ActivityType::from_value(&activity["type"]);
+    if !matches!(
+        ty,
+        ActivityType::Announce | ActivityType::Create | ActivityType::Update
+    ) {
+        return None;
+    }
+
+    let object_type = &activity["object"]["type"];
+    let types: Vec<&str> = match object_type.as_array() {
+        Some(arr) => arr.iter().flat_map(|t| t.as_str()).collect(),
+        None => object_type.as_str().into_iter().collect(),
+    };
+    let listed = |types: &[String], t: &str| types.iter().any(|listed| listed == t);
+
+    types
+        .into_iter()
+        .find(|&t| {
+            let allowed = &cfg.allowed_object_types;
+            (!allowed.is_empty() && !listed(allowed, t)) || listed(&cfg.denied_object_types, t)
+        })
+        .map(|t| t.to_owned())
+}
+
+// Object types are chosen by remote servers, so only those named in the config are used
+// as metric labels
+fn object_type_label<'a>(object_type: &'a str, cfg: &PolicyConfig) -> &'a str {
+    let mut configured = cfg
+        .allowed_object_types
+        .iter()
+        .chain(&cfg.denied_object_types);
+    if configured.any(|t| t == object_type) {
+        object_type
+    } else {
+        "other"
+    }
+}
+
+// The sending actor along with the actor and author(s) of the activity's object, which
+// for an Announce may be on a different instance to the one sending it. An Announce (or
+// Create) that only gives the id of its object doesn't say who wrote it, so the object is
+// looked up (from the object cache if we have it) to find out.
+async fn authors(actor_id: &str, activity: &Value, state: &State) -> Result<Vec<String>> {
+    let mut authors: Vec<String> = declared_authors(actor_id, activity)
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    let is_shared = matches!(
+        ActivityType::from_value(&activity["type"]),
+        ActivityType::Announce | ActivityType::Create
+    );
+    if let (Value::String(object_id), true) = (&activity["object"], is_shared) {
+        match state.fetch_object(object_id).await {
+            Ok(object) => authors.extend(attributions(&object).into_iter().map(String::from)),
+            Err(e) if is_transient(&e) => return Err(e),
+            Err(e) => warn!(%object_id, error=%e, "unable to fetch object to find its author"),
+        }
+    }
+
+    Ok(authors)
+}
+
+// The authors named by the activity itself, without looking up objects given by id
+fn declared_authors<'a>(actor_id: &'a str, activity: &'a Value) -> Vec<&'a str> {
+    let mut authors = vec![actor_id];
+    authors.extend(first_id(&activity["actor"]));
+    authors.extend(attributions(&activity["object"]));
+
+    authors
+}
+
+// attributedTo may be a single id, an embedded actor or an array of either
+fn attributions(object: &Value) -> Vec<&str> {
+    let attributed_to = &object["attributedTo"];
+    let attributions = match attributed_to.as_array() {
+        Some(arr) => arr.iter().collect(),
+        None => vec![attributed_to],
+    };
+
+    attributions
+        .into_iter()
+        .flat_map(|a| a.as_str().or_else(|| a["id"].as_str()))
+        .collect()
+}
+
+// The object of a Create is fetched back from its origin before being announced so that
+// we don't relay posts that don't exist or that are attributed to someone else. Fetches
+// that fail transiently are returned as errors so that the activity is retried.
+pub(crate) async fn verify_object(actor_id: &str, activity: &Value, state: &State) -> Result<bool> {
+    let object = &activity["object"];
+    let object_id = object["id"].as_str().or_else(|| object.as_str());
+    let mut claimed = attributions(object);
+    if claimed.is_empty() {
+        claimed.extend(first_id(&activity["actor"]));
+    }
+
+    let verified = match object_id {
+        Some(object_id) => match state.fetch_object(object_id).await {
+            Ok(fetched) => is_matching_object(&fetched, object_id, &claimed),
+            Err(e) if is_transient(&e) => return Err(e),
+            Err(e) => {
+                warn!(%object_id, error=%e, "unable to fetch object");
+                false
+            }
+        },
+        None => false,
+    };
+
+    if !verified {
+        let origin = host_from_uri(actor_id)?;
+        info!(actor=%actor_id, object=?object_id, "not relaying object that could not be verified");
+        state.metrics.incr(
+            "actiserve_unverified_objects_total",
+            &[("instance", &origin)],
+        );
+        state.record_origin_event(&origin, Event::Filtered);
+    }
+
+    Ok(verified)
+}
+
+fn is_matching_object(fetched: &Value, object_id: &str, claimed: &[&str]) -> bool {
+    let mut claimed = claimed.to_vec();
+    let mut attributed = attributions(fetched);
+    claimed.sort_unstable();
+    attributed.sort_unstable();
+
+    fetched["id"] == object_id && !attributed.is_empty() && attributed == claimed
+}
+
+// Objects are only relayed once by each relay actor however many times we receive them,
+// but a Delete of an object we relayed still needs forwarding to the instances we sent it to
+pub(crate) fn is_duplicate(
+    relay: &RelayActor<'_>,
+    activity: &Value,
+    state: &State,
+) -> Result<bool> {
+    let object_id = history_key(activity)?;
+    let is_delete = ActivityType::from_value(&activity["type"]) == ActivityType::Delete;
+
+    let duplicate = match state.history.entry(relay.name, &object_id) {
+        Some(entry) if is_delete && !entry.deleted => false,
+        Some(entry) => {
+            info!(%object_id, activity_id=%entry.activity_id, "ID has already been relayed");
+            true
+        }
+        None => false,
+    };
+
+    Ok(duplicate)
+}
+
+// Add and Remove (pinning and unpinning a post to a featured collection) refer to an
+// object that has usually been relayed already, so they are tracked under their own id
+// rather than that of their object. Mastodon sends them without one, in which case a
+// fragment of the object id is used instead.
+fn history_key(activity: &Value) -> Result<String> {
+    let ty = ActivityType::from_value(&activity["type"]);
+    if !matches!(ty, ActivityType::Add | ActivityType::Remove) {
+        return id_from_json(activity);
+    }
+
+    match activity["id"].as_str() {
+        Some(id) => Ok(id.to_owned()),
+        None => Ok(format!("{}#{ty}", id_from_json(activity)?)),
+    }
+}
+
+// Mastodon sends pins and unpins without an id, so they are tracked under a fragment of
+// the object id that forwarding the opposite change has to clear for the post to be
+// pinned (or unpinned) again
+fn undone_history_key(activity: &Value) -> Option<String> {
+    if activity["id"].is_string() {
+        return None;
+    }
+
+    let undone = match ActivityType::from_value(&activity["type"]) {
+        ActivityType::Add => ActivityType::Remove,
+        ActivityType::Remove => ActivityType::Add,
+        _ => return None,
+    };
+
+    Some(format!("{}#{undone}", id_from_json(activity).ok()?))
+}
+
+// Mastodon pins and unpins posts with an Add or Remove targeting the author's featured
+// collection. These have no addressing of their own as the collection is as public as
+// the actor it belongs to.
+pub(crate) fn is_featured_update(actor: &RemoteActor, activity: &Value) -> bool {
+    let ty = ActivityType::from_value(&activity["type"]);
+
+    matches!(ty, ActivityType::Add | ActivityType::Remove)
+        && actor.featured.is_some()
+        && first_id(&activity["target"]) == actor.featured.as_deref()
+}
+
+// Posts mentioning the relay actor are kept for the operator rather than being relayed,
+// unless they are commands from an allowed actor. Returns whether the activity was such
+// a message.
+async fn receive_message(inbound: &Inbound<'_>, state: &State) -> Result<bool> {
+    let recipient = inbound.relay.id(inbound.host);
+    let message = match Message::from_create(&inbound.actor_id, &recipient, &inbound.activity) {
+        Some(message) => message,
+        None => return Ok(false),
+    };
+
+    let command = Command::parse(&message.summary());
+    if let Some(command) = command.filter(|_| commands::is_allowed(&inbound.actor_id, state)) {
+        let (relay, host) = (&inbound.relay, inbound.host);
+        let inbox = inbound
+            .actor()?
+            .inbox
+            .as_ref()
+            .ok_or(Error::StatusAndMessage {
+                status: StatusCode::BAD_REQUEST,
+                message: "actor has no inbox",
+            })?;
+        let text = commands::execute(command, relay, host, &inbound.actor_id, state).await?;
+        let reply = commands::reply(relay, host, &inbound.actor_id, &message.object, &text);
+        state.deliver(vec![Delivery::from_message(relay.name, inbox, &reply)?]);
+
+        return Ok(true);
+    }
+
+    // Retries and copies delivered to more than one of our inboxes are neither kept
+    // again nor counted towards the limit for their origin
+    if state.db.has_message(&message.object) {
+        debug!(actor=%inbound.actor_id, object=%message.object, "ignoring repeated message");
+        return Ok(true);
+    }
+
+    let domain = host_from_uri(origin_actor(&inbound.actor_id, &inbound.activity, state))?;
+    let max = state.cfg.inbox.max_messages_per_hour;
+    if !state.message_limits.allow(&domain, max, Instant::now()) {
+        debug!(actor=%inbound.actor_id, object=%message.object, "dropping message over the hourly limit");
+        state
+            .metrics
+            .incr("actiserve_messages_dropped_total", &[("instance", &domain)]);
+        return Ok(true);
+    }
+
+    let summary = format!("{}: {}", inbound.actor_id, message.summary());
+    let object = message.object.clone();
+    if !state.db.add_message(message) {
+        return Ok(true);
+    }
+
+    info!(actor=%inbound.actor_id, %object, "received message for the relay");
+    state.metrics.incr(
+        "actiserve_messages_received_total",
+        &[("instance", &domain)],
+    );
+    state
+        .notifications
+        .notify(NotificationKind::Message, Some(&domain), summary);
+
+    Ok(true)
+}
+
+// Every activity counts towards its origin's rate, but only Announces are held back or
+// dropped while the origin is throttled. Returns whether this activity was.
+fn is_throttled(inbound: &Inbound<'_>, state: &State) -> Result<bool> {
+    let cfg = &state.cfg.flood;
+    let origin = host_from_uri(origin_actor(&inbound.actor_id, &inbound.activity, state))?;
+    let verdict = state.flood.record(cfg, &origin, Instant::now());
+
+    if verdict == (Verdict::Throttled { newly: true }) {
+        state
+            .metrics
+            .incr("actiserve_flood_throttles_total", &[("instance", &origin)]);
+        state.notifications.notify(
+            NotificationKind::InstanceThrottled,
+            Some(&origin),
+            format!(
+                "{origin} is sending more than {}x its usual volume of activities and has been throttled for {}s",
+                cfg.threshold, cfg.throttle_secs
+            ),
+        );
+    }
+    if verdict == Verdict::Allow || inbound.ty != ActivityType::Announce {
+        return Ok(false);
+    }
+
+    let held = Held {
+        relay: inbound.relay.name.to_owned(),
+        actor_id: inbound.actor_id.clone(),
+        activity: inbound.activity.clone(),
+        host: inbound.host.to_owned(),
+        path: inbound.path.to_owned(),
+    };
+    let action = match cfg.action {
+        FloodAction::Queue if state.flood.hold(cfg, &origin, held) => "queued",
+        _ => {
+            state.record_origin_event(&origin, Event::Filtered);
+            "dropped"
+        }
+    };
+
+    debug!(actor=%inbound.actor_id, %action, "throttling announce");
+    state.metrics.incr(
+        "actiserve_flood_announces_total",
+        &[("instance", &origin), ("action", action)],
+    );
+
+    Ok(true)
+}
+
+#[tracing::instrument(level = "info", skip(relay, state, activity, raw), fields(relay = relay.name), err)]
+pub(crate) async fn handle_relay(
+    relay: &RelayActor<'_>,
+    actor: &Actor,
+    mut activity: Value,
+    raw: Option<&[u8]>,
+    host: &str,
+    state: &State,
+) -> Result<()> {
+    let object_id = id_from_json(&activity)?;
+    let object_id_uri = &object_id
+        .parse::<http::Uri>()
+        .map_err(|_e| Error::InvalidUri {
+            uri: object_id.clone(),
+        })?;
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+
+    if is_private(actor_id, &activity, state)? {
+        return Ok(());
+    }
+
+    let origin = host_from_uri(origin_actor(actor_id, &activity, state))?;
+    if !relay.accepts(&activity) {
+        debug!(%object_id, "activity does not match the relay's topic");
+        state.record_origin_event(&origin, Event::Filtered);
+        return Ok(());
+    }
+
+    if let Some(hours) = state.cfg.max_object_age_hours {
+        if is_stale(&activity, Duration::hours(hours as i64), Utc::now()) {
+            info!(%object_id, "not relaying stale object");
+            state
+                .metrics
+                .incr("actiserve_stale_objects_total", &[("instance", &origin)]);
+            state.record_origin_event(&origin, Event::Filtered);
+            return Ok(());
+        }
+    }
+
+    if !passes_integrity_checks(&mut activity, actor_id, state)? {
+        return Ok(());
+    }
+
+    // Announcing a PeerTube video by id leaves subscribers to fetch it back from its
+    // origin, which many can't make sense of, so the (signed) Create is relayed instead.
+    // That is only possible if it is exactly as its author sent it: anything modified by
+    // a policy or our own checks is announced like any other post.
+    let is_create = ActivityType::from_value(&activity["type"]) == ActivityType::Create;
+    if is_create && is_peertube_object(&activity["object"]) {
+        match unmodified_body(raw, &activity, state) {
+            Some(raw) => {
+                info!(id=%actor_id, "relaying video from actor");
+                let activity_id = activity["id"].as_str().unwrap_or(&object_id).to_owned();
+
+                return state
+                    .post_raw_for_actor(relay, actor, object_id, activity_id, &activity, raw)
+                    .await;
+            }
+            None => debug!(%object_id, "announcing modified video rather than relaying it"),
+        }
+    }
+
+    info!(id=%actor_id, "relaying post from actor");
+    let activity_id = format!("https://{host}/activities/{}", Uuid::new_v4());
+    let activity_id_uri = &activity_id
+        .parse::<http::Uri>()
+        .map_err(|_e| Error::InvalidUri {
+            uri: activity_id.clone(),
+        })?;
+
+    let our_actor = relay.id(host);
+    let actor_uri = our_actor
+        .parse::<http::Uri>()
+        .map_err(|_e| Error::InvalidUri { uri: our_actor })?;
+
+    let message = ActivityBuilder::new(
+        String::from("Announce"),
+        String::from("announcing post from actor"),
+    )
+    .to(vec![relay.followers(host)])
+    .id(activity_id_uri.clone())
+    .actor(ActorBuilder::new(String::from("Actor")).url(actor_uri))
+    .object(ObjectBuilder::new().id(object_id_uri.clone()))
+    .build();
+    let mut message = serde_json::to_value(message).map_err(|e| Error::InvalidJson {
+        uri: activity_id.clone(),
+        raw: e.to_string(),
+    })?;
+    if state.cfg.activity_pub.preserve_attribution {
+        add_attribution(&mut message, &activity, &relay.followers(host));
+    }
+
+    debug!(?message, "relaying message");
+    state
+        .post_for_actor(relay, actor, object_id, activity_id, message)
+        .await
+}
+
+// Our Announce credits the original author(s) and, for public posts, is itself addressed
+// publicly so that it is rendered as a boost rather than a followers-only share. Authors
+// are only ever taken from the object itself: the actor of the activity may well be
+// someone boosting it or a group, so if the object doesn't say who wrote it then neither
+// do we.
+fn add_attribution(announce: &mut Value, activity: &Value, followers: &str) {
+    let authors = attributions(&activity["object"]);
+
+    let mut cc: Vec<&str> = vec![];
+    if is_public(activity) {
+        announce["to"] = json!([jsonld::PUBLIC]);
+        cc.push(followers);
+    }
+    cc.extend(&authors);
+
+    if !cc.is_empty() {
+        announce["cc"] = json!(cc);
+    }
+    match authors.as_slice() {
+        [] => (),
+        [author] => announce["attributedTo"] = json!(author),
+        _ => announce["attributedTo"] = json!(authors),
+    }
+}
+
+// Followers-only posts and DMs should never be sent to a relay, but a misconfigured
+// instance could deliver them to us and we mustn't make them public
+pub(crate) fn is_addressed_publicly(
+    actor_id: &str,
+    activity: &Value,
+    state: &State,
+) -> Result<bool> {
+    if is_public(activity) {
+        return Ok(true);
+    }
+
+    let origin = host_from_uri(actor_id)?;
+    let activity_id = activity["id"].as_str().unwrap_or_default();
+    info!(actor=%actor_id, %activity_id, "not relaying activity that isn't addressed publicly");
+    state.metrics.incr(
+        "actiserve_non_public_activities_total",
+        &[("instance", &origin)],
+    );
+    state.record_origin_event(&origin, Event::Filtered);
+
+    Ok(false)
+}
+
+// A last line of defence for privacy: whatever the configured pipeline lets through,
+// posts that are only visible to specific recipients are never relayed or forwarded
+fn is_private(actor_id: &str, activity: &Value, state: &State) -> Result<bool> {
+    refuse_if_private(actor_id, activity, Visibility::of(activity), state)
+}
+
+// What matters when forwarding is who can see the object: an Update making a post
+// followers-only mustn't be forwarded whatever the Update itself is addressed to. Deletes
+// and Undos usually have no addressing of their own, which is fine for objects that we
+// have already relayed.
+fn is_private_forward(
+    relay: &RelayActor<'_>,
+    actor_id: &str,
+    activity: &Value,
+    state: &State,
+) -> Result<bool> {
+    let object = &activity["object"];
+    if is_addressed(object) {
+        return refuse_if_private(actor_id, activity, Visibility::of(object), state);
+    }
+
+    let was_relayed = forwarded_object_id(activity)
+        .map(|id| state.history.entry(relay.name, id).is_some())
+        .unwrap_or(false);
+    if was_relayed {
+        return Ok(false);
+    }
+
+    is_private(actor_id, activity, state)
+}
+
+// For an Undo this is the object of the activity being undone
+fn forwarded_object_id(activity: &Value) -> Option<&str> {
+    let object = &activity["object"];
+    match ActivityType::from_value(&activity["type"]) {
+        ActivityType::Undo => first_id(&object["object"]),
+        _ => first_id(object),
+    }
+}
+
+fn refuse_if_private(
+    actor_id: &str,
+    activity: &Value,
+    visibility: Visibility,
+    state: &State,
+) -> Result<bool> {
+    if !visibility.is_private() {
+        return Ok(false);
+    }
+
+    let origin = host_from_uri(actor_id)?;
+    let activity_id = activity["id"].as_str().unwrap_or_default();
+    warn!(actor=%actor_id, %origin, %activity_id, %visibility, "refusing to relay private activity");
+    state.metrics.incr(
+        "actiserve_private_activities_refused_total",
+        &[("instance", &origin), ("visibility", visibility.as_str())],
+    );
+    state.record_origin_event(&origin, Event::Filtered);
+
+    Ok(true)
+}
+
+// Fetching the Follow back from the instance it claims to originate from ("double
+// knocking") confirms that the instance really did send it, guarding against spoofed
+// follows being accepted on behalf of peers that don't validate signatures correctly.
+async fn verify_follow(actor_id: &str, activity: &Value, state: &State) -> Result<()> {
+    let follow_id = activity["id"].as_str().unwrap_or_default();
+    let instance = host_from_uri(actor_id)?;
+
+    let verified = match host_from_uri(follow_id) {
+        Ok(host) if host == instance => match state.client.get_activity(follow_id).await {
+            Ok(fetched) => is_matching_follow(&fetched, follow_id, actor_id),
+            Err(e) => {
+                warn!(%follow_id, error=%e, "unable to fetch follow");
+                false
+            }
+        },
+        _ => false,
+    };
+
+    if verified {
+        return Ok(());
+    }
+
+    warn!(actor=%actor_id, %follow_id, "rejecting follow that could not be verified");
+    state.metrics.incr(
+        "actiserve_unverified_follows_total",
+        &[("instance", &instance)],
+    );
+
+    Err(Error::StatusAndMessage {
+        status: StatusCode::UNAUTHORIZED,
+        message: "unable to verify follow",
+    })
+}
+
+// An activity delivered by someone other than its author is only trusted if the author
+// signed it. Failures to fetch the author's key that may be transient are returned as
+// errors so that the activity is retried.
+pub(crate) async fn check_ld_signature(
+    signer: &str,
+    author: &str,
+    activity: &Value,
+    state: &State,
+) -> Result<bool> {
+    let origin = host_from_uri(signer)?;
+
+    let outcome = match ldsig::creator(activity) {
+        None => "missing",
+        Some(creator) => match ld_signature_key(creator, author, state).await {
+            Ok(Some(key)) => match ldsig::verify(activity, &key) {
+                Ok(()) => "verified",
+                // Treated the same as not being signed at all
+                Err(e) if e == ldsig::UNVERIFIABLE_SIG => "unverifiable",
+                Err(_) => "invalid",
+            },
+            Ok(None) => "invalid",
+            Err(e) if is_transient(&e) => return Err(e),
+            Err(e) => {
+                warn!(%creator, error=%e, "unable to fetch linked data signature key");
+                "invalid"
+            }
+        },
+    };
+
+    state.metrics.incr(
+        "actiserve_ld_signatures_total",
+        &[("instance", &origin), ("outcome", outcome)],
+    );
+
+    match outcome {
+        "verified" => Ok(true),
+        "missing" | "unverifiable" if !state.cfg.inbox.require_ld_signatures => Ok(true),
+        _ => {
+            info!(%signer, %author, %outcome, "not handling activity forwarded without a valid signature from its author");
+            state.record_origin_event(&origin, Event::Filtered);
+            Ok(false)
+        }
+    }
+}
+
+// The key named as the creator of a linked data signature, as long as it belongs to the
+// author of the activity. Authors may have more than one key so the key is looked up by
+// its own id rather than taken to be the author's main key, and only keys served from
+// the author's own host are trusted to say who they belong to.
+async fn ld_signature_key(
+    creator: &str,
+    author: &str,
+    state: &State,
+) -> Result<Option<RsaPublicKey>> {
+    if host_from_uri(creator)? != host_from_uri(author)? {
+        debug!(%creator, %author, "linked data signature key is not hosted by its author");
+        return Ok(None);
+    }
+
+    let doc = state.client.get_key(creator).await?;
+    match ldsig::find_key(&doc, creator) {
+        Ok((owner, key)) if owner == author => Ok(Some(key)),
+        Ok((owner, _)) => {
+            debug!(%creator, %owner, %author, "linked data signature key belongs to someone else");
+            Ok(None)
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+fn is_matching_follow(fetched: &Value, follow_id: &str, actor_id: &str) -> bool {
+    let fetched_actor = first_id(&fetched["actor"]);
+
+    fetched["type"] == "Follow" && fetched["id"] == follow_id && fetched_actor == Some(actor_id)
+}
+
+// Offending attachments may be stripped from the activity rather than it being dropped
+// depending on the configured policy.
+fn passes_integrity_checks(activity: &mut Value, actor_id: &str, state: &State) -> Result<bool> {
+    match check_activity(activity, &state.cfg.attachments) {
+        Ok(()) => Ok(true),
+        Err(reason) => {
+            info!(%actor_id, %reason, "dropping activity that failed integrity checks");
+            let origin = host_from_uri(actor_id)?;
+            state.metrics.incr(
+                "actiserve_integrity_failures_total",
+                &[("instance", &origin)],
+            );
+            state.record_origin_event(&origin, Event::Filtered);
+
+            Ok(false)
+        }
+    }
+}
+
+// Uses the published time of the object if it is embedded in the activity, falling back
+// to the time the activity itself was published. Activities without either are never
+// considered stale.
+fn is_stale(activity: &Value, max_age: Duration, now: DateTime<Utc>) -> bool {
+    let published = activity["object"]["published"]
+        .as_str()
+        .or_else(|| activity["published"].as_str())
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok());
+
+    match published {
+        Some(published) => now.signed_duration_since(published) > max_age,
+        None => false,
+    }
+}
+
+#[tracing::instrument(level = "info", skip(relay, state, activity, raw), fields(relay = relay.name), err)]
+async fn handle_forward(
+    relay: &RelayActor<'_>,
+    actor: &RemoteActor,
+    mut activity: Value,
+    raw: Option<&[u8]>,
+    state: &State,
+) -> Result<()> {
+    let object_id = id_from_json(&activity)?;
+    let key = history_key(&activity)?;
+    let undone_key = undone_history_key(&activity);
+
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+
+    if (!is_featured_update(actor, &activity)
+        && is_private_forward(relay, actor_id, &activity, state)?)
+        || !passes_integrity_checks(&mut activity, actor_id, state)?
+    {
+        return Ok(());
+    }
+
+    info!(%actor_id, "forwarding post");
+    let raw = unmodified_body(raw, &activity, state);
+    if ActivityType::from_value(&activity["type"]) == ActivityType::Delete {
+        return state
+            .forward_delete(relay, actor, object_id, activity, raw)
+            .await;
+    }
+
+    match raw {
+        Some(raw) => {
+            state
+                .post_raw_for_actor(relay, actor, key, object_id, &activity, raw)
+                .await?
+        }
+        None => {
+            state
+                .post_for_actor(relay, actor, key, object_id, activity)
+                .await?
+        }
+    }
+
+    // Pinning a post that was previously unpinned (or the reverse) isn't a duplicate
+    if let Some(undone_key) = undone_key {
+        state.history.remove(relay.name, &undone_key);
+    }
+
+    Ok(())
+}
+
+// Reactions are only of interest to the instances that were sent the object being
+// reacted to, so they are forwarded to those and nothing else. An Undo is forwarded along
+// with the reaction it undoes.
+#[tracing::instrument(level = "info", skip(relay, state, activity, raw), fields(relay = relay.name), err)]
+async fn handle_reaction(
+    relay: &RelayActor<'_>,
+    actor: &Actor,
+    activity: Value,
+    raw: Option<&[u8]>,
+    state: &State,
+) -> Result<()> {
+    if !state.cfg.inbox.forward_reactions {
+        return Ok(());
+    }
+
+    let reaction = match ActivityType::from_value(&activity["type"]) {
+        ActivityType::Undo => &activity["object"],
+        _ => &activity,
+    };
+    let object_id = id_from_json(reaction)?;
+    let activity_id = activity["id"]
+        .as_str()
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "activity has no id",
+        })?
+        .to_owned();
+
+    if !is_reacting_to_relayed(relay, &object_id, &activity_id, state) {
+        return Ok(());
+    }
+
+    let raw = unmodified_body(raw, &activity, state);
+    state
+        .forward_reaction(relay, actor, object_id, activity_id, activity, raw)
+        .await
+}
+
+// Reactions are only forwarded once each, and only for objects that are in the history
+fn is_reacting_to_relayed(
+    relay: &RelayActor<'_>,
+    object_id: &str,
+    activity_id: &str,
+    state: &State,
+) -> bool {
+    match state.history.entry(relay.name, object_id) {
+        Some(entry) if !entry.deleted => (),
+        _ => {
+            debug!(%object_id, %activity_id, "not forwarding reaction to an object we haven't relayed");
+            return false;
+        }
+    }
+
+    if !state.reactions.insert(relay.name, activity_id) {
+        info!(%activity_id, "reaction has already been forwarded");
+        return false;
+    }
+
+    true
+}
+
+#[tracing::instrument(level = "info", skip(relay, state, activity), fields(relay = relay.name), err)]
+async fn handle_follow(
+    relay: &RelayActor<'_>,
+    actor: &RemoteActor,
+    key_id: &str,
+    activity: Value,
+    host: &str,
+    state: &State,
+) -> Result<()> {
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+    let inbox = actor.inbox.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no inbox",
+    })?;
+    let mut related = vec![inbox.as_str(), key_id];
+    related.extend(actor.shared_inbox());
+    check_follow_domains(actor_id, &related)?;
+    if state.cfg.inbox.verify_follows {
+        verify_follow(actor_id, &activity, state).await?;
+    }
+    let shared_inbox = actor.shared_inbox().map(|s| s.to_owned());
+    if relay
+        .db
+        .add_inbox_if_unknown(inbox.to_owned(), shared_inbox)?
+    {
+        // New inbox so follow the remote actor
+        let follow = state.client.follow_actor(relay.name, actor_id).await?;
+        state.deliver(vec![follow]);
+    }
+    let fingerprint = key_fingerprint(&actor.key()?)?;
+    let quarantined_until = match state.cfg.quarantine_secs {
+        0 => None,
+        secs => Some(Utc::now() + Duration::seconds(secs as i64)),
+    };
+    relay
+        .db
+        .record_instance(actor_id, Some(fingerprint), quarantined_until)?;
+    let domain = host_from_uri(actor_id)?;
+    let follow_id = activity["id"].as_str().map(String::from);
+    relay
+        .db
+        .update_instance(&domain, |instance| instance.follow_id = follow_id);
+
+    // The software inventory is best effort here, being refreshed whenever the
+    // instance is re-verified
+    match state.client.get_nodeinfo(&domain).await {
+        Ok(info) => relay
+            .db
+            .update_instance(&domain, |instance| instance.record_nodeinfo(info)),
+        Err(e) => debug!(%domain, error=%e, "unable to fetch nodeinfo"),
+    }
+
+    let our_actor = state.client.actor_id(relay.name);
+    let object_id = id_from_json(&activity)?;
+    let message_id = Uuid::new_v4();
+
+    let message = ActivityBuilder::new(String::from("Accept"), String::from("accepting follow"))
+        .to(vec![actor_id.clone()])
+        .object(
+            // FIXME: object does not have a property "actor":
+            // https://www.w3.org/TR/activitystreams-vocabulary/#types
+            // .actor
+            // object does not have a property "object":
+            // https://www.w3.org/TR/activitystreams-vocabulary/#types
+            // .object
+            ObjectBuilder::new().id(object_id
+                .parse::<http::Uri>()
+                .map_err(|_e| Error::InvalidUri { uri: object_id })?),
+        )
+        .actor(
+            ActorBuilder::new(String::from("Actor")).url(
+                our_actor
+                    .parse::<http::Uri>()
+                    .map_err(|_e| Error::InvalidUri { uri: our_actor })?,
+            ),
+        )
+        .id(format!("https://{host}/activities/{message_id}")
+            .parse::<http::Uri>()
+            .map_err(|_e| Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "failed to create parseable message id",
+            })?)
+        .build();
+
+    state.deliver(vec![Delivery::from_message(relay.name, inbox, &message)?]);
+
+    Ok(())
+}
+
+// The reason for the rejection is included as the summary of the Reject so that admins
+// of the rejected instance can see why their Follow was not accepted.
+#[tracing::instrument(level = "info", skip(relay, state, activity), fields(relay = relay.name), err)]
+async fn handle_reject(
+    relay: &RelayActor<'_>,
+    actor: &Actor,
+    activity: Value,
+    reason: &str,
+    host: &str,
+    state: &State,
+) -> Result<()> {
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+    let inbox = actor.inbox.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no inbox",
+    })?;
+
+    let our_actor = state.client.actor_id(relay.name);
+    let follow_id = match activity["id"].as_str() {
+        Some(id) => id.to_owned(),
+        None => id_from_json(&activity)?,
+    };
+    let message_id = Uuid::new_v4();
+
+    let summary = format!("{reason}. See https://{host}/about for the relay's rules");
+    let message = ActivityBuilder::new(String::from("Reject"), summary)
+        .to(vec![actor_id.clone()])
+        .object(
+            ObjectBuilder::new().id(follow_id
+                .parse::<http::Uri>()
+                .map_err(|_e| Error::InvalidUri { uri: follow_id })?),
+        )
+        .actor(
+            ActorBuilder::new(String::from("Actor")).url(
+                our_actor
+                    .parse::<http::Uri>()
+                    .map_err(|_e| Error::InvalidUri { uri: our_actor })?,
+            ),
+        )
+        .id(format!("https://{host}/activities/{message_id}")
+            .parse::<http::Uri>()
+            .map_err(|_e| Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "failed to create parseable message id",
+            })?)
+        .build();
+
+    state.metrics.incr(
+        "actiserve_rejected_follows_total",
+        &[("instance", &host_from_uri(actor_id)?)],
+    );
+    state.deliver(vec![Delivery::from_message(relay.name, inbox, &message)?]);
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "info", skip(relay, state, activity, raw), fields(relay = relay.name), err)]
+async fn handle_undo(
+    relay: &RelayActor<'_>,
+    actor: &RemoteActor,
+    activity: Value,
+    raw: Option<&[u8]>,
+    state: &State,
+) -> Result<()> {
+    let ty = match activity["object"].get("type") {
+        Some(ty) => ActivityType::from_value(ty),
+        None => {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_REQUEST,
+                message: "no object type",
+            })
+        }
+    };
+
+    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "actor has no id",
+    })?;
+
+    match ty {
+        ActivityType::Follow => {
+            relay.db.remove_inbox(actor_id)?;
+            let unfollow = state.client.unfollow_actor(relay.name, actor_id).await?;
+            state.deliver(vec![unfollow]);
+
+            Ok(())
+        }
+
+        ActivityType::Announce => handle_forward(relay, actor, activity, raw, state).await,
+
+        ty if ty.is_reaction() => handle_reaction(relay, actor, activity, raw, state).await,
+
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use super::*;
+
+    use crate::signature::tests::test_actor;
+    use crate::{
+        actors::TopicActor,
+        blocklist::ADMIN_SOURCE,
+        config::{ActorConfig, DomainRule, DomainRules, DomainScope},
+        history::HistoryEntry,
+        invites::Invite,
+        pipeline::{Pipeline, Stage},
+        state::Db,
+    };
+
+    use simple_test_case::test_case;
+    use std::{
+        env::temp_dir,
+        fs::remove_dir_all,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[test_case(&["https://example.com/inbox", "https://example.com/actor#main-key"], true; "same host")]
+    #[test_case(&["https://social.example.com/inbox", "https://example.com/actor#main-key"], true; "subdomain")]
+    #[test_case(&["https://example.co.uk/inbox"], false; "same label different suffix")]
+    #[test_case(&["https://victim.example/inbox"], false; "other inbox")]
+    #[test_case(&["https://example.com/inbox", "https://victim.example/actor#main-key"], false; "other key")]
+    #[test_case(&["https://example.com/inbox", ""], false; "missing key id")]
+    #[test]
+    fn check_follow_domains_works(related: &[&str], ok: bool) {
+        let res = check_follow_domains("https://example.com/users/relay", related);
+
+        assert_eq!(res.is_ok(), ok);
+    }
+
+    #[test_case("/inbox"; "inbox")]
+    #[test_case("/actor/inbox"; "actor inbox")]
+    #[test_case("/users/relay/inbox"; "users inbox")]
+    #[tokio::test]
+    async fn inbox_path_variants_are_routed_to_the_inbox(path: &str) {
+        use tower::ServiceExt;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let app = crate::routes::build_routes(Arc::new(State::new_with_test_key(db)));
+        let req = Request::builder()
+            .method("POST")
+            .uri(path)
+            .header("host", "localhost")
+            .body(Body::from("not an activity"))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        // Reaching the inbox handler rather than a 404
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(false, StatusCode::ACCEPTED, 0; "accepted")]
+    #[test_case(true, StatusCode::OK, 2; "legacy")]
+    #[tokio::test]
+    async fn accepted_activities_get_the_configured_response(
+        legacy: bool,
+        status: StatusCode,
+        body_len: usize,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.legacy_response = legacy;
+
+        let res = accepted(&state);
+        let res_status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+        assert_eq!(res_status, status);
+        assert_eq!(body.len(), body_len);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(json!({"type": "Add", "id": "https://a.example/pins/1", "object": "https://a.example/notes/1"}), "https://a.example/pins/1"; "add with id")]
+    #[test_case(json!({"type": "Remove", "object": "https://a.example/notes/1"}), "https://a.example/notes/1#Remove"; "remove without id")]
+    #[test_case(json!({"type": "Update", "id": "https://a.example/updates/1", "object": {"id": "https://a.example/notes/1"}}), "https://a.example/notes/1"; "update")]
+    #[test]
+    fn history_keys_work(activity: Value, expected: &str) {
+        assert_eq!(history_key(&activity).unwrap(), expected);
+    }
+
+    #[test_case("https://a.example/notes/1", "https://b.example/likes/2", false, true; "relayed")]
+    #[test_case("https://a.example/notes/1", "https://b.example/likes/2", true, false; "deleted")]
+    #[test_case("https://a.example/notes/2", "https://b.example/likes/2", false, false; "not relayed")]
+    #[test_case("https://a.example/notes/1", "https://b.example/likes/1", false, false; "already forwarded")]
+    #[test]
+    fn reactions_are_only_forwarded_for_relayed_objects(
+        object_id: &str,
+        activity_id: &str,
+        deleted: bool,
+        expected: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        state.history.record(HistoryEntry {
+            relay: relay.name.to_owned(),
+            object_id: "https://a.example/notes/1".to_owned(),
+            activity_id: "https://a.example/notes/1".to_owned(),
+            origin: "a.example".to_owned(),
+            relayed_at: Utc::now(),
+            recipients: None,
+            deleted,
+        });
+        state
+            .reactions
+            .insert(relay.name, "https://b.example/likes/1");
+
+        assert_eq!(
+            is_reacting_to_relayed(&relay, object_id, activity_id, &state),
+            expected
+        );
+        if expected {
+            assert!(
+                !is_reacting_to_relayed(&relay, object_id, activity_id, &state),
+                "forwarded twice"
+            );
+        }
+        assert!(
+            state.history.entry(relay.name, activity_id).is_none(),
+            "reaction recorded in the history"
+        );
+        state.clear();
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn pinning_a_relayed_object_is_not_a_duplicate() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        let note = "https://a.example/notes/1";
+        let add = json!({"type": "Add", "object": note, "target": "https://a.example/featured"});
+        state.history.record(HistoryEntry {
+            relay: relay.name.to_owned(),
+            object_id: note.to_owned(),
+            activity_id: note.to_owned(),
+            origin: "a.example".to_owned(),
+            relayed_at: Utc::now(),
+            recipients: None,
+            deleted: false,
+        });
+
+        assert!(is_duplicate(&relay, &json!({"type": "Create", "object": note}), &state).unwrap());
+        assert!(!is_duplicate(&relay, &add, &state).unwrap());
+
+        state.history.record(HistoryEntry {
+            relay: relay.name.to_owned(),
+            object_id: history_key(&add).unwrap(),
+            activity_id: note.to_owned(),
+            origin: "a.example".to_owned(),
+            relayed_at: Utc::now(),
+            recipients: None,
+            deleted: false,
+        });
+
+        assert!(is_duplicate(&relay, &add, &state).unwrap());
+        state.clear();
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(ActivityType::Accept; "accept")]
+    #[test_case(ActivityType::Announce; "announce")]
+    #[test_case(ActivityType::Create; "create")]
+    #[test_case(ActivityType::Delete; "delete")]
+    #[test_case(ActivityType::Undo; "undo")]
+    #[test_case(ActivityType::Update; "update")]
+    #[tokio::test]
+    async fn non_follow_for_unknown_inbox_is_an_error(ty: ActivityType) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let res = validate_request(
+            &RelayActor::main(&state),
+            &test_actor("https://example.com/actor"),
+            ty,
+            SubscriptionScope::Exact,
+        )
+        .await;
+
+        assert_eq!(
+            res,
+            Err(Error::StatusAndMessage {
+                status: StatusCode::UNAUTHORIZED,
+                message: "access denied"
+            })
+        );
+
+        state.clear();
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("https://blocked.example/actor", true; "blocked")]
+    #[test_case("https://BLOCKED.example/users/bob", true; "blocked mixed case")]
+    #[test_case("https://other.example/actor", false; "not blocked")]
+    #[test]
+    fn blocked_instances_are_rejected(actor_id: &str, blocked: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state
+            .blocklist
+            .set_source("feed", ["blocked.example".to_owned()].into())
+            .unwrap();
+
+        let res = check_not_blocked(actor_id, &state);
+
+        assert_eq!(res.is_err(), blocked);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("Follow", "https://blocked.example/actor", Some(Flow::Stop); "blocked follow")]
+    #[test_case("Create", "https://blocked.example/actor", None; "blocked create")]
+    #[test_case("Follow", "https://other.example/actor", Some(Flow::Continue); "follow")]
+    #[tokio::test]
+    async fn follows_from_blocked_instances_are_dropped(
+        ty: &str,
+        actor_id: &str,
+        expected: Option<Flow>,
+    ) {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state
+            .blocklist
+            .set_source("feed", ["blocked.example".to_owned()].into())
+            .unwrap();
+
+        let headers = HeaderMap::new();
+        let mut inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty: ActivityType::from_value(&json!(ty)),
+            actor_id: actor_id.into(),
+            activity: json!({ "type": ty, "actor": actor_id, "object": "https://relay.example/actor" }),
+            actor: None,
+        };
+
+        let flow = stages::Blocklist.run(&mut inbound, &state).await;
+
+        assert_eq!(flow.ok(), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("https://relay.example/actor", json!("https://other.example/alice"), true; "not blocked")]
+    #[test_case("https://blocked.example/bob", json!("https://other.example/alice"), false; "blocked sender")]
+    #[test_case("https://relay.example/actor", json!("https://blocked.example/bob"), false; "blocked author")]
+    #[test_case("https://relay.example/actor", json!([{ "id": "https://blocked.example/bob" }]), false; "blocked in list")]
+    #[test_case("https://relay.example/actor", json!("https://BLOCKED.example/bob/"), false; "blocked author not normalized")]
+    #[tokio::test]
+    async fn activities_by_blocked_actors_are_dropped(
+        actor_id: &str,
+        attributed_to: Value,
+        relayed: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state.db.add_actor_block("https://blocked.example/bob");
+        let mut activity = json!({
+            "type": "Announce",
+            "actor": actor_id,
+            "object": { "type": "Note", "attributedTo": attributed_to },
+        });
+
+        let res = is_allowed_by_policy(actor_id, &mut activity, &state).await;
+
+        assert_eq!(res.unwrap(), relayed);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("https://blocked.example/bob", false; "blocked author")]
+    #[test_case("https://other.example/alice", true; "not blocked")]
+    #[tokio::test]
+    async fn announced_object_ids_are_fetched_to_find_their_author(
+        attributed_to: &str,
+        relayed: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state.db.add_actor_block("https://blocked.example/bob");
+        let id = "https://other.example/notes/1";
+        let note = json!({ "id": id, "type": "Note", "attributedTo": attributed_to });
+        state.objects.insert(id, note, Utc::now());
+        let actor_id = "https://relay.example/actor";
+        let mut activity = json!({ "type": "Announce", "actor": actor_id, "object": id });
+
+        let res = is_allowed_by_policy(actor_id, &mut activity, &state).await;
+
+        assert_eq!(res.unwrap(), relayed);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("Announce", vec!["https://relay.example/actor", "https://relay.example/actor", "https://other.example/alice"]; "announce")]
+    #[test_case("Create", vec!["https://relay.example/actor", "https://relay.example/actor", "https://other.example/alice"]; "create")]
+    #[test_case("Like", vec!["https://relay.example/actor", "https://relay.example/actor"]; "like")]
+    #[tokio::test]
+    async fn authors_of_objects_given_by_id_are_looked_up(ty: &str, expected: Vec<&str>) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let id = "https://other.example/notes/1";
+        let note =
+            json!({ "id": id, "type": "Note", "attributedTo": "https://other.example/alice" });
+        state.objects.insert(id, note, Utc::now());
+        let actor_id = "https://relay.example/actor";
+        let activity = json!({ "type": ty, "actor": actor_id, "object": id });
+
+        let res = authors(actor_id, &activity, &state).await;
+
+        assert_eq!(res.unwrap(), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    // An Announce of a new post to a Lemmy community, as sent to the community's followers
+    fn lemmy_announce(wrapped_type: &str) -> Value {
+        json!({
+            "@context": ["https://join-lemmy.org/context.json", "https://www.w3.org/ns/activitystreams"],
+            "actor": "https://lemmy.example/c/rust",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": {
+                "actor": "https://lemmy.example/u/alice",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": {
+                    "type": "Page",
+                    "id": "https://lemmy.example/post/1",
+                    "attributedTo": "https://lemmy.example/u/alice",
+                    "to": ["https://lemmy.example/c/rust", "https://www.w3.org/ns/activitystreams#Public"],
+                    "name": "Announcing Rust 1.80",
+                    "content": "<p>Release notes</p>",
+                    "mediaType": "text/html",
+                    "published": "2024-07-25T10:00:00Z",
+                    "audience": "https://lemmy.example/c/rust"
+                },
+                "cc": ["https://lemmy.example/c/rust"],
+                "type": wrapped_type,
+                "id": "https://lemmy.example/activities/create/8c1d4f6e",
+                "audience": "https://lemmy.example/c/rust"
+            },
+            "cc": ["https://lemmy.example/c/rust/followers"],
+            "type": "Announce",
+            "id": "https://lemmy.example/activities/announce/5a2b9e04"
+        })
+    }
+
+    #[test]
+    fn group_announces_of_creates_are_unwrapped() {
+        let mut activity = lemmy_announce("Create");
+
+        assert!(unwrap_group_announce(&mut activity));
+        assert_eq!(
+            id_from_json(&activity).unwrap(),
+            "https://lemmy.example/post/1"
+        );
+        assert_eq!(
+            declared_authors("https://lemmy.example/c/rust", &activity),
+            vec![
+                "https://lemmy.example/c/rust",
+                "https://lemmy.example/c/rust",
+                "https://lemmy.example/u/alice"
+            ]
+        );
+        assert!(is_public(&activity));
+    }
+
+    #[test]
+    fn unwrapped_group_posts_are_attributed_to_the_creating_actor() {
+        let mut activity = lemmy_announce("Create");
+        activity["object"]["object"]
+            .as_object_mut()
+            .unwrap()
+            .remove("attributedTo");
+
+        assert!(unwrap_group_announce(&mut activity));
+        assert_eq!(
+            activity["object"]["attributedTo"],
+            "https://lemmy.example/u/alice"
+        );
+    }
+
+    #[test_case("Like"; "vote")]
+    #[test_case("Update"; "edit")]
+    #[test_case("Delete"; "removal")]
+    #[test]
+    fn other_group_announces_are_not_relayed(wrapped_type: &str) {
+        let mut activity = lemmy_announce(wrapped_type);
+
+        assert!(!unwrap_group_announce(&mut activity));
+    }
+
+    #[test_case(json!("https://lemmy.example/post/1"); "by id")]
+    #[test_case(json!({"type": "Page", "id": "https://lemmy.example/post/1"}); "embedded object")]
+    #[test]
+    fn group_announces_of_objects_are_left_alone(object: Value) {
+        let mut activity = json!({"type": "Announce", "object": object.clone()});
+
+        assert!(unwrap_group_announce(&mut activity));
+        assert_eq!(activity["object"], object);
+    }
+
+    #[test_case("Group", "https://lemmy.example/post/1"; "group")]
+    #[test_case("Person", "https://lemmy.example/activities/create/8c1d4f6e"; "person")]
+    #[tokio::test]
+    async fn announces_are_only_unwrapped_for_groups(actor_type: &str, object_id: &str) {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let actor = RemoteActor::from_json(
+            "https://lemmy.example/c/rust",
+            json!({
+                "type": actor_type,
+                "id": "https://lemmy.example/c/rust",
+                "inbox": "https://lemmy.example/c/rust/inbox",
+            }),
+        )
+        .unwrap();
+
+        let headers = HeaderMap::new();
+        let mut inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty: ActivityType::Announce,
+            actor_id: "https://lemmy.example/c/rust".into(),
+            activity: lemmy_announce("Create"),
+            actor: Some(actor),
+        };
+
+        let flow = stages::GroupAnnounce
+            .run(&mut inbound, &state)
+            .await
+            .unwrap();
+
+        assert_eq!(flow, Flow::Continue);
+        assert_eq!(id_from_json(&inbound.activity).unwrap(), object_id);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    const FEATURED: &str = "https://mastodon.example/users/alice/collections/featured";
+
+    // As sent by Mastodon when pinning or unpinning a post: no id and no addressing
+    fn mastodon_pin(ty: &str, target: &str) -> Value {
+        json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "type": ty,
+            "actor": "https://mastodon.example/users/alice",
+            "object": "https://mastodon.example/users/alice/statuses/109",
+            "target": target
+        })
+    }
+
+    async fn run_after_group_announce(state: &State, activity: Value) -> Flow {
+        let actor = RemoteActor::from_json(
+            "https://mastodon.example/users/alice",
+            json!({
+                "type": "Person",
+                "id": "https://mastodon.example/users/alice",
+                "inbox": "https://mastodon.example/users/alice/inbox",
+                "featured": FEATURED,
+            }),
+        )
+        .unwrap();
+
+        let headers = HeaderMap::new();
+        let mut inbound = Inbound {
+            relay: RelayActor::main(state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty: ActivityType::from_value(&activity["type"]),
+            actor_id: "https://mastodon.example/users/alice".into(),
+            activity,
+            actor: Some(actor),
+        };
+
+        state
+            .pipeline
+            .run_after("group_announce", &mut inbound, state)
+            .await
+            .unwrap()
+    }
+
+    fn n_queued(state: &State) -> usize {
+        std::iter::from_fn(|| state.deliveries.next_ready()).count()
+    }
+
+    #[tokio::test]
+    async fn mastodon_pins_are_forwarded_and_can_be_repeated() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        db.add_inbox_if_unknown("https://b.example/inbox".to_owned(), None)
+            .unwrap();
+        let state = State::new_with_test_key(db);
+
+        for ty in ["Add", "Remove", "Add"] {
+            let flow = run_after_group_announce(&state, mastodon_pin(ty, FEATURED)).await;
+
+            assert_eq!(flow, Flow::Continue, "{ty}");
+            assert_eq!(n_queued(&state), 1, "{ty} should have been forwarded");
+        }
+
+        // Repeating the same change without the opposite one in between is a duplicate
+        run_after_group_announce(&state, mastodon_pin("Add", FEATURED)).await;
+        assert_eq!(n_queued(&state), 0);
+
+        // Other collections aren't publicly addressed
+        let other = "https://mastodon.example/users/alice/collections/tags";
+        let flow = run_after_group_announce(&state, mastodon_pin("Add", other)).await;
+        assert_eq!(flow, Flow::Stop);
+        assert_eq!(n_queued(&state), 0);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(&[], &[], json!({"type": "Create", "object": {"type": "Video"}}), None, None; "no restrictions")]
+    #[test_case(&["Note", "Article"], &[], json!({"type": "Create", "object": {"type": "Article"}}), None, None; "allowed")]
+    #[test_case(&["Note", "Article"], &[], json!({"type": "Announce", "object": {"type": "Page"}}), Some("Page"), Some("other"); "not allowed")]
+    #[test_case(&[], &["Video"], json!({"type": "Update", "object": {"type": "Video"}}), Some("Video"), Some("Video"); "denied")]
+    #[test_case(&[], &["Video"], json!({"type": "Create", "object": {"type": ["Note", "Video"]}}), Some("Video"), Some("Video"); "denied in list")]
+    #[test_case(&["Note"], &[], json!({"type": "Announce", "object": "https://a.example/videos/1"}), None, None; "announce by id")]
+    #[test_case(&["Note"], &[], json!({"type": "Delete", "object": {"type": "Tombstone"}}), None, None; "delete")]
+    #[test]
+    fn object_types_are_filtered(
+        allowed: &[&str],
+        denied: &[&str],
+        activity: Value,
+        expected: Option<&str>,
+        label: Option<&str>,
+    ) {
+        let cfg = PolicyConfig {
+            allowed_object_types: allowed.iter().map(|t| t.to_string()).collect(),
+            denied_object_types: denied.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let object_type = disallowed_object_type(&activity, &cfg);
+
+        assert_eq!(object_type.as_deref(), expected);
+        assert_eq!(
+            object_type.as_deref().map(|t| object_type_label(t, &cfg)),
+            label
+        );
+    }
+
+    const WITH_MEDIA: &str =
+        r#"{ "type": "Note", "attachment": [{ "url": "https://limited.example/a.png" }] }"#;
+    const WITHOUT_MEDIA: &str = r#"{ "type": "Note", "attachment": [] }"#;
+
+    #[test_case(Severity::Silence, "Create", WITH_MEDIA, false, false, true; "silenced")]
+    #[test_case(Severity::StripMedia, "Create", WITH_MEDIA, false, false, true; "create with media")]
+    #[test_case(Severity::StripMedia, "Create", WITHOUT_MEDIA, false, true, false; "create without media")]
+    #[test_case(Severity::StripMedia, "Update", WITH_MEDIA, false, true, false; "update stripped")]
+    #[test_case(Severity::StripMedia, "Update", WITH_MEDIA, true, false, true; "ld signed update")]
+    #[test_case(Severity::StripMedia, "Update", WITHOUT_MEDIA, true, true, false; "ld signed update without media")]
+    #[tokio::test]
+    async fn block_severities_are_applied(
+        severity: Severity,
+        ty: &str,
+        object: &str,
+        ld_signed: bool,
+        relayed: bool,
+        has_media: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state
+            .blocklist
+            .add(ADMIN_SOURCE, "limited.example", severity);
+        let object: Value = serde_json::from_str(object).unwrap();
+        let mut activity = json!({ "type": ty, "object": object });
+        if ld_signed {
+            activity["signature"] = json!({ "type": "RsaSignature2017" });
+        }
+
+        let res =
+            apply_block_severity("https://limited.example/actor", &mut activity, &state).await;
+
+        assert_eq!(res.unwrap(), relayed);
+        assert_eq!(has_attachments(&activity["object"]), has_media);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(WITH_MEDIA, false; "with media")]
+    #[test_case(WITHOUT_MEDIA, true; "without media")]
+    #[tokio::test]
+    async fn announced_objects_are_fetched_to_check_for_media(object: &str, relayed: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        state
+            .blocklist
+            .add(ADMIN_SOURCE, "limited.example", Severity::StripMedia);
+        let id = "https://limited.example/notes/1";
+        let object: Value = serde_json::from_str(object).unwrap();
+        state.objects.insert(id, object, Utc::now());
+        let mut activity = json!({ "type": "Announce", "object": id });
+
+        let res =
+            apply_block_severity("https://limited.example/actor", &mut activity, &state).await;
+
+        assert_eq!(res.unwrap(), relayed);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("https://silenced.example/actor", false, None, false; "silenced")]
+    #[test_case("https://other.example/actor", false, None, false; "not blocked")]
+    #[test_case("https://other.example/actor", true, None, true; "not on allow list")]
+    #[test_case("https://ALLOWED.example/actor", true, None, false; "on allow list")]
+    #[test_case("https://bootstrapped.example/actor", true, None, false; "allowed via admin api")]
+    #[test_case("https://social.bootstrapped.example/actor", true, None, false; "allowed via admin api by registrable domain")]
+    #[test_case("https://a.friends.example/actor", true, None, false; "allowed via admin api by pattern")]
+    #[test_case("https://other.example/actor", false, Some(1), true; "relay full")]
+    #[test_case("https://other.example/actor", false, Some(2), false; "relay not full")]
+    #[test_case("https://subscribed.example/actor", false, Some(1), false; "already subscribed")]
+    #[test]
+    fn follows_are_rejected_with_a_reason(
+        actor_id: &str,
+        allow_list: bool,
+        max_subscribers: Option<usize>,
+        rejected: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.activity_pub.allow_list = allow_list;
+        state.cfg.activity_pub.allowed_instances =
+            DomainRules::new(vec!["allowed.example".into()]).unwrap();
+        state.db.set_allowed_instances(
+            DomainRules::new(vec![
+                "bootstrapped.example".into(),
+                DomainRule::new("media.bootstrapped.example", DomainScope::RegistrableDomain),
+                DomainRule::parse("*.friends.example"),
+            ])
+            .unwrap(),
+        );
+        state
+            .blocklist
+            .add(ADMIN_SOURCE, "silenced.example", Severity::Silence);
+        state.cfg.max_subscribers = max_subscribers;
+        state
+            .db
+            .add_inbox_if_unknown("https://subscribed.example/inbox".into(), None)
+            .unwrap();
+
+        let reason = follow_rejection(actor_id, &state).unwrap();
+
+        assert_eq!(reason.is_some(), rejected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("Announce", "https://ok.example/notes/1", Flow::Continue; "announce")]
+    #[test_case("Announce", "https://blocked.example/notes/1", Flow::Stop; "blocked origin")]
+    #[test_case("Announce", "https://silenced.example/notes/1", Flow::Stop; "silenced origin")]
+    #[test_case("Follow", "https://relay.example/actor", Flow::Stop; "follow back")]
+    #[tokio::test]
+    async fn upstream_activities_are_filtered_by_origin(ty: &str, object: &str, expected: Flow) {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.upstreams = vec!["https://upstream.example/actor".into()];
+        state
+            .blocklist
+            .set_source("feed", ["blocked.example".to_owned()].into())
+            .unwrap();
+        state
+            .blocklist
+            .add(ADMIN_SOURCE, "silenced.example", Severity::Silence);
+
+        let headers = HeaderMap::new();
+        let mut inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty: ActivityType::from_value(&json!(ty)),
+            actor_id: "https://upstream.example/actor".into(),
+            activity: json!({ "type": ty, "actor": "https://upstream.example/actor", "object": object }),
+            actor: None,
+        };
+
+        let flow = stages::Upstream.run(&mut inbound, &state).await.unwrap();
+
+        assert_eq!(flow, expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(json!("https://ok.example/notes/1"), Flow::Continue; "by id")]
+    #[test_case(json!({ "id": "https://ok.example/notes/1", "to": [jsonld::PUBLIC] }), Flow::Continue; "public")]
+    #[test_case(json!({ "id": "https://ok.example/notes/1", "to": ["https://ok.example/users/a/followers"] }), Flow::Stop; "followers only")]
+    #[tokio::test]
+    async fn upstream_activities_are_checked_by_what_they_embed(object: Value, expected: Flow) {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.upstreams = vec!["https://upstream.example/actor".into()];
+
+        let headers = HeaderMap::new();
+        let mut inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty: ActivityType::Announce,
+            actor_id: "https://upstream.example/actor".into(),
+            activity: json!({
+                "type": "Announce",
+                "actor": "https://upstream.example/actor",
+                "to": ["https://upstream.example/followers"],
+                "object": object
+            }),
+            actor: None,
+        };
+
+        let flow = stages::Addressing.run(&mut inbound, &state).await.unwrap();
+
+        assert_eq!(flow, expected);
+        let filtered = state.stats.origin("ok.example").map(|s| s.filtered);
+        assert_eq!(
+            filtered.unwrap_or_default(),
+            (expected == Flow::Stop) as u64
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(json!({ "to": [jsonld::PUBLIC], "tag": [{ "type": "Mention", "href": "https://relay.example/actor" }] }), Flow::Stop, 1; "mention")]
+    #[test_case(json!({ "to": ["https://relay.example/actor"] }), Flow::Stop, 1; "direct message")]
+    #[test_case(json!({ "to": [jsonld::PUBLIC], "tag": [{ "type": "Mention", "href": "https://relay.example/actors/art" }] }), Flow::Continue, 0; "other actor")]
+    #[test_case(json!({ "to": [jsonld::PUBLIC] }), Flow::Continue, 0; "no mention")]
+    #[tokio::test]
+    async fn mentions_of_the_relay_are_kept_as_messages(
+        object: Value,
+        expected: Flow,
+        n_messages: usize,
+    ) {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+
+        let headers = HeaderMap::new();
+        let mut inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty: ActivityType::Create,
+            actor_id: "https://a.example/users/admin".into(),
+            activity: json!({ "type": "Create", "actor": "https://a.example/users/admin", "object": object }),
+            actor: None,
+        };
+
+        let flow = stages::Mention.run(&mut inbound, &state).await.unwrap();
+
+        assert_eq!(flow, expected);
+        assert_eq!(state.db.messages().len(), n_messages);
+        assert_eq!(state.notifications.recent().len(), n_messages);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn messages_over_the_hourly_limit_are_dropped() {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.max_messages_per_hour = 2;
+
+        let headers = HeaderMap::new();
+        for i in 0..3 {
+            let mut inbound = Inbound {
+                relay: RelayActor::main(&state),
+                headers: &headers,
+                host: "relay.example",
+                path: "/inbox",
+                body: &[],
+                ty: ActivityType::Create,
+                actor_id: "https://a.example/users/admin".into(),
+                activity: json!({
+                    "type": "Create",
+                    "actor": "https://a.example/users/admin",
+                    "object": { "id": format!("https://a.example/notes/{i}"), "to": ["https://relay.example/actor"] }
+                }),
+                actor: None,
+            };
+
+            let flow = stages::Mention.run(&mut inbound, &state).await.unwrap();
+            assert_eq!(flow, Flow::Stop, "message {i} was relayed");
+        }
+
+        assert_eq!(state.db.messages().len(), 2);
+        assert_eq!(state.notifications.recent().len(), 2);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn repeated_messages_are_only_kept_once() {
+        use crate::pipeline::Stage;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.max_messages_per_hour = 2;
+
+        // The same post delivered three times (as retries or to more than one inbox)
+        // followed by a new one, which is still within the limit
+        let headers = HeaderMap::new();
+        for (i, path) in [
+            (1, "/inbox"),
+            (1, "/inbox"),
+            (1, "/actor/inbox"),
+            (2, "/inbox"),
+        ] {
+            let mut inbound = Inbound {
+                relay: RelayActor::main(&state),
+                headers: &headers,
+                host: "relay.example",
+                path,
+                body: &[],
+                ty: ActivityType::Create,
+                actor_id: "https://a.example/users/admin".into(),
+                activity: json!({
+                    "type": "Create",
+                    "actor": "https://a.example/users/admin",
+                    "object": { "id": format!("https://a.example/notes/{i}"), "to": ["https://relay.example/actor"] }
+                }),
+                actor: None,
+            };
+
+            let flow = stages::Mention.run(&mut inbound, &state).await.unwrap();
+            assert_eq!(flow, Flow::Stop, "message {i} was relayed");
+        }
+
+        let messages = state.db.messages();
+        let objects: Vec<&str> = messages.iter().map(|m| m.object.as_str()).collect();
+
+        assert_eq!(
+            objects,
+            vec!["https://a.example/notes/2", "https://a.example/notes/1"]
+        );
+        assert_eq!(state.notifications.recent().len(), 2);
+        assert_eq!(state.db.message(&messages[1].id), Some(messages[1].clone()));
+        assert_eq!(
+            state.db.remove_message(&messages[1].id),
+            Some(messages[1].clone())
+        );
+        assert!(!state.db.has_message("https://a.example/notes/1"));
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(UnrecognizedActivities::Ignore, ActivityType::Other, true, true, 0; "ignored")]
+    #[test_case(UnrecognizedActivities::Record, ActivityType::Other, true, true, 1; "recorded")]
+    #[test_case(UnrecognizedActivities::Reject, ActivityType::Other, true, false, 1; "rejected")]
+    #[test_case(UnrecognizedActivities::Reject, ActivityType::Like, true, true, 0; "recognized")]
+    #[test_case(UnrecognizedActivities::Record, ActivityType::Other, false, true, 0; "not subscribed")]
+    #[test_case(UnrecognizedActivities::Reject, ActivityType::Other, false, false, 0; "rejected when not subscribed")]
+    #[tokio::test]
+    async fn unrecognized_activities_are_optionally_recorded(
+        policy: UnrecognizedActivities,
+        ty: ActivityType,
+        subscribed: bool,
+        accepted: bool,
+        n_recorded: usize,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.unrecognized_activities = policy;
+        if subscribed {
+            state
+                .db
+                .add_inbox_if_unknown("https://a.example/inbox".into(), None)
+                .unwrap();
+        }
+        let actor = RemoteActor::from_json(
+            "https://a.example/users/admin",
+            json!({
+                "type": "Person",
+                "id": "https://a.example/users/admin",
+                "inbox": "https://a.example/users/admin/inbox",
+            }),
+        )
+        .unwrap();
+
+        let headers = HeaderMap::new();
+        let inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "relay.example",
+            path: "/inbox",
+            body: &[],
+            ty,
+            actor_id: "https://a.example/users/admin".into(),
+            activity: json!({ "type": "EmojiReact", "actor": "https://a.example/users/admin" }),
+            actor: Some(actor),
+        };
+
+        // Repeats are counted against the same type and origin
+        for _ in 0..2 {
+            assert_eq!(check_unrecognized(&inbound, &state).await.is_ok(), accepted);
+        }
+
+        let recorded = state.db.unrecognized();
+        assert_eq!(recorded.len(), n_recorded);
+        if let Some(unrecognized) = recorded.first() {
+            assert_eq!(unrecognized.activity_type, "EmojiReact");
+            assert_eq!(unrecognized.origin, "a.example");
+            assert_eq!(unrecognized.count, 2);
+        }
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("https://other.example/actor", 3, false; "counted once")]
+    #[test_case("https://other.example/actor", 2, true; "full")]
+    #[test_case("https://art.example/actor", 2, false; "subscribed to another actor")]
+    #[test]
+    fn subscribers_of_every_actor_are_counted_once(actor_id: &str, max: usize, rejected: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.max_subscribers = Some(max);
+        let art = TopicActor::open(
+            ActorConfig {
+                name: "art".into(),
+                summary: None,
+                tags: vec![],
+                private_key_path: None,
+            },
+            &dir,
+        )
+        .unwrap();
+        for inbox in [
+            "https://art.example/inbox",
+            "https://subscribed.example/inbox",
+        ] {
+            art.db.add_inbox_if_unknown(inbox.into(), None).unwrap();
+        }
+        state.actors.insert("art".into(), art);
+        state
+            .db
+            .add_inbox_if_unknown("https://subscribed.example/inbox".into(), None)
+            .unwrap();
+
+        let reason = follow_rejection(actor_id, &state).unwrap();
+
+        assert_eq!(reason.is_some(), rejected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("https://invited.example/actor", false; "invited")]
+    #[test_case("https://INVITED.example/actor", false; "invited case insensitive")]
+    #[test_case("https://other.example/actor", true; "not invited")]
+    #[test]
+    fn invite_only_relays_reject_uninvited_follows(actor_id: &str, rejected: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.activity_pub.invite_only = true;
+        let invite = Invite::new(None);
+        state.db.add_invite(invite.clone());
+        state.db.add_invite(Invite::new(None));
+        state
+            .db
+            .bind_invite(&invite.code, "invited.example")
+            .unwrap();
+
+        let reason = follow_rejection(actor_id, &state).unwrap();
+
+        assert_eq!(reason.is_some(), rejected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    // A Create of a video as sent by PeerTube, cut down to the parts we look at
+    fn peertube_create(captions: usize) -> Value {
+        let subtitles: Vec<Value> = (0..captions)
+            .map(|i| {
+                json!({
+                    "identifier": format!("lang{i}"),
+                    "name": format!("Language {i}"),
+                    "url": format!("https://peertube.example/lazy-static/video-captions/{i}.vtt")
+                })
+            })
+            .collect();
+
+        json!({
+            "type": "Create",
+            "id": "https://peertube.example/videos/watch/9c9de5e8/activity",
+            "actor": "https://peertube.example/accounts/alice",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": {
+                "type": "Video",
+                "id": "https://peertube.example/videos/watch/9c9de5e8",
+                "name": "A video",
+                "duration": "PT120S",
+                "uuid": "9c9de5e8",
+                "subtitleLanguage": subtitles,
+                "hasParts": "https://peertube.example/videos/watch/9c9de5e8/chapters",
+                "url": [
+                    {"type": "Link", "mediaType": "text/html", "href": "https://peertube.example/w/9c9de5e8"},
+                    {"type": "Link", "mediaType": "video/mp4", "href": "https://peertube.example/static/web-videos/9c9de5e8-720.mp4", "height": 720}
+                ],
+                "attributedTo": [
+                    {"type": "Person", "id": "https://peertube.example/accounts/alice"},
+                    {"type": "Group", "id": "https://peertube.example/video-channels/alice_channel"}
+                ]
+            }
+        })
+    }
+
+    #[test_case(peertube_create(1), 16; "video")]
+    #[test_case(json!({"type": "Create", "object": {"type": "Playlist"}}), 16; "playlist")]
+    #[test_case(json!({"type": "Create", "object": {"type": ["Video", "pt:Video"]}}), 16; "typed as a list")]
+    #[test_case(json!({"type": "Create", "object": {"type": "Note"}}), 4; "note")]
+    #[test_case(json!({"type": "Announce", "object": "https://peertube.example/videos/watch/9c9de5e8"}), 4; "announce by id")]
+    #[test]
+    fn videos_are_allowed_larger_payloads(activity: Value, expected: u64) {
+        let cfg = InboxConfig {
+            max_payload_bytes: 4,
+            max_video_payload_bytes: 16,
+            ..Default::default()
+        };
+
+        assert_eq!(max_payload_bytes(&activity, &cfg), expected);
+    }
+
+    #[test_case(false, "Create"; "as received")]
+    #[test_case(true, "Announce"; "modified")]
+    #[tokio::test]
+    async fn videos_are_only_relayed_as_received_if_unmodified(modified: bool, expected: &str) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        db.add_inbox_if_unknown("https://b.example/inbox".to_owned(), None)
+            .unwrap();
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        let actor = test_actor("https://peertube.example/accounts/alice");
+
+        // Formatted differently to how we would serialize it
+        let raw = serde_json::to_vec_pretty(&peertube_create(1)).unwrap();
+        let mut activity = peertube_create(1);
+        if modified {
+            activity["object"]["name"] = json!("A policy changed this");
+        }
+
+        handle_relay(
+            &relay,
+            &actor,
+            activity,
+            Some(&raw),
+            "relay.example",
+            &state,
+        )
+        .await
+        .unwrap();
+
+        let queued = state.deliveries.next_ready().unwrap();
+        let body = queued.delivery.body.bytes();
+        let sent: Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(sent["type"], expected);
+        assert_eq!(body.as_ref() == raw.as_slice(), !modified);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("Note", StatusCode::PAYLOAD_TOO_LARGE; "note")]
+    #[test_case("Video", StatusCode::FORBIDDEN; "video")]
+    #[tokio::test]
+    async fn oversized_payloads_are_rejected(object_type: &str, status: StatusCode) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.max_payload_bytes = 10_000;
+        // Blocked so that we don't try to fetch the actor
+        state
+            .blocklist
+            .set_source("feed", ["peertube.example".to_owned()].into())
+            .unwrap();
+
+        let mut activity = peertube_create(200);
+        activity["object"]["type"] = json!(object_type);
+        let req = json!({
+            "type": "Create",
+            "actor": "https://peertube.example/accounts/alice",
+            "activity": activity,
+        });
+        let body = serde_json::to_vec(&req).unwrap();
+        assert!(body.len() > 10_000);
+
+        let res = handle_post(
+            "relay",
+            &HeaderMap::new(),
+            "localhost",
+            "/inbox",
+            &state,
+            &body,
+        )
+        .await;
+
+        match res {
+            Err(Error::StatusAndMessage { status: s, .. }) => assert_eq!(s, status),
+            other => panic!("expected an error, got {other:?}"),
+        }
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(10, StatusCode::BAD_REQUEST; "within the limit")]
+    #[test_case(20, StatusCode::PAYLOAD_TOO_LARGE; "over the limit")]
+    #[tokio::test]
+    async fn bodies_over_the_video_limit_are_not_read(len: usize, status: StatusCode) {
+        use tower::ServiceExt;
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.max_payload_bytes = 5;
+        state.cfg.inbox.max_video_payload_bytes = 15;
+        let app = crate::routes::build_routes(Arc::new(state));
+
+        // Sent as a stream so that there is no Content-Length to check up front
+        let chunks: Vec<Result<String, std::io::Error>> = vec![Ok("x".repeat(len))];
+        let req = Request::builder()
+            .method("POST")
+            .uri("/inbox")
+            .header("host", "localhost")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), status);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("100", 0; "small")]
+    #[test_case("2000000", 1; "large")]
+    #[tokio::test]
+    async fn large_payloads_are_counted(content_length: &str, expected: u64) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        // Blocked so that we don't try to fetch the actor
+        state
+            .blocklist
+            .set_source("feed", ["blocked.example".to_owned()].into())
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, content_length.parse().unwrap());
+        let req = json!({
+            "type": "Create",
+            "actor": "https://blocked.example/actor",
+            "activity": {},
+        });
+        let body = serde_json::to_vec(&req).unwrap();
+
+        let res = handle_post("relay", &headers, "localhost", "/inbox", &state, &body).await;
+        let labels = [("instance", "blocked.example")];
+
+        assert!(res.is_err());
+        assert_eq!(
+            state
+                .metrics
+                .counter("actiserve_inbox_large_payloads_total", &labels),
+            expected
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(false, true; "optional")]
+    #[test_case(true, false; "required")]
+    #[tokio::test]
+    async fn forwarded_activities_without_ld_signatures(required: bool, expected: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.require_ld_signatures = required;
+        let activity = json!({
+            "type": "Delete",
+            "actor": "https://a.example/users/alice",
+            "object": "https://a.example/notes/1",
+        });
+
+        let res = check_ld_signature(
+            "https://b.example/actor",
+            "https://a.example/users/alice",
+            &activity,
+            &state,
+        )
+        .await;
+        let labels = [("instance", "b.example"), ("outcome", "missing")];
+
+        assert_eq!(res, Ok(expected));
+        assert_eq!(
+            state
+                .metrics
+                .counter("actiserve_ld_signatures_total", &labels),
+            1
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn ld_signature_keys_from_other_hosts_are_invalid() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.require_ld_signatures = true;
+        let activity = json!({
+            "type": "Delete",
+            "actor": "https://a.example/users/alice",
+            "object": "https://a.example/notes/1",
+            "signature": {
+                "type": "RsaSignature2017",
+                "creator": "https://evil.example/users/mallory#main-key",
+                "created": "2023-03-14T09:26:53Z",
+                "signatureValue": "c2lnbmF0dXJl",
+            },
+        });
+
+        let res = check_ld_signature(
+            "https://b.example/actor",
+            "https://a.example/users/alice",
+            &activity,
+            &state,
+        )
+        .await;
+        let labels = [("instance", "b.example"), ("outcome", "invalid")];
+
+        assert_eq!(res, Ok(false));
+        assert_eq!(
+            state
+                .metrics
+                .counter("actiserve_ld_signatures_total", &labels),
+            1
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
 
-    let actor_domain = host_from_uri(actor_id)?;
-    if ty != "Follow" && state.db.inbox(&actor_domain).is_none() {
-        info!(actor=%actor_id, "rejecting actor for trying to POST without following");
-        return Err(Error::StatusAndMessage {
-            status: StatusCode::UNAUTHORIZED,
-            message: "access denied",
+    #[test_case(false, None; "disabled")]
+    #[test_case(true, Some("https://a.example/notes/1"); "enabled")]
+    #[test]
+    fn inbox_requests_are_optionally_normalized(enabled: bool, expected: Option<&str>) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.normalize_json_ld = enabled;
+
+        let req = json!({
+            "as:type": "as:Create",
+            "as:actor": "https://a.example/actor",
+            "activity": {
+                "@type": ["https://www.w3.org/ns/activitystreams#Create"],
+                "https://www.w3.org/ns/activitystreams#object": [{ "@id": "https://a.example/notes/1" }],
+            },
         });
+        let body = serde_json::to_vec(&req).unwrap();
+        let res = parse_request(&body, &state);
+
+        assert_eq!(
+            res.ok()
+                .and_then(|r| r.activity["object"].as_str().map(String::from)),
+            expected.map(String::from)
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
 
-    Ok(())
-}
+    #[test_case(false, true; "as received")]
+    #[test_case(true, false; "modified")]
+    #[test]
+    fn normalized_activities_are_forwarded_as_received(modified: bool, expected: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.normalize_json_ld = true;
 
-#[tracing::instrument(level = "info", skip(state, activity), err)]
-async fn handle_relay(actor: &Actor, activity: Value, host: &str, state: Arc<State>) -> Result<()> {
-    let object_id = id_from_json(&activity);
-    let object_id_uri = &object_id
-        .parse::<http::Uri>()
-        .map_err(|_e| Error::InvalidUri {
-            uri: object_id.clone(),
-        })?;
-    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
-        status: StatusCode::BAD_REQUEST,
-        message: "actor has no id",
-    })?;
+        let req = json!({
+            "type": "Update",
+            "actor": "https://a.example/actor",
+            "activity": {
+                "as:type": "as:Update",
+                "as:actor": "https://a.example/actor",
+                "as:object": { "id": "https://a.example/notes/1", "type": "Note" },
+                "to": "as:Public",
+            },
+        });
+        // Formatted differently to how we would serialize it
+        let body = serde_json::to_vec_pretty(&req).unwrap();
+        let mut parsed = parse_request(&body, &state).expect("request to parse");
+        if modified {
+            parsed.activity["object"]["content"] = json!("A policy changed this");
+        }
 
-    if let Some(activity_id) = state.get_from_cache(&object_id) {
-        info!(%object_id, %activity_id, "ID has already been relayed");
-        return Ok(());
+        let raw = raw_activity(&body).expect("activity to be found");
+        let forwarded = unmodified_body(Some(raw), &parsed.activity, &state);
+
+        assert_eq!(parsed.activity["type"], "Update");
+        assert_eq!(
+            serde_json::from_slice::<Value>(raw).unwrap(),
+            req["activity"]
+        );
+        assert_eq!(forwarded.as_deref() == Some(raw), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
 
-    info!(id=%actor_id, "relaying post from actor");
-    let activity_id = format!("https://{host}/activities/{}", Uuid::new_v4());
-    let activity_id_uri = &activity_id
-        .parse::<http::Uri>()
-        .map_err(|_e| Error::InvalidUri {
-            uri: activity_id.clone(),
-        })?;
+    const MISSKEY_CONTEXT: &str = r#"[
+        "https://www.w3.org/ns/activitystreams",
+        "https://w3id.org/security/v1",
+        {
+            "quoteUrl": "as:quoteUrl",
+            "misskey": "https://misskey-hub.net/ns#",
+            "_misskey_content": "misskey:_misskey_content",
+            "_misskey_quote": "misskey:_misskey_quote",
+            "_misskey_reaction": "misskey:_misskey_reaction",
+            "isCat": "misskey:isCat"
+        }
+    ]"#;
 
-    let actor_uri = format!("https://{host}/actor")
-        .parse::<http::Uri>()
-        .map_err(|_e| Error::InvalidUri {
-            uri: format!("https://{host}/actor"),
-        })?;
+    // A Create of a quote post as sent by Misskey, Firefish or Sharkey. Each takes a
+    // different approach to the actor and carries its own non-standard fields.
+    fn misskey_family_create(software: &str) -> Value {
+        let (host, actor) = match software {
+            "misskey" => (
+                "misskey.example",
+                json!("https://misskey.example/users/9abc"),
+            ),
+            "firefish" => (
+                "firefish.example",
+                json!(["https://firefish.example/users/9abc"]),
+            ),
+            _ => (
+                "sharkey.example",
+                json!({"id": "https://sharkey.example/users/9abc", "type": "Person"}),
+            ),
+        };
+        let author = format!("https://{host}/users/9abc");
 
-    let message = ActivityBuilder::new(
-        String::from("Announce"),
-        String::from("announcing post from actor"),
-    )
-    .to(vec![format!("https://{host}/followers")])
-    .id(activity_id_uri.clone())
-    .actor(ActorBuilder::new(String::from("Actor")).url(actor_uri))
-    .object(ObjectBuilder::new().id(object_id_uri.clone()))
-    .build();
+        json!({
+            "@context": serde_json::from_str::<Value>(MISSKEY_CONTEXT).unwrap(),
+            "id": format!("https://{host}/notes/9tq1x2/activity"),
+            "type": "Create",
+            "actor": actor,
+            "published": "2024-05-01T10:00:00.000Z",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": [format!("{author}/followers")],
+            "object": {
+                "id": format!("https://{host}/notes/9tq1x2"),
+                "type": "Note",
+                "attributedTo": author,
+                "content": "<p>$[tada hello]</p>",
+                "_misskey_content": "$[tada hello]",
+                "source": {"content": "$[tada hello]", "mediaType": "text/x.misskeymarkdown"},
+                "_misskey_quote": "https://other.example/notes/1",
+                "quoteUrl": "https://other.example/notes/1",
+                "quoteUri": "https://other.example/notes/1",
+                "published": "2024-05-01T10:00:00.000Z",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "cc": [format!("{author}/followers")],
+                "inReplyTo": null,
+                "attachment": [],
+                "sensitive": false,
+                "tag": []
+            }
+        })
+    }
 
-    debug!(?message, "relaying message");
-    state
-        .post_for_actor(actor, object_id, activity_id, message)
-        .await
-}
+    #[test_case("misskey", false; "misskey")]
+    #[test_case("firefish", false; "firefish")]
+    #[test_case("sharkey", false; "sharkey")]
+    #[test_case("misskey", true; "misskey normalized")]
+    #[test_case("firefish", true; "firefish normalized")]
+    #[test_case("sharkey", true; "sharkey normalized")]
+    #[test]
+    fn misskey_family_activities_are_parsed_with_their_fields_intact(
+        software: &str,
+        normalize: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.normalize_json_ld = normalize;
 
-#[tracing::instrument(level = "info", skip(state, activity), err)]
-async fn handle_forward(actor: &Actor, activity: Value, state: Arc<State>) -> Result<()> {
-    let object_id = id_from_json(&activity);
+        let activity = misskey_family_create(software);
+        let req = json!({
+            "type": activity["type"],
+            "actor": activity["actor"],
+            "activity": activity,
+        });
+        let body = serde_json::to_vec(&req).unwrap();
+        let mut req = parse_request(&body, &state).expect("request to parse");
+        let actor_id = format!("https://{software}.example/users/9abc");
 
-    if state.get_from_cache(&object_id).is_some() {
-        info!(%object_id, "already forwarded");
-        return Ok(());
+        assert_eq!(req.ty, ActivityType::Create);
+        assert_eq!(req.actor, actor_id);
+        assert!(passes_integrity_checks(&mut req.activity, &actor_id, &state).unwrap());
+        for field in [
+            "_misskey_content",
+            "_misskey_quote",
+            "quoteUrl",
+            "quoteUri",
+            "source",
+        ] {
+            assert_eq!(
+                req.activity["object"][field], activity["object"][field],
+                "{field}"
+            );
+        }
+        assert_eq!(
+            declared_authors(&actor_id, &req.activity),
+            vec![actor_id.as_str(), actor_id.as_str(), actor_id.as_str()]
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
 
-    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
-        status: StatusCode::BAD_REQUEST,
-        message: "actor has no id",
-    })?;
+    #[test_case(json!(["https://a.example/users/1"]), true; "array")]
+    #[test_case(json!({"id": "https://a.example/users/1"}), true; "embedded")]
+    #[test_case(json!([]), false; "empty array")]
+    #[test_case(json!(42), false; "number")]
+    #[test]
+    fn actors_are_parsed_leniently(actor: Value, ok: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
 
-    info!(%actor_id, "forwarding post");
-    state
-        .post_for_actor(actor, object_id.clone(), object_id, activity)
-        .await
-}
+        let req = json!({ "type": "Create", "actor": actor, "activity": {} });
+        let body = serde_json::to_vec(&req).unwrap();
 
-#[tracing::instrument(level = "info", skip(state, activity), err)]
-async fn handle_follow(
-    actor: &Actor,
-    activity: Value,
-    host: &str,
-    state: Arc<State>,
-) -> Result<()> {
-    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
-        status: StatusCode::BAD_REQUEST,
-        message: "actor has no id",
-    })?;
-    let inbox = actor.inbox.as_ref().ok_or(Error::StatusAndMessage {
-        status: StatusCode::BAD_REQUEST,
-        message: "actor has no inbox",
-    })?;
-    if state.db.add_inbox_if_unknown(inbox.to_owned())? {
-        // New inbox so follow the remote actor
-        state.client.follow_actor(actor_id).await?;
+        assert_eq!(parse_request(&body, &state).is_ok(), ok);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
 
-    let our_actor = format!("https://{}/actor", state.cfg.base_url());
-    let object_id = id_from_json(&activity);
-    let message_id = Uuid::new_v4();
+    // Stands in for every stage up to and including the signature check
+    #[derive(Debug)]
+    struct Verified;
 
-    let message = ActivityBuilder::new(String::from("Accept"), String::from("accepting follow"))
-        .to(vec![actor_id.clone()])
-        .object(
-            // FIXME: object does not have a property "actor":
-            // https://www.w3.org/TR/activitystreams-vocabulary/#types
-            // .actor
-            // object does not have a property "object":
-            // https://www.w3.org/TR/activitystreams-vocabulary/#types
-            // .object
-            ObjectBuilder::new().id(object_id
-                .parse::<http::Uri>()
-                .map_err(|_e| Error::InvalidUri { uri: object_id })?),
-        )
-        .actor(
-            ActorBuilder::new(String::from("Actor")).url(
-                our_actor
-                    .parse::<http::Uri>()
-                    .map_err(|_e| Error::InvalidUri { uri: our_actor })?,
-            ),
-        )
-        .id(format!("https://{host}/activities/{message_id}")
-            .parse::<http::Uri>()
-            .map_err(|_e| Error::StatusAndMessage {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                message: "failed to create parseable message id",
-            })?)
-        .build();
+    #[axum::async_trait]
+    impl Stage for Verified {
+        fn name(&self) -> &'static str {
+            ACCEPT_AFTER_STAGE
+        }
 
-    state.client.json_post(inbox, message).await?;
+        async fn run(&self, _: &mut Inbound<'_>, _: &State) -> Result<Flow> {
+            Ok(Flow::Continue)
+        }
+    }
 
-    Ok(())
-}
+    // Counts its runs, failing the first of them if asked to
+    #[derive(Debug)]
+    struct Counted {
+        name: &'static str,
+        fail_first: bool,
+        runs: Arc<AtomicUsize>,
+    }
 
-#[tracing::instrument(level = "info", skip(state, activity), err)]
-async fn handle_undo(actor: &Actor, activity: Value, state: Arc<State>) -> Result<()> {
-    let ty = match activity["object"]["type"].as_str() {
-        Some(ty) => ty.to_owned(),
-        None => {
-            return Err(Error::StatusAndMessage {
-                status: StatusCode::BAD_REQUEST,
-                message: "no object type",
-            })
+    #[axum::async_trait]
+    impl Stage for Counted {
+        fn name(&self) -> &'static str {
+            self.name
         }
-    };
 
-    let actor_id = actor.id.as_ref().ok_or(Error::StatusAndMessage {
-        status: StatusCode::BAD_REQUEST,
-        message: "actor has no id",
-    })?;
+        async fn run(&self, _: &mut Inbound<'_>, _: &State) -> Result<Flow> {
+            if self.runs.fetch_add(1, Ordering::SeqCst) == 0 && self.fail_first {
+                return Err(Error::StatusAndMessage {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    message: "try again later",
+                });
+            }
 
-    match ty.as_ref() {
-        "Follow" => {
-            state.db.remove_inbox(actor_id)?;
-            state.client.unfollow_actor(actor_id).await
+            Ok(Flow::Continue)
         }
+    }
+
+    #[tokio::test]
+    async fn retried_activities_resume_after_the_last_completed_stage() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
 
-        "Announce" => handle_forward(actor, activity, state).await,
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        let (first_runs, flaky_runs) =
+            (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        state.pipeline = Pipeline::new(vec![
+            Box::new(Verified),
+            Box::new(Counted {
+                name: "first",
+                fail_first: false,
+                runs: first_runs.clone(),
+            }),
+            Box::new(Counted {
+                name: "flaky",
+                fail_first: true,
+                runs: flaky_runs.clone(),
+            }),
+        ]);
 
-        _ => Ok(()),
+        let actor_id = "https://a.example/actor";
+        let actor = RemoteActor::from_json(
+            actor_id,
+            json!({ "id": actor_id, "type": "Application", "inbox": "https://a.example/inbox" }),
+        )
+        .unwrap();
+        let req = json!({ "type": "Create", "actor": actor_id, "activity": {} });
+        let body = serde_json::to_vec(&req).unwrap();
+        let headers = HeaderMap::new();
+        let inbound = Inbound {
+            relay: RelayActor::main(&state),
+            headers: &headers,
+            host: "localhost",
+            path: "/inbox",
+            body: &body,
+            ty: ActivityType::Create,
+            actor_id: actor_id.into(),
+            activity: req["activity"].clone(),
+            actor: None,
+        };
+        state.ingest.enqueue(Ingested::new(&inbound), Some(actor));
+        let mut job = state.ingest.next_ready().unwrap();
+
+        assert!(run_ingested(&mut job, &state).await.is_err());
+        assert_eq!(job.ingested.completed_stage.as_deref(), Some("first"));
+        assert!(run_ingested(&mut job, &state).await.is_ok());
+        assert_eq!(job.ingested.completed_stage.as_deref(), Some("flaky"));
+
+        assert_eq!(first_runs.load(Ordering::SeqCst), 1);
+        assert_eq!(flaky_runs.load(Ordering::SeqCst), 2);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
-}
 
-#[cfg(test)]
-mod validation_tests {
-    use super::*;
+    #[test_case(json!("https://a.example/notes/1"), true; "referenced by id")]
+    #[test_case(json!({"id": "https://a.example/notes/1", "type": "Note"}), true; "embedded")]
+    #[test_case(json!({"type": "Note"}), false; "embedded without an id")]
+    #[test_case(json!(["https://a.example/notes/1"]), false; "array")]
+    #[tokio::test]
+    async fn relayed_activities_without_an_object_id_are_rejected(object: Value, accepted: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
 
-    use crate::signature::tests::test_actor;
-    use crate::state::Db;
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.pipeline = Pipeline::new(vec![Box::new(Verified)]);
+        let req = json!({
+            "type": "Create",
+            "actor": "https://a.example/actor",
+            "activity": { "type": "Create", "actor": "https://a.example/actor", "object": object },
+        });
+        let body = serde_json::to_vec(&req).unwrap();
+
+        let res = handle_post(
+            "relay",
+            &HeaderMap::new(),
+            "localhost",
+            "/inbox",
+            &state,
+            &body,
+        )
+        .await;
+
+        assert_eq!(res.is_ok(), accepted, "{res:?}");
+        assert_eq!(state.ingest.len(), accepted as usize);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
 
-    use simple_test_case::test_case;
-    use std::{env::temp_dir, fs::remove_dir_all};
-
-    #[test_case("Accept"; "accept")]
-    #[test_case("Announce"; "announce")]
-    #[test_case("Create"; "create")]
-    #[test_case("Delete"; "delete")]
-    #[test_case("Undo"; "undo")]
-    #[test_case("Update"; "update")]
     #[tokio::test]
-    async fn non_follow_for_unknown_inbox_is_an_error(ty: &str) {
+    async fn unverified_rejections_are_not_counted_for_their_claimed_origin() {
         let mut dir = temp_dir();
         dir.push(Uuid::new_v4().to_string());
 
         let db = Db::new(dir.clone()).expect("unable to create database");
         let state = State::new_with_test_key(db);
-        let res = validate_request(&test_actor("https://example.com/actor"), ty, &state).await;
+        state
+            .blocklist
+            .set_source("feed", ["blocked.example".to_owned()].into())
+            .unwrap();
+        let req = json!({
+            "type": "Create",
+            "actor": "https://blocked.example/actor",
+            "activity": {},
+        });
+        let body = serde_json::to_vec(&req).unwrap();
 
-        assert_eq!(
-            res,
-            Err(Error::StatusAndMessage {
-                status: StatusCode::UNAUTHORIZED,
-                message: "access denied"
-            })
-        );
+        let res = handle_post(
+            "relay",
+            &HeaderMap::new(),
+            "localhost",
+            "/inbox",
+            &state,
+            &body,
+        )
+        .await;
+        let stats = state.stats.origin(UNVERIFIED_ORIGIN).unwrap();
 
-        state.clear();
+        assert!(res.is_err());
+        assert_eq!((stats.received, stats.rejected, stats.relayed), (1, 1, 0));
+        assert_eq!(state.stats.origin("blocked.example"), None);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn unverified_requests_are_not_labelled_with_their_claimed_domain() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.inbox.large_payload_bytes = 0;
+        state
+            .blocklist
+            .set_source("feed", ["blocked.example".to_owned()].into())
+            .unwrap();
+        let req = json!({
+            "type": "Create",
+            "actor": "https://blocked.example/actor",
+            "activity": {},
+        });
+        let body = serde_json::to_vec(&req).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, body.len().into());
+
+        let res = handle_post("relay", &headers, "localhost", "/inbox", &state, &body).await;
+        let large = |instance: &str| {
+            state.metrics.counter(
+                "actiserve_inbox_large_payloads_total",
+                &[("instance", instance)],
+            )
+        };
+
+        assert!(res.is_err());
+        assert_eq!(large("blocked.example"), 0);
+        assert_eq!(large(UNVERIFIED_ORIGIN), 1);
         remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
 
@@ -268,22 +3540,27 @@ mod validation_tests {
 
         let db = Db::new(dir.clone()).expect("unable to create database");
         let state = State::new_with_test_key(db);
-        let res =
-            validate_request(&test_actor("https://example.com/actor"), "Follow", &state).await;
+        let res = validate_request(
+            &RelayActor::main(&state),
+            &test_actor("https://example.com/actor"),
+            ActivityType::Follow,
+            SubscriptionScope::Exact,
+        )
+        .await;
 
         assert_eq!(res, Ok(()));
         state.clear();
         remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
 
-    #[test_case("Accept"; "accept")]
-    #[test_case("Announce"; "announce")]
-    #[test_case("Create"; "create")]
-    #[test_case("Delete"; "delete")]
-    #[test_case("Undo"; "undo")]
-    #[test_case("Update"; "update")]
+    #[test_case(ActivityType::Accept; "accept")]
+    #[test_case(ActivityType::Announce; "announce")]
+    #[test_case(ActivityType::Create; "create")]
+    #[test_case(ActivityType::Delete; "delete")]
+    #[test_case(ActivityType::Undo; "undo")]
+    #[test_case(ActivityType::Update; "update")]
     #[tokio::test]
-    async fn non_follow_for_known_inbox_is_ok(ty: &str) {
+    async fn non_follow_for_known_inbox_is_ok(ty: ActivityType) {
         let mut dir = temp_dir();
         dir.push(Uuid::new_v4().to_string());
 
@@ -291,13 +3568,194 @@ mod validation_tests {
         let state = State::new_with_test_key(db);
         state
             .db
-            .add_inbox_if_unknown("https://example.com/actor".to_owned())
+            .add_inbox_if_unknown("https://example.com/actor".to_owned(), None)
             .unwrap();
 
-        let res = validate_request(&test_actor("https://example.com/actor"), ty, &state).await;
+        let res = validate_request(
+            &RelayActor::main(&state),
+            &test_actor("https://example.com/actor"),
+            ty,
+            SubscriptionScope::Exact,
+        )
+        .await;
 
         assert_eq!(res, Ok(()));
         state.clear();
         remove_dir_all(dir).expect("to be able to clear up our temp directory");
     }
+
+    #[test_case(json!({ "object": { "published": "2023-01-01T10:00:00Z" } }), false; "recent object")]
+    #[test_case(json!({ "object": { "published": "2022-12-30T10:00:00Z" } }), true; "old object")]
+    #[test_case(json!({ "object": { "published": "2022-12-30T10:00:00+00:00" }, "published": "2023-01-01T10:00:00Z" }), true; "old object recent activity")]
+    #[test_case(json!({ "object": "https://example.com/1", "published": "2022-12-30T10:00:00Z" }), true; "old announce")]
+    #[test_case(json!({ "object": "https://example.com/1" }), false; "no published time")]
+    #[test_case(json!({ "object": { "published": "last tuesday" } }), false; "invalid published time")]
+    #[test]
+    fn is_stale_works(activity: Value, expected: bool) {
+        let now = DateTime::parse_from_rfc3339("2023-01-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(is_stale(&activity, Duration::hours(24), now), expected);
+    }
+
+    #[test]
+    fn activities_not_addressed_publicly_are_counted() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let actor = "https://a.example/users/alice";
+        let followers_only = json!({ "to": ["https://a.example/users/alice/followers"] });
+        let public = json!({ "cc": ["https://www.w3.org/ns/activitystreams#Public"] });
+
+        assert_eq!(
+            is_addressed_publicly(actor, &followers_only, &state),
+            Ok(false)
+        );
+        assert_eq!(is_addressed_publicly(actor, &public, &state), Ok(true));
+        assert_eq!(
+            state.metrics.counter(
+                "actiserve_non_public_activities_total",
+                &[("instance", "a.example")]
+            ),
+            1
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(json!({ "type": "Delete", "object": "https://a.example/notes/1" }), false; "delete of relayed")]
+    #[test_case(json!({ "type": "Delete", "object": "https://a.example/notes/2" }), true; "delete of unknown")]
+    #[test_case(json!({ "type": "Undo", "object": { "type": "Announce", "object": "https://a.example/notes/1" } }), false; "undo of relayed")]
+    #[test_case(json!({ "type": "Update", "to": [jsonld::PUBLIC], "object": { "id": "https://a.example/notes/1", "to": ["https://b.example/users/bob"] } }), true; "update to limited")]
+    #[test_case(json!({ "type": "Update", "object": { "id": "https://a.example/notes/2", "to": [jsonld::PUBLIC] } }), false; "update to public")]
+    #[test]
+    fn forwarded_activities_are_checked_by_their_object(activity: Value, refused: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        state.history.record(HistoryEntry {
+            relay: relay.name.to_owned(),
+            object_id: "https://a.example/notes/1".to_owned(),
+            activity_id: "https://relay.example/activities/1".to_owned(),
+            origin: "a.example".to_owned(),
+            relayed_at: Utc::now(),
+            recipients: None,
+            deleted: false,
+        });
+
+        let res = is_private_forward(&relay, "https://a.example/users/alice", &activity, &state);
+
+        assert_eq!(res, Ok(refused));
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn private_activities_are_refused() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let actor = "https://a.example/users/alice";
+        let direct = json!({
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": { "directMessage": true }
+        });
+        let followers_only = json!({ "to": ["https://a.example/users/alice/followers"] });
+
+        assert_eq!(is_private(actor, &direct, &state), Ok(true));
+        assert_eq!(is_private(actor, &followers_only, &state), Ok(false));
+        assert_eq!(
+            state.metrics.counter(
+                "actiserve_private_activities_refused_total",
+                &[("instance", "a.example"), ("visibility", "direct")]
+            ),
+            1
+        );
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(
+        json!({ "actor": "https://a.example/users/alice", "to": ["https://www.w3.org/ns/activitystreams#Public"], "object": { "attributedTo": "https://a.example/users/alice" } }),
+        json!({ "to": ["https://www.w3.org/ns/activitystreams#Public"], "cc": ["https://relay.example/followers", "https://a.example/users/alice"], "attributedTo": "https://a.example/users/alice" });
+        "public post"
+    )]
+    #[test_case(
+        json!({ "actor": "https://a.example/users/alice", "object": "https://a.example/notes/1" }),
+        json!({ "to": ["https://relay.example/followers"] });
+        "unaddressed announce"
+    )]
+    #[test_case(
+        json!({ "actor": "https://a.example/users/bob", "to": "as:Public", "object": "https://b.example/notes/1" }),
+        json!({ "to": ["https://www.w3.org/ns/activitystreams#Public"], "cc": ["https://relay.example/followers"] });
+        "boost of an object by id"
+    )]
+    #[test_case(
+        json!({ "actor": "https://a.example/groups/rust", "to": "as:Public", "object": { "id": "https://b.example/notes/1", "type": "Note" } }),
+        json!({ "to": ["https://www.w3.org/ns/activitystreams#Public"], "cc": ["https://relay.example/followers"] });
+        "unattributed object"
+    )]
+    #[test_case(
+        json!({ "actor": "https://a.example/users/alice", "to": "as:Public", "object": { "attributedTo": ["https://a.example/users/alice", { "id": "https://b.example/users/bob" }] } }),
+        json!({ "to": ["https://www.w3.org/ns/activitystreams#Public"], "cc": ["https://relay.example/followers", "https://a.example/users/alice", "https://b.example/users/bob"], "attributedTo": ["https://a.example/users/alice", "https://b.example/users/bob"] });
+        "multiple authors"
+    )]
+    #[test]
+    fn announces_credit_the_original_author(activity: Value, expected: Value) {
+        let mut announce = json!({ "to": ["https://relay.example/followers"] });
+        add_attribution(&mut announce, &activity, "https://relay.example/followers");
+
+        assert_eq!(announce, expected);
+    }
+
+    #[test_case(json!({ "type": "Follow", "id": "https://a.example/follows/1", "actor": "https://a.example/actor" }), true; "matching follow")]
+    #[test_case(json!({ "type": "Follow", "id": "https://a.example/follows/1", "actor": { "id": "https://a.example/actor" } }), true; "embedded actor")]
+    #[test_case(json!({ "type": "Follow", "id": "https://a.example/follows/1", "actor": "https://a.example/users/bob" }), false; "different actor")]
+    #[test_case(json!({ "type": "Follow", "id": "https://a.example/follows/2", "actor": "https://a.example/actor" }), false; "different id")]
+    #[test_case(json!({ "type": "Note", "id": "https://a.example/follows/1" }), false; "not a follow")]
+    #[test]
+    fn is_matching_follow_works(fetched: Value, expected: bool) {
+        let res = is_matching_follow(
+            &fetched,
+            "https://a.example/follows/1",
+            "https://a.example/actor",
+        );
+
+        assert_eq!(res, expected);
+    }
+
+    #[test_case(json!({ "id": "https://a.example/notes/1", "attributedTo": "https://a.example/users/alice" }), true; "matching object")]
+    #[test_case(json!({ "id": "https://a.example/notes/1", "attributedTo": { "id": "https://a.example/users/alice" } }), true; "embedded author")]
+    #[test_case(json!({ "id": "https://a.example/notes/1", "attributedTo": ["https://a.example/users/alice"] }), true; "array of authors")]
+    #[test_case(json!({ "id": "https://a.example/notes/1", "attributedTo": "https://a.example/users/mallory" }), false; "different author")]
+    #[test_case(json!({ "id": "https://a.example/notes/2", "attributedTo": "https://a.example/users/alice" }), false; "different id")]
+    #[test_case(json!({ "id": "https://a.example/notes/1" }), false; "no author")]
+    #[test_case(json!({ "error": "Record not found" }), false; "missing object")]
+    #[test]
+    fn is_matching_object_works(fetched: Value, expected: bool) {
+        let res = is_matching_object(
+            &fetched,
+            "https://a.example/notes/1",
+            &["https://a.example/users/alice"],
+        );
+
+        assert_eq!(res, expected);
+    }
+
+    #[tokio::test]
+    async fn follows_from_another_host_are_not_verified() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let activity = json!({ "id": "https://b.example/follows/1" });
+
+        let res = verify_follow("https://a.example/actor", &activity, &state).await;
+
+        assert!(res.is_err());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
 }
@@ -0,0 +1,530 @@
+//! The stages making up the default inbox [Pipeline](crate::pipeline::Pipeline), in the
+//! order that they are run.
+use super::*;
+use crate::{
+    pipeline::{Flow, Pipeline, Stage},
+    upstreams::{is_upstream, origin_actor, record_response},
+};
+use axum::async_trait;
+
+/// The stages that every activity POSTed to one of our inboxes is run through, in
+/// order. Further stages can be added to (or swapped into) the returned pipeline.
+pub fn default_pipeline() -> Pipeline {
+    Pipeline::new(vec![
+        Box::new(Blocklist),
+        Box::new(FetchActor),
+        Box::new(Signature),
+        Box::new(FollowRejection),
+        Box::new(DomainVerification),
+        Box::new(PinnedKey),
+        Box::new(Flood),
+        Box::new(Mention),
+        Box::new(Upstream),
+        Box::new(Subscription),
+        Box::new(LdSignature),
+        Box::new(GroupAnnounce),
+        Box::new(Addressing),
+        Box::new(Dedup),
+        Box::new(Quarantine),
+        Box::new(BlockSeverity),
+        Box::new(Policy),
+        Box::new(ObjectVerification),
+        Box::new(Dispatch),
+    ])
+}
+
+/// Reject requests from blocked instances before making any requests to them. Follows
+/// from them are dropped without a response of any kind, as sending a Reject would mean
+/// fetching the following actor and delivering to its inbox.
+#[derive(Debug)]
+pub struct Blocklist;
+
+#[async_trait]
+impl Stage for Blocklist {
+    fn name(&self) -> &'static str {
+        "blocklist"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        match check_not_blocked(&inbound.actor_id, state) {
+            Err(_) if inbound.ty == ActivityType::Follow => Ok(Flow::Stop),
+            res => res.map(|_| Flow::Continue),
+        }
+    }
+}
+
+/// Fetch the sending actor so that we have its key.
+#[derive(Debug)]
+pub struct FetchActor;
+
+#[async_trait]
+impl Stage for FetchActor {
+    fn name(&self) -> &'static str {
+        "fetch_actor"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        match state.client.get_actor(&inbound.actor_id).await {
+            Ok(actor) => inbound.actor = Some(actor),
+            // The error from the sender's own server (which may well be a 5xx) isn't
+            // passed back to them: what matters is that we can't verify the request
+            Err(e) => {
+                let outcome = Outcome::KeyFetchFailure;
+                record_signature_outcome(&inbound.actor_id, inbound.headers, outcome, state);
+                warn!(actor=%inbound.actor_id, error=%e, "unable to fetch signing actor");
+                return Err(Error::StatusAndMessage {
+                    status: StatusCode::UNAUTHORIZED,
+                    message: "unable to fetch the signing actor",
+                });
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Verify the HTTP signature of the request.
+#[derive(Debug)]
+pub struct Signature;
+
+#[async_trait]
+impl Stage for Signature {
+    fn name(&self) -> &'static str {
+        "signature"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        let actor = inbound.actor()?;
+        let mut outcome =
+            check_signature(actor, "post", inbound.path, inbound.headers, inbound.body);
+        if outcome == Outcome::Ok && state.cfg.inbox.strict_signatures {
+            outcome = check_coverage("post", inbound.headers);
+        }
+        if outcome == Outcome::Ok {
+            outcome = check_date(
+                inbound.headers,
+                Utc::now(),
+                state.cfg.inbox.max_date_skew_secs,
+            );
+        }
+        record_signature_outcome(&inbound.actor_id, inbound.headers, outcome, state);
+        outcome.into_result()?;
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Send a Reject in response to Follows from instances we won't relay for.
+#[derive(Debug)]
+pub struct FollowRejection;
+
+#[async_trait]
+impl Stage for FollowRejection {
+    fn name(&self) -> &'static str {
+        "follow_rejection"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.ty != ActivityType::Follow {
+            return Ok(Flow::Continue);
+        }
+
+        match follow_rejection(&inbound.actor_id, state)? {
+            Some(reason) => {
+                let activity = std::mem::take(&mut inbound.activity);
+                let (relay, host) = (inbound.relay, inbound.host);
+                handle_reject(&relay, inbound.actor()?, activity, reason, host, state).await?;
+                Ok(Flow::Stop)
+            }
+            None => Ok(Flow::Continue),
+        }
+    }
+}
+
+/// Reject Follows from domains that don't resolve or don't serve the following actor.
+#[derive(Debug)]
+pub struct DomainVerification;
+
+#[async_trait]
+impl Stage for DomainVerification {
+    fn name(&self) -> &'static str {
+        "domain_verification"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.ty != ActivityType::Follow || !state.cfg.inbox.verify_domains {
+            return Ok(Flow::Continue);
+        }
+
+        if let Err(e) = state.client.verify_actor_host(&inbound.actor_id).await {
+            let domain = host_from_uri(&inbound.actor_id)?;
+            info!(%domain, error=%e, "rejecting follow from unverified domain");
+            state.metrics.incr(
+                "actiserve_domain_verification_failures_total",
+                &[("instance", &domain)],
+            );
+
+            let activity = std::mem::take(&mut inbound.activity);
+            let (relay, host) = (inbound.relay, inbound.host);
+            let reason = "The relay was unable to verify the domain of this instance";
+            handle_reject(&relay, inbound.actor()?, activity, reason, host, state).await?;
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Check the sender's key against the one pinned for its instance.
+#[derive(Debug)]
+pub struct PinnedKey;
+
+#[async_trait]
+impl Stage for PinnedKey {
+    fn name(&self) -> &'static str {
+        "pinned_key"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        check_pinned_key(&inbound.relay, inbound.actor()?, state)?;
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Keep posts mentioning the relay actor as messages for the operator (or run the
+/// command they contain) instead of relaying them. These are accepted from any instance
+/// that isn't blocked, so that the admins of instances yet to subscribe can also get in
+/// touch, but only up to `inbox.maxMessagesPerHour` from each instance and only once
+/// they have counted towards its flood rate. Each post is only kept (and notified about)
+/// once, however many times it is delivered.
+#[derive(Debug)]
+pub struct Mention;
+
+#[async_trait]
+impl Stage for Mention {
+    fn name(&self) -> &'static str {
+        "mention"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.ty == ActivityType::Create && receive_message(inbound, state).await? {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Handle activities from the upstream relays that we follow: their responses to our
+/// Follows are recorded and anything they relay to us is also checked against the
+/// blocklist using the origin of the object being relayed. Anything else from them
+/// (such as a Follow back) is ignored.
+#[derive(Debug)]
+pub struct Upstream;
+
+#[async_trait]
+impl Stage for Upstream {
+    fn name(&self) -> &'static str {
+        "upstream"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if !is_upstream(&inbound.actor_id, state) {
+            return Ok(Flow::Continue);
+        }
+
+        match inbound.ty {
+            ActivityType::Accept | ActivityType::Reject => {
+                let accepted = inbound.ty == ActivityType::Accept;
+                record_response(&inbound.actor_id, &inbound.activity, accepted, state);
+                Ok(Flow::Stop)
+            }
+
+            _ if inbound.is_relayable() => {
+                let object_id = id_from_json(&inbound.activity)?;
+                if check_not_blocked(&object_id, state).is_err()
+                    || !apply_block_severity(&object_id, &mut inbound.activity, state).await?
+                {
+                    state.record_origin_event(&host_from_uri(&object_id)?, Event::Filtered);
+                    return Ok(Flow::Stop);
+                }
+
+                Ok(Flow::Continue)
+            }
+
+            _ => Ok(Flow::Stop),
+        }
+    }
+}
+
+/// Only subscribed instances (and upstream relays) may send anything other than a
+/// Follow.
+#[derive(Debug)]
+pub struct Subscription;
+
+#[async_trait]
+impl Stage for Subscription {
+    fn name(&self) -> &'static str {
+        "subscription"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if is_upstream(&inbound.actor_id, state) {
+            return Ok(Flow::Continue);
+        }
+
+        let scope = state.cfg.activity_pub.subscription_scope;
+        validate_request(&inbound.relay, inbound.actor()?, inbound.ty, scope).await?;
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Check the Linked Data signature of activities delivered by someone other than their
+/// author, as their HTTP signature only tells us who forwarded them.
+#[derive(Debug)]
+pub struct LdSignature;
+
+#[async_trait]
+impl Stage for LdSignature {
+    fn name(&self) -> &'static str {
+        "ld_signature"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        let activity = &inbound.activity;
+        let author = first_id(&activity["actor"]);
+
+        let forwarded = author.filter(|a| *a != inbound.actor_id);
+        if let Some(author) = forwarded {
+            if !check_ld_signature(&inbound.actor_id, author, activity, state).await? {
+                return Ok(Flow::Stop);
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Unwrap the activities that Lemmy and kbin communities Announce to their followers, so
+/// that the object being posted is relayed rather than the Create wrapping it.
+#[derive(Debug)]
+pub struct GroupAnnounce;
+
+#[async_trait]
+impl Stage for GroupAnnounce {
+    fn name(&self) -> &'static str {
+        "group_announce"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, _state: &State) -> Result<Flow> {
+        if inbound.ty == ActivityType::Announce
+            && inbound.actor()?.is_group()
+            && !unwrap_group_announce(&mut inbound.activity)
+        {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Only relay activities that are addressed to the public collection. Relays announce
+/// posts to their followers rather than publicly, so for activities from upstream relays
+/// it is the addressing of what they embed that is checked (objects relayed by id are
+/// fetched by subscribers from their origin, which applies its own addressing). Changes
+/// to an actor's pinned posts have no addressing of their own.
+#[derive(Debug)]
+pub struct Addressing;
+
+#[async_trait]
+impl Stage for Addressing {
+    fn name(&self) -> &'static str {
+        "addressing"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if !inbound.is_relayable() {
+            return Ok(Flow::Continue);
+        }
+
+        let addressed = if is_upstream(&inbound.actor_id, state) {
+            let object = &inbound.activity["object"];
+            let origin = origin_actor(&inbound.actor_id, &inbound.activity, state);
+            !object.is_object() || is_addressed_publicly(origin, object, state)?
+        } else {
+            is_featured_update(inbound.actor()?, &inbound.activity)
+                || is_addressed_publicly(&inbound.actor_id, &inbound.activity, state)?
+        };
+
+        if !addressed {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Drop objects that have already been relayed.
+#[derive(Debug)]
+pub struct Dedup;
+
+#[async_trait]
+impl Stage for Dedup {
+    fn name(&self) -> &'static str {
+        "dedup"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.is_relayable() && is_duplicate(&inbound.relay, &inbound.activity, state)? {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Hold back activities from newly subscribed instances.
+#[derive(Debug)]
+pub struct Quarantine;
+
+#[async_trait]
+impl Stage for Quarantine {
+    fn name(&self) -> &'static str {
+        "quarantine"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.is_relayable() && is_quarantined(&inbound.relay, inbound.actor()?, state)? {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Apply silence and strip_media blocks.
+#[derive(Debug)]
+pub struct BlockSeverity;
+
+#[async_trait]
+impl Stage for BlockSeverity {
+    fn name(&self) -> &'static str {
+        "block_severity"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.is_relayable()
+            && !apply_block_severity(&inbound.actor_id, &mut inbound.activity, state).await?
+        {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Drop activities by blocked actors and consult the configured custom policies.
+#[derive(Debug)]
+pub struct Policy;
+
+#[async_trait]
+impl Stage for Policy {
+    fn name(&self) -> &'static str {
+        "policy"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if !inbound.is_relayable() {
+            return Ok(Flow::Continue);
+        }
+
+        let origin = origin_actor(&inbound.actor_id, &inbound.activity, state).to_owned();
+        if !is_allowed_by_policy(&origin, &mut inbound.activity, state).await? {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Fetch the object of a Create back from its origin and check its attribution before
+/// announcing it.
+#[derive(Debug)]
+pub struct ObjectVerification;
+
+#[async_trait]
+impl Stage for ObjectVerification {
+    fn name(&self) -> &'static str {
+        "object_verification"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if inbound.ty == ActivityType::Create
+            && state.cfg.inbox.verify_objects
+            && !verify_object(&inbound.actor_id, &inbound.activity, state).await?
+        {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Throttle origins whose activity has spiked. This runs as early as it can so that
+/// everything a signed actor sends counts towards its origin's rate: the Announces held
+/// back are passed through the stages that follow when they are released.
+#[derive(Debug)]
+pub struct Flood;
+
+#[async_trait]
+impl Stage for Flood {
+    fn name(&self) -> &'static str {
+        "flood"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        if state.cfg.flood.enabled && is_throttled(inbound, state)? {
+            return Ok(Flow::Stop);
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+/// Relay, forward or otherwise act on the activity according to its type.
+#[derive(Debug)]
+pub struct Dispatch;
+
+#[async_trait]
+impl Stage for Dispatch {
+    fn name(&self) -> &'static str {
+        "dispatch"
+    }
+
+    async fn run(&self, inbound: &mut Inbound<'_>, state: &State) -> Result<Flow> {
+        let activity = std::mem::take(&mut inbound.activity);
+        let (relay, host, raw) = (&inbound.relay, inbound.host, raw_activity(inbound.body));
+        let actor = inbound.actor()?;
+
+        match inbound.ty {
+            ActivityType::Announce | ActivityType::Create => {
+                handle_relay(relay, actor, activity, raw, host, state).await?
+            }
+            ActivityType::Add
+            | ActivityType::Delete
+            | ActivityType::Remove
+            | ActivityType::Update => handle_forward(relay, actor, activity, raw, state).await?,
+            ActivityType::Follow => {
+                let key_id = signature_key_id(inbound.headers).unwrap_or_default();
+                handle_follow(relay, actor, key_id, activity, host, state).await?
+            }
+            ActivityType::Undo => handle_undo(relay, actor, activity, raw, state).await?,
+            ty if ty.is_reaction() => handle_reaction(relay, actor, activity, raw, state).await?,
+            _ => (),
+        };
+
+        Ok(Flow::Continue)
+    }
+}
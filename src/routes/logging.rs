@@ -0,0 +1,94 @@
+//! Per-request logging.
+//!
+//! Every request is given an id which is attached to the tracing span for the request
+//! and returned in the `x-request-id` response header, so that failures reported by
+//! remote instances can be correlated with our logs.
+use crate::util::host_from_uri;
+use axum::{
+    http::{HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+pub async fn log_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let request_id = Uuid::new_v4().to_string();
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let source = source_domain(req.headers()).unwrap_or_else(|| "-".to_owned());
+
+    let span = info_span!("request", %request_id, %method, %path, %source);
+    let start = Instant::now();
+    let mut res = next.run(req).instrument(span.clone()).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let status = res.status().as_u16();
+
+    span.in_scope(|| {
+        if res.status().is_server_error() {
+            warn!(status, latency_ms, "request failed");
+        } else {
+            info!(status, latency_ms, "request complete");
+        }
+    });
+
+    if let Ok(val) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, val);
+    }
+
+    res
+}
+
+// The domain of the key used to sign the request, if it was signed
+fn source_domain(headers: &HeaderMap) -> Option<String> {
+    let signature = headers.get("signature")?.to_str().ok()?;
+    let (_, rest) = signature.split_once("keyId=\"")?;
+    let (key_id, _) = rest.split_once('"')?;
+
+    host_from_uri(key_id).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        routes::build_routes,
+        state::{Db, State},
+    };
+    use axum::body::Body;
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, sync::Arc};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn responses_have_a_request_id() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let req = Request::builder().uri("/capabilities").body(Body::empty());
+        let res = app.oneshot(req.unwrap()).await.unwrap();
+        let request_id = res.headers().get(REQUEST_ID_HEADER).unwrap();
+
+        assert!(Uuid::parse_str(request_id.to_str().unwrap()).is_ok());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(None, None; "unsigned")]
+    #[test_case(Some(r#"keyId="https://example.com/actor#main-key",algorithm="rsa-sha256""#), Some("example.com"); "signed")]
+    #[test_case(Some(r#"algorithm="rsa-sha256",keyId="https://example.com/actor#main-key""#), Some("example.com"); "key id not first")]
+    #[test_case(Some(r#"keyId="not a uri""#), None; "invalid key id")]
+    #[test]
+    fn source_domain_works(signature: Option<&str>, expected: Option<&str>) {
+        let mut headers = HeaderMap::new();
+        if let Some(sig) = signature {
+            headers.insert("signature", HeaderValue::from_str(sig).unwrap());
+        }
+
+        assert_eq!(source_domain(&headers).as_deref(), expected);
+    }
+}
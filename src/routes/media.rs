@@ -0,0 +1,30 @@
+//! Profile images for our relay actors (see [crate::images]).
+use crate::{state::State, Error, Result};
+use axum::{
+    extract::{Extension, Path},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+pub async fn get(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Response> {
+    let image = state.images.get(&name).ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "unknown image",
+    })?;
+
+    Ok((
+        [
+            (CONTENT_TYPE, image.media_type),
+            (CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        image.data.clone(),
+    )
+        .into_response())
+}
@@ -2,30 +2,71 @@
 //!
 //! We are implementing a subset of the activitypub API in order to function as a relay
 
-use crate::state::State;
+use crate::{
+    actors::{RelayActor, DEFAULT_ACTOR},
+    jsonld::PUBLIC,
+    state::State,
+    Error, Result,
+};
 
 use axum::{
-    extract::Host,
+    extract::{Host, Path},
+    http::StatusCode,
+    middleware,
     routing::{get, post},
     Extension, Router,
 };
 use rustypub::core::ContextBuilder;
 use serde_json::{json, Value};
 use std::sync::Arc;
+use uuid::Uuid;
 
+mod about;
+mod admin;
+mod capabilities;
 mod extractors;
-mod inbox;
+pub(crate) mod inbox;
+mod logging;
+mod media;
 mod nodeinfo;
-mod well_known;
+mod oauth;
+mod statuses;
+pub(crate) mod well_known;
 
 pub fn build_routes(state: Arc<State>) -> Router {
+    let inboxes = Router::new()
+        .route("/inbox", post(inbox::post))
+        // Some software POSTs to paths derived from the actor rather than using the
+        // inbox it advertises. The original path is still used to verify signatures.
+        .route("/actor/inbox", post(inbox::post))
+        .route("/users/relay/inbox", post(inbox::post))
+        .route("/actors/:name/inbox", post(inbox::post_for_topic))
+        .layer(middleware::from_fn(inbox::limit_body));
+
     Router::new()
+        .merge(inboxes)
         .route("/actor", get(get_actor))
-        .route("/inbox", post(inbox::post))
+        .route(
+            "/outbox",
+            get(statuses::get_outbox).post(statuses::post_outbox),
+        )
+        .route("/statuses/:id", get(statuses::get_status))
+        .route("/actors/:name", get(get_topic_actor))
+        .route("/media/:name", get(media::get))
+        .route("/about", get(about::get))
         .route("/.well-known/webfinger", get(well_known::webfinger))
         .route("/.well-known/host-meta", get(well_known::host_meta))
+        .route(
+            "/.well-known/host-meta.json",
+            get(well_known::host_meta_json),
+        )
         .route("/.well-known/nodeinfo", get(well_known::nodeinfo))
         .route("/nodeinfo/2.0", get(nodeinfo::get))
+        .route("/capabilities", get(capabilities::get))
+        .route("/oauth/token", post(oauth::token))
+        .route("/api/v1/selftest", get(admin::selftest))
+        .nest("/api/v1/admin", admin::routes())
+        .layer(middleware::from_fn(logging::log_requests))
         .layer(Extension(state))
 }
 
@@ -33,24 +74,144 @@ pub async fn get_actor(
     Host(host): Host,
     Extension(state): Extension<Arc<State>>,
 ) -> extractors::Activity<Value> {
-    extractors::Activity(json!({
+    extractors::Activity(actor_document(&RelayActor::main(&state), &host, &state))
+}
+
+pub async fn get_topic_actor(
+    Path(name): Path<String>,
+    Host(host): Host,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<extractors::Activity<Value>> {
+    let relay = state
+        .actors
+        .get(&name)
+        .map(RelayActor::topic)
+        .ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown actor",
+        })?;
+
+    Ok(extractors::Activity(actor_document(&relay, &host, &state)))
+}
+
+/// An Update of the given relay actor's profile, for telling its followers about
+/// changes to it. As with statuses, this is always built for the configured host.
+pub(crate) fn actor_update(relay: &RelayActor<'_>, state: &State) -> Value {
+    let host = &state.cfg.activity_pub.host;
+
+    json!({
+        "@context": ContextBuilder::default().build(),
+        "id": format!("https://{host}/activities/{}", Uuid::new_v4()),
+        "type": "Update",
+        "actor": relay.id(host),
+        "to": [PUBLIC],
+        "object": actor_document(relay, host, state),
+    })
+}
+
+/// The actor document of the given relay actor when served on the given host. The main
+/// actor keeps the shared inbox and outbox at the root of the server while topic actors
+/// have their own inbox under their actor path.
+fn actor_document(relay: &RelayActor<'_>, host: &str, state: &State) -> Value {
+    let id = relay.id(host);
+    let is_main = relay.name == DEFAULT_ACTOR;
+    let base = if is_main {
+        format!("https://{host}")
+    } else {
+        id.clone()
+    };
+    let inbox = format!("{base}/inbox");
+    let metadata = relay.db.actor_metadata();
+    let name = metadata.name.unwrap_or_else(|| {
+        if is_main {
+            "Actiserve".to_owned()
+        } else {
+            format!("Actiserve ({})", relay.name)
+        }
+    });
+
+    let mut actor = json!({
         "@context": ContextBuilder::default().build(),
         "endpoints": {
-            "sharedInbox": format!("https://{host}/inbox"),
+            "sharedInbox": inbox,
         },
-        "followers": format!("https://{host}/followers"),
-        "following": format!("https://{host}/following"),
-        "inbox": format!("https://{host}/inbox"),
-        "name": "Actiserve",
+        "followers": relay.followers(host),
+        "following": format!("{base}/following"),
+        "inbox": inbox,
+        "name": name,
         "type": "Application",
-        "id": format!("https://{host}/actor"),
+        "id": id,
         "publicKey": {
-            "id": format!("https://{host}/actor#main-key"),
-            "owner": format!("https://{host}/actor"),
-            "publicKeyPem": state.client.pub_key(),
+            "id": format!("{id}#main-key"),
+            "owner": id,
+            "publicKeyPem": state.client.pub_key(relay.name),
         },
-        "summary": "Actiserve bot",
-        "preferredUsername": "relay",
-        "url": format!("https://{host}/actor"),
-    }))
+        "summary": metadata
+            .summary
+            .as_deref()
+            .or(relay.summary)
+            .unwrap_or("Actiserve bot"),
+        "preferredUsername": relay.name,
+        "url": id,
+    });
+    if is_main {
+        actor["outbox"] = json!(format!("https://{host}/outbox"));
+    }
+    state.images.add_to_actor(&mut actor, host);
+
+    actor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{actors::TopicActor, config::ActorConfig, state::Db};
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all};
+
+    #[test_case(None, "https://relay.example/actor", "https://relay.example/inbox", "Actiserve", true; "main")]
+    #[test_case(Some("art"), "https://relay.example/actors/art", "https://relay.example/actors/art/inbox", "Actiserve (art)", false; "topic")]
+    #[test]
+    fn actor_documents_are_built_alike(
+        topic: Option<&str>,
+        id: &str,
+        inbox: &str,
+        name: &str,
+        has_outbox: bool,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.actors.insert(
+            "art".into(),
+            TopicActor::open(
+                ActorConfig {
+                    name: "art".into(),
+                    summary: Some("Art from around the fediverse".into()),
+                    tags: vec![],
+                    private_key_path: None,
+                },
+                &dir,
+            )
+            .unwrap(),
+        );
+        let relay = match topic {
+            Some(name) => RelayActor::topic(&state.actors[name]),
+            None => RelayActor::main(&state),
+        };
+
+        let actor = actor_document(&relay, "relay.example", &state);
+
+        assert_eq!(actor["id"], id);
+        assert_eq!(actor["url"], id);
+        assert_eq!(actor["publicKey"]["owner"], id);
+        assert_eq!(actor["publicKey"]["id"], format!("{id}#main-key"));
+        assert_eq!(actor["inbox"], inbox);
+        assert_eq!(actor["endpoints"]["sharedInbox"], inbox);
+        assert_eq!(actor["name"], name);
+        assert_eq!(actor["preferredUsername"], relay.name);
+        assert_eq!(actor.get("outbox").is_some(), has_outbox);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
 }
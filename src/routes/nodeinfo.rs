@@ -2,10 +2,10 @@
 //!
 //! The schema for the reponse format can be found here:
 //!   http://nodeinfo.diaspora.software/ns/schema/2.0#
-use crate::state::State;
+use crate::{routes::capabilities::Capabilities, state::State};
 use axum::{extract::Json, http::header, response::IntoResponse, Extension};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 use std::sync::Arc;
 
 pub const NODE_INFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/2.0";
@@ -30,7 +30,7 @@ pub struct NodeInfo {
     open_registrations: bool,
     usage: UsageStats,
     #[serde(skip_serializing_if = "Option::is_none")]
-    meta_data: Option<Value>,
+    metadata: Option<Value>,
 }
 
 impl NodeInfo {
@@ -42,11 +42,36 @@ impl NodeInfo {
             services: Services::default(),
             open_registrations: false, // TODO: double check what we should return here as a relay
             usage: UsageStats::new(state),
-            meta_data: None,
+            metadata: Some(metadata(state)),
         }
     }
 }
 
+// Operator details are only included if they have been configured
+fn metadata(state: &State) -> Value {
+    let operator = &state.cfg.operator;
+    let mut metadata = json!({ "capabilities": Capabilities::new(&state.cfg) });
+
+    let contact: Map<String, Value> = [
+        ("account", &operator.contact_account),
+        ("email", &operator.contact_email),
+    ]
+    .into_iter()
+    .filter_map(|(k, v)| Some((k.to_owned(), v.clone()?.into())))
+    .collect();
+    if !contact.is_empty() {
+        metadata["contact"] = contact.into();
+    }
+    if let Some(url) = &operator.rules_url {
+        metadata["rulesUrl"] = url.clone().into();
+    }
+    if let Some(summary) = &operator.policy_summary {
+        metadata["policySummary"] = summary.clone().into();
+    }
+
+    metadata
+}
+
 /// Metadata about server software in use.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Software {
@@ -169,3 +194,31 @@ pub struct UserStats {
     // active_half_year: u32,
     // active_month: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Db;
+    use std::{env::temp_dir, fs::remove_dir_all};
+    use uuid::Uuid;
+
+    #[test]
+    fn configured_operator_details_are_included_in_metadata() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.operator.contact_email = Some("admin@relay.example".into());
+        state.cfg.operator.rules_url = Some("https://relay.example/about".into());
+
+        let metadata = metadata(&state);
+
+        assert_eq!(
+            metadata["contact"],
+            json!({ "email": "admin@relay.example" })
+        );
+        assert_eq!(metadata["rulesUrl"], "https://relay.example/about");
+        assert!(metadata.get("policySummary").is_none());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
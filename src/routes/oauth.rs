@@ -0,0 +1,203 @@
+//! The OAuth2 token endpoint for clients of the admin API.
+//!
+//! Only the client credentials grant is supported: see RFC 6749 section 4.4
+//!   https://www.rfc-editor.org/rfc/rfc6749#section-4.4
+use crate::{
+    auth::{Scope, TOKEN_TTL_SECS},
+    state::State,
+    Error, Result,
+};
+use axum::{
+    extract::{Extension, Form, Json},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    grant_type: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    /// Space separated scopes being requested: defaults to all of the client's scopes
+    scope: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+}
+
+pub async fn token(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+    Form(req): Form<TokenRequest>,
+) -> Result<Json<TokenResponse>> {
+    // OAuth clients are only usable when the admin API is enabled
+    if state.cfg.admin_token.is_none() {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "admin API is disabled",
+        });
+    }
+
+    if req.grant_type != "client_credentials" {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "unsupported_grant_type",
+        });
+    }
+
+    let invalid_client = Error::StatusAndMessage {
+        status: StatusCode::UNAUTHORIZED,
+        message: "invalid_client",
+    };
+
+    let (client_id, secret) = match (
+        basic_credentials(&headers),
+        req.client_id,
+        req.client_secret,
+    ) {
+        (Some(creds), _, _) => creds,
+        (None, Some(id), Some(secret)) => (id, secret),
+        _ => return Err(invalid_client),
+    };
+
+    let client = match state.db.oauth_client(&client_id) {
+        Some(client) if client.verify_secret(&secret) => client,
+        _ => return Err(invalid_client),
+    };
+
+    let invalid_scope = || Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "invalid_scope",
+    };
+
+    let scopes: Vec<Scope> = match req.scope {
+        Some(scope) => scope
+            .split_whitespace()
+            .map(|s| s.parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| invalid_scope())?,
+        None => client.scopes.clone(),
+    };
+    if scopes.iter().any(|s| !client.scopes.contains(s)) {
+        return Err(invalid_scope());
+    }
+
+    info!(client_id=%client.client_id, ?scopes, "issuing access token");
+    let scope = scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let (access_token, _) = state.tokens.issue(&client.client_id, scopes);
+
+    Ok(Json(TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: TOKEN_TTL_SECS,
+        scope,
+    }))
+}
+
+// Client credentials provided via HTTP Basic authentication
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+
+    Some((id.to_owned(), secret.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        auth::{OAuthClient, Scope},
+        routes::build_routes,
+        state::{Db, State},
+    };
+    use axum::{
+        body::Body,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE},
+            Request, StatusCode,
+        },
+    };
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, sync::Arc};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    #[test_case("grant_type=client_credentials&client_id={id}&client_secret={secret}", StatusCode::OK; "valid credentials")]
+    #[test_case("grant_type=client_credentials&client_id={id}&client_secret=nope", StatusCode::UNAUTHORIZED; "wrong secret")]
+    #[test_case("grant_type=client_credentials&client_id=nope&client_secret={secret}", StatusCode::UNAUTHORIZED; "unknown client")]
+    #[test_case("grant_type=client_credentials", StatusCode::UNAUTHORIZED; "missing credentials")]
+    #[test_case("grant_type=password&client_id={id}&client_secret={secret}", StatusCode::BAD_REQUEST; "unsupported grant")]
+    #[test_case("grant_type=client_credentials&client_id={id}&client_secret={secret}&scope=read:stats", StatusCode::OK; "granted scope")]
+    #[test_case("grant_type=client_credentials&client_id={id}&client_secret={secret}&scope=write:blocks", StatusCode::BAD_REQUEST; "scope not granted to client")]
+    #[test_case("grant_type=client_credentials&client_id={id}&client_secret={secret}&scope=admin", StatusCode::BAD_REQUEST; "unknown scope")]
+    #[tokio::test]
+    async fn token_requests_are_validated(body: &str, expected: StatusCode) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let (client, secret) = OAuthClient::new("stats".into(), vec![Scope::ReadStats]);
+        let body = body
+            .replace("{id}", &client.client_id)
+            .replace("{secret}", &secret);
+        db.add_oauth_client(client);
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let req = Request::post("/oauth/token")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn issued_tokens_can_access_the_admin_api() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let (client, secret) = OAuthClient::new("stats".into(), vec![Scope::ReadStats]);
+        let basic = base64::encode(format!("{}:{secret}", client.client_id));
+        db.add_oauth_client(client);
+        let app = build_routes(Arc::new(State::new_with_test_key(db)));
+
+        let req = Request::post("/oauth/token")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(AUTHORIZATION, format!("Basic {basic}"))
+            .body(Body::from("grant_type=client_credentials"))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["token_type"], "Bearer");
+        assert_eq!(json["scope"], "read:stats");
+
+        let token = json["access_token"].as_str().unwrap();
+        let req = Request::get("/api/v1/admin/metrics")
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
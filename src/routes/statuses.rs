@@ -0,0 +1,296 @@
+//! Posts published by the main relay actor itself.
+//!
+//! Operators can POST a Note (or a Create wrapping one) to the relay actor's outbox,
+//! following the ActivityPub client-to-server API, to announce things like planned
+//! maintenance or policy changes to every subscribed instance. Posting requires the
+//! admin token or an access token with the `write:statuses` scope.
+use super::{
+    admin::{Admin, WriteStatuses},
+    extractors,
+};
+use crate::{actors::RelayActor, delivery::Delivery, jsonld::PUBLIC, state::State, Error, Result};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{header::LOCATION, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use rustypub::core::ContextBuilder;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+/// The number of statuses in each page of the outbox.
+pub const OUTBOX_PAGE_SIZE: usize = 20;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct OutboxParams {
+    /// The (1 based) page of statuses to return
+    page: Option<usize>,
+}
+
+/// Publish a Note as the relay actor, delivering it to every subscribed instance.
+///
+/// The ids of published statuses are stored and served for as long as the status is
+/// kept, so they are always built from the configured host rather than whichever host
+/// the request happened to be made to.
+pub async fn post_outbox(
+    _: Admin<WriteStatuses>,
+    Extension(state): Extension<Arc<State>>,
+    Json(posted): Json<Value>,
+) -> Result<Response> {
+    let relay = RelayActor::main(&state);
+    let id = Uuid::new_v4().to_string();
+    let create = build_create(&relay, &state.cfg.activity_pub.host, &id, &posted)?;
+    let activity_id = create["id"].as_str().unwrap_or_default().to_owned();
+
+    let deliveries = Delivery::fanout(relay.name, &state.db.delivery_inboxes(), &create);
+    info!(%activity_id, n_inboxes = deliveries.len(), "publishing status");
+    state.db.add_status(id, create.clone());
+    state.deliver(deliveries);
+
+    Ok((
+        StatusCode::CREATED,
+        [(LOCATION, activity_id)],
+        extractors::Activity(create),
+    )
+        .into_response())
+}
+
+/// The statuses published by the relay actor, newest first. The collection itself only
+/// links to its first page, with each page linking on to the next.
+pub async fn get_outbox(
+    Query(params): Query<OutboxParams>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<extractors::Activity<Value>> {
+    let outbox = format!("https://{}/outbox", state.cfg.activity_pub.host);
+    let items = state.db.statuses();
+
+    let page = match params.page {
+        None => {
+            return Ok(extractors::Activity(json!({
+                "@context": ContextBuilder::default().build(),
+                "id": outbox,
+                "type": "OrderedCollection",
+                "totalItems": items.len(),
+                "first": format!("{outbox}?page=1"),
+            })))
+        }
+        Some(0) => {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_REQUEST,
+                message: "pages start at 1",
+            })
+        }
+        Some(page) => page,
+    };
+
+    let start = (page - 1).saturating_mul(OUTBOX_PAGE_SIZE);
+    let has_next = items.len() > start.saturating_add(OUTBOX_PAGE_SIZE);
+    let page_items: Vec<Value> = items
+        .into_iter()
+        .skip(start)
+        .take(OUTBOX_PAGE_SIZE)
+        .collect();
+
+    let mut collection_page = json!({
+        "@context": ContextBuilder::default().build(),
+        "id": format!("{outbox}?page={page}"),
+        "type": "OrderedCollectionPage",
+        "partOf": outbox,
+        "orderedItems": page_items,
+    });
+    if has_next {
+        collection_page["next"] = format!("{outbox}?page={}", page + 1).into();
+    }
+    if page > 1 {
+        collection_page["prev"] = format!("{outbox}?page={}", page - 1).into();
+    }
+
+    Ok(extractors::Activity(collection_page))
+}
+
+/// A single Note published by the relay actor.
+pub async fn get_status(
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<extractors::Activity<Value>> {
+    let mut create = state.db.status(&id).ok_or(Error::StatusAndMessage {
+        status: StatusCode::NOT_FOUND,
+        message: "unknown status",
+    })?;
+
+    let mut note = create["object"].take();
+    note["@context"] = create["@context"].take();
+
+    Ok(extractors::Activity(note))
+}
+
+/// Wrap the posted Note in a Create from the relay actor, addressed publicly and to
+/// the relay's followers. Only the content, summary (content warning) and sensitive
+/// flag of the posted Note are used: everything else is set by us.
+fn build_create(relay: &RelayActor<'_>, host: &str, id: &str, posted: &Value) -> Result<Value> {
+    let note = match posted["type"].as_str() {
+        Some("Create") => &posted["object"],
+        _ => posted,
+    };
+    if note["type"] != "Note" {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::BAD_REQUEST,
+            message: "only Notes can be posted",
+        });
+    }
+    let content = match note["content"].as_str() {
+        Some(content) if !content.trim().is_empty() => content,
+        _ => {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_REQUEST,
+                message: "notes must have content",
+            })
+        }
+    };
+
+    let actor = relay.id(host);
+    let followers = relay.followers(host);
+    let note_id = format!("https://{host}/statuses/{id}");
+    let published = Utc::now().to_rfc3339();
+
+    let mut object = json!({
+        "id": note_id,
+        "type": "Note",
+        "attributedTo": actor,
+        "content": content,
+        "published": published,
+        "sensitive": note["sensitive"] == true,
+        "to": [PUBLIC],
+        "cc": [followers],
+        "url": note_id,
+    });
+    if let Some(summary) = note["summary"].as_str().filter(|s| !s.is_empty()) {
+        object["summary"] = summary.into();
+    }
+
+    Ok(json!({
+        "@context": ContextBuilder::default().build(),
+        "id": format!("{note_id}/activity"),
+        "type": "Create",
+        "actor": actor,
+        "published": published,
+        "to": [PUBLIC],
+        "cc": [followers],
+        "object": object,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes::build_routes, state::Db};
+    use axum::{
+        body::Body,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE, HOST},
+            Request,
+        },
+    };
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all};
+    use tower::ServiceExt;
+
+    #[test_case(json!({ "type": "Note", "content": "<p>hello</p>" }), true; "note")]
+    #[test_case(json!({ "type": "Create", "object": { "type": "Note", "content": "hi" } }), true; "create")]
+    #[test_case(json!({ "type": "Note", "content": " " }), false; "empty content")]
+    #[test_case(json!({ "type": "Question", "content": "hi" }), false; "not a note")]
+    #[test]
+    fn only_notes_with_content_can_be_posted(posted: Value, ok: bool) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+
+        let res = build_create(&relay, "relay.example", "1", &posted);
+
+        assert_eq!(res.is_ok(), ok);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(Some("Bearer test-token"), StatusCode::CREATED; "admin token")]
+    #[test_case(None, StatusCode::UNAUTHORIZED; "missing token")]
+    #[tokio::test]
+    async fn statuses_are_published_to_subscribers(auth: Option<&str>, expected: StatusCode) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        state
+            .db
+            .add_inbox_if_unknown("https://a.example/inbox".into(), None)
+            .unwrap();
+        let app = build_routes(state.clone());
+
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/outbox")
+            .header(HOST, "other.example")
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(auth) = auth {
+            req = req.header(AUTHORIZATION, auth);
+        }
+        let body = Body::from(r#"{"type":"Note","content":"Maintenance tonight"}"#);
+        let res = app.oneshot(req.body(body).unwrap()).await.unwrap();
+
+        assert_eq!(res.status(), expected);
+        if expected == StatusCode::CREATED {
+            let statuses = state.db.statuses();
+            assert_eq!(statuses.len(), 1);
+            // The configured host is used whatever host the request was made to
+            assert_eq!(statuses[0]["actor"], "https://localhost/actor");
+            assert_eq!(statuses[0]["object"]["content"], "Maintenance tonight");
+            assert_eq!(state.deliveries.status().queued, 1);
+        }
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(None, None, None; "collection")]
+    #[test_case(Some(1), Some("https://localhost/outbox?page=2"), None; "first page")]
+    #[test_case(Some(2), Some("https://localhost/outbox?page=3"), Some("https://localhost/outbox?page=1"); "middle page")]
+    #[test_case(Some(3), None, Some("https://localhost/outbox?page=2"); "last page")]
+    #[tokio::test]
+    async fn the_outbox_is_paginated(page: Option<usize>, next: Option<&str>, prev: Option<&str>) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = Arc::new(State::new_with_test_key(db));
+        let relay = RelayActor::main(&state);
+        let posted = json!({ "type": "Note", "content": "hi" });
+        for n in 0..(2 * OUTBOX_PAGE_SIZE + 1) {
+            let id = n.to_string();
+            let create = build_create(&relay, "localhost", &id, &posted).unwrap();
+            state.db.add_status(id, create);
+        }
+
+        let params = OutboxParams { page };
+        let extractors::Activity(res) = get_outbox(Query(params), Extension(state)).await.unwrap();
+
+        match page {
+            None => {
+                assert_eq!(res["type"], "OrderedCollection");
+                assert_eq!(res["totalItems"], 2 * OUTBOX_PAGE_SIZE + 1);
+                assert_eq!(res["first"], "https://localhost/outbox?page=1");
+                assert!(res.get("orderedItems").is_none());
+            }
+            Some(page) => {
+                let expected = if page == 3 { 1 } else { OUTBOX_PAGE_SIZE };
+                assert_eq!(res["type"], "OrderedCollectionPage");
+                assert_eq!(res["partOf"], "https://localhost/outbox");
+                assert_eq!(res["orderedItems"].as_array().unwrap().len(), expected);
+                assert_eq!(res["next"].as_str(), next);
+                assert_eq!(res["prev"].as_str(), prev);
+            }
+        }
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
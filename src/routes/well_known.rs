@@ -1,28 +1,45 @@
 use crate::{
-    routes::{extractors::Jrd, nodeinfo::NODE_INFO_SCHEMA},
+    actors::actor_path,
+    routes::{
+        extractors::{Jrd, Xrd},
+        nodeinfo::NODE_INFO_SCHEMA,
+    },
     state::State,
     Error, Result,
 };
 use axum::{
     extract::{Extension, Host, Query},
-    http::{header, StatusCode},
-    response::IntoResponse,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::sync::Arc;
 
-pub async fn host_meta(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
-    let headers = [(header::CONTENT_TYPE, "application/xrd+xml")];
-    let base = state.cfg.base_url();
-    let body = format!(
-        r#"<?xml version="1.0"?>
-<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
-  <Link rel="lrdd" type="application/xrd+xml" template="{base}/.well-known/webfinger?resource={{uri}}"/>
-</XRD>"#
-    );
+pub async fn host_meta(Extension(state): Extension<Arc<State>>) -> Xrd {
+    Xrd(host_meta_resource(&state.cfg.base_url(), "application/xrd+xml").to_xrd())
+}
+
+pub async fn host_meta_json(Extension(state): Extension<Arc<State>>) -> Jrd<Resource> {
+    Jrd(host_meta_resource(
+        &state.cfg.base_url(),
+        "application/jrd+json",
+    ))
+}
 
-    (headers, body)
+// https://www.rfc-editor.org/rfc/rfc6415
+fn host_meta_resource(base: &str, ty: &str) -> Resource {
+    Resource {
+        aliases: vec![],
+        subject: None,
+        links: vec![Link {
+            rel: "lrdd".to_owned(),
+            ty: Some(ty.to_owned()),
+            href: None,
+            template: Some(format!("{base}/.well-known/webfinger?resource={{uri}}")),
+        }],
+    }
 }
 
 pub async fn nodeinfo(Extension(state): Extension<Arc<State>>) -> Jrd<Value> {
@@ -38,33 +55,83 @@ pub async fn nodeinfo(Extension(state): Extension<Arc<State>>) -> Jrd<Value> {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Resource {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     aliases: Vec<String>,
     links: Vec<Link>,
-    subject: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+}
+
+impl Resource {
+    /// Only keep links matching one of the requested rels (if any were requested)
+    fn retain_rels(&mut self, rels: &[String]) {
+        if !rels.is_empty() {
+            self.links.retain(|link| rels.contains(&link.rel));
+        }
+    }
+
+    /// Render this resource as an XRD document
+    // https://docs.oasis-open.org/xri/xrd/v1.0/xrd-1.0.html
+    fn to_xrd(&self) -> String {
+        let mut lines = vec![
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_owned(),
+            r#"<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">"#.to_owned(),
+        ];
+
+        if let Some(subject) = &self.subject {
+            lines.push(format!("  <Subject>{}</Subject>", xml_escape(subject)));
+        }
+
+        lines.extend(
+            self.aliases
+                .iter()
+                .map(|alias| format!("  <Alias>{}</Alias>", xml_escape(alias))),
+        );
+
+        lines.extend(self.links.iter().map(|link| {
+            let attrs = [
+                ("rel", Some(&link.rel)),
+                ("type", link.ty.as_ref()),
+                ("href", link.href.as_ref()),
+                ("template", link.template.as_ref()),
+            ];
+
+            let attrs = attrs
+                .iter()
+                .filter_map(|(k, v)| v.map(|v| format!(r#"{k}="{}""#, xml_escape(v))))
+                .join(" ");
+
+            format!("  <Link {attrs}/>")
+        }));
+
+        lines.push("</XRD>".to_owned());
+
+        lines.join("\n")
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Link {
-    href: String,
     rel: String,
-    #[serde(rename = "type")]
-    ty: String,
-}
-
-// TODO: support rel?
-#[derive(Debug, Deserialize)]
-pub struct Params {
-    resource: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    ty: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    href: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
 }
 
 // https://tools.ietf.org/html/rfc7033
+//
+// The query params are extracted as raw pairs as rel may be provided multiple times.
 pub async fn webfinger(
     Host(host): Host,
-    params: Option<Query<Params>>,
+    headers: HeaderMap,
+    Query(params): Query<Vec<(String, String)>>,
     Extension(state): Extension<Arc<State>>,
-) -> Result<Jrd<Resource>> {
-    let Query(Params { resource }) = match params {
-        Some(params) => params,
+) -> Result<Response> {
+    let resource = match params.iter().find(|(k, _)| k == "resource") {
+        Some((_, resource)) => resource,
         None => {
             return Err(Error::StatusAndMessage {
                 status: StatusCode::BAD_REQUEST,
@@ -72,39 +139,93 @@ pub async fn webfinger(
             })
         }
     };
+    let rels: Vec<String> = params
+        .iter()
+        .filter(|(k, _)| k == "rel")
+        .map(|(_, v)| v.clone())
+        .collect();
 
-    let (user, domain) = parse_webfinger_resource(&resource)?;
+    let (user, domain) = parse_webfinger_resource(resource)?;
 
-    if user != "relay" || domain != host {
+    if state.actor(user).is_none() || domain != host {
         return Err(Error::StatusAndMessage {
             status: StatusCode::NOT_FOUND,
             message: "user not found",
         });
     }
 
-    let href = format!("{}/actor", state.cfg.base_url());
+    // Built from the host the request was made to, in the same way as the ids of the
+    // actors themselves, rather than from the address we listen on (which is usually
+    // a local address behind a reverse proxy and was previously served here by mistake)
+    let href = format!("https://{host}{}", actor_path(user));
 
-    Ok(Jrd(Resource {
+    let mut resource = Resource {
         aliases: vec![href.clone()],
-        subject: resource.clone(),
+        subject: Some(resource.clone()),
         links: vec![
             Link {
-                href: href.clone(),
                 rel: "self".to_owned(),
-                ty: r#"application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\""#
-                    .to_owned(),
+                ty: Some(
+                    r#"application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\""#
+                        .to_owned(),
+                ),
+                href: Some(href.clone()),
+                template: None,
             },
             Link {
-                href,
                 rel: "self".to_owned(),
-                ty: "application/activity+json".to_owned(),
+                ty: Some("application/activity+json".to_owned()),
+                href: Some(href),
+                template: None,
             },
         ],
-    }))
+    };
+    resource.retain_rels(&rels);
+
+    if prefers_xrd(&headers) {
+        Ok(Xrd(resource.to_xrd()).into_response())
+    } else {
+        Ok(Jrd(resource).into_response())
+    }
+}
+
+// JRD is the default representation for webfinger so we only return XRD if the client
+// has explicitly asked for it with a higher preference than JSON.
+fn prefers_xrd(headers: &HeaderMap) -> bool {
+    let accept = match headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    let (mut xrd, mut json) = (0.0, 0.0);
+    for media_range in accept.split(',') {
+        let mut parts = media_range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media_type {
+            "application/xrd+xml" | "application/xml" | "text/xml" => xrd = f32::max(xrd, q),
+            "application/jrd+json" | "application/json" | "*/*" => json = f32::max(json, q),
+            _ => (),
+        }
+    }
+
+    xrd > json
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
 }
 
 // parse a resource param of the form: /.well-known/webfinger?resource=acct:bob@my-example.com
-fn parse_webfinger_resource(resource: &str) -> Result<(&str, &str)> {
+pub(crate) fn parse_webfinger_resource(resource: &str) -> Result<(&str, &str)> {
     let uri = match resource.strip_prefix("acct:") {
         Some(s) => s,
 
@@ -143,4 +264,97 @@ mod tests {
 
         assert_eq!(res, expected)
     }
+
+    #[test_case(None, false; "no accept header")]
+    #[test_case(Some("application/jrd+json"), false; "jrd")]
+    #[test_case(Some("application/xrd+xml"), true; "xrd")]
+    #[test_case(Some("*/*"), false; "anything")]
+    #[test_case(Some("application/xrd+xml, application/jrd+json"), false; "both")]
+    #[test_case(Some("application/xrd+xml, application/jrd+json;q=0.5"), true; "xrd preferred")]
+    #[test_case(Some("application/xrd+xml;q=0.2, */*;q=0.1"), true; "xrd preferred over wildcard")]
+    #[test]
+    fn prefers_xrd_works(accept: Option<&str>, expected: bool) {
+        let mut headers = HeaderMap::new();
+        if let Some(accept) = accept {
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+        }
+
+        assert_eq!(prefers_xrd(&headers), expected);
+    }
+
+    fn test_resource() -> Resource {
+        Resource {
+            aliases: vec!["https://example.com/actor".to_owned()],
+            subject: Some("acct:relay@example.com".to_owned()),
+            links: vec![
+                Link {
+                    rel: "self".to_owned(),
+                    ty: Some("application/activity+json".to_owned()),
+                    href: Some("https://example.com/actor".to_owned()),
+                    template: None,
+                },
+                Link {
+                    rel: "http://webfinger.net/rel/profile-page".to_owned(),
+                    ty: Some("text/html".to_owned()),
+                    href: Some("https://example.com/about".to_owned()),
+                    template: None,
+                },
+            ],
+        }
+    }
+
+    #[test_case(&[], 2; "no rels")]
+    #[test_case(&["self"], 1; "single rel")]
+    #[test_case(&["self", "http://webfinger.net/rel/profile-page"], 2; "multiple rels")]
+    #[test_case(&["unknown"], 0; "unknown rel")]
+    #[test]
+    fn retain_rels_works(rels: &[&str], expected: usize) {
+        let rels: Vec<String> = rels.iter().map(|s| s.to_string()).collect();
+        let mut resource = test_resource();
+
+        resource.retain_rels(&rels);
+
+        assert_eq!(resource.links.len(), expected);
+    }
+
+    #[test]
+    fn to_xrd_works() {
+        let xrd = test_resource().to_xrd();
+
+        let expected = r#"<?xml version="1.0" encoding="UTF-8"?>
+<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+  <Subject>acct:relay@example.com</Subject>
+  <Alias>https://example.com/actor</Alias>
+  <Link rel="self" type="application/activity+json" href="https://example.com/actor"/>
+  <Link rel="http://webfinger.net/rel/profile-page" type="text/html" href="https://example.com/about"/>
+</XRD>"#;
+
+        assert_eq!(xrd, expected);
+    }
+
+    #[test]
+    fn host_meta_xml_and_json_share_the_same_data() {
+        let xml = host_meta_resource("https://example.com", "application/xrd+xml").to_xrd();
+        let json = serde_json::to_value(host_meta_resource(
+            "https://example.com",
+            "application/jrd+json",
+        ))
+        .unwrap();
+
+        let expected_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+  <Link rel="lrdd" type="application/xrd+xml" template="https://example.com/.well-known/webfinger?resource={uri}"/>
+</XRD>"#;
+
+        let expected_json = json!({
+            "links": [{
+                "rel": "lrdd",
+                "type": "application/jrd+json",
+                "template": "https://example.com/.well-known/webfinger?resource={uri}",
+            }]
+        });
+
+        assert_eq!(xml, expected_xml);
+        assert_eq!(json, expected_json);
+    }
 }
@@ -0,0 +1,165 @@
+//! Checks of our own federation setup.
+//!
+//! Peers only ever see the relay via the configured public host, so a misconfigured
+//! `activityPub.host` (or a proxy in front of us that doesn't route correctly) shows up
+//! as failed deliveries and follows on their side. The self-test requests our own
+//! documents via the public host so that these problems can be caught up front.
+use crate::{
+    actors::{actor_path, DEFAULT_ACTOR},
+    probe::{actor_link, ProbeReport},
+    state::State,
+    Error, Result,
+};
+use axum::http::StatusCode;
+use std::net::{IpAddr, ToSocketAddrs};
+
+/// Run every check, reporting the outcome of each.
+pub async fn selftest(state: &State) -> ProbeReport {
+    let host = &state.cfg.activity_pub.host;
+    let actor_id = format!("https://{host}{}", actor_path(DEFAULT_ACTOR));
+    let mut report = ProbeReport::new(host);
+
+    report.step("dns", check_dns(host.clone())).await;
+
+    let keys = async {
+        for relay in state.relay_actors() {
+            state.client.check_key(relay.name).await?;
+        }
+
+        Ok(((), "all actor keys sign and verify".to_owned()))
+    };
+    report.step("keys", keys).await;
+
+    let actor = async {
+        let actor = state
+            .client
+            .get_unsigned_json(&actor_id, "application/activity+json")
+            .await?;
+
+        if actor["id"] != actor_id.as_str() {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_GATEWAY,
+                message: "actor id does not match the configured host",
+            });
+        }
+        if actor["publicKey"]["publicKeyPem"] != state.client.pub_key(DEFAULT_ACTOR).as_str() {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_GATEWAY,
+                message: "actor is not publishing our public key",
+            });
+        }
+
+        Ok(((), format!("fetched {actor_id}")))
+    };
+    report.step("actor", actor).await;
+
+    let webfinger = async {
+        let resource = format!("acct:{DEFAULT_ACTOR}@{host}");
+        let uri = format!("https://{host}/.well-known/webfinger?resource={resource}");
+        let jrd = state
+            .client
+            .get_unsigned_json(&uri, "application/jrd+json")
+            .await?;
+
+        match actor_link(&jrd) {
+            Some(href) if href == actor_id => Ok(((), format!("resolved {resource}"))),
+            _ => Err(Error::StatusAndMessage {
+                status: StatusCode::BAD_GATEWAY,
+                message: "webfinger does not resolve to our actor",
+            }),
+        }
+    };
+    report.step("webfinger", webfinger).await;
+
+    report
+}
+
+async fn check_dns(host: String) -> Result<((), String)> {
+    let unresolvable = || Error::StatusAndMessage {
+        status: StatusCode::BAD_GATEWAY,
+        message: "host does not resolve",
+    };
+
+    let addrs: Vec<IpAddr> =
+        tokio::task::spawn_blocking(move || (host.as_str(), 443).to_socket_addrs())
+            .await
+            .map_err(|_| unresolvable())?
+            .map_err(|_| unresolvable())?
+            .map(|addr| addr.ip())
+            .collect();
+
+    if addrs.is_empty() {
+        return Err(unresolvable());
+    }
+    if !addrs.iter().any(|ip| is_public(*ip)) {
+        return Err(Error::StatusAndMessage {
+            status: StatusCode::BAD_GATEWAY,
+            message: "host only resolves to private addresses",
+        });
+    }
+
+    Ok(((), format!("resolves to {addrs:?}")))
+}
+
+// Whether an address is reachable from the wider internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+            let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Db;
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all};
+    use uuid::Uuid;
+
+    #[test_case("1.1.1.1", true; "public v4")]
+    #[test_case("10.1.2.3", false; "private v4")]
+    #[test_case("127.0.0.1", false; "loopback v4")]
+    #[test_case("169.254.0.1", false; "link local v4")]
+    #[test_case("2606:4700::1111", true; "public v6")]
+    #[test_case("::1", false; "loopback v6")]
+    #[test_case("fd00::1", false; "unique local v6")]
+    #[test_case("fe80::1", false; "link local v6")]
+    #[test]
+    fn is_public_works(ip: &str, expected: bool) {
+        assert_eq!(is_public(ip.parse().unwrap()), expected);
+    }
+
+    #[tokio::test]
+    async fn local_setups_fail_the_selftest() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let db = Db::new(dir.clone()).expect("unable to create database");
+        let state = State::new_with_test_key(db);
+
+        let report = selftest(&state).await;
+        let failed: Vec<&str> = report
+            .steps
+            .iter()
+            .filter(|s| !s.ok)
+            .map(|s| s.name)
+            .collect();
+
+        assert!(!report.ok);
+        assert!(failed.contains(&"dns"));
+        assert!(!failed.contains(&"keys"));
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
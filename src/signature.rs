@@ -1,11 +1,15 @@
-use crate::{Error, Result};
-use axum::http::{HeaderMap, Uri};
-use chrono::Utc;
+use crate::{config::SignatureAlgorithm, signer::Signer, Error, Result};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, Uri},
+};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use itertools::Itertools;
 use reqwest::StatusCode;
 use rsa::{
-    pkcs1v15::{Signature, SigningKey, VerifyingKey},
-    signature::{RandomizedSigner, Signature as _, Verifier},
+    pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
+    pkcs1v15::{Signature, VerifyingKey},
+    signature::Verifier,
     RsaPublicKey,
 };
 use rustypub::extended::Actor;
@@ -13,6 +17,14 @@ use sha2::{Digest, Sha256, Sha512};
 use std::{collections::HashMap, convert::TryInto};
 use tracing::debug;
 
+// How long signatures using (expires) are valid for, and how far we allow the clocks of
+// other servers to drift from ours when checking (created) and (expires)
+const SIGNATURE_VALIDITY_SECS: i64 = 5 * 60;
+const MAX_CLOCK_SKEW_SECS: f64 = 5.0 * 60.0;
+// Signatures giving (created) are rejected once they are older than this, whether or
+// not they give (expires), so that captured requests can't be replayed indefinitely
+const MAX_SIGNATURE_AGE_SECS: f64 = SIGNATURE_VALIDITY_SECS as f64 + MAX_CLOCK_SKEW_SECS;
+
 // If something was wrong with the signature we don't want to leak any details about
 // why we have rejected it.
 const INVALID_SIG: Error = Error::StatusAndMessage {
@@ -20,43 +32,82 @@ const INVALID_SIG: Error = Error::StatusAndMessage {
     message: "invalid HTTP signature",
 };
 
-pub fn sign_request_headers(
-    base: &str,
+/// A serialized request body along with the signed headers derived from it, so that
+/// the same body can be signed for any number of requests without being serialized or
+/// hashed again. Only the headers that differ per request (the request target, host
+/// and date) are computed when signing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Body {
+    bytes: Bytes,
+    content_length: String,
+    digest: String,
+}
+
+impl Body {
+    pub fn new(bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        let h = hmac_sha256::Hash::hash(&bytes);
+        let digest = format!("SHA-256={}", base64::encode(h));
+        let content_length = bytes.len().to_string();
+
+        Self {
+            bytes,
+            content_length,
+            digest,
+        }
+    }
+
+    /// The body as bytes, which are shared rather than copied when cloned.
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    /// The value of the Digest header for the body.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+}
+
+pub async fn sign_request_headers(
+    key_id: &str,
     uri: &str,
-    data: Option<&str>,
-    sig_key: &SigningKey<Sha256>,
+    body: Option<&Body>,
+    signer: &dyn Signer,
+    algorithm: SignatureAlgorithm,
 ) -> Result<HeaderMap> {
     let uri = uri.parse::<Uri>().map_err(|_| Error::InvalidUri {
         uri: uri.to_owned(),
     })?;
 
-    let method = if data.is_some() { "post" } else { "get" };
-    let path = uri.path();
+    let method = if body.is_some() { "post" } else { "get" };
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
     let host = uri.host().ok_or(Error::InvalidUri {
         uri: uri.to_string(),
     })?;
     let target = format!("{method} {path}");
     let date = now();
+    let created = Utc::now().timestamp();
+    let (created, expires) = (
+        created.to_string(),
+        (created + SIGNATURE_VALIDITY_SECS).to_string(),
+    );
+
+    let mut pairs: Vec<(&str, &str)> = vec![("(request-target)", &target)];
+    // The (created) and (expires) pseudo-headers must not be used with algorithms that
+    // name a specific hash
+    if algorithm == SignatureAlgorithm::Hs2019 {
+        pairs.push(("(created)", &created));
+        pairs.push(("(expires)", &expires));
+    }
+    pairs.push(("date", &date));
+    pairs.push(("host", host));
 
-    let mut pairs: Vec<(&str, &str)> = vec![
-        ("(request-target)", &target),
-        ("date", &date),
-        ("host", host),
-    ];
-
-    let data_vals = data.map(|s| {
-        let h = hmac_sha256::Hash::hash(s.as_bytes());
-        let digest = format!("SHA-256={}", base64::encode(h));
-
-        (s.len().to_string(), digest)
-    });
-
-    if let Some((content_len, digest)) = data_vals.as_ref() {
-        pairs.push(("content-length", content_len));
-        pairs.push(("digest", digest));
+    if let Some(body) = body {
+        pairs.push(("content-length", &body.content_length));
+        pairs.push(("digest", &body.digest));
     }
 
-    let signature = create_signature(base, &pairs, sig_key);
+    let signature = create_signature(key_id, &pairs, signer, algorithm).await?;
     let mut headers: HashMap<String, String> = pairs
         .into_iter()
         .map(|(k, v)| (k.to_owned(), v.to_owned()))
@@ -64,11 +115,178 @@ pub fn sign_request_headers(
 
     headers.insert("signature".into(), signature);
 
-    // Now that we've generated the signature we can remove what we no longer need
-    headers.remove("(request-target)");
-    // headers.remove("host");
+    // Now that we've generated the signature we can remove the pseudo-headers, which
+    // are derived from the request and signature rather than sent
+    headers.retain(|k, _| !k.starts_with('('));
+
+    (&headers).try_into().map_err(|_| Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "unable to build signature headers",
+    })
+}
+
+/// The outcome of validating the signature of an incoming request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    MissingSignature,
+    /// A header listed as being signed was not present in the request
+    MissingHeader,
+    /// The Digest header did not match the request body
+    BadDigest,
+    /// The signature has expired (or was created in the future)
+    Expired,
+    /// The signature is valid but doesn't cover all of the headers that we require
+    InsufficientCoverage,
+    UnknownAlgorithm,
+    /// We were unable to fetch or parse the signing actor's key
+    KeyFetchFailure,
+    /// The Signature header couldn't be parsed
+    Malformed(Malformed),
+    /// The signature didn't match the request
+    Invalid,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::MissingSignature => "missing_signature",
+            Self::MissingHeader => "missing_header",
+            Self::BadDigest => "bad_digest",
+            Self::Expired => "expired",
+            Self::InsufficientCoverage => "insufficient_coverage",
+            Self::UnknownAlgorithm => "unknown_algorithm",
+            Self::KeyFetchFailure => "key_fetch_failure",
+            Self::Malformed(_) => "malformed",
+            Self::Invalid => "invalid",
+        }
+    }
+
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            Self::Ok => Ok(()),
+            Self::MissingSignature => Err(Error::MissingSignature),
+            Self::InsufficientCoverage => Err(Error::StatusAndMessage {
+                status: StatusCode::UNAUTHORIZED,
+                message: "signature does not cover the required headers",
+            }),
+            _ => Err(INVALID_SIG),
+        }
+    }
+}
+
+/// The ways in which the Signature header of a request can be malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Malformed {
+    /// The header contained something other than visible ASCII characters
+    NonAscii,
+    /// A parameter was not of the form `key=value`
+    MissingValue,
+    /// A parameter other than created or expires had an unquoted value
+    Unquoted,
+    /// A quoted parameter value had no closing quote
+    Unterminated,
+    /// A parameter was given more than once, so we can't know which was signed
+    DuplicateParam,
+    /// The headers or signature parameter was missing
+    MissingParam,
+    /// The created or expires parameter was not a unix timestamp
+    BadTimestamp,
+    /// The signature parameter was not valid base64
+    BadEncoding,
+}
+
+/// The id of the key used to sign a request, if it was signed.
+pub fn signature_key_id(headers: &HeaderMap) -> Option<&str> {
+    let sig = headers.get("signature")?.to_str().ok()?;
+
+    split_signature(sig).ok()?.get("keyId").copied()
+}
+
+/// The algorithm named in the signature of a request, for use as a metric label.
+pub fn signature_algorithm(headers: &HeaderMap) -> &'static str {
+    let algorithm = headers
+        .get("signature")
+        .and_then(|sig| sig.to_str().ok())
+        .and_then(|sig| split_signature(sig).ok())
+        .and_then(|sig| sig.get("algorithm").copied());
+
+    match algorithm {
+        Some("rsa-sha256") => "rsa-sha256",
+        Some("rsa-sha512") => "rsa-sha512",
+        Some("hs2019") => "hs2019",
+        Some(_) => "other",
+        None => "none",
+    }
+}
+
+/// Check that a signature covers the request target, host, date and (for POSTs) the
+/// digest of the body, so that it can't be replayed against a different endpoint or
+/// with a different body. The (created) pseudo-header is accepted in place of date.
+pub fn check_coverage(method: &str, headers: &HeaderMap) -> Outcome {
+    let signed = headers
+        .get("signature")
+        .and_then(|sig| sig.to_str().ok())
+        .and_then(|sig| split_signature(sig).ok())
+        .and_then(|sig| sig.get("headers").map(|h| h.to_ascii_lowercase()));
+    let signed: Vec<&str> = match &signed {
+        Some(signed) => signed.split(' ').collect(),
+        None => return Outcome::InsufficientCoverage,
+    };
+
+    let covered = signed.contains(&"(request-target)")
+        && signed.contains(&"host")
+        && (signed.contains(&"date") || signed.contains(&"(created)"))
+        && (!method.eq_ignore_ascii_case("post") || signed.contains(&"digest"));
+
+    if covered {
+        Outcome::Ok
+    } else {
+        Outcome::InsufficientCoverage
+    }
+}
+
+/// Check that the Date header of a request is within the given number of seconds of
+/// now, if the signature covers it. Without this a captured request signed over its date
+/// (rather than (created) and (expires)) could be replayed indefinitely.
+pub fn check_date(headers: &HeaderMap, now: DateTime<Utc>, max_skew_secs: u64) -> Outcome {
+    let signs_date = headers
+        .get("signature")
+        .and_then(|sig| sig.to_str().ok())
+        .and_then(|sig| split_signature(sig).ok())
+        .and_then(|sig| sig.get("headers").map(|h| h.to_ascii_lowercase()))
+        .is_some_and(|signed| signed.split(' ').any(|h| h == "date"));
+    if !signs_date {
+        return Outcome::Ok;
+    }
+
+    let date = match headers
+        .get("date")
+        .and_then(|date| date.to_str().ok())
+        .and_then(parse_http_date)
+    {
+        Some(date) => date,
+        None => return Outcome::Malformed(Malformed::BadTimestamp),
+    };
+
+    if (now - date).num_seconds().unsigned_abs() > max_skew_secs {
+        Outcome::Expired
+    } else {
+        Outcome::Ok
+    }
+}
+
+// HTTP dates are always in GMT, though we (and others) have historically labelled them
+// as UTC
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    let (date, zone) = date.trim().rsplit_once(' ')?;
+    if zone != "GMT" && zone != "UTC" {
+        return None;
+    }
+    let date = NaiveDateTime::parse_from_str(date, "%a, %d %b %Y %H:%M:%S").ok()?;
 
-    Ok((&headers).try_into().expect("valid headers"))
+    Some(Utc.from_utc_datetime(&date))
 }
 
 pub fn validate_signature(
@@ -76,56 +294,172 @@ pub fn validate_signature(
     method: &str,
     path: &str,
     headers: &HeaderMap,
+    body: &[u8],
 ) -> Result<()> {
-    let sig = match headers.get("signature") {
-        Some(sig) => sig,
-        None => return Err(Error::MissingSignature),
-    };
-    let pub_key = actor.key()?;
-    let mut sig = split_signature(sig.to_str().map_err(|_| INVALID_SIG)?)?;
+    check_signature(actor, method, path, headers, body).into_result()
+}
+
+/// Validate the signature of a request, reporting why it failed if it did.
+pub fn check_signature(
+    actor: &Actor,
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Outcome {
+    match try_check_signature(actor, method, path, headers, body) {
+        Ok(()) => Outcome::Ok,
+        Err(outcome) => outcome,
+    }
+}
+
+fn try_check_signature(
+    actor: &Actor,
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> std::result::Result<(), Outcome> {
+    let sig = headers.get("signature").ok_or(Outcome::MissingSignature)?;
+    let pub_key = actor.key().map_err(|_| Outcome::KeyFetchFailure)?;
+    let sig = sig
+        .to_str()
+        .map_err(|_| Outcome::Malformed(Malformed::NonAscii))?;
+    let sig = split_signature(sig).map_err(Outcome::Malformed)?;
     let target = format!("{method} {path}");
-    sig.insert("(request-target)", &target);
+    check_validity_window(&sig, Utc::now().timestamp())?;
 
     // Need to convert to a bare hash map as HeaderMap will reject (request-target) as a key
     let mut headers: HashMap<&str, &str> = headers
         .iter()
         .map(|(k, v)| match v.to_str() {
             Ok(v) => Ok((k.as_str(), v)),
-            Err(_) => Err(INVALID_SIG),
+            Err(_) => Err(Outcome::Invalid),
         })
-        .collect::<Result<_>>()?;
+        .collect::<std::result::Result<_, _>>()?;
     headers.insert("(request-target)", &target);
+    for (pseudo, param) in [("(created)", "created"), ("(expires)", "expires")] {
+        if let Some(value) = sig.get(param) {
+            headers.insert(pseudo, value);
+        }
+    }
 
-    let string_sig = sig.get("signature").ok_or(INVALID_SIG)?;
-    let sig_data = base64::decode(string_sig).map_err(|_| INVALID_SIG)?;
+    if let Some(digest) = headers.get("digest") {
+        if !digest_matches(digest, body) {
+            return Err(Outcome::BadDigest);
+        }
+    }
+
+    let string_sig = sig
+        .get("signature")
+        .ok_or(Outcome::Malformed(Malformed::MissingParam))?;
+    let sig_data =
+        base64::decode(string_sig).map_err(|_| Outcome::Malformed(Malformed::BadEncoding))?;
     let signature = Signature::from(sig_data);
 
     let ordered_headers: Vec<(&str, &str)> = sig
         .get("headers")
-        .ok_or(INVALID_SIG)?
+        .ok_or(Outcome::Malformed(Malformed::MissingParam))?
         .split(' ')
         .map(|k| {
             headers
                 .get(k)
-                .ok_or_else(|| panic!("missing key: {k}"))
                 .map(|v| (k, *v))
+                .ok_or(Outcome::MissingHeader)
         })
-        .collect::<Result<_>>()?;
+        .collect::<std::result::Result<_, _>>()?;
 
     let signing_string = build_signing_string(&ordered_headers);
 
-    let (_, hash_algorithm) = sig
-        .get("algorithm")
-        .ok_or(INVALID_SIG)?
-        .split_once('-')
-        .ok_or(INVALID_SIG)?;
+    let res = match sig.get("algorithm").copied() {
+        // "rsa-sha1" => (),
+        Some("rsa-sha256") => verify::<Sha256>(pub_key, signing_string.as_bytes(), &signature),
+        Some("rsa-sha512") => verify::<Sha512>(pub_key, signing_string.as_bytes(), &signature),
+        // With hs2019 (or no algorithm at all) the algorithm is derived from the key.
+        // Actor keys are always RSA and in practice are always used with SHA-256.
+        Some("hs2019") | None => verify::<Sha256>(pub_key, signing_string.as_bytes(), &signature),
+        _ => return Err(Outcome::UnknownAlgorithm),
+    };
+
+    res.map_err(|_| Outcome::Invalid)
+}
+
+// The created and expires parameters are unix timestamps, optionally with a fractional
+// part. Parsing as a float also accepts NaN and infinity, neither of which can be
+// compared against the window.
+fn check_validity_window(sig: &HashMap<&str, &str>, now: i64) -> std::result::Result<(), Outcome> {
+    let timestamp = |param| -> std::result::Result<Option<f64>, Outcome> {
+        match sig.get(param) {
+            Some(v) => match v.parse::<f64>() {
+                Ok(t) if t.is_finite() => Ok(Some(t)),
+                _ => Err(Outcome::Malformed(Malformed::BadTimestamp)),
+            },
+            None => Ok(None),
+        }
+    };
+    let now = now as f64;
+
+    if let Some(created) = timestamp("created")? {
+        if created > now + MAX_CLOCK_SKEW_SECS || created < now - MAX_SIGNATURE_AGE_SECS {
+            return Err(Outcome::Expired);
+        }
+    }
+    if let Some(expires) = timestamp("expires")? {
+        if expires < now - MAX_CLOCK_SKEW_SECS {
+            return Err(Outcome::Expired);
+        }
+    }
+
+    Ok(())
+}
+
+// The Digest header may list multiple digests (RFC 3230) all of which must match. We
+// require at least one that we know how to check.
+fn digest_matches(header: &str, body: &[u8]) -> bool {
+    let mut checked = false;
+
+    for digest in header.split(',') {
+        let (algorithm, expected) = match digest.trim().split_once('=') {
+            Some(parts) => parts,
+            None => return false,
+        };
 
-    match hash_algorithm {
-        // "sha1" => (),
-        "sha256" => verify::<Sha256>(pub_key, signing_string.as_bytes(), &signature),
-        "sha512" => verify::<Sha512>(pub_key, signing_string.as_bytes(), &signature),
-        _ => Err(INVALID_SIG),
+        let actual = match algorithm.to_ascii_lowercase().as_str() {
+            "sha-256" => base64::encode(Sha256::digest(body)),
+            "sha-512" => base64::encode(Sha512::digest(body)),
+            _ => continue,
+        };
+        if actual != expected {
+            return false;
+        }
+        checked = true;
     }
+
+    checked
+}
+
+/// A stable fingerprint for a public key that can be stored and compared against
+/// later fetches of the same actor.
+pub fn key_fingerprint(key: &RsaPublicKey) -> Result<String> {
+    let der = key.to_pkcs1_der()?;
+
+    Ok(base64::encode(Sha256::digest(der.as_bytes())))
+}
+
+/// Check that data signed with the given key can be verified using the given public key
+/// (in PKCS#1 PEM format), i.e. that the key we publish matches the key we sign with.
+pub async fn check_key_pair(signer: &dyn Signer, pub_key_pem: &str) -> Result<()> {
+    let pub_key =
+        RsaPublicKey::from_pkcs1_pem(pub_key_pem).map_err(|e| Error::InvalidPublicKey {
+            error: e.to_string(),
+        })?;
+    let data = b"actiserve key check";
+    let signature = Signature::from(signer.sign(data).await?);
+
+    // Our signing keys include the DigestInfo prefix so we need to check against that
+    VerifyingKey::<Sha256>::new_with_prefix(pub_key)
+        .verify(data, &signature)
+        .map_err(|_| INVALID_SIG)
 }
 
 fn verify<D: Digest>(pub_key: RsaPublicKey, data: &[u8], signature: &Signature) -> Result<()> {
@@ -137,18 +471,26 @@ fn verify<D: Digest>(pub_key: RsaPublicKey, data: &[u8], signature: &Signature)
     })
 }
 
-fn create_signature(base: &str, pairs: &[(&str, &str)], sig_key: &SigningKey<Sha256>) -> String {
-    let signed_bytes = sig_key
-        .sign_with_rng(
-            &mut rand::thread_rng(),
-            build_signing_string(pairs).as_bytes(),
-        )
-        .as_bytes()
-        .to_vec();
+async fn create_signature(
+    key_id: &str,
+    pairs: &[(&str, &str)],
+    signer: &dyn Signer,
+    algorithm: SignatureAlgorithm,
+) -> Result<String> {
+    let param = |pseudo| pairs.iter().find(|(k, _)| *k == pseudo).map(|(_, v)| *v);
+    let (created, expires) = (param("(created)"), param("(expires)"));
 
+    let signed_bytes = signer.sign(build_signing_string(pairs).as_bytes()).await?;
     let signature = base64::encode(signed_bytes);
 
-    build_sig_header(base, signature, pairs.iter().map(|(k, _)| *k))
+    Ok(build_sig_header(
+        key_id,
+        algorithm,
+        created,
+        expires,
+        signature,
+        pairs.iter().map(|(k, _)| *k),
+    ))
 }
 
 fn build_signing_string(pairs: &[(&str, &str)]) -> String {
@@ -158,33 +500,49 @@ fn build_signing_string(pairs: &[(&str, &str)]) -> String {
         .join("\n")
 }
 
-fn split_signature(s: &str) -> Result<HashMap<&str, &str>> {
-    s.split(',')
-        .map(|pair| {
-            let (k, v) = pair.split_once('=').ok_or(INVALID_SIG)?;
+// Values are quoted strings other than the created and expires timestamps
+pub(crate) fn split_signature(s: &str) -> std::result::Result<HashMap<&str, &str>, Malformed> {
+    let mut params = HashMap::new();
 
-            v.strip_prefix('"')
-                .and_then(|v| v.strip_suffix('"'))
-                .map(|v| (k, v))
-                .ok_or(INVALID_SIG)
-        })
-        .collect()
+    for pair in s.split(',') {
+        let (k, v) = pair.trim().split_once('=').ok_or(Malformed::MissingValue)?;
+        let v = match v.strip_prefix('"') {
+            Some(v) => v.strip_suffix('"').ok_or(Malformed::Unterminated)?,
+            None if matches!(k, "created" | "expires") => v,
+            None => return Err(Malformed::Unquoted),
+        };
+
+        if params.insert(k, v).is_some() {
+            return Err(Malformed::DuplicateParam);
+        }
+    }
+
+    Ok(params)
 }
 
 fn build_sig_header<'a>(
-    base: &str,
+    key_id: &str,
+    algorithm: SignatureAlgorithm,
+    created: Option<&str>,
+    expires: Option<&str>,
     signature: String,
     mut headers: impl Iterator<Item = &'a str>,
 ) -> String {
     let headers = headers.join(" ");
+    let mut params = vec![
+        format!("keyId=\"{key_id}\""),
+        format!("algorithm=\"{}\"", algorithm.as_str()),
+    ];
+    if let Some(created) = created {
+        params.push(format!("created={created}"));
+    }
+    if let Some(expires) = expires {
+        params.push(format!("expires={expires}"));
+    }
+    params.push(format!("headers=\"{headers}\""));
+    params.push(format!("signature=\"{signature}\""));
 
-    vec![
-        format!("keyId=\"https://{}/actor#main-key\"", base),
-        "algorithm=\"rsa-sha256\"".to_owned(),
-        format!("headers=\"{headers}\""),
-        format!("signature=\"{signature}\""),
-    ]
-    .join(",")
+    params.join(",")
 }
 
 fn now() -> String {
@@ -195,11 +553,15 @@ fn now() -> String {
 pub(crate) mod tests {
     use super::*;
     use crate::map;
+    use axum::http::HeaderValue;
     use rsa::{
         pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+        pkcs1v15::SigningKey,
+        signature::RandomizedSigner,
         RsaPrivateKey,
     };
     use rustypub::extended::{ActorBuilder, PublicKeyInfo};
+    use simple_test_case::test_case;
 
     // A valid but low bit size private key for use in running unit tests
     // without needing to generate one on demand.
@@ -235,8 +597,167 @@ JHDXEfYsCzSikhI33KHhsxu0yf168jlNorlgT8Yzax2y5QkpqbtFAgMBAAE=
             .into()
     }
 
-    pub fn sign_test_req(uri: &str, data: Option<&str>) -> HeaderMap {
-        sign_request_headers("127.0.0.1:4242", uri, data, &sig_key()).expect("to sign")
+    pub async fn sign_test_req(uri: &str, data: Option<&str>) -> HeaderMap {
+        sign_test_req_with(uri, data, SignatureAlgorithm::RsaSha256).await
+    }
+
+    async fn sign_test_req_with(
+        uri: &str,
+        data: Option<&str>,
+        alg: SignatureAlgorithm,
+    ) -> HeaderMap {
+        let body = data.map(|s| Body::new(s.to_owned()));
+        sign_request_headers(
+            "https://127.0.0.1:4242/actor#main-key",
+            uri,
+            body.as_ref(),
+            &sig_key(),
+            alg,
+        )
+        .await
+        .expect("to sign")
+    }
+
+    #[test_case("(request-target) host date digest", "post", Outcome::Ok; "full post")]
+    #[test_case("(request-target) host date", "get", Outcome::Ok; "full get")]
+    #[test_case("(request-target) (created) host digest", "post", Outcome::Ok; "created in place of date")]
+    #[test_case("(request-target) host date", "post", Outcome::InsufficientCoverage; "post without digest")]
+    #[test_case("host date digest", "post", Outcome::InsufficientCoverage; "no request target")]
+    #[test_case("(request-target) date digest", "post", Outcome::InsufficientCoverage; "no host")]
+    #[test_case("(request-target) host digest", "post", Outcome::InsufficientCoverage; "no date")]
+    #[test_case("date", "get", Outcome::InsufficientCoverage; "date only")]
+    #[test]
+    fn check_coverage_works(signed: &str, method: &str, expected: Outcome) {
+        let sig = format!(r#"keyId="k",algorithm="rsa-sha256",headers="{signed}",signature="s""#);
+        let mut headers = HeaderMap::new();
+        headers.insert("signature", sig.parse().unwrap());
+
+        assert_eq!(check_coverage(method, &headers), expected);
+    }
+
+    #[tokio::test]
+    async fn our_signatures_have_full_coverage() {
+        let headers = sign_test_req("https://example.com/inbox", Some("{}")).await;
+        assert_eq!(check_coverage("post", &headers), Outcome::Ok);
+
+        let headers = sign_test_req("https://example.com/actor", None).await;
+        assert_eq!(check_coverage("get", &headers), Outcome::Ok);
+    }
+
+    #[test]
+    fn unquoted_timestamps_are_split() {
+        let signature = r#"keyId="k",algorithm="hs2019",created=1402170695, expires=1402170995.5,headers="(created)",signature="s""#;
+        let split = split_signature(signature).expect("test signature to be valid");
+
+        assert_eq!(split.get("created"), Some(&"1402170695"));
+        assert_eq!(split.get("expires"), Some(&"1402170995.5"));
+        assert_eq!(
+            split_signature(r#"keyId=k,signature="s""#),
+            Err(Malformed::Unquoted)
+        );
+    }
+
+    #[test_case(None, None, Ok(()); "no timestamps")]
+    #[test_case(Some("1000"), Some("1300"), Ok(()); "within window")]
+    #[test_case(Some("1200"), None, Ok(()); "created within skew")]
+    #[test_case(Some("1400"), None, Err(Outcome::Expired); "created in the future")]
+    #[test_case(Some("300"), None, Err(Outcome::Expired); "created too long ago")]
+    #[test_case(Some("300"), Some("2000"), Err(Outcome::Expired); "created too long ago with a later expiry")]
+    #[test_case(None, Some("800"), Ok(()); "expired within skew")]
+    #[test_case(None, Some("600"), Err(Outcome::Expired); "expired")]
+    #[test_case(None, Some("600.5"), Err(Outcome::Expired); "fractional expiry")]
+    #[test_case(Some("soon"), None, Err(Outcome::Malformed(Malformed::BadTimestamp)); "invalid timestamp")]
+    #[test_case(None, Some("NaN"), Err(Outcome::Malformed(Malformed::BadTimestamp)); "nan")]
+    #[test_case(Some("-inf"), None, Err(Outcome::Malformed(Malformed::BadTimestamp)); "infinite")]
+    #[test]
+    fn check_validity_window_works(
+        created: Option<&str>,
+        expires: Option<&str>,
+        expected: std::result::Result<(), Outcome>,
+    ) {
+        let mut sig = HashMap::new();
+        if let Some(created) = created {
+            sig.insert("created", created);
+        }
+        if let Some(expires) = expires {
+            sig.insert("expires", expires);
+        }
+
+        assert_eq!(check_validity_window(&sig, 1000), expected);
+    }
+
+    #[test_case("(request-target) host date", 0, Outcome::Ok; "signed and current")]
+    #[test_case("(request-target) host date", 200, Outcome::Ok; "signed and within skew")]
+    #[test_case("(request-target) host date", -200, Outcome::Ok; "signed and ahead within skew")]
+    #[test_case("(request-target) host date", 400, Outcome::Expired; "signed and stale")]
+    #[test_case("(request-target) host date", -400, Outcome::Expired; "signed and in the future")]
+    #[test_case("(request-target) host (created)", 400, Outcome::Ok; "not signed")]
+    #[test]
+    fn check_date_works(signed: &str, age_secs: i64, expected: Outcome) {
+        let now = Utc::now();
+        let date = now - chrono::Duration::seconds(age_secs);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "signature",
+            format!(r#"keyId="k",headers="{signed}",signature="s""#)
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(
+            "date",
+            date.format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+                .parse()
+                .unwrap(),
+        );
+
+        assert_eq!(check_date(&headers, now, 300), expected);
+    }
+
+    #[test_case("Fri, 16 Oct 2026 09:18:19 GMT", true; "gmt")]
+    #[test_case("Fri, 16 Oct 2026 09:18:19 UTC", true; "utc")]
+    #[test_case("Fri, 16 Oct 2026 09:18:19 +0100", false; "offset")]
+    #[test_case("yesterday", false; "not a date")]
+    #[test]
+    fn parse_http_date_works(date: &str, valid: bool) {
+        assert_eq!(parse_http_date(date).is_some(), valid);
+    }
+
+    #[test]
+    fn our_own_dates_can_be_parsed() {
+        assert!(parse_http_date(&now()).is_some());
+    }
+
+    #[tokio::test]
+    async fn hs2019_signatures_cover_created_and_expires() {
+        let body = r#"{ "hello": "world" }"#;
+        let headers = sign_test_req_with(
+            "https://example.com/inbox",
+            Some(body),
+            SignatureAlgorithm::Hs2019,
+        )
+        .await;
+        let raw = headers["signature"].to_str().unwrap();
+        let sig = split_signature(raw).unwrap();
+
+        assert!(sig["headers"].starts_with("(request-target) (created) (expires) "));
+        assert!(sig.contains_key("created") && sig.contains_key("expires"));
+        assert!(!headers.keys().any(|k| k.as_str().starts_with('(')));
+
+        // The timestamps are covered by the signature so can't be altered
+        let created = sig["created"];
+        let tampered = raw.replace(
+            &format!("created={created}"),
+            &format!("created={}", created.parse::<i64>().unwrap() - 1),
+        );
+        let mut headers = headers.clone();
+        headers.insert("signature", tampered.parse().unwrap());
+        let actor = test_actor("https://example.com/actor");
+
+        assert_eq!(
+            check_signature(&actor, "post", "/inbox", &headers, body.as_bytes()),
+            Outcome::Invalid
+        );
     }
 
     #[test]
@@ -288,16 +809,127 @@ JHDXEfYsCzSikhI33KHhsxu0yf168jlNorlgT8Yzax2y5QkpqbtFAgMBAAE=
             .build()
     }
 
-    #[test]
-    fn we_can_verify_our_own_signatures() {
+    #[tokio::test]
+    async fn we_can_verify_our_own_signatures() {
         let uri = "https://example.com/inbox";
-        let data = Some(r#"{ "hello": "world" }"#);
-        let headers = sign_test_req(uri, data);
+        let data = r#"{ "hello": "world" }"#;
+        let headers = sign_test_req(uri, Some(data)).await;
 
         // Will provide the TEST_PUB_KEY public key for verification
         let actor = test_actor("https://example.com/actor");
 
-        let res = validate_signature(&actor, "post", "/inbox", &headers);
+        let res = validate_signature(&actor, "post", "/inbox", &headers, data.as_bytes());
         assert_eq!(res, Ok(()));
     }
+
+    #[test_case(SignatureAlgorithm::RsaSha256; "rsa-sha256")]
+    #[test_case(SignatureAlgorithm::Hs2019; "hs2019")]
+    #[tokio::test]
+    async fn we_can_verify_our_own_signatures_with_each_algorithm(alg: SignatureAlgorithm) {
+        let body = r#"{ "hello": "world" }"#;
+        let headers = sign_test_req_with("https://example.com/inbox", Some(body), alg).await;
+        let actor = test_actor("https://example.com/actor");
+
+        assert_eq!(signature_algorithm(&headers), alg.as_str());
+        assert_eq!(
+            check_signature(&actor, "post", "/inbox", &headers, body.as_bytes()),
+            Outcome::Ok
+        );
+    }
+
+    #[test_case("hmac-sha256"; "hmac")]
+    #[test_case("ed25519"; "ed25519")]
+    #[tokio::test]
+    async fn unknown_algorithms_are_rejected(alg: &str) {
+        let body = r#"{ "hello": "world" }"#;
+        let mut headers = sign_test_req("https://example.com/inbox", Some(body)).await;
+        let sig = headers["signature"]
+            .to_str()
+            .unwrap()
+            .replace("rsa-sha256", alg);
+        headers.insert("signature", sig.parse().unwrap());
+        let actor = test_actor("https://example.com/actor");
+
+        assert_eq!(
+            check_signature(&actor, "post", "/inbox", &headers, body.as_bytes()),
+            Outcome::UnknownAlgorithm
+        );
+    }
+
+    #[test_case(r#"{ "hello": "world" }"#, None, Outcome::Ok; "valid")]
+    #[test_case(r#"{ "hello": "mallory" }"#, None, Outcome::BadDigest; "body swapped")]
+    #[test_case(r#"{ "hello": "world" }"#, Some("SHA-256=nope"), Outcome::BadDigest; "wrong digest")]
+    #[test_case(r#"{ "hello": "world" }"#, Some("MD5=nope"), Outcome::BadDigest; "unsupported digest")]
+    #[tokio::test]
+    async fn digests_are_checked(body: &str, digest: Option<&str>, expected: Outcome) {
+        let mut headers =
+            sign_test_req("https://example.com/inbox", Some(r#"{ "hello": "world" }"#)).await;
+        if let Some(digest) = digest {
+            headers.insert("digest", digest.parse().unwrap());
+        }
+        let actor = test_actor("https://example.com/actor");
+
+        let outcome = check_signature(&actor, "post", "/inbox", &headers, body.as_bytes());
+        assert_eq!(outcome, expected);
+    }
+
+    #[test_case("signature", Outcome::MissingSignature; "missing signature")]
+    #[test_case("date", Outcome::MissingHeader; "missing signed header")]
+    #[tokio::test]
+    async fn missing_headers_are_reported(header: &str, expected: Outcome) {
+        let body = r#"{ "hello": "world" }"#;
+        let mut headers = sign_test_req("https://example.com/inbox", Some(body)).await;
+        headers.remove(header);
+        let actor = test_actor("https://example.com/actor");
+
+        let outcome = check_signature(&actor, "post", "/inbox", &headers, body.as_bytes());
+        assert_eq!(outcome, expected);
+    }
+
+    #[test_case(r#"keyId="k",algorithm="rsa-sha256",headers="date",signature="s""#, "rsa-sha256"; "known")]
+    #[test_case(r#"keyId="k",algorithm="ed25519",headers="date",signature="s""#, "other"; "unknown")]
+    #[test_case(r#"keyId="k",headers="date",signature="s""#, "none"; "missing")]
+    #[test]
+    fn signature_algorithm_works(sig: &str, expected: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert("signature", sig.parse().unwrap());
+
+        assert_eq!(signature_algorithm(&headers), expected);
+    }
+
+    #[test_case("", Malformed::MissingValue; "empty")]
+    #[test_case("garbage", Malformed::MissingValue; "no parameters")]
+    #[test_case(",,,", Malformed::MissingValue; "only separators")]
+    #[test_case("\"", Malformed::MissingValue; "lone quote")]
+    #[test_case("=", Malformed::Unquoted; "lone equals")]
+    #[test_case("keyId=k", Malformed::Unquoted; "unquoted value")]
+    #[test_case(r#"keyId="k"#, Malformed::Unterminated; "unterminated value")]
+    #[test_case(r#"keyId="""#, Malformed::Unterminated; "only an opening quote")]
+    #[test_case(r#"keyId="ü",headers="date",signature="s""#, Malformed::NonAscii; "non ascii")]
+    #[test_case(r#"keyId="k",headers="date",headers="host",signature="s""#, Malformed::DuplicateParam; "duplicate parameter")]
+    #[test_case(r#"keyId="k",headers="date""#, Malformed::MissingParam; "no signature")]
+    #[test_case(r#"keyId="k",signature="czE=""#, Malformed::MissingParam; "no headers")]
+    #[test_case(r#"keyId="k",headers="date",signature="!!!""#, Malformed::BadEncoding; "signature not base64")]
+    #[test_case(r#"keyId="k",created=soon,headers="date",signature="s""#, Malformed::BadTimestamp; "created not a number")]
+    #[test_case(r#"keyId="k",expires=inf,headers="date",signature="s""#, Malformed::BadTimestamp; "expires infinite")]
+    #[tokio::test]
+    async fn garbage_signature_headers_are_rejected(sig: &str, expected: Malformed) {
+        let body = r#"{ "hello": "world" }"#;
+        let mut headers = sign_test_req("https://example.com/inbox", Some(body)).await;
+        headers.insert(
+            "signature",
+            HeaderValue::from_bytes(sig.as_bytes()).unwrap(),
+        );
+        let actor = test_actor("https://example.com/actor");
+
+        let outcome = check_signature(&actor, "post", "/inbox", &headers, body.as_bytes());
+
+        assert_eq!(outcome, Outcome::Malformed(expected));
+        assert_eq!(outcome.as_str(), "malformed");
+        assert_eq!(signature_algorithm(&headers), "none");
+        assert_eq!(
+            check_coverage("post", &headers),
+            Outcome::InsufficientCoverage
+        );
+    }
 }
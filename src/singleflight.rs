@@ -0,0 +1,108 @@
+//! Coalescing of concurrent identical requests.
+//!
+//! A burst of activities from an actor we haven't seen before would otherwise fetch
+//! the same actor document (and so its key) once per activity. Callers asking for the
+//! same key while a fetch is in flight wait on and share its result instead. Nothing is
+//! cached once the fetch has completed.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+#[derive(Debug)]
+pub struct SingleFlight<T> {
+    inflight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            inflight: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    /// Run `f` for the given key unless it is already running, in which case wait for
+    /// and return the result of that run. Should the caller running `f` be cancelled,
+    /// one of the waiting callers runs it in its place.
+    pub async fn run<F, Fut>(&self, key: &str, f: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+
+        let res = cell.get_or_init(f).await.clone();
+
+        // Only the first caller to finish removes the entry, so that a newer run for
+        // the same key started after that isn't removed as well
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight
+            .get(key)
+            .map(|c| Arc::ptr_eq(c, &cell))
+            .unwrap_or(false)
+        {
+            inflight.remove(key);
+        }
+
+        res
+    }
+
+    /// The number of keys with a run in flight.
+    pub fn len(&self) -> usize {
+        self.inflight.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn concurrent_runs_for_the_same_key_are_coalesced() {
+        let flight = SingleFlight::default();
+        let runs = AtomicUsize::new(0);
+        let fetch = |key: &'static str| {
+            let (flight, runs) = (&flight, &runs);
+            async move {
+                flight
+                    .run(key, || async {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        sleep(Duration::from_millis(50)).await;
+                        key.to_uppercase()
+                    })
+                    .await
+            }
+        };
+
+        let results = join_all((0..10).map(|i| fetch(if i % 2 == 0 { "a" } else { "b" }))).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(results.iter().filter(|r| *r == "A").count(), 5);
+        assert_eq!(results.iter().filter(|r| *r == "B").count(), 5);
+        assert!(flight.is_empty());
+
+        // Completed runs aren't cached
+        fetch("a").await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
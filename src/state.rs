@@ -1,105 +1,759 @@
 //! Server shared state
-use crate::{client::ActivityPubClient, config::Config, util::host_from_uri, Error, Result};
+use crate::{
+    about::About,
+    actors::{ActorMetadata, RelayActor, TopicActor, DEFAULT_ACTOR},
+    auth::{OAuthClient, Tokens},
+    blocklist::{Blocklist, Severity, ADMIN_SOURCE},
+    client::{ActivityPubClient, NodeInfo, SoftwareInfo, HOST_BUSY},
+    config::{Config, DomainRule, DomainRules, SubscriptionScope},
+    delivery::{Deliveries, Delivery, Queued, Shed},
+    flood::FloodGuard,
+    history::{History, HistoryEntry},
+    images::ActorImages,
+    import::Imports,
+    ingest::{Ingest, INGEST_DIR},
+    invites::Invite,
+    logging::LogFilter,
+    messages::{Message, MessageLimits, MAX_MESSAGES},
+    metrics::Metrics,
+    migrations,
+    notifications::Notifications,
+    objects::ObjectCache,
+    pipeline::Pipeline,
+    policy::Policy,
+    reactions::ForwardedReactions,
+    routes::inbox::default_pipeline,
+    signer::ActorKey,
+    stats::{Event, Stats},
+    storage::{open_json, DirRecordStorage, JsonFileStorage},
+    timeseries::{self, Counts, Rollups},
+    unrecognized::{Unrecognized, UnrecognizedActivity},
+    upstreams::{is_upstream, Upstream},
+    util::{host_from_uri, normalize_id, registrable_domain},
+    Error, Result,
+};
 use acidjson::AcidJson;
-use axum::http::StatusCode;
-use futures::future::try_join_all;
+use axum::{body::Bytes, http::StatusCode};
+use chrono::{DateTime, Utc};
 use rustypub::extended::Actor;
-use serde::Serialize;
-use std::{collections::HashMap, path::PathBuf, sync::Mutex};
-use tracing::trace;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+};
+use tracing::{debug, info, trace, warn};
 
 #[derive(Debug)]
 pub struct State {
     pub cfg: Config,
+    /// Followers of the main relay actor
     pub db: Db,
+    /// Topic actors served alongside the main relay actor, keyed by name
+    pub actors: BTreeMap<String, TopicActor>,
     pub client: ActivityPubClient,
-    object_cache: Mutex<HashMap<String, String>>,
+    pub deliveries: Deliveries,
+    pub metrics: Metrics,
+    pub blocklist: Blocklist,
+    pub imports: Imports,
+    pub history: History,
+    /// Access tokens issued to OAuth clients of the admin API
+    pub tokens: Tokens,
+    pub policy: Policy,
+    /// Activity counts for each origin instance
+    pub stats: Stats,
+    pub flood: FloodGuard,
+    pub notifications: Notifications,
+    /// Messages received from each instance over the last hour
+    pub message_limits: MessageLimits,
+    /// Reactions recently forwarded to subscribers
+    pub reactions: ForwardedReactions,
+    /// The stages that activities POSTed to our inboxes pass through
+    pub pipeline: Pipeline,
+    /// Activities accepted by our inboxes that are waiting to be processed
+    pub ingest: Ingest,
+    pub images: ActorImages,
+    /// The rendered /about page
+    pub about: About,
+    /// Remote objects fetched to verify them
+    pub objects: ObjectCache,
+    /// Changes the log filter at runtime
+    pub log_filter: LogFilter,
+}
+
+// The parts of the server state that are built from the config alone, which are also
+// built when validating a config so that anything stopping the server from starting
+// is caught there too
+struct Components {
+    blocklist: Blocklist,
+    policy: Policy,
+    notifications: Notifications,
+    images: ActorImages,
+    about: About,
+}
+
+impl Components {
+    fn new(cfg: &Config, client: &mut ActivityPubClient) -> Result<Self> {
+        client.configure(&cfg.proxy, &cfg.http)?;
+        client.set_signature_algorithm(cfg.activity_pub.signature_algorithm);
+
+        Ok(Self {
+            blocklist: Blocklist::new(&cfg.activity_pub.blocked_instances)?,
+            policy: Policy::new(&cfg.policy)?,
+            notifications: Notifications::new(&cfg.notifications, &cfg.activity_pub.host)?,
+            images: ActorImages::load(&cfg.images, &cfg.data_dir)?,
+            about: About::load(cfg)?,
+        })
+    }
 }
 
 impl State {
-    pub fn new(cfg: Config, db: Db, private_key_pem: &str) -> Self {
-        let client = ActivityPubClient::new_with_priv_key(private_key_pem, cfg.base_url());
+    pub fn new(cfg: Config, db: Db, private_key_pem: &str) -> Result<Self> {
+        Self::new_with_key(cfg, db, ActorKey::from_pem(private_key_pem)?)
+    }
 
-        Self {
+    /// Create the server state, signing as the main relay actor with the given key.
+    pub fn new_with_key(cfg: Config, db: Db, key: ActorKey) -> Result<Self> {
+        let mut client = ActivityPubClient::new_with_key(key, cfg.base_url());
+        let Components {
+            blocklist,
+            policy,
+            notifications,
+            images,
+            about,
+        } = Components::new(&cfg, &mut client)?;
+        let deliveries = Deliveries::new(&cfg.delivery);
+        blocklist.set_source_with_severities(ADMIN_SOURCE, &db.domain_blocks())?;
+        let history = History::new(
+            Box::new(JsonFileStorage::open(&cfg.data_dir, "history.json")?),
+            &cfg.history,
+        );
+        let ingest = Ingest::new(
+            Box::new(DirRecordStorage::open(&cfg.data_dir.join(INGEST_DIR))?),
+            &cfg.ingest,
+        );
+        let objects = ObjectCache::new(
+            Box::new(JsonFileStorage::open(&cfg.data_dir, "objects.json")?),
+            &cfg.object_cache,
+        );
+        let actors = cfg
+            .actors
+            .iter()
+            .map(|actor| {
+                let topic_actor = TopicActor::open(actor.clone(), &cfg.data_dir)?;
+                client.add_actor_key(&actor.name, topic_actor.key(&cfg.signer.passphrase)?);
+
+                Ok((actor.name.clone(), topic_actor))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
             cfg,
             db,
+            actors,
             client,
-            object_cache: Default::default(),
+            deliveries,
+            metrics: Default::default(),
+            blocklist,
+            imports: Default::default(),
+            history,
+            tokens: Default::default(),
+            policy,
+            stats: Default::default(),
+            flood: Default::default(),
+            notifications,
+            message_limits: Default::default(),
+            reactions: Default::default(),
+            pipeline: default_pipeline(),
+            ingest,
+            images,
+            about,
+            objects,
+            log_filter: Default::default(),
+        })
+    }
+
+    /// Check that the server state could be created from the given config, as far as
+    /// that can be done without touching the data dir.
+    pub fn validate(cfg: &Config, key: ActorKey) -> Result<()> {
+        let mut client = ActivityPubClient::new_with_key(key, cfg.base_url());
+        Components::new(cfg, &mut client)?;
+        for actor in cfg.actors.iter() {
+            TopicActor::check(actor, &cfg.data_dir, &cfg.signer.passphrase)?;
+        }
+
+        Ok(())
+    }
+
+    /// Look up one of our relay actors by name.
+    pub fn actor(&self, name: &str) -> Option<RelayActor<'_>> {
+        if name == DEFAULT_ACTOR {
+            return Some(RelayActor::main(self));
+        }
+
+        self.actors.get(name).map(RelayActor::topic)
+    }
+
+    /// Write the stores that are kept in memory between periodic flushes to disk.
+    pub fn flush(&self) {
+        self.history.flush();
+        self.objects.flush();
+        for relay in self.relay_actors() {
+            relay.db.flush();
         }
     }
 
-    #[tracing::instrument(skip(self, message), err)]
+    /// All of the relay actors served by this process, starting with the main actor.
+    pub fn relay_actors(&self) -> Vec<RelayActor<'_>> {
+        let mut actors = vec![RelayActor::main(self)];
+        actors.extend(self.actors.values().map(RelayActor::topic));
+
+        actors
+    }
+
+    #[tracing::instrument(skip(self, relay, message), fields(relay = relay.name), err)]
     pub async fn post_for_actor<T: Serialize + Clone>(
         &self,
+        relay: &RelayActor<'_>,
         actor: &Actor,
         object_id: String,
         cache_value: String,
         message: T,
     ) -> Result<()> {
-        let inboxes = self.db.inboxes_for_actor(actor, &object_id)?;
-        trace!(?inboxes, "posting message to all inboxes");
-
-        // TODO: this will need to be smarter
-        let res = try_join_all(
-            inboxes
-                .into_iter()
-                .map(|inbox| self.client.json_post(inbox, message.clone())),
-        )
-        .await
-        .map(|_| ());
+        let inboxes = relay.db.inboxes_for_actor(actor, &object_id)?;
+        let message = serde_json::to_value(message).map_err(|e| Error::InvalidJson {
+            uri: object_id.clone(),
+            raw: e.to_string(),
+        })?;
+
+        self.deliver_to(relay, &inboxes, &message, None);
+        self.record_relayed(relay, actor, object_id, cache_value)
+    }
+
+    /// As [State::post_for_actor] but relaying the message exactly as we received it.
+    #[tracing::instrument(skip(self, relay, message, raw), fields(relay = relay.name), err)]
+    pub async fn post_raw_for_actor(
+        &self,
+        relay: &RelayActor<'_>,
+        actor: &Actor,
+        object_id: String,
+        cache_value: String,
+        message: &Value,
+        raw: Bytes,
+    ) -> Result<()> {
+        let inboxes = relay.db.inboxes_for_actor(actor, &object_id)?;
+        self.deliver(Delivery::fanout_raw(relay.name, &inboxes, message, raw));
+        self.record_relayed(relay, actor, object_id, cache_value)
+    }
+
+    fn record_relayed(
+        &self,
+        relay: &RelayActor<'_>,
+        actor: &Actor,
+        object_id: String,
+        cache_value: String,
+    ) -> Result<()> {
+        let origin = self.origin_of(actor, &object_id)?;
+        self.record_origin_event(&origin, Event::Relayed);
+        self.history.record(HistoryEntry {
+            relay: relay.name.to_owned(),
+            object_id,
+            activity_id: cache_value,
+            origin,
+            relayed_at: Utc::now(),
+            recipients: None,
+            deleted: false,
+        });
+
+        Ok(())
+    }
+
+    /// Forward a Delete only to the subscribers that were sent the object being deleted.
+    /// Deletes of objects that we never relayed aren't forwarded at all. If the raw
+    /// message is given it is sent exactly as we received it.
+    #[tracing::instrument(skip(self, relay, message, raw), fields(relay = relay.name), err)]
+    pub async fn forward_delete(
+        &self,
+        relay: &RelayActor<'_>,
+        actor: &Actor,
+        object_id: String,
+        message: Value,
+        raw: Option<Bytes>,
+    ) -> Result<()> {
+        let entry = match self.history.entry(relay.name, &object_id) {
+            Some(entry) => entry,
+            None => {
+                debug!(%object_id, "not forwarding delete of an object we haven't relayed");
+                return Ok(());
+            }
+        };
 
-        self.cache_object(object_id, cache_value);
+        let all = relay.db.inboxes_for_actor(actor, &object_id)?;
+        let inboxes = sent_to(&all, entry.recipients.as_deref());
 
-        res
+        debug!(%object_id, n_inboxes = inboxes.len(), "forwarding delete");
+        self.deliver_to(relay, &inboxes, &message, raw);
+        let origin = self.origin_of(actor, &object_id)?;
+        self.record_origin_event(&origin, Event::Relayed);
+        self.history.record(HistoryEntry {
+            relay: relay.name.to_owned(),
+            activity_id: message["id"].as_str().unwrap_or(&object_id).to_owned(),
+            object_id,
+            origin,
+            relayed_at: Utc::now(),
+            recipients: recorded_recipients(&all, inboxes),
+            deleted: true,
+        });
+
+        Ok(())
+    }
+
+    /// Forward a reaction only to the subscribers that were sent the object being reacted
+    /// to. If the raw message is given it is sent exactly as we received it.
+    #[tracing::instrument(skip(self, relay, message, raw), fields(relay = relay.name), err)]
+    pub async fn forward_reaction(
+        &self,
+        relay: &RelayActor<'_>,
+        actor: &Actor,
+        object_id: String,
+        activity_id: String,
+        message: Value,
+        raw: Option<Bytes>,
+    ) -> Result<()> {
+        let all = relay.db.inboxes_for_actor(actor, &object_id)?;
+        let sent = self
+            .history
+            .entry(relay.name, &object_id)
+            .and_then(|e| e.recipients);
+        let inboxes = sent_to(&all, sent.as_deref());
+
+        debug!(%object_id, %activity_id, n_inboxes = inboxes.len(), "forwarding reaction");
+        self.deliver_to(relay, &inboxes, &message, raw);
+        let origin = self.origin_of(actor, &object_id)?;
+        self.record_origin_event(&origin, Event::Relayed);
+
+        Ok(())
+    }
+
+    fn deliver_to(
+        &self,
+        relay: &RelayActor<'_>,
+        inboxes: &[String],
+        message: &Value,
+        raw: Option<Bytes>,
+    ) {
+        let deliveries = match raw {
+            Some(raw) => Delivery::fanout_raw(relay.name, inboxes, message, raw),
+            None => Delivery::fanout(relay.name, inboxes, message),
+        };
+
+        self.deliver(deliveries);
+    }
+
+    /// Count an activity from the given origin instance in both the per-origin stats
+    /// and the metrics.
+    pub fn record_origin_event(&self, origin: &str, event: Event) {
+        self.stats.record(origin, event);
+        self.metrics.incr(
+            "actiserve_origin_activities_total",
+            &[("instance", origin), ("event", event.as_str())],
+        );
+    }
+
+    // Objects relayed to us by an upstream relay are credited to the instance that they
+    // came from rather than to the upstream
+    fn origin_of(&self, actor: &Actor, object_id: &str) -> Result<String> {
+        match &actor.id {
+            Some(id) if is_upstream(id, self) => host_from_uri(object_id),
+            Some(id) => host_from_uri(id),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Queue deliveries to be sent by the delivery workers.
+    pub fn deliver(&self, deliveries: Vec<Delivery>) {
+        trace!(n_deliveries = deliveries.len(), "queueing deliveries");
+        let shed = self.deliveries.enqueue(deliveries);
+        self.record_shed(shed);
+    }
+
+    /// Attempt to send a queued delivery, requeueing it to be retried if it fails.
+    pub async fn attempt_delivery(&self, queued: Queued) {
+        let inbox = &queued.delivery.inbox;
+
+        let outcome = match self.send(&queued.delivery).await {
+            Ok(()) => "delivered",
+
+            // Put it back rather than holding this worker up until the host is free
+            Err(e) if e == HOST_BUSY => {
+                trace!(%inbox, "host busy: deferring delivery");
+                let shed = self.deliveries.defer(queued);
+                self.record_shed(shed);
+
+                return;
+            }
+
+            Err(e) if queued.attempts + 1 >= self.cfg.delivery.max_attempts => {
+                warn!(%inbox, error=%e, attempts=queued.attempts + 1, "giving up on delivery");
+                "failed"
+            }
+
+            Err(e) => {
+                debug!(%inbox, error=%e, attempts=queued.attempts + 1, "delivery failed");
+                self.metrics.incr(
+                    "actiserve_deliveries_total",
+                    &[("instance", &queued.host), ("outcome", "retried")],
+                );
+                let shed = self.deliveries.retry(queued);
+                self.record_shed(shed);
+
+                return;
+            }
+        };
+
+        self.metrics.incr(
+            "actiserve_deliveries_total",
+            &[("instance", &queued.host), ("outcome", outcome)],
+        );
     }
 
-    pub fn get_from_cache(&self, id: &str) -> Option<String> {
-        self.object_cache.lock().unwrap().get(id).cloned()
+    async fn send(&self, delivery: &Delivery) -> Result<()> {
+        let Delivery {
+            actor, inbox, body, ..
+        } = delivery;
+
+        if self.cfg.dry_run {
+            let message = String::from_utf8_lossy(body.bytes());
+            info!(%inbox, "dry run: skipping delivery");
+            debug!(%message, "dry run: message that would have been delivered");
+            return Ok(());
+        }
+
+        let res = self.client.post_body(actor, inbox, body).await?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Error::FailedRequest {
+                method: "POST".to_owned(),
+                status,
+                error: "unsuccessful response from inbox".to_owned(),
+                uri: inbox.to_owned(),
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn cache_object(&self, object_id: String, activity_id: String) {
-        self.object_cache
-            .lock()
-            .unwrap()
-            .insert(object_id, activity_id);
+    fn record_shed(&self, shed: Vec<Shed>) {
+        for Shed {
+            delivery,
+            host,
+            reason,
+        } in shed
+        {
+            warn!(inbox=%delivery.inbox, %reason, "dropping queued delivery");
+            self.metrics.incr(
+                "actiserve_deliveries_shed_total",
+                &[("instance", &host), ("reason", reason)],
+            );
+        }
+    }
+
+    /// Fetch a remote object, using the cached copy if we have one that is still fresh.
+    pub async fn fetch_object(&self, id: &str) -> Result<Value> {
+        let now = Utc::now();
+        if let Some(object) = self.objects.get(id, now) {
+            self.metrics
+                .incr("actiserve_object_cache_total", &[("outcome", "hit")]);
+            return Ok(object);
+        }
+
+        self.metrics
+            .incr("actiserve_object_cache_total", &[("outcome", "miss")]);
+        let object = self.client.get_activity(id).await?;
+        let evicted = self.objects.insert(id, object.clone(), now);
+        if evicted > 0 {
+            self.metrics.incr_by(
+                "actiserve_object_cache_evictions_total",
+                &[],
+                evicted as u64,
+            );
+        }
+
+        Ok(object)
+    }
+
+    /// The id of the activity we relayed the given object as, if we have already
+    /// relayed it.
+    pub fn get_from_cache(&self, relay: &str, id: &str) -> Option<String> {
+        self.history.get(relay, id)
+    }
+}
+
+// The current subscribers that were sent an object, when the history records that it
+// was only sent to some of them
+fn sent_to(all: &[String], recipients: Option<&[String]>) -> Vec<String> {
+    match recipients {
+        Some(sent) => all.iter().filter(|i| sent.contains(i)).cloned().collect(),
+        None => all.to_vec(),
     }
 }
 
+// Recipients are only recorded when they weren't every subscriber, so that the history
+// doesn't hold a copy of the subscriber list for each relayed object
+fn recorded_recipients(all: &[String], inboxes: Vec<String>) -> Option<Vec<String>> {
+    (inboxes.len() != all.len()).then_some(inboxes)
+}
+
+/// What we know about a subscribed instance beyond its inbox.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Instance {
+    /// The id of the actor that followed the relay
+    pub actor: String,
+    /// Fingerprint of the public key the actor presented when last verified
+    pub key_fingerprint: Option<String>,
+    /// Software details from the instance's nodeinfo
+    pub software: Option<SoftwareInfo>,
+    /// Whether the instance's nodeinfo reports that it has open registrations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_registrations: Option<bool>,
+    /// The metadata object from the instance's nodeinfo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodeinfo_metadata: Option<Value>,
+    /// When the instance's nodeinfo was last successfully fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodeinfo_updated: Option<DateTime<Utc>>,
+    /// Problems detected the last time the instance was verified
+    #[serde(default)]
+    pub flags: Vec<InstanceFlag>,
+    pub last_verified: Option<DateTime<Utc>>,
+    /// Activities from the instance are not relayed until this time has passed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined_until: Option<DateTime<Utc>>,
+    /// The id of the Follow the instance subscribed with, which a Reject ending the
+    /// subscription has to refer to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_id: Option<String>,
+}
+
+impl Instance {
+    pub fn new(actor: String, key_fingerprint: Option<String>) -> Self {
+        Self {
+            actor,
+            key_fingerprint,
+            software: None,
+            open_registrations: None,
+            nodeinfo_metadata: None,
+            nodeinfo_updated: None,
+            flags: vec![],
+            last_verified: None,
+            quarantined_until: None,
+            follow_id: None,
+        }
+    }
+
+    /// Whether the instance is still in its probation period as a new subscriber
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined_until
+            .map(|until| until > Utc::now())
+            .unwrap_or(false)
+    }
+
+    /// Update our view of this instance from the result of refetching its actor
+    /// and nodeinfo.
+    ///
+    /// The first key we see for an instance is pinned: later fetches returning a
+    /// different key are flagged rather than replacing it.
+    pub fn record_verification(
+        &mut self,
+        actor: std::result::Result<Option<String>, String>,
+        nodeinfo: Option<NodeInfo>,
+    ) {
+        let mut flags = vec![];
+
+        match actor {
+            Ok(fingerprint) => match (&self.key_fingerprint, fingerprint) {
+                (Some(previous), Some(current)) if previous != &current => {
+                    flags.push(InstanceFlag::KeyChanged {
+                        previous: previous.clone(),
+                        current,
+                    });
+                }
+                (None, current) => self.key_fingerprint = current,
+                _ => (),
+            },
+
+            Err(error) => flags.push(InstanceFlag::Unreachable { error }),
+        }
+
+        if let Some(info) = nodeinfo {
+            self.record_nodeinfo(info);
+        }
+        self.flags = flags;
+        self.last_verified = Some(Utc::now());
+    }
+
+    /// Update the software details we hold for this instance from its nodeinfo.
+    pub fn record_nodeinfo(&mut self, info: NodeInfo) {
+        self.software = Some(info.software);
+        self.open_registrations = info.open_registrations;
+        self.nodeinfo_metadata = info.metadata;
+        self.nodeinfo_updated = Some(Utc::now());
+    }
+
+    /// Compare the key presented by the subscribed actor against the one we have
+    /// pinned, flagging the instance if it has changed. Returns whether the key
+    /// matches.
+    pub fn check_key(&mut self, fingerprint: &str) -> bool {
+        let previous = match &self.key_fingerprint {
+            Some(previous) if previous != fingerprint => previous.clone(),
+            Some(_) => return true,
+            None => {
+                self.key_fingerprint = Some(fingerprint.to_owned());
+                return true;
+            }
+        };
+
+        self.flags
+            .retain(|f| !matches!(f, InstanceFlag::KeyChanged { .. }));
+        self.flags.push(InstanceFlag::KeyChanged {
+            previous,
+            current: fingerprint.to_owned(),
+        });
+
+        false
+    }
+
+    // Whether the key is already known to match or to have been flagged as changed, in
+    // which case checking it again wouldn't change the instance record
+    fn known_key_match(&self, fingerprint: &str) -> Option<bool> {
+        let flagged = || {
+            self.flags.iter().any(
+                |f| matches!(f, InstanceFlag::KeyChanged { current, .. } if current == fingerprint),
+            )
+        };
+
+        match &self.key_fingerprint {
+            Some(pinned) if pinned == fingerprint => Some(true),
+            Some(_) if flagged() => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Accept the most recently seen key for this instance as the new pinned key.
+    /// Returns the newly trusted fingerprint if there was a key change to accept.
+    pub fn trust_new_key(&mut self) -> Option<String> {
+        let ix = self
+            .flags
+            .iter()
+            .position(|f| matches!(f, InstanceFlag::KeyChanged { .. }))?;
+
+        match self.flags.remove(ix) {
+            InstanceFlag::KeyChanged { current, .. } => {
+                self.key_fingerprint = Some(current.clone());
+                Some(current)
+            }
+            _ => unreachable!("only key changes are removed"),
+        }
+    }
+}
+
+/// Problems detected when re-verifying a subscribed instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum InstanceFlag {
+    /// We were unable to fetch the instance's actor
+    Unreachable { error: String },
+    /// The actor is now presenting a different key to the one we have pinned
+    KeyChanged { previous: String, current: String },
+}
+
 #[derive(Debug)]
 pub struct Db {
     // map of host to inbox
     inboxes: AcidJson<HashMap<String, String>>,
+    // map of host to shared inbox for instances that advertise one
+    shared_inboxes: AcidJson<HashMap<String, String>>,
+    // map of host to instance metadata
+    instances: AcidJson<HashMap<String, Instance>>,
+    // map of client id to OAuth clients registered for the admin API
+    oauth_clients: AcidJson<HashMap<String, OAuthClient>>,
+    // map of domain to the severity of blocks added via the admin API (see migration 3)
+    domain_blocks: AcidJson<HashMap<String, Severity>>,
+    // ids of individual remote actors blocked via the admin API
+    actor_blocks: AcidJson<BTreeSet<String>>,
+    // hourly rollups of the key relay counters
+    timeseries: AcidJson<Rollups>,
+    // map of code to invites generated via the admin API
+    invites: AcidJson<HashMap<String, Invite>>,
+    // map of actor to our subscriptions to upstream relays
+    upstreams: AcidJson<HashMap<String, Upstream>>,
+    // map of id to the Create activities of statuses published by the relay actor
+    statuses: AcidJson<HashMap<String, Value>>,
+    // map of id to posts mentioning the relay actors
+    messages: AcidJson<HashMap<String, Message>>,
+    // map of origin and type to activities of types that we don't recognise
+    unrecognized: Unrecognized,
+    // rules for instances allowed to subscribe via the admin API in addition to those in
+    // the config
+    allowed_instances: AcidJson<DomainRules>,
+    // profile metadata for the actor set via the admin API
+    actor_metadata: AcidJson<ActorMetadata>,
 }
 
 impl Db {
-    pub fn new(mut path: PathBuf) -> Result<Self> {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        // Checked before anything is written so that existing data dirs without a schema
+        // version are still migrated from version 0
+        let is_new = !std::fs::read_dir(&path).is_ok_and(|mut entries| entries.next().is_some());
         if std::fs::create_dir_all(&path).is_err() {
             return Err(Error::StatusAndMessage {
                 status: StatusCode::INTERNAL_SERVER_ERROR,
                 message: "unable to create data dir",
             });
         }
-        path.push("statedb.json");
-        if std::fs::read(&path).is_err() && std::fs::write(&path, b"{}").is_err() {
-            return Err(Error::StatusAndMessage {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                message: "unable to create initial state db",
-            });
-        }
 
-        match AcidJson::open(path.as_path()) {
-            Ok(db) => Ok(Self { inboxes: db }),
-            Err(_) => Err(Error::StatusAndMessage {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                message: "unable to open state db",
-            }),
+        if is_new {
+            migrations::initialize(&path)?;
+        } else {
+            migrations::migrate(&path)?;
         }
+
+        Ok(Self {
+            inboxes: open_json(&path, "statedb.json")?,
+            shared_inboxes: open_json(&path, "sharedinboxes.json")?,
+            instances: open_json(&path, "instances.json")?,
+            oauth_clients: open_json(&path, "oauthclients.json")?,
+            domain_blocks: open_json(&path, "domainblocks.json")?,
+            actor_blocks: open_json(&path, "actorblocks.json")?,
+            timeseries: open_json(&path, "timeseries.json")?,
+            invites: open_json(&path, "invites.json")?,
+            upstreams: open_json(&path, "upstreams.json")?,
+            statuses: open_json(&path, "statuses.json")?,
+            messages: open_json(&path, "messages.json")?,
+            unrecognized: Unrecognized::new(Box::new(JsonFileStorage::open(
+                &path,
+                "unrecognized.json",
+            )?)),
+            allowed_instances: open_json(&path, "allowedinstances.json")?,
+            actor_metadata: open_json(&path, "actormetadata.json")?,
+        })
     }
 
-    pub fn add_inbox_if_unknown(&self, inbox: String) -> Result<bool> {
+    pub fn add_inbox_if_unknown(
+        &self,
+        inbox: String,
+        shared_inbox: Option<String>,
+    ) -> Result<bool> {
         let host = host_from_uri(&inbox)?;
 
-        if self.inboxes.read().contains_key(&inbox) {
+        if let Some(shared_inbox) = shared_inbox {
+            self.shared_inboxes
+                .write()
+                .insert(host.clone(), shared_inbox);
+        }
+
+        if self.inboxes.read().contains_key(&host) {
             Ok(false)
         } else {
             self.inboxes.write().insert(host, inbox);
@@ -109,6 +763,8 @@ impl Db {
 
     pub fn remove_inbox(&self, inbox: &str) -> Result<String> {
         let host = host_from_uri(inbox)?;
+        self.shared_inboxes.write().remove(&host);
+        self.instances.write().remove(&host);
 
         self.inboxes
             .write()
@@ -119,12 +775,362 @@ impl Db {
             })
     }
 
+    /// Record the actor (and its current key) that subscribed on behalf of an instance.
+    /// Newly subscribed instances are quarantined until the given time (if any).
+    pub fn record_instance(
+        &self,
+        actor_id: &str,
+        key_fingerprint: Option<String>,
+        quarantined_until: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let host = host_from_uri(actor_id)?;
+
+        self.instances
+            .write()
+            .entry(host)
+            .and_modify(|instance| instance.actor = actor_id.to_owned())
+            .or_insert_with(|| Instance {
+                quarantined_until,
+                ..Instance::new(actor_id.to_owned(), key_fingerprint)
+            });
+
+        Ok(())
+    }
+
+    /// Check the key presented by an actor against the key pinned for its instance.
+    /// Actors other than the one that subscribed on behalf of the instance are not
+    /// pinned, so always pass.
+    pub fn check_actor_key(&self, actor_id: &str, fingerprint: &str) -> Result<bool> {
+        let host = host_from_uri(actor_id)?;
+
+        // This is checked for every request so the instances file is only rewritten when
+        // a key is seen for the first time or has changed
+        match self.instances.read().get(&host) {
+            Some(instance) if instance.actor == actor_id => {
+                if let Some(matches) = instance.known_key_match(fingerprint) {
+                    return Ok(matches);
+                }
+            }
+            _ => return Ok(true),
+        }
+
+        match self.instances.write().get_mut(&host) {
+            Some(instance) if instance.actor == actor_id => Ok(instance.check_key(fingerprint)),
+            _ => Ok(true),
+        }
+    }
+
+    pub fn instance(&self, host: &str) -> Option<Instance> {
+        self.instances.read().get(host).cloned()
+    }
+
+    pub fn instances(&self) -> HashMap<String, Instance> {
+        self.instances.read().clone()
+    }
+
+    /// Apply an update to the instance record for the given host if we have one.
+    pub fn update_instance(&self, host: &str, f: impl FnOnce(&mut Instance)) {
+        if let Some(instance) = self.instances.write().get_mut(host) {
+            f(instance);
+        }
+    }
+
+    pub fn add_oauth_client(&self, client: OAuthClient) {
+        self.oauth_clients
+            .write()
+            .insert(client.client_id.clone(), client);
+    }
+
+    pub fn oauth_client(&self, client_id: &str) -> Option<OAuthClient> {
+        self.oauth_clients.read().get(client_id).cloned()
+    }
+
+    pub fn oauth_clients(&self) -> Vec<OAuthClient> {
+        let mut clients: Vec<OAuthClient> = self.oauth_clients.read().values().cloned().collect();
+        clients.sort_by_key(|c| c.created_at);
+
+        clients
+    }
+
+    pub fn remove_oauth_client(&self, client_id: &str) -> Option<OAuthClient> {
+        self.oauth_clients.write().remove(client_id)
+    }
+
+    pub fn add_domain_block(&self, domain: &str, severity: Severity) {
+        self.domain_blocks
+            .write()
+            .insert(domain.to_owned(), severity);
+    }
+
+    pub fn remove_domain_block(&self, domain: &str) -> Option<Severity> {
+        self.domain_blocks.write().remove(domain)
+    }
+
+    pub fn domain_blocks(&self) -> HashMap<String, Severity> {
+        self.domain_blocks.read().clone()
+    }
+
+    /// Block an individual actor, returning false if it was already blocked. Actor ids
+    /// are normalized (see [normalize_id]) so that differences in host case or a
+    /// trailing slash don't let an actor slip past its block.
+    pub fn add_actor_block(&self, actor_id: &str) -> bool {
+        self.actor_blocks.write().insert(normalize_id(actor_id))
+    }
+
+    pub fn remove_actor_block(&self, actor_id: &str) -> bool {
+        self.actor_blocks.write().remove(&normalize_id(actor_id))
+    }
+
+    pub fn actor_blocks(&self) -> Vec<String> {
+        self.actor_blocks.read().iter().cloned().collect()
+    }
+
+    pub fn allowed_instances(&self) -> BTreeSet<DomainRule> {
+        self.allowed_instances
+            .read()
+            .rules()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Replace the rules for instances allowed to subscribe in addition to those in the
+    /// config.
+    pub fn set_allowed_instances(&self, rules: DomainRules) {
+        *self.allowed_instances.write() = rules;
+    }
+
+    /// Whether the domain matches any of the allowed instance rules, in the same way as
+    /// the allow list in the config.
+    pub fn is_allowed_instance(&self, domain: &str) -> bool {
+        self.allowed_instances.read().matches(domain)
+    }
+
+    pub fn actor_metadata(&self) -> ActorMetadata {
+        self.actor_metadata.read().clone()
+    }
+
+    pub fn set_actor_metadata(&self, metadata: ActorMetadata) {
+        *self.actor_metadata.write() = metadata;
+    }
+
+    pub fn add_invite(&self, invite: Invite) {
+        self.invites.write().insert(invite.code.clone(), invite);
+    }
+
+    pub fn invites(&self) -> Vec<Invite> {
+        let mut invites: Vec<Invite> = self.invites.read().values().cloned().collect();
+        invites.sort_by_key(|i| i.created_at);
+
+        invites
+    }
+
+    /// Bind an invite to the domain it was passed on to.
+    pub fn bind_invite(&self, code: &str, domain: &str) -> Result<Invite> {
+        let mut invites = self.invites.write();
+        let invite = invites.get_mut(code).ok_or(Error::StatusAndMessage {
+            status: StatusCode::NOT_FOUND,
+            message: "unknown invite",
+        })?;
+
+        if !invite.bind(domain) {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::CONFLICT,
+                message: "invite has already been used by another domain",
+            });
+        }
+
+        Ok(invite.clone())
+    }
+
+    pub fn remove_invite(&self, code: &str) -> Option<Invite> {
+        self.invites.write().remove(code)
+    }
+
+    /// Whether the given domain has been bound to an invite.
+    pub fn is_invited(&self, domain: &str) -> bool {
+        self.invites
+            .read()
+            .values()
+            .any(|i| i.domain.as_deref() == Some(domain))
+    }
+
+    pub fn upstream(&self, actor: &str) -> Option<Upstream> {
+        self.upstreams.read().get(actor).cloned()
+    }
+
+    pub fn upstreams(&self) -> Vec<Upstream> {
+        let mut upstreams: Vec<Upstream> = self.upstreams.read().values().cloned().collect();
+        upstreams.sort_by(|a, b| a.actor.cmp(&b.actor));
+
+        upstreams
+    }
+
+    pub fn set_upstream(&self, upstream: Upstream) {
+        self.upstreams
+            .write()
+            .insert(upstream.actor.clone(), upstream);
+    }
+
+    pub fn remove_upstream(&self, actor: &str) {
+        self.upstreams.write().remove(actor);
+    }
+
+    pub fn add_status(&self, id: String, create: Value) {
+        self.statuses.write().insert(id, create);
+    }
+
+    pub fn status(&self, id: &str) -> Option<Value> {
+        self.statuses.read().get(id).cloned()
+    }
+
+    /// Statuses published by the relay actor, newest first.
+    pub fn statuses(&self) -> Vec<Value> {
+        let mut statuses: Vec<Value> = self.statuses.read().values().cloned().collect();
+        statuses.sort_by(|a, b| b["published"].as_str().cmp(&a["published"].as_str()));
+
+        statuses
+    }
+
+    /// Whether we already have a message for the given post.
+    pub fn has_message(&self, object: &str) -> bool {
+        self.messages.read().contains_key(object)
+    }
+
+    /// Keep a message sent to the relay operator, dropping the oldest once we have
+    /// more than [MAX_MESSAGES]. Messages are keyed by the post that they were sent in
+    /// so that each post is only kept once, however many times it is delivered to us:
+    /// returns false if it already had been.
+    pub fn add_message(&self, message: Message) -> bool {
+        let mut messages = self.messages.write();
+        if messages.contains_key(&message.object) {
+            return false;
+        }
+        messages.insert(message.object.clone(), message);
+
+        if messages.len() > MAX_MESSAGES {
+            let oldest = messages
+                .iter()
+                .min_by_key(|(_, m)| m.received_at)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                messages.remove(&key);
+            }
+        }
+
+        true
+    }
+
+    /// Messages sent to the relay operator, newest first.
+    pub fn messages(&self) -> Vec<Message> {
+        let mut messages: Vec<Message> = self.messages.read().values().cloned().collect();
+        messages.sort_by(|a, b| b.received_at.cmp(&a.received_at));
+
+        messages
+    }
+
+    pub fn message(&self, id: &str) -> Option<Message> {
+        self.messages.read().values().find(|m| m.id == id).cloned()
+    }
+
+    pub fn remove_message(&self, id: &str) -> Option<Message> {
+        let mut messages = self.messages.write();
+        let key = messages.iter().find(|(_, m)| m.id == id)?.0.clone();
+
+        messages.remove(&key)
+    }
+
+    /// Count an activity of a type that we don't recognise.
+    pub fn record_unrecognized(&self, unrecognized: UnrecognizedActivity) {
+        self.unrecognized.record(unrecognized);
+    }
+
+    /// Activities of types that we don't recognise, most recently seen first.
+    pub fn unrecognized(&self) -> Vec<UnrecognizedActivity> {
+        self.unrecognized.list()
+    }
+
+    /// Write the records kept in memory to storage.
+    pub fn flush(&self) {
+        self.unrecognized.flush();
+    }
+
+    /// Add counts to the hourly rollup covering the given time.
+    pub fn record_rollup(&self, at: DateTime<Utc>, counts: &Counts) {
+        timeseries::record(&mut self.timeseries.write(), at, counts);
+    }
+
+    /// The hourly rollups from the given time onwards, oldest first.
+    pub fn rollups_since(&self, since: DateTime<Utc>) -> Vec<(DateTime<Utc>, Counts)> {
+        self.timeseries
+            .read()
+            .range(timeseries::hour_of(since)..)
+            .map(|(hour, counts)| (*hour, counts.clone()))
+            .collect()
+    }
+
+    pub fn is_actor_blocked(&self, actor_id: &str) -> bool {
+        self.actor_blocks.read().contains(&normalize_id(actor_id))
+    }
+
+    /// The inbox we should deliver to for the given host, preferring the shared inbox
+    /// if the instance has advertised one.
+    pub fn delivery_inbox(&self, domain: &str) -> Option<String> {
+        let domain = host_from_uri(domain).ok()?;
+
+        match self.shared_inboxes.read().get(&domain) {
+            Some(shared_inbox) => Some(shared_inbox.clone()),
+            None => self.inboxes.read().get(&domain).cloned(),
+        }
+    }
+
+    /// The domains of every instance subscribed.
+    pub fn subscribed_domains(&self) -> Vec<String> {
+        self.inboxes.read().keys().cloned().collect()
+    }
+
+    /// The inboxes to deliver to in order to reach every subscribed instance.
+    pub fn delivery_inboxes(&self) -> Vec<String> {
+        let shared_inboxes = self.shared_inboxes.read();
+        let mut inboxes: Vec<String> = self
+            .inboxes
+            .read()
+            .iter()
+            .map(|(host, inbox)| shared_inboxes.get(host).unwrap_or(inbox).to_owned())
+            .collect();
+        inboxes.sort();
+        inboxes.dedup();
+
+        inboxes
+    }
+
     pub fn inbox(&self, domain: &str) -> Option<String> {
         let domain = host_from_uri(domain).ok()?;
 
         self.inboxes.read().get(&domain).cloned()
     }
 
+    /// The inbox of the subscribed instance matching the given domain. Matching on the
+    /// registrable domain allows requests from instances whose actors live on a
+    /// different subdomain to the one that subscribed.
+    pub fn matching_inbox(&self, domain: &str, scope: SubscriptionScope) -> Option<String> {
+        if let Some(inbox) = self.inbox(domain) {
+            return Some(inbox);
+        }
+
+        match scope {
+            SubscriptionScope::Exact => None,
+            SubscriptionScope::RegistrableDomain => {
+                let domain = registrable_domain(&host_from_uri(domain).ok()?);
+                self.inboxes
+                    .read()
+                    .iter()
+                    .find(|(host, _)| registrable_domain(host) == domain)
+                    .map(|(_, inbox)| inbox.clone())
+            }
+        }
+    }
+
     pub fn inboxes_for_actor(&self, actor: &Actor, object_id: &str) -> Result<Vec<String>> {
         let origin_host = host_from_uri(object_id)?;
 
@@ -133,14 +1139,19 @@ impl Db {
             message: "actor has no inbox",
         })?;
 
-        let inboxes = self
+        let shared_inboxes = self.shared_inboxes.read();
+        let mut inboxes: Vec<String> = self
             .inboxes
             .read()
             .iter()
             .filter(|&(host, inbox)| inbox != actor_inbox && host != &origin_host)
-            .map(|(_, inbox)| inbox.to_owned())
+            .map(|(host, inbox)| shared_inboxes.get(host).unwrap_or(inbox).to_owned())
             .collect();
 
+        // Multiple hosts may share a single inbox so make sure we only deliver once
+        inboxes.sort();
+        inboxes.dedup();
+
         Ok(inboxes)
     }
 }
@@ -148,31 +1159,582 @@ impl Db {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::ActivityPubConfig;
+    use crate::{
+        config::{ActivityPubConfig, ListenAddr},
+        signature::tests::test_actor,
+        storage::{MemoryRecordStorage, MemoryStorage},
+    };
+    use simple_test_case::test_case;
     use std::net::Ipv4Addr;
 
     impl State {
         pub fn new_with_test_key(db: Db) -> Self {
             Self {
                 cfg: Config {
-                    listen: Ipv4Addr::new(127, 0, 0, 1),
+                    listen: vec![ListenAddr::Ip(Ipv4Addr::new(127, 0, 0, 1).into())],
                     port: 4242,
                     data_dir: PathBuf::from("."),
                     private_key_path: PathBuf::from("private-key.pem"),
                     activity_pub: ActivityPubConfig {
                         host: "localhost".into(),
-                        blocked_instances: vec![],
+                        blocked_instances: Default::default(),
                         allow_list: false,
-                        allowed_instances: vec![],
+                        allowed_instances: Default::default(),
+                        invite_only: false,
+                        subscription_scope: Default::default(),
+                        signature_algorithm: Default::default(),
+                        preserve_attribution: false,
                     },
+                    admin_token: Some("test-token".into()),
+                    reverify_interval_secs: 60,
+                    dry_run: false,
+                    delivery: Default::default(),
+                    inbox: Default::default(),
+                    ingest: Default::default(),
+                    key_change_policy: Default::default(),
+                    quarantine_secs: 0,
+                    max_object_age_hours: None,
+                    max_subscribers: None,
+                    upstreams: vec![],
+                    history: Default::default(),
+                    object_cache: Default::default(),
+                    blocklists: Default::default(),
+                    policy: Default::default(),
+                    attachments: Default::default(),
+                    actors: vec![],
+                    proxy: Default::default(),
+                    http: Default::default(),
+                    logging: Default::default(),
+                    flood: Default::default(),
+                    notifications: Default::default(),
+                    commands: Default::default(),
+                    signer: Default::default(),
+                    images: Default::default(),
+                    operator: Default::default(),
                 },
                 db,
+                actors: Default::default(),
                 client: ActivityPubClient::new_with_test_key(),
-                object_cache: Default::default(),
+                deliveries: Deliveries::new(&Default::default()),
+                metrics: Default::default(),
+                blocklist: Default::default(),
+                imports: Default::default(),
+                history: History::new(Box::<MemoryStorage<_>>::default(), &Default::default()),
+                tokens: Default::default(),
+                policy: Default::default(),
+                stats: Default::default(),
+                flood: Default::default(),
+                notifications: Default::default(),
+                message_limits: Default::default(),
+                reactions: Default::default(),
+                pipeline: default_pipeline(),
+                ingest: Ingest::new(
+                    Box::<MemoryRecordStorage<_>>::default(),
+                    &Default::default(),
+                ),
+                images: Default::default(),
+                about: Default::default(),
+                objects: ObjectCache::new(Box::<MemoryStorage<_>>::default(), &Default::default()),
+                log_filter: Default::default(),
             }
         }
         pub fn clear(&self) {
             self.db.inboxes.write().clear();
+            self.db.shared_inboxes.write().clear();
+            self.db.instances.write().clear();
+            self.db.oauth_clients.write().clear();
+            self.db.domain_blocks.write().clear();
+            self.db.actor_blocks.write().clear();
+            self.db.timeseries.write().clear();
+            self.db.invites.write().clear();
+            self.db.upstreams.write().clear();
+            self.db.statuses.write().clear();
+            self.db.messages.write().clear();
+            self.db.unrecognized.clear();
+            *self.db.allowed_instances.write() = Default::default();
+            *self.db.actor_metadata.write() = Default::default();
+        }
+    }
+
+    fn test_db() -> (Db, PathBuf) {
+        let mut dir = std::env::temp_dir();
+        dir.push(uuid::Uuid::new_v4().to_string());
+
+        (
+            Db::new(dir.clone()).expect("unable to create database"),
+            dir,
+        )
+    }
+
+    #[test]
+    fn new_data_dirs_record_the_latest_schema_version() {
+        let (_db, dir) = test_db();
+
+        assert_eq!(
+            migrations::schema_version(&dir).unwrap(),
+            migrations::latest_version()
+        );
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn shared_inboxes_are_preferred_for_delivery() {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown(
+            "https://example.com/users/alice/inbox".to_owned(),
+            Some("https://example.com/inbox".to_owned()),
+        )
+        .unwrap();
+        db.add_inbox_if_unknown("https://other.example/users/bob/inbox".to_owned(), None)
+            .unwrap();
+
+        assert_eq!(
+            db.delivery_inbox("https://example.com").as_deref(),
+            Some("https://example.com/inbox")
+        );
+        assert_eq!(
+            db.delivery_inbox("https://other.example").as_deref(),
+            Some("https://other.example/users/bob/inbox")
+        );
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case("social.example.com", SubscriptionScope::Exact, true; "exact")]
+    #[test_case("media.example.com", SubscriptionScope::Exact, false; "sibling exact")]
+    #[test_case("media.example.com", SubscriptionScope::RegistrableDomain, true; "sibling registrable")]
+    #[test_case("example.org", SubscriptionScope::RegistrableDomain, false; "unrelated")]
+    #[test]
+    fn matching_inbox_works(domain: &str, scope: SubscriptionScope, found: bool) {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown("https://social.example.com/inbox".to_owned(), None)
+            .unwrap();
+
+        assert_eq!(db.matching_inbox(domain, scope).is_some(), found);
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn removing_an_inbox_removes_its_shared_inbox() {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown(
+            "https://example.com/users/alice/inbox".to_owned(),
+            Some("https://example.com/inbox".to_owned()),
+        )
+        .unwrap();
+
+        db.remove_inbox("https://example.com/users/alice").unwrap();
+
+        assert_eq!(db.delivery_inbox("https://example.com"), None);
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn post_for_actor_queues_deliveries_and_caches() {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown("https://other.invalid/inbox".to_owned(), None)
+            .unwrap();
+        let state = State::new_with_test_key(db);
+
+        let res = state
+            .post_for_actor(
+                &RelayActor::main(&state),
+                &test_actor("https://example.com/actor"),
+                "https://example.com/objects/1".to_owned(),
+                "https://localhost/activities/1".to_owned(),
+                serde_json::json!({ "type": "Announce" }),
+            )
+            .await;
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(state.deliveries.status().queued, 1);
+        assert_eq!(
+            state
+                .get_from_cache("relay", "https://example.com/objects/1")
+                .as_deref(),
+            Some("https://localhost/activities/1")
+        );
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    fn queued_inboxes(state: &State) -> Vec<String> {
+        std::iter::from_fn(|| state.deliveries.next_ready())
+            .map(|q| q.delivery.inbox)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn deletes_are_forwarded_with_their_own_id() {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown("https://a.invalid/inbox".to_owned(), None)
+            .unwrap();
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        let actor = test_actor("https://example.com/actor");
+        let object_id = "https://example.com/objects/1";
+
+        state
+            .post_for_actor(
+                &relay,
+                &actor,
+                object_id.to_owned(),
+                "https://localhost/activities/1".to_owned(),
+                serde_json::json!({ "type": "Announce" }),
+            )
+            .await
+            .unwrap();
+        let delete = serde_json::json!({
+            "id": "https://example.com/objects/1#delete",
+            "type": "Delete",
+            "object": object_id
+        });
+        let res = state
+            .forward_delete(&relay, &actor, object_id.to_owned(), delete, None)
+            .await;
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(
+            queued_inboxes(&state),
+            vec!["https://a.invalid/inbox", "https://a.invalid/inbox"]
+        );
+        let entry = state.history.entry("relay", object_id).unwrap();
+        assert!(entry.deleted);
+        assert_eq!(entry.activity_id, "https://example.com/objects/1#delete");
+        // Every subscriber was sent it so there is no need to list them
+        assert_eq!(entry.recipients, None);
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn deletes_are_forwarded_as_received_if_given() {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown("https://a.invalid/inbox".to_owned(), None)
+            .unwrap();
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        let actor = test_actor("https://example.com/actor");
+        let object_id = "https://example.com/objects/1";
+
+        state
+            .post_for_actor(
+                &relay,
+                &actor,
+                object_id.to_owned(),
+                "https://localhost/activities/1".to_owned(),
+                serde_json::json!({ "type": "Announce" }),
+            )
+            .await
+            .unwrap();
+        state.deliveries.next_ready().unwrap();
+        let delete = serde_json::json!({
+            "id": "https://example.com/objects/1#delete",
+            "type": "Delete",
+            "object": object_id
+        });
+        let raw = Bytes::from(serde_json::to_vec_pretty(&delete).unwrap());
+        let res = state
+            .forward_delete(
+                &relay,
+                &actor,
+                object_id.to_owned(),
+                delete,
+                Some(raw.clone()),
+            )
+            .await;
+
+        assert_eq!(res, Ok(()));
+        let queued = state.deliveries.next_ready().unwrap();
+        assert_eq!(queued.delivery.body.bytes().as_ref(), raw.as_ref());
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn deletes_are_only_forwarded_to_recorded_recipients() {
+        let (db, dir) = test_db();
+        for inbox in ["https://a.invalid/inbox", "https://b.invalid/inbox"] {
+            db.add_inbox_if_unknown(inbox.to_owned(), None).unwrap();
         }
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        let actor = test_actor("https://example.com/actor");
+        let object_id = "https://example.com/objects/1";
+        state.history.record(HistoryEntry {
+            relay: "relay".into(),
+            object_id: object_id.into(),
+            activity_id: "https://localhost/activities/1".into(),
+            origin: "example.com".into(),
+            relayed_at: Utc::now(),
+            recipients: Some(vec!["https://a.invalid/inbox".into()]),
+            deleted: false,
+        });
+
+        let delete = serde_json::json!({ "type": "Delete", "object": object_id });
+        let res = state
+            .forward_delete(&relay, &actor, object_id.to_owned(), delete, None)
+            .await;
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(queued_inboxes(&state), vec!["https://a.invalid/inbox"]);
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn deletes_of_objects_never_relayed_are_not_forwarded() {
+        let (db, dir) = test_db();
+        db.add_inbox_if_unknown("https://a.invalid/inbox".to_owned(), None)
+            .unwrap();
+        let state = State::new_with_test_key(db);
+        let relay = RelayActor::main(&state);
+        let actor = test_actor("https://example.com/actor");
+        let object_id = "https://example.com/objects/1";
+
+        let delete = serde_json::json!({ "type": "Delete", "object": object_id });
+        let res = state
+            .forward_delete(&relay, &actor, object_id.to_owned(), delete, None)
+            .await;
+
+        assert_eq!(res, Ok(()));
+        assert!(queued_inboxes(&state).is_empty());
+        assert_eq!(state.history.entry("relay", object_id), None);
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn fetched_objects_are_served_from_the_cache() {
+        let (db, dir) = test_db();
+        let state = State::new_with_test_key(db);
+        // Nothing listens on this port so only a cached copy can be returned, without
+        // needing to look anything up
+        let id = "https://127.0.0.1:1/notes/1";
+        let note = serde_json::json!({ "id": id, "type": "Note" });
+
+        assert!(state.fetch_object(id).await.is_err());
+        state.objects.insert(id, note.clone(), Utc::now());
+        assert_eq!(state.fetch_object(id).await, Ok(note));
+
+        for outcome in ["hit", "miss"] {
+            let labels = [("outcome", outcome)];
+            assert_eq!(
+                state
+                    .metrics
+                    .counter("actiserve_object_cache_total", &labels),
+                1
+            );
+        }
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(false, "retried"; "normal")]
+    #[test_case(true, "delivered"; "dry run")]
+    #[tokio::test]
+    async fn attempt_delivery_respects_dry_run(dry_run: bool, outcome: &str) {
+        let (db, dir) = test_db();
+        let mut state = State::new_with_test_key(db);
+        state.cfg.dry_run = dry_run;
+
+        // Unresolvable so any real attempt at delivery will fail
+        state.deliver(vec![Delivery::new(
+            "relay",
+            "https://other.invalid/inbox",
+            serde_json::json!({ "type": "Announce" }),
+        )]);
+        let queued = state.deliveries.next_ready().unwrap();
+        state.attempt_delivery(queued).await;
+
+        let labels = [("instance", "other.invalid"), ("outcome", outcome)];
+        assert_eq!(
+            state.metrics.counter("actiserve_deliveries_total", &labels),
+            1
+        );
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    fn software(version: &str) -> SoftwareInfo {
+        SoftwareInfo {
+            name: "mastodon".into(),
+            version: version.into(),
+        }
+    }
+
+    fn nodeinfo(version: &str) -> NodeInfo {
+        NodeInfo {
+            software: software(version),
+            open_registrations: Some(true),
+            metadata: Some(serde_json::json!({ "nodeName": "Example" })),
+        }
+    }
+
+    #[test]
+    fn verification_pins_the_first_key_seen() {
+        let mut instance = Instance::new("https://example.com/actor".into(), None);
+
+        instance.record_verification(Ok(Some("key-1".into())), Some(nodeinfo("4.0.2")));
+
+        assert_eq!(instance.key_fingerprint.as_deref(), Some("key-1"));
+        assert_eq!(instance.software, Some(software("4.0.2")));
+        assert_eq!(instance.open_registrations, Some(true));
+        assert!(instance.nodeinfo_updated.is_some());
+        assert!(instance.flags.is_empty());
+        assert!(instance.last_verified.is_some());
+    }
+
+    #[test]
+    fn verification_flags_a_changed_key() {
+        let mut instance = Instance::new("https://example.com/actor".into(), Some("key-1".into()));
+
+        instance.record_verification(Ok(Some("key-2".into())), None);
+
+        assert_eq!(instance.key_fingerprint.as_deref(), Some("key-1"));
+        assert_eq!(
+            instance.flags,
+            vec![InstanceFlag::KeyChanged {
+                previous: "key-1".into(),
+                current: "key-2".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn verification_flags_unreachable_instances_and_keeps_old_software() {
+        let mut instance = Instance::new("https://example.com/actor".into(), Some("key-1".into()));
+        instance.software = Some(software("4.0.2"));
+
+        instance.record_verification(Err("connection refused".into()), None);
+
+        assert_eq!(instance.software, Some(software("4.0.2")));
+        assert_eq!(
+            instance.flags,
+            vec![InstanceFlag::Unreachable {
+                error: "connection refused".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn a_changed_key_is_flagged_once() {
+        let mut instance = Instance::new("https://example.com/actor".into(), Some("key-1".into()));
+
+        assert!(instance.check_key("key-1"));
+        assert!(!instance.check_key("key-2"));
+        assert!(!instance.check_key("key-2"));
+
+        assert_eq!(instance.key_fingerprint.as_deref(), Some("key-1"));
+        assert_eq!(
+            instance.flags,
+            vec![InstanceFlag::KeyChanged {
+                previous: "key-1".into(),
+                current: "key-2".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn trusting_a_new_key_repins_it() {
+        let mut instance = Instance::new("https://example.com/actor".into(), Some("key-1".into()));
+        instance.check_key("key-2");
+
+        assert_eq!(instance.trust_new_key().as_deref(), Some("key-2"));
+        assert_eq!(instance.key_fingerprint.as_deref(), Some("key-2"));
+        assert!(instance.flags.is_empty());
+        assert!(instance.check_key("key-2"));
+        assert_eq!(instance.trust_new_key(), None);
+    }
+
+    #[test]
+    fn only_the_subscribed_actor_is_pinned() {
+        let (db, dir) = test_db();
+        db.record_instance("https://example.com/actor", Some("key-1".into()), None)
+            .unwrap();
+
+        assert!(db
+            .check_actor_key("https://example.com/actor", "key-1")
+            .unwrap());
+        assert!(!db
+            .check_actor_key("https://example.com/actor", "key-2")
+            .unwrap());
+        assert!(db
+            .check_actor_key("https://example.com/users/bob", "key-3")
+            .unwrap());
+        assert!(db
+            .check_actor_key("https://other.example/actor", "key-4")
+            .unwrap());
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn known_keys_do_not_rewrite_the_instance_record() {
+        let (db, dir) = test_db();
+        db.record_instance("https://example.com/actor", Some("key-1".into()), None)
+            .unwrap();
+        db.check_actor_key("https://example.com/actor", "key-2")
+            .unwrap();
+        let path = dir.join("instances.json");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(db
+            .check_actor_key("https://example.com/actor", "key-1")
+            .unwrap());
+        assert!(!db
+            .check_actor_key("https://example.com/actor", "key-2")
+            .unwrap());
+        assert!(!path.exists());
+
+        assert!(!db
+            .check_actor_key("https://example.com/actor", "key-3")
+            .unwrap());
+        assert!(path.exists());
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(None, false; "never quarantined")]
+    #[test_case(Some(-60), false; "quarantine over")]
+    #[test_case(Some(60), true; "quarantined")]
+    #[test]
+    fn quarantine_only_applies_to_new_subscribers(offset_secs: Option<i64>, expected: bool) {
+        let (db, dir) = test_db();
+        let until = offset_secs.map(|secs| Utc::now() + chrono::Duration::seconds(secs));
+        db.record_instance("https://example.com/actor", None, until)
+            .unwrap();
+
+        assert_eq!(
+            db.instance("example.com").unwrap().is_quarantined(),
+            expected
+        );
+
+        // Re-following doesn't reset the quarantine
+        db.record_instance(
+            "https://example.com/actor",
+            None,
+            Some(Utc::now() + chrono::Duration::seconds(60)),
+        )
+        .unwrap();
+        assert_eq!(
+            db.instance("example.com").unwrap().is_quarantined(),
+            expected
+        );
+
+        std::fs::remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn flags_are_cleared_once_an_instance_verifies_again() {
+        let mut instance = Instance::new("https://example.com/actor".into(), Some("key-1".into()));
+
+        instance.record_verification(Err("connection refused".into()), None);
+        instance.record_verification(Ok(Some("key-1".into())), None);
+
+        assert!(instance.flags.is_empty());
     }
 }
@@ -0,0 +1,138 @@
+//! Counts of the activities received from each origin instance and what became of them,
+//! so that operators can see which subscribers account for most of the relay's traffic.
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// What happened to an activity received from an origin instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The activity was received by one of our inboxes
+    Received,
+    /// The activity was relayed to other subscribers
+    Relayed,
+    /// The activity was accepted but not relayed (by policy, quarantine etc)
+    Filtered,
+    /// The request was refused with an error
+    Rejected,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Received => "received",
+            Self::Relayed => "relayed",
+            Self::Filtered => "filtered",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OriginStats {
+    pub origin: String,
+    pub received: u64,
+    pub relayed: u64,
+    pub filtered: u64,
+    pub rejected: u64,
+}
+
+/// The most origins that stats are kept for at once.
+pub const MAX_ORIGINS: usize = 10_000;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    origins: Mutex<BTreeMap<String, OriginStats>>,
+}
+
+impl Stats {
+    /// Record an event for an origin. Once stats are being kept for [MAX_ORIGINS]
+    /// origins, the one that we have received the fewest activities from is dropped to
+    /// make room for a new one.
+    pub fn record(&self, origin: &str, event: Event) {
+        let mut origins = self.origins.lock().unwrap();
+        if origins.len() >= MAX_ORIGINS && !origins.contains_key(origin) {
+            let quietest = origins
+                .values()
+                .min_by_key(|s| s.received)
+                .map(|s| s.origin.clone());
+            if let Some(quietest) = quietest {
+                origins.remove(&quietest);
+            }
+        }
+
+        let stats = origins
+            .entry(origin.to_owned())
+            .or_insert_with(|| OriginStats {
+                origin: origin.to_owned(),
+                ..Default::default()
+            });
+
+        match event {
+            Event::Received => stats.received += 1,
+            Event::Relayed => stats.relayed += 1,
+            Event::Filtered => stats.filtered += 1,
+            Event::Rejected => stats.rejected += 1,
+        }
+    }
+
+    pub fn origin(&self, origin: &str) -> Option<OriginStats> {
+        self.origins.lock().unwrap().get(origin).cloned()
+    }
+
+    /// Stats for every origin we have received activities from, busiest first.
+    pub fn origins(&self) -> Vec<OriginStats> {
+        let mut origins: Vec<OriginStats> =
+            self.origins.lock().unwrap().values().cloned().collect();
+        origins.sort_by_key(|s| std::cmp::Reverse(s.received));
+
+        origins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_are_ordered_by_activities_received() {
+        let stats = Stats::default();
+        stats.record("quiet.example", Event::Received);
+        stats.record("quiet.example", Event::Relayed);
+        for _ in 0..3 {
+            stats.record("busy.example", Event::Received);
+        }
+        stats.record("busy.example", Event::Rejected);
+
+        let origins: Vec<(String, u64)> = stats
+            .origins()
+            .into_iter()
+            .map(|s| (s.origin, s.received))
+            .collect();
+
+        assert_eq!(
+            origins,
+            vec![("busy.example".into(), 3), ("quiet.example".into(), 1)]
+        );
+        assert_eq!(stats.origin("busy.example").unwrap().rejected, 1);
+    }
+
+    #[test]
+    fn the_quietest_origin_is_dropped_when_full() {
+        let stats = Stats::default();
+        for n in 0..MAX_ORIGINS {
+            stats.record(&format!("{n}.example"), Event::Received);
+            stats.record(&format!("{n}.example"), Event::Received);
+        }
+        stats.record("0.example", Event::Received);
+        stats.record("new.example", Event::Received);
+
+        assert_eq!(stats.origins().len(), MAX_ORIGINS);
+        assert_eq!(stats.origin("new.example").unwrap().received, 1);
+        assert_eq!(stats.origin("0.example").unwrap().received, 3);
+        assert_eq!(
+            stats.origins().iter().filter(|s| s.received == 2).count(),
+            MAX_ORIGINS - 2
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Pluggable persistence for server state.
+use crate::{Error, Result};
+use acidjson::AcidJson;
+use axum::http::StatusCode;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// Somewhere to keep a value that needs to be persisted between updates.
+pub trait Storage<T>: Debug + Send + Sync {
+    /// A copy of the currently stored value
+    fn load(&self) -> T;
+
+    /// Apply an update to the stored value
+    fn update(&self, f: &mut dyn FnMut(&mut T));
+}
+
+/// Storage that only lives as long as the process.
+#[derive(Debug, Default)]
+pub struct MemoryStorage<T> {
+    value: Mutex<T>,
+}
+
+impl<T> Storage<T> for MemoryStorage<T>
+where
+    T: Clone + Debug + Send,
+{
+    fn load(&self) -> T {
+        self.value.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: &mut dyn FnMut(&mut T)) {
+        f(&mut self.value.lock().unwrap());
+    }
+}
+
+/// Storage backed by a JSON file on disk so that it survives restarts.
+#[derive(Debug)]
+pub struct JsonFileStorage<T>
+where
+    T: Serialize + DeserializeOwned + Sync,
+{
+    inner: AcidJson<T>,
+}
+
+impl<T> JsonFileStorage<T>
+where
+    T: Serialize + DeserializeOwned + Sync + Default,
+{
+    pub fn open(dir: &Path, name: &str) -> Result<Self> {
+        Ok(Self {
+            inner: open_json(dir, name)?,
+        })
+    }
+}
+
+impl<T> Storage<T> for JsonFileStorage<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Debug + Send + Sync,
+{
+    fn load(&self) -> T {
+        self.inner.read().clone()
+    }
+
+    fn update(&self, f: &mut dyn FnMut(&mut T)) {
+        f(&mut self.inner.write());
+    }
+}
+
+/// Open (creating if needed) a JSON backed store in the given data directory
+pub fn open_json<T>(dir: &Path, name: &str) -> Result<AcidJson<T>>
+where
+    T: Serialize + DeserializeOwned + Sync + Default,
+{
+    let path = dir.join(name);
+    if std::fs::read(&path).is_err() {
+        let initial = serde_json::to_vec(&T::default()).map_err(|_| Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "unable to create initial state db",
+        })?;
+
+        if std::fs::write(&path, initial).is_err() {
+            return Err(Error::StatusAndMessage {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: "unable to create initial state db",
+            });
+        }
+    }
+
+    AcidJson::open(&path).map_err(|_| Error::StatusAndMessage {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: "unable to open state db",
+    })
+}
+
+/// Somewhere to keep a collection of records that are each written individually, so
+/// that storing or removing one record doesn't mean rewriting all of the others.
+pub trait RecordStorage<T>: Debug + Send + Sync {
+    /// Every stored record
+    fn load_all(&self) -> Vec<T>;
+
+    /// Store a record, replacing any existing record with the same key
+    fn put(&self, key: &str, record: &T);
+
+    fn remove(&self, key: &str);
+}
+
+/// Record storage that only lives as long as the process.
+#[derive(Debug)]
+pub struct MemoryRecordStorage<T> {
+    records: Mutex<BTreeMap<String, T>>,
+}
+
+impl<T> Default for MemoryRecordStorage<T> {
+    fn default() -> Self {
+        Self {
+            records: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<T> RecordStorage<T> for MemoryRecordStorage<T>
+where
+    T: Clone + Debug + Send,
+{
+    fn load_all(&self) -> Vec<T> {
+        self.records.lock().unwrap().values().cloned().collect()
+    }
+
+    fn put(&self, key: &str, record: &T) {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), record.clone());
+    }
+
+    fn remove(&self, key: &str) {
+        self.records.lock().unwrap().remove(key);
+    }
+}
+
+/// Record storage keeping each record as its own JSON file in a directory. Records are
+/// written to a temporary file and renamed into place so that a crash can't leave one
+/// partially written. Keys are used as file names so must be safe to use as such.
+#[derive(Debug)]
+pub struct DirRecordStorage {
+    dir: PathBuf,
+}
+
+impl DirRecordStorage {
+    /// Open (creating if needed) the given directory for storing records
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).map_err(|_| Error::StatusAndMessage {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "unable to create record storage dir",
+        })?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+}
+
+impl<T> RecordStorage<T> for DirRecordStorage
+where
+    T: Serialize + DeserializeOwned,
+{
+    // Records that can't be read are logged and skipped rather than preventing the
+    // others from loading
+    fn load_all(&self) -> Vec<T> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(dir = %self.dir.display(), error = %e, "unable to read record storage dir");
+                return vec![];
+            }
+        };
+
+        entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"))
+            .filter_map(|path| {
+                let record = fs::read(&path)
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+                if record.is_none() {
+                    warn!(path = %path.display(), "skipping unreadable record");
+                }
+
+                record
+            })
+            .collect()
+    }
+
+    fn put(&self, key: &str, record: &T) {
+        let path = self.path(key);
+        let tmp = self.dir.join(format!("{key}.json.tmp"));
+        let res = serde_json::to_vec(record)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| fs::write(&tmp, bytes).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, &path).map_err(|e| e.to_string()));
+
+        if let Err(error) = res {
+            warn!(path = %path.display(), %error, "unable to store record");
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let path = self.path(key);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!(path = %path.display(), error = %e, "unable to remove record");
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env::temp_dir, fs::remove_dir_all};
+    use uuid::Uuid;
+
+    #[test]
+    fn records_are_stored_individually() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let storage = DirRecordStorage::open(&dir).unwrap();
+
+        storage.put("a", &1u32);
+        storage.put("b", &2u32);
+        storage.put("a", &3u32);
+        storage.remove("b");
+        storage.remove("missing");
+
+        let records: Vec<u32> = storage.load_all();
+        assert_eq!(records, vec![3]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test]
+    fn unreadable_records_are_skipped() {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let storage = DirRecordStorage::open(&dir).unwrap();
+
+        storage.put("a", &1u32);
+        fs::write(dir.join("b.json"), "{not json").unwrap();
+
+        let records: Vec<u32> = storage.load_all();
+        assert_eq!(records, vec![1]);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
@@ -0,0 +1,164 @@
+//! Integration with systemd service management.
+//!
+//! When run as a socket activated service we are handed already bound listeners
+//! (`LISTEN_FDS`) and when run as a `Type=notify` service we report readiness and
+//! watchdog keep-alives over `NOTIFY_SOCKET`. Both are no-ops when not running under
+//! systemd. See sd_listen_fds(3) and sd_notify(3) for details of the protocols.
+use std::{
+    env,
+    net::TcpListener,
+    os::unix::{io::FromRawFd, net::UnixDatagram},
+    process,
+    time::Duration,
+};
+use tokio::time::interval;
+use tracing::warn;
+
+// Inherited file descriptors start immediately after stdin, stdout and stderr
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take ownership of the listeners passed to us by systemd, if any.
+///
+/// The environment variables describing the listeners are cleared so that they are
+/// not inherited by child processes, meaning this should only be called once.
+pub fn listeners() -> Vec<TcpListener> {
+    let n_fds = n_listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    );
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + n_fds as i32)
+        .map(|fd| {
+            // SAFETY: systemd guarantees that these descriptors are open sockets that
+            // have been passed to this process, and we only take ownership of them once
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener
+                .set_nonblocking(true)
+                .expect("inherited listener to be usable");
+
+            listener
+        })
+        .collect()
+}
+
+/// Send a state update (e.g. "READY=1") to systemd if we were asked to.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let res = UnixDatagram::unbound().and_then(|sock| {
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            Some(name) => send_abstract(&sock, name, state),
+            None => sock.send_to(state.as_bytes(), path.as_ref()).map(|_| ()),
+        }
+    });
+
+    if let Err(e) = res {
+        warn!(%state, error=%e, "unable to notify systemd");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(sock: &UnixDatagram, name: &str, state: &str) -> std::io::Result<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    sock.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &str, _: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Send keep-alives to the systemd watchdog (if it is enabled for the service) at half
+/// of the configured timeout.
+pub async fn run_watchdog() {
+    let timeout = watchdog_timeout(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    );
+
+    if let Some(timeout) = timeout {
+        let mut ticker = interval(timeout / 2);
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    }
+}
+
+// Listeners are only for us if LISTEN_PID matches our pid
+fn n_listen_fds(pid: Option<&str>, fds: Option<&str>, our_pid: u32) -> u32 {
+    match (pid.and_then(|p| p.parse::<u32>().ok()), fds) {
+        (Some(pid), Some(fds)) if pid == our_pid => fds.parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+// The watchdog applies to us if WATCHDOG_PID is unset or matches our pid
+fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>, our_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>() != Ok(our_pid) {
+            return None;
+        }
+    }
+
+    match usec?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    #[test_case(Some("42"), Some("2"), 2; "for us")]
+    #[test_case(Some("7"), Some("2"), 0; "for another process")]
+    #[test_case(None, Some("2"), 0; "missing pid")]
+    #[test_case(Some("42"), None, 0; "missing fds")]
+    #[test_case(Some("42"), Some("nope"), 0; "invalid fds")]
+    #[test]
+    fn n_listen_fds_works(pid: Option<&str>, fds: Option<&str>, expected: u32) {
+        assert_eq!(n_listen_fds(pid, fds, 42), expected);
+    }
+
+    #[test_case(Some("30000000"), None, Some(30); "no pid")]
+    #[test_case(Some("30000000"), Some("42"), Some(30); "for us")]
+    #[test_case(Some("30000000"), Some("7"), None; "for another process")]
+    #[test_case(Some("0"), None, None; "disabled")]
+    #[test_case(None, None, None; "not set")]
+    #[test]
+    fn watchdog_timeout_works(usec: Option<&str>, pid: Option<&str>, expected: Option<u64>) {
+        assert_eq!(
+            watchdog_timeout(usec, pid, 42),
+            expected.map(Duration::from_secs)
+        );
+    }
+
+    #[test]
+    fn notify_sends_the_state_to_the_socket() {
+        let mut path = std::env::temp_dir();
+        path.push(uuid::Uuid::new_v4().to_string());
+        let sock = UnixDatagram::bind(&path).unwrap();
+
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0; 16];
+        let n = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+//! Background tasks run alongside the server
+use crate::{
+    blocklist::parse_feed,
+    routes::inbox::relay_held,
+    signature::key_fingerprint,
+    state::{Db, State},
+    timeseries::Counts,
+    upstreams,
+};
+use chrono::Utc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::interval;
+use tracing::{debug, info, warn};
+
+/// Periodically refetch the actor and nodeinfo of every subscribed instance,
+/// flagging any that are no longer reachable or that are presenting a new key.
+pub async fn reverify_instances(state: Arc<State>) {
+    let mut ticker = interval(Duration::from_secs(state.cfg.reverify_interval_secs));
+    // The first tick completes immediately and we don't want to hammer every
+    // subscriber each time the server restarts.
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        for relay in state.relay_actors() {
+            let instances = relay.db.instances();
+            info!(
+                relay = relay.name,
+                n_instances = instances.len(),
+                "re-verifying subscribed instances"
+            );
+
+            for (host, instance) in instances {
+                reverify_instance(&state, relay.db, &host, &instance.actor).await;
+            }
+        }
+    }
+}
+
+#[tracing::instrument(level = "debug", skip(state, db))]
+async fn reverify_instance(state: &State, db: &Db, host: &str, actor_id: &str) {
+    let actor = match state.client.get_actor(actor_id).await {
+        Ok(actor) => Ok(actor.key().ok().and_then(|k| key_fingerprint(&k).ok())),
+        Err(e) => {
+            warn!(%host, error=%e, "subscribed instance is unreachable");
+            Err(e.to_string())
+        }
+    };
+
+    let nodeinfo = match state.client.get_nodeinfo(host).await {
+        Ok(info) => Some(info),
+        Err(e) => {
+            debug!(%host, error=%e, "unable to fetch nodeinfo");
+            None
+        }
+    };
+
+    db.update_instance(host, |instance| {
+        instance.record_verification(actor, nodeinfo);
+        if !instance.flags.is_empty() {
+            warn!(%host, flags=?instance.flags, "flagging subscribed instance");
+        }
+    });
+}
+
+/// Periodically refetch each subscribed blocklist feed, replacing the blocks it
+/// previously contributed. If a feed can't be fetched we keep its last known blocks.
+pub async fn refresh_blocklists(state: Arc<State>) {
+    let interval_secs = state.cfg.blocklists.refresh_interval_secs;
+    let mut ticker = interval(Duration::from_secs(interval_secs));
+
+    loop {
+        ticker.tick().await;
+
+        for feed in state.cfg.blocklists.feeds.iter() {
+            match state.client.get_blocklist(feed).await {
+                Ok(body) => {
+                    let domains = parse_feed(&body);
+                    let n_domains = domains.len();
+                    match state.blocklist.set_source(feed, domains) {
+                        Ok(()) => info!(%feed, n_domains, "refreshed blocklist"),
+                        Err(e) => warn!(%feed, error=%e, "keeping previous blocks from blocklist"),
+                    }
+                }
+                Err(e) => warn!(%feed, error=%e, "unable to refresh blocklist"),
+            }
+        }
+    }
+}
+
+// Frequent enough that a restart loses little, with the rollups themselves being hourly
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Periodically add the increase in the key relay counters to the hourly rollups
+/// stored in the [Db].
+pub async fn roll_up_metrics(state: Arc<State>) {
+    let mut ticker = interval(ROLLUP_INTERVAL);
+    let mut last = Counts::default();
+
+    loop {
+        ticker.tick().await;
+
+        let current = Counts::from_metrics(&state.metrics);
+        state.db.record_rollup(Utc::now(), &current.since(&last));
+        last = current;
+    }
+}
+
+// How long changes to the stores kept in memory may go unwritten
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Periodically write the stores that are kept in memory to disk.
+pub async fn flush_stores(state: Arc<State>) {
+    let mut ticker = interval(FLUSH_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+        state.flush();
+    }
+}
+
+// How often Follows are re-sent to upstream relays that haven't accepted them yet
+const UPSTREAM_FOLLOW_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Follow the configured upstream relays (and unfollow removed ones) on startup,
+/// retrying periodically for any that haven't accepted.
+pub async fn follow_upstreams(state: Arc<State>) {
+    let mut ticker = interval(UPSTREAM_FOLLOW_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+        upstreams::follow_upstreams(&state).await;
+    }
+}
+
+/// Periodically log how many log lines have been dropped by sampling.
+pub async fn summarize_sampled_logs(state: Arc<State>) {
+    let secs = state.cfg.logging.sampling.summary_interval_secs.max(1);
+    let mut ticker = interval(Duration::from_secs(secs));
+
+    loop {
+        ticker.tick().await;
+        state.log_filter.summarize_sampling();
+    }
+}
+
+/// Relay the Announces held back from throttled origins once their throttle expires,
+/// and forget the counts of origins that have gone quiet.
+pub async fn release_held_announces(state: Arc<State>) {
+    let mut ticker = interval(Duration::from_secs(state.cfg.flood.window_secs.max(1)));
+
+    loop {
+        ticker.tick().await;
+
+        let now = Instant::now();
+        state.flood.prune(&state.cfg.flood, now);
+        for held in state.flood.release(now) {
+            let actor_id = held.actor_id.clone();
+            if let Err(e) = relay_held(held, &state).await {
+                warn!(actor=%actor_id, error=%e, "unable to relay held announce");
+            }
+        }
+    }
+}
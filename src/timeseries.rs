@@ -0,0 +1,175 @@
+//! Hourly rollups of the key relay counters, kept in the [Db](crate::state::Db) so that
+//! the admin dashboard can chart recent activity without an external metrics stack.
+//!
+//! The in-process [Metrics] only hold running totals since the server started, so the
+//! rollup task periodically snapshots them and adds the increase since the previous
+//! snapshot to the bucket for the current hour.
+use crate::metrics::Metrics;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How long hourly rollups are kept for, which is also the longest range that can be
+/// requested.
+pub const RETENTION_DAYS: i64 = 90;
+
+/// Rollups keyed by the start of the hour they cover.
+pub type Rollups = BTreeMap<DateTime<Utc>, Counts>;
+
+/// The counters tracked over time.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Counts {
+    /// Activities relayed or forwarded to subscribers
+    pub relayed: u64,
+    /// Deliveries that were given up on after exhausting their retries
+    pub delivery_failures: u64,
+    /// Activities received by our inboxes, by type
+    pub received: BTreeMap<String, u64>,
+}
+
+impl Counts {
+    /// The current totals of the tracked counters.
+    pub fn from_metrics(metrics: &Metrics) -> Self {
+        let events = metrics.sum_by("actiserve_origin_activities_total", "event");
+        let deliveries = metrics.sum_by("actiserve_deliveries_total", "outcome");
+
+        Self {
+            relayed: events.get("relayed").copied().unwrap_or_default(),
+            delivery_failures: deliveries.get("failed").copied().unwrap_or_default(),
+            received: metrics.sum_by("actiserve_inbox_activities_total", "type"),
+        }
+    }
+
+    /// The increase in each counter since an earlier snapshot.
+    pub fn since(&self, earlier: &Self) -> Self {
+        let received = self
+            .received
+            .iter()
+            .map(|(ty, &n)| {
+                let before = earlier.received.get(ty).copied().unwrap_or_default();
+                (ty.clone(), n.saturating_sub(before))
+            })
+            .filter(|&(_, n)| n > 0)
+            .collect();
+
+        Self {
+            relayed: self.relayed.saturating_sub(earlier.relayed),
+            delivery_failures: self
+                .delivery_failures
+                .saturating_sub(earlier.delivery_failures),
+            received,
+        }
+    }
+
+    pub fn add(&mut self, other: &Self) {
+        self.relayed += other.relayed;
+        self.delivery_failures += other.delivery_failures;
+        for (ty, n) in other.received.iter() {
+            *self.received.entry(ty.clone()).or_default() += n;
+        }
+    }
+}
+
+/// The start of the hour containing the given time.
+pub fn hour_of(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(Duration::hours(1)).unwrap_or(at)
+}
+
+/// Add counts to the rollup for the hour containing `at`, dropping any rollups that
+/// have passed the retention period.
+pub fn record(rollups: &mut Rollups, at: DateTime<Utc>, counts: &Counts) {
+    rollups.entry(hour_of(at)).or_default().add(counts);
+
+    let cutoff = at - Duration::days(RETENTION_DAYS);
+    rollups.retain(|hour, _| *hour >= cutoff);
+}
+
+/// Parse a range such as `7d` or `12h`, which must be within the retention period.
+pub fn parse_range(range: &str) -> Option<Duration> {
+    let parse = |n: &str| n.parse::<i64>().ok().filter(|&n| n > 0);
+    let hours = match (range.strip_suffix('h'), range.strip_suffix('d')) {
+        (Some(n), _) => parse(n)?,
+        (_, Some(n)) => parse(n)?.checked_mul(24)?,
+        _ => return None,
+    };
+
+    Some(Duration::hours(hours.min(RETENTION_DAYS * 24)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_test_case::test_case;
+
+    fn counts(relayed: u64, creates: u64) -> Counts {
+        Counts {
+            relayed,
+            delivery_failures: 0,
+            received: [("Create".to_owned(), creates)].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn counts_are_taken_from_metrics() {
+        let metrics = Metrics::default();
+        for (instance, event) in [("a.example", "relayed"), ("b.example", "relayed")] {
+            metrics.incr(
+                "actiserve_origin_activities_total",
+                &[("instance", instance), ("event", event)],
+            );
+        }
+        metrics.incr(
+            "actiserve_deliveries_total",
+            &[("instance", "a.example"), ("outcome", "delivered")],
+        );
+        metrics.incr("actiserve_inbox_activities_total", &[("type", "Create")]);
+
+        assert_eq!(Counts::from_metrics(&metrics), counts(2, 1));
+    }
+
+    #[test]
+    fn rollups_accumulate_increases_per_hour() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let mut rollups = Rollups::new();
+
+        record(&mut rollups, at("2023-01-01T10:05:00Z"), &counts(1, 2));
+        record(&mut rollups, at("2023-01-01T10:55:00Z"), &counts(2, 0));
+        record(&mut rollups, at("2023-01-01T11:00:00Z"), &counts(3, 1));
+
+        assert_eq!(
+            rollups,
+            [
+                (at("2023-01-01T10:00:00Z"), counts(3, 2)),
+                (at("2023-01-01T11:00:00Z"), counts(3, 1)),
+            ]
+            .into_iter()
+            .collect::<Rollups>()
+        );
+
+        record(&mut rollups, at("2023-06-01T00:00:00Z"), &counts(1, 1));
+        assert_eq!(rollups.len(), 1);
+    }
+
+    #[test]
+    fn increases_are_relative_to_the_previous_snapshot() {
+        assert_eq!(
+            counts(5, 3).since(&counts(2, 3)),
+            Counts {
+                relayed: 3,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test_case("7d", Some(Duration::days(7)); "days")]
+    #[test_case("12h", Some(Duration::hours(12)); "hours")]
+    #[test_case("365d", Some(Duration::days(RETENTION_DAYS)); "capped to retention")]
+    #[test_case("0d", None; "empty")]
+    #[test_case("7w", None; "unknown unit")]
+    #[test_case("", None; "missing")]
+    #[test]
+    fn ranges_are_parsed(range: &str, expected: Option<Duration>) {
+        assert_eq!(parse_range(range), expected);
+    }
+}
@@ -0,0 +1,217 @@
+//! A record of the types of activity that peers send us but that we don't recognise.
+//!
+//! Unrecognised activities are ignored by default. With `inbox.unrecognizedActivities`
+//! set to record (or reject) them, a count per type and origin is kept along with the
+//! most recent payload so that operators can see what is being sent that we don't
+//! handle.
+//!
+//! Records are kept in memory and written to storage by [Unrecognized::flush], which is
+//! run periodically, so that a peer sending a stream of them doesn't mean rewriting the
+//! whole record for each one.
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Mutex};
+
+/// The number of distinct types and origins that are kept for the admin API
+pub const MAX_UNRECOGNIZED: usize = 500;
+
+/// Activity types longer than this are cut down before being recorded
+const MAX_TYPE_CHARS: usize = 100;
+
+/// Sample payloads longer than this are cut down before being recorded
+const MAX_SAMPLE_CHARS: usize = 4096;
+
+/// Activities of a single unrecognised type received from a single origin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnrecognizedActivity {
+    /// The `type` of the activities
+    pub activity_type: String,
+    /// The instance that sent them
+    pub origin: String,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// The most recently received activity as JSON, cut down to a bounded length
+    pub sample: String,
+}
+
+impl UnrecognizedActivity {
+    pub fn new(origin: &str, activity: &Value) -> Self {
+        let now = Utc::now();
+
+        Self {
+            activity_type: activity_type(activity),
+            origin: origin.to_owned(),
+            count: 1,
+            first_seen: now,
+            last_seen: now,
+            sample: truncate(&activity.to_string(), MAX_SAMPLE_CHARS),
+        }
+    }
+
+    /// The key that activities are grouped under.
+    pub fn key(&self) -> String {
+        format!("{} {}", self.origin, self.activity_type)
+    }
+
+    /// Count another activity of the same type from the same origin.
+    pub fn merge(&mut self, other: Self) {
+        self.count += other.count;
+        self.last_seen = other.last_seen;
+        self.sample = other.sample;
+    }
+}
+
+/// map of origin and type to the activities recorded for them
+pub type Recorded = HashMap<String, UnrecognizedActivity>;
+
+#[derive(Debug, Default)]
+struct Inner {
+    recorded: Recorded,
+    // whether there are changes that haven't been flushed to storage
+    dirty: bool,
+}
+
+/// The unrecognised activities that have been recorded.
+#[derive(Debug)]
+pub struct Unrecognized {
+    storage: Box<dyn Storage<Recorded>>,
+    inner: Mutex<Inner>,
+}
+
+impl Unrecognized {
+    pub fn new(storage: Box<dyn Storage<Recorded>>) -> Self {
+        let recorded = storage.load();
+
+        Self {
+            storage,
+            inner: Mutex::new(Inner {
+                recorded,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Count an activity of a type that we don't recognise, dropping the least recently
+    /// seen type and origin once we have more than [MAX_UNRECOGNIZED].
+    pub fn record(&self, unrecognized: UnrecognizedActivity) {
+        let mut inner = self.inner.lock().unwrap();
+        let recorded = &mut inner.recorded;
+        match recorded.get_mut(&unrecognized.key()) {
+            Some(existing) => existing.merge(unrecognized),
+            None => {
+                recorded.insert(unrecognized.key(), unrecognized);
+            }
+        }
+
+        if recorded.len() > MAX_UNRECOGNIZED {
+            let oldest = recorded
+                .values()
+                .min_by_key(|u| u.last_seen)
+                .map(|u| u.key());
+            if let Some(key) = oldest {
+                recorded.remove(&key);
+            }
+        }
+        inner.dirty = true;
+    }
+
+    /// Activities of types that we don't recognise, most recently seen first.
+    pub fn list(&self) -> Vec<UnrecognizedActivity> {
+        let mut recorded: Vec<UnrecognizedActivity> = self
+            .inner
+            .lock()
+            .unwrap()
+            .recorded
+            .values()
+            .cloned()
+            .collect();
+        recorded.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+
+        recorded
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.recorded.clear();
+        inner.dirty = true;
+    }
+
+    /// Write anything recorded since the last flush to storage.
+    pub fn flush(&self) {
+        let recorded = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            inner.recorded.clone()
+        };
+
+        self.storage
+            .update(&mut |stored| *stored = recorded.clone());
+    }
+}
+
+// Types are usually a string but can be anything (including missing)
+fn activity_type(activity: &Value) -> String {
+    let ty = match &activity["type"] {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+
+    truncate(&ty, MAX_TYPE_CHARS)
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    match s.char_indices().nth(max_chars) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    #[test_case(json!({"type": "EmojiReact"}), "EmojiReact"; "string")]
+    #[test_case(json!({"type": ["Create", "Note"]}), r#"["Create","Note"]"#; "list")]
+    #[test_case(json!({}), "null"; "missing")]
+    #[test_case(json!({"type": "X".repeat(200)}), &format!("{}…", "X".repeat(100)); "long")]
+    #[test]
+    fn activity_types_are_recorded_as_strings(activity: Value, expected: &str) {
+        assert_eq!(
+            UnrecognizedActivity::new("a.example", &activity).activity_type,
+            expected
+        );
+    }
+
+    #[test]
+    fn records_are_only_stored_when_flushed() {
+        let unrecognized = Unrecognized::new(Box::<MemoryStorage<_>>::default());
+        unrecognized.record(UnrecognizedActivity::new(
+            "a.example",
+            &json!({"type": "EmojiReact"}),
+        ));
+
+        assert_eq!(unrecognized.list().len(), 1);
+        assert!(unrecognized.storage.load().is_empty());
+
+        unrecognized.flush();
+        assert_eq!(unrecognized.storage.load().len(), 1);
+    }
+
+    #[test]
+    fn samples_are_cut_down() {
+        let activity = json!({"type": "Big", "content": "x".repeat(10_000)});
+        let unrecognized = UnrecognizedActivity::new("a.example", &activity);
+
+        assert_eq!(unrecognized.sample.chars().count(), MAX_SAMPLE_CHARS + 1);
+    }
+}
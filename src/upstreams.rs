@@ -0,0 +1,267 @@
+//! Aggregating other relays.
+//!
+//! When upstream relays are configured our main relay actor follows each of them, and
+//! the activities they relay to us are passed through the inbox pipeline like those of
+//! any subscriber. Local blocklists and policies are applied (including to the origin
+//! of each relayed object, not just to the upstream relay) before anything is relayed
+//! on to our own subscribers, making the relay a filtering proxy between large relays
+//! and smaller instances.
+use crate::{actors::DEFAULT_ACTOR, state::State, util::first_id};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UpstreamStatus {
+    /// We have sent a Follow and are waiting for a response
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// Our subscription to an upstream relay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Upstream {
+    /// The upstream relay's actor
+    pub actor: String,
+    pub status: UpstreamStatus,
+    /// When we last sent the upstream a Follow
+    pub followed_at: DateTime<Utc>,
+    /// The id of the Follow we last sent, which the upstream's response has to refer to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_id: Option<String>,
+    /// When the upstream last responded to a Follow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub responded_at: Option<DateTime<Utc>>,
+}
+
+/// Whether the actor is one of the configured upstream relays.
+pub fn is_upstream(actor_id: &str, state: &State) -> bool {
+    state.cfg.upstreams.iter().any(|u| u == actor_id)
+}
+
+/// The actor that an activity originates from. Activities relayed to us by an upstream
+/// relay are attributed to the author of what is being relayed (or to the object itself
+/// when it is only referenced by id), so that policies, flood limits and stats apply to
+/// the instance it came from rather than to the upstream.
+pub fn origin_actor<'a>(actor_id: &'a str, activity: &'a Value, state: &State) -> &'a str {
+    if !is_upstream(actor_id, state) {
+        return actor_id;
+    }
+
+    let object = &activity["object"];
+    first_id(&object["actor"])
+        .or_else(|| first_id(&object["attributedTo"]))
+        .or_else(|| first_id(object))
+        .unwrap_or(actor_id)
+}
+
+/// Follow each configured upstream relay that hasn't yet accepted a Follow from us.
+/// Upstreams that have rejected us are retried, as they may since have been configured
+/// to allow us.
+pub async fn follow_upstreams(state: &State) {
+    unfollow_removed_upstreams(state).await;
+
+    for actor in state.cfg.upstreams.iter() {
+        let accepted = state
+            .db
+            .upstream(actor)
+            .map(|u| u.status == UpstreamStatus::Accepted)
+            .unwrap_or(false);
+        if accepted {
+            continue;
+        }
+
+        match state.client.follow_relay(DEFAULT_ACTOR, actor).await {
+            Ok((follow, follow_id)) => {
+                info!(%actor, "following upstream relay");
+                state.deliver(vec![follow]);
+                state.db.set_upstream(Upstream {
+                    actor: actor.clone(),
+                    status: UpstreamStatus::Pending,
+                    followed_at: Utc::now(),
+                    follow_id: Some(follow_id),
+                    responded_at: None,
+                });
+            }
+            Err(e) => warn!(%actor, error=%e, "unable to follow upstream relay"),
+        }
+    }
+}
+
+/// Undo our Follow of any upstream relay that has been removed from the config so that
+/// it stops relaying to us. Upstreams that can't be reached are tried again next time.
+pub async fn unfollow_removed_upstreams(state: &State) {
+    for upstream in state.db.upstreams() {
+        if is_upstream(&upstream.actor, state) {
+            continue;
+        }
+
+        let actor = &upstream.actor;
+        let follow_id = upstream.follow_id.as_deref();
+        match state
+            .client
+            .unfollow_relay(DEFAULT_ACTOR, actor, follow_id)
+            .await
+        {
+            Ok(undo) => {
+                info!(%actor, "unfollowing removed upstream relay");
+                state.deliver(vec![undo]);
+                state.db.remove_upstream(actor);
+            }
+            Err(e) => warn!(%actor, error=%e, "unable to unfollow removed upstream relay"),
+        }
+    }
+}
+
+/// Record an upstream relay accepting or rejecting our Follow. Responses are ignored
+/// unless their object is the Follow that we most recently sent.
+pub fn record_response(actor_id: &str, response: &Value, accepted: bool, state: &State) {
+    let status = if accepted {
+        UpstreamStatus::Accepted
+    } else {
+        UpstreamStatus::Rejected
+    };
+    let follow_id = first_id(&response["object"]);
+
+    match state.db.upstream(actor_id) {
+        Some(upstream) if follow_id.is_none() || upstream.follow_id.as_deref() != follow_id => {
+            warn!(actor=%actor_id, ?follow_id, "response from upstream relay to an unknown follow")
+        }
+        Some(mut upstream) => {
+            info!(actor=%actor_id, ?status, "upstream relay responded to our follow");
+            upstream.status = status;
+            upstream.responded_at = Some(Utc::now());
+            state.db.set_upstream(upstream);
+        }
+        None => warn!(actor=%actor_id, "response from upstream relay that we haven't followed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ProxyConfig, jsonld::PUBLIC, state::Db};
+    use axum::{routing::get, Json, Router};
+    use serde_json::json;
+    use simple_test_case::test_case;
+    use std::{env::temp_dir, fs::remove_dir_all, net::TcpListener};
+    use uuid::Uuid;
+
+    const UPSTREAM: &str = "https://big-relay.example/actor";
+
+    const FOLLOW_ID: &str = "https://relay.example/activities/1";
+
+    fn test_state(dir: &std::path::Path) -> State {
+        let db = Db::new(dir.to_owned()).expect("unable to create database");
+        let mut state = State::new_with_test_key(db);
+        state.cfg.upstreams = vec![UPSTREAM.into()];
+        state.db.set_upstream(Upstream {
+            actor: UPSTREAM.into(),
+            status: UpstreamStatus::Pending,
+            followed_at: Utc::now(),
+            follow_id: Some(FOLLOW_ID.into()),
+            responded_at: None,
+        });
+
+        state
+    }
+
+    #[test_case(true, json!(FOLLOW_ID), UpstreamStatus::Accepted; "accepted")]
+    #[test_case(false, json!({ "id": FOLLOW_ID, "type": "Follow" }), UpstreamStatus::Rejected; "rejected")]
+    #[test_case(true, json!("https://relay.example/activities/2"), UpstreamStatus::Pending; "other follow")]
+    #[test_case(true, Value::Null, UpstreamStatus::Pending; "no object")]
+    #[test]
+    fn responses_to_our_follows_are_recorded(
+        accepted: bool,
+        object: Value,
+        expected: UpstreamStatus,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let state = test_state(&dir);
+        let response = json!({ "type": "Accept", "actor": UPSTREAM, "object": object });
+
+        record_response(UPSTREAM, &response, accepted, &state);
+        record_response("https://unknown.example/actor", &response, accepted, &state);
+
+        assert!(is_upstream(UPSTREAM, &state));
+        assert_eq!(
+            state.db.upstream(UPSTREAM).map(|u| u.status),
+            Some(expected)
+        );
+        assert_eq!(state.db.upstream("https://unknown.example/actor"), None);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[tokio::test]
+    async fn removed_upstreams_are_unfollowed() {
+        const REMOVED: &str = "http://old-relay.onion/actor";
+
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let mut state = test_state(&dir);
+        state.db.set_upstream(Upstream {
+            actor: REMOVED.into(),
+            status: UpstreamStatus::Accepted,
+            followed_at: Utc::now(),
+            follow_id: Some(FOLLOW_ID.into()),
+            responded_at: Some(Utc::now()),
+        });
+
+        // The removed upstream is served over plain HTTP by proxying .onion hosts to it
+        let actor = json!({
+            "id": REMOVED,
+            "type": "Application",
+            "inbox": "http://old-relay.onion/inbox",
+        });
+        let app = Router::new().route("/actor", get(move || async move { Json(actor) }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let proxy = ProxyConfig {
+            onion_url: Some(format!("http://{addr}")),
+            ..Default::default()
+        };
+        state.client.configure(&proxy, &Default::default()).unwrap();
+
+        unfollow_removed_upstreams(&state).await;
+
+        let queued = state.deliveries.next_ready().unwrap();
+        let undo: Value = serde_json::from_slice(queued.delivery.body.bytes()).unwrap();
+        assert_eq!(queued.delivery.inbox, "http://old-relay.onion/inbox");
+        assert_eq!(undo["type"], "Undo");
+        assert_eq!(undo["object"]["id"], FOLLOW_ID);
+        assert_eq!(undo["object"]["type"], "Follow");
+        assert_eq!(undo["object"]["object"], PUBLIC);
+        assert!(state.deliveries.next_ready().is_none());
+        assert_eq!(state.db.upstream(REMOVED), None);
+        assert!(state.db.upstream(UPSTREAM).is_some());
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+
+    #[test_case(UPSTREAM, json!({ "object": "https://a.example/notes/1" }), "https://a.example/notes/1"; "object by id")]
+    #[test_case(UPSTREAM, json!({ "object": { "id": "https://a.example/notes/1", "attributedTo": "https://a.example/users/alice" } }), "https://a.example/users/alice"; "embedded object")]
+    #[test_case(UPSTREAM, json!({ "object": { "type": "Create", "actor": "https://a.example/users/alice" } }), "https://a.example/users/alice"; "embedded activity")]
+    #[test_case("https://b.example/users/bob", json!({ "object": "https://a.example/notes/1" }), "https://b.example/users/bob"; "not an upstream")]
+    #[test]
+    fn upstream_activities_are_attributed_to_their_origin(
+        actor_id: &str,
+        activity: Value,
+        expected: &str,
+    ) {
+        let mut dir = temp_dir();
+        dir.push(Uuid::new_v4().to_string());
+        let state = test_state(&dir);
+
+        assert_eq!(origin_actor(actor_id, &activity, &state), expected);
+        remove_dir_all(dir).expect("to be able to clear up our temp directory");
+    }
+}
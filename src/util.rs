@@ -2,6 +2,12 @@
 use crate::{Error, Result};
 use axum::http::{HeaderValue, StatusCode, Uri};
 use serde_json::Value;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+};
 
 #[macro_export]
 macro_rules! map {
@@ -39,7 +45,63 @@ pub fn host_from_uri(uri: &str) -> Result<String> {
     Ok(host.to_owned())
 }
 
-pub fn id_from_json(val: &Value) -> String {
+/// The registrable domain (one label below the public suffix) of a host, so that for
+/// example `social.example.co.uk` and `example.co.uk` can be treated as the same site.
+/// Hosts without a known public suffix (such as IP addresses) are returned as is.
+pub fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+
+    match psl::domain_str(&host) {
+        Some(domain) => domain.to_owned(),
+        None => host,
+    }
+}
+
+/// Whether the host is part of one of the given overlay network TLDs (e.g. "onion").
+pub fn is_overlay_host(host: &str, tlds: &[&str]) -> bool {
+    let host = host.trim_end_matches('.');
+
+    tlds.iter().any(|tld| {
+        host.strip_suffix(tld)
+            .map(|rest| rest.ends_with('.'))
+            .unwrap_or(false)
+    })
+}
+
+/// The id of a value referring to an actor or object, which may be given as a bare id,
+/// an embedded object or (as some Misskey forks do even for functional properties such
+/// as actor) an array of either, in which case the first is used.
+pub fn first_id(val: &Value) -> Option<&str> {
+    match val {
+        Value::String(id) => Some(id),
+        Value::Object(obj) => obj.get("id").and_then(|id| id.as_str()),
+        Value::Array(arr) => arr.first().and_then(first_id),
+        _ => None,
+    }
+}
+
+/// Normalize an actor or object id for comparison: the scheme and host are case
+/// insensitive and a trailing slash is ignored. Ids that aren't URIs are left as is.
+pub fn normalize_id(id: &str) -> String {
+    let id = id.trim().trim_end_matches('/');
+    match id.split_once("://") {
+        Some((scheme, rest)) => {
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            format!(
+                "{}://{}{path}",
+                scheme.to_ascii_lowercase(),
+                authority.to_ascii_lowercase()
+            )
+        }
+        None => id.to_owned(),
+    }
+}
+
+/// The id of the object of an activity, whether it is embedded or referenced by id.
+pub fn id_from_json(val: &Value) -> Result<String> {
     let obj = &val["object"];
 
     let id = match obj.get("id") {
@@ -47,7 +109,10 @@ pub fn id_from_json(val: &Value) -> String {
         None => obj.as_str(),
     };
 
-    id.unwrap().to_owned()
+    id.map(|id| id.to_owned()).ok_or(Error::StatusAndMessage {
+        status: StatusCode::BAD_REQUEST,
+        message: "activity has no object id",
+    })
 }
 
 // We should never be trying to construct an invalid header value in sign_request
@@ -59,9 +124,24 @@ pub fn header_val(s: &str) -> Result<HeaderValue> {
     })
 }
 
+/// Write a file that only its owner can read, such as a private key. The permissions are
+/// set when the file is created so that its contents are never readable by anyone else.
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    opts.open(path)?.write_all(contents.as_ref())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
     use simple_test_case::test_case;
 
     #[test_case("https://example.com/foo/bar"; "https")]
@@ -74,6 +154,85 @@ mod tests {
         assert_eq!(res.as_deref(), Ok("example.com"));
     }
 
+    #[test_case("example.com", "example.com"; "registrable domain")]
+    #[test_case("social.example.com", "example.com"; "subdomain")]
+    #[test_case("a.b.example.co.uk", "example.co.uk"; "multi label suffix")]
+    #[test_case("Social.Example.COM.", "example.com"; "normalized")]
+    #[test_case("alice.github.io", "alice.github.io"; "private suffix")]
+    #[test_case("127.0.0.1", "127.0.0.1"; "ip address")]
+    #[test_case("localhost", "localhost"; "single label")]
+    #[test]
+    fn registrable_domain_works(host: &str, expected: &str) {
+        assert_eq!(registrable_domain(host), expected);
+    }
+
+    #[test_case("https://a.example/users/alice"; "already normalized")]
+    #[test_case("https://A.Example/users/alice"; "host case")]
+    #[test_case("HTTPS://a.example/users/alice"; "scheme case")]
+    #[test_case("https://a.example/users/alice/"; "trailing slash")]
+    #[test]
+    fn normalize_id_works(id: &str) {
+        assert_eq!(normalize_id(id), "https://a.example/users/alice");
+    }
+
+    #[test]
+    fn normalize_id_preserves_path_case() {
+        assert_eq!(
+            normalize_id("https://a.example/users/Alice"),
+            "https://a.example/users/Alice"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_only_readable_by_their_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut path = std::env::temp_dir();
+        path.push(uuid::Uuid::new_v4().to_string());
+
+        write_private(&path, "secret").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+        std::fs::remove_file(path).expect("to be able to clear up our temp file");
+    }
+
+    #[test_case("abc.onion", true; "onion")]
+    #[test_case("abc.onion.", true; "fully qualified onion")]
+    #[test_case("abc.i2p", true; "i2p")]
+    #[test_case("onion", false; "bare tld")]
+    #[test_case("onion.example", false; "onion subdomain")]
+    #[test_case("abconion", false; "suffix without dot")]
+    #[test]
+    fn is_overlay_host_works(host: &str, expected: bool) {
+        assert_eq!(is_overlay_host(host, &["onion", "i2p"]), expected);
+    }
+
+    #[test_case(json!("https://a.example/users/1"), Some("https://a.example/users/1"); "bare id")]
+    #[test_case(json!({"id": "https://a.example/users/1"}), Some("https://a.example/users/1"); "embedded")]
+    #[test_case(json!(["https://a.example/users/1", "https://a.example/users/2"]), Some("https://a.example/users/1"); "array")]
+    #[test_case(json!([{"id": "https://a.example/users/1"}]), Some("https://a.example/users/1"); "array of embedded")]
+    #[test_case(json!([]), None; "empty array")]
+    #[test_case(json!(null), None; "missing")]
+    #[test]
+    fn first_id_works(val: Value, expected: Option<&str>) {
+        assert_eq!(first_id(&val), expected);
+    }
+
+    #[test_case(json!({"object": "https://a.example/notes/1"}), true; "bare id")]
+    #[test_case(json!({"object": {"id": "https://a.example/notes/1"}}), true; "embedded")]
+    #[test_case(json!({"object": {"type": "Note"}}), false; "embedded without id")]
+    #[test_case(json!({"object": ["https://a.example/notes/1"]}), false; "array")]
+    #[test_case(json!({}), false; "missing")]
+    #[test]
+    fn id_from_json_works(val: Value, ok: bool) {
+        let res = id_from_json(&val);
+
+        assert_eq!(res.is_ok(), ok, "{res:?}");
+    }
+
     #[test]
     fn host_from_uri_rejects_an_invalid_uri() {
         let uri = "example.com/foo/bar";
@@ -0,0 +1,174 @@
+//! Who an activity is visible to, as determined from its addressing.
+//!
+//! Instances should only ever deliver public posts to a relay, but a misconfigured or
+//! buggy instance could deliver followers-only posts or direct messages to us as well.
+//! Anything we relay or forward is effectively made public, so those have to be caught
+//! before they go any further.
+use crate::jsonld::PUBLIC;
+use serde_json::Value;
+use std::fmt;
+
+const RECIPIENT_FIELDS: [&str; 5] = ["to", "cc", "bto", "bcc", "audience"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Addressed to the public collection
+    Public,
+    /// Copied to the public collection, so public but not listed on public timelines
+    Unlisted,
+    /// Addressed to the author's followers
+    FollowersOnly,
+    /// Addressed to a specific set of recipients
+    Limited,
+    /// Addressed only to the actors mentioned in it, or flagged as a direct message
+    Direct,
+}
+
+impl Visibility {
+    /// The visibility of an activity, taking into account the addressing of its object
+    /// if it is embedded.
+    pub fn of(activity: &Value) -> Self {
+        let object = &activity["object"];
+        let nodes = [activity, object];
+
+        if nodes.iter().any(|n| n["directMessage"] == true) {
+            return Self::Direct;
+        }
+        if nodes.iter().any(|n| addresses_public(n, &["to"])) {
+            return Self::Public;
+        }
+        if nodes
+            .iter()
+            .any(|n| addresses_public(n, &["cc", "bto", "bcc", "audience"]))
+        {
+            return Self::Unlisted;
+        }
+
+        let recipients: Vec<&str> = nodes.iter().flat_map(|n| recipients(n)).collect();
+        if recipients.iter().any(|r| is_followers_collection(r)) {
+            return Self::FollowersOnly;
+        }
+
+        let mentioned = mentions(object);
+        if !recipients.is_empty() && recipients.iter().all(|r| mentioned.contains(r)) {
+            return Self::Direct;
+        }
+
+        Self::Limited
+    }
+
+    /// Whether the activity is only visible to specific recipients
+    pub fn is_private(&self) -> bool {
+        matches!(self, Self::Limited | Self::Direct)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Unlisted => "unlisted",
+            Self::FollowersOnly => "followers_only",
+            Self::Limited => "limited",
+            Self::Direct => "direct",
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether an activity (or its object) is addressed to the public collection in to or cc.
+pub fn is_public(activity: &Value) -> bool {
+    [activity, &activity["object"]]
+        .iter()
+        .any(|n| addresses_public(n, &["to", "cc"]))
+}
+
+/// Whether a node has any addressing of its own, as opposed to relying on that of the
+/// activity it is embedded in.
+pub fn is_addressed(node: &Value) -> bool {
+    node["directMessage"] == true || !recipients(node).is_empty()
+}
+
+// Addressing may use the full IRI of the public collection or one of its compact forms
+fn is_public_iri(iri: &str) -> bool {
+    matches!(iri, PUBLIC | "as:Public" | "Public")
+}
+
+fn addresses_public(node: &Value, fields: &[&str]) -> bool {
+    fields.iter().flat_map(|f| ids(&node[f])).any(is_public_iri)
+}
+
+fn recipients(node: &Value) -> Vec<&str> {
+    RECIPIENT_FIELDS
+        .iter()
+        .flat_map(|f| ids(&node[f]))
+        .collect()
+}
+
+// There is no way to tell that a collection is someone's followers without fetching
+// their actor, but every implementation we know of names it this way
+fn is_followers_collection(iri: &str) -> bool {
+    iri.trim_end_matches('/').ends_with("/followers")
+}
+
+fn mentions(object: &Value) -> Vec<&str> {
+    match object["tag"].as_array() {
+        Some(tags) => tags
+            .iter()
+            .filter(|t| t["type"] == "Mention")
+            .filter_map(|t| t["href"].as_str())
+            .collect(),
+        None => vec![],
+    }
+}
+
+// Addressing can be a single id, an embedded object with an id, or an array of either
+fn ids(val: &Value) -> Vec<&str> {
+    fn id(v: &Value) -> Option<&str> {
+        v.as_str().or_else(|| v["id"].as_str())
+    }
+
+    match val {
+        Value::Array(arr) => arr.iter().filter_map(id).collect(),
+        v => id(v).into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use simple_test_case::test_case;
+
+    #[test_case(json!({ "to": ["https://www.w3.org/ns/activitystreams#Public"] }), true; "public")]
+    #[test_case(json!({ "to": [], "cc": "as:Public" }), true; "unlisted compact")]
+    #[test_case(json!({ "object": { "to": ["Public"] } }), true; "public object")]
+    #[test_case(json!({ "to": ["https://a.example/users/alice/followers"] }), false; "followers only")]
+    #[test_case(json!({ "to": ["https://b.example/users/bob"] }), false; "direct")]
+    #[test]
+    fn is_public_works(activity: Value, expected: bool) {
+        assert_eq!(is_public(&activity), expected);
+    }
+
+    #[test_case(json!({ "to": [PUBLIC], "cc": ["https://a.example/users/alice/followers"] }), Visibility::Public; "public")]
+    #[test_case(json!({ "to": ["https://a.example/users/alice/followers"], "cc": [PUBLIC] }), Visibility::Unlisted; "unlisted")]
+    #[test_case(json!({ "to": ["https://a.example/users/alice/followers"] }), Visibility::FollowersOnly; "followers only")]
+    #[test_case(
+        json!({
+            "to": ["https://b.example/users/bob"],
+            "object": { "to": ["https://b.example/users/bob"], "tag": [{ "type": "Mention", "href": "https://b.example/users/bob" }] }
+        }),
+        Visibility::Direct;
+        "mentions only"
+    )]
+    #[test_case(json!({ "to": [PUBLIC], "object": { "directMessage": true } }), Visibility::Direct; "flagged as direct")]
+    #[test_case(json!({ "to": ["https://b.example/users/bob", "https://c.example/users/carol"] }), Visibility::Limited; "limited")]
+    #[test_case(json!({ "object": "https://a.example/notes/1" }), Visibility::Limited; "unaddressed")]
+    #[test]
+    fn visibility_is_determined_from_addressing(activity: Value, expected: Visibility) {
+        assert_eq!(Visibility::of(&activity), expected);
+    }
+}
@@ -5,8 +5,10 @@ use simple_test_case::test_case;
 #[test_case(".well-known/webfinger?resource=acct:relay@127.0.0.1:4242"; "webfinger")]
 #[test_case(".well-known/nodeinfo"; "well known node info")]
 #[test_case(".well-known/host-meta"; "host meta")]
+#[test_case(".well-known/host-meta.json"; "host meta json")]
 #[test_case("nodeinfo/2.0"; "node info")]
 #[test_case("actor"; "actor")]
+#[test_case("capabilities"; "capabilities")]
 #[cfg_attr(not(feature = "need_local_server"), ignore)]
 #[tokio::test]
 async fn happy_path_get(uri: &str) -> anyhow::Result<()> {